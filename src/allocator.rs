@@ -1,5 +1,6 @@
 // #[alloc_error_handler] attribute specifies a function that is called when an allocation
 // error occurs, similar to how our panic handler is called when a panic occurs.
+// Before it gets there, a failed allocation runs the shrinkers and is retried once.
 
 use x86_64::{
     structures::paging::{
@@ -12,7 +13,12 @@ use x86_64::{
     },
     VirtAddr,
 };
-use linked_list_allocator::LockedHeap;
+use core::{
+    alloc::{GlobalAlloc, Layout},
+    ptr::{self, NonNull},
+};
+use linked_list_allocator::Heap;
+use crate::{shrinker, sync::Mutex};
 
 /// The #[global_allocator] attribute tells the Rust compiler which allocator instance
 /// it should use as the global heap allocator. The attribute is only applicable to
@@ -20,16 +26,93 @@ use linked_list_allocator::LockedHeap;
 /// Since the Dummy allocator is a zero sized type, we don’t need to specify any
 /// fields in the initialization expression.
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: Allocator = Allocator(Mutex::new(Heap::empty()));
+
+// The heap, with the shrinkers run when it is low or out of memory.
+struct Allocator(Mutex<Heap>);
+
+impl Allocator {
+    fn try_alloc(&self, layout: Layout) -> Option<*mut u8> {
+        let mut heap = self.0.lock();
+        let ptr = heap.allocate_first_fit(layout).ok()?;
+        let free = heap.free();
+        // the shrinkers may allocate, don't hold the heap when they run.
+        drop(heap);
+        shrinker::check_heap(free);
+        Some(ptr.as_ptr())
+    }
+}
+
+unsafe impl GlobalAlloc for Allocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if let Some(ptr) = self.try_alloc(layout) {
+            return ptr;
+        }
+        if shrinker::shrink(layout.size()) == 0 {
+            return ptr::null_mut();
+        }
+        self.try_alloc(layout).unwrap_or(ptr::null_mut())
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        // GlobalAlloc only hands back pointers that alloc returned, which are never null.
+        unsafe { self.0.lock().deallocate(NonNull::new_unchecked(ptr), layout) }
+    }
+}
 
 pub const HEAP_START: usize = 0x4444_4444_000;
 // 100KiB, if we need more space in the future, we can increase it.
 pub const HEAP_SIZE: usize = 100 * 1024;
+// An unmapped page right below and right above the heap, so that accesses just past
+// the heap fault at the boundary instead of landing in whatever is mapped next to it.
+const GUARD_PAGE_SIZE: u64 = 4096;
+
+/// A snapshot of the heap usage in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    pub size: usize,
+    pub used: usize,
+    pub free: usize,
+}
+
+/// Returns the current heap usage, or `None` if the heap is locked, which happens
+/// when the caller interrupted (or panicked inside) an allocation.
+pub fn stats() -> Option<HeapStats> {
+    ALLOCATOR.0.try_lock().map(|heap| HeapStats {
+        size: heap.size(),
+        used: heap.used(),
+        free: heap.free(),
+    })
+}
+
+/// Returns whether the address lies in one of the guard pages around the heap.
+pub fn is_guard_page(addr: VirtAddr) -> bool {
+    let heap_start = HEAP_START as u64;
+    let heap_end = heap_start + HEAP_SIZE as u64;
+    let addr = addr.as_u64();
+    (heap_start - GUARD_PAGE_SIZE..heap_start).contains(&addr)
+        || (heap_end..heap_end + GUARD_PAGE_SIZE).contains(&addr)
+}
 
 pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
     frame_allocator: &mut impl FrameAllocator<Size4KiB>,
 ) -> Result<(), MapToError<Size4KiB>> {
+    // the guard pages only line up with the heap boundaries if the heap is page aligned.
+    const _: () = assert!(
+        (HEAP_START as u64).is_multiple_of(GUARD_PAGE_SIZE)
+            && (HEAP_SIZE as u64).is_multiple_of(GUARD_PAGE_SIZE)
+    );
+
+    // make sure nothing is mapped in the guard pages. Not being mapped is the expected case,
+    // a frame that was mapped there is leaked, the boot frame allocator can't free frames.
+    for guard in [HEAP_START as u64 - GUARD_PAGE_SIZE, (HEAP_START + HEAP_SIZE) as u64] {
+        let page: Page<Size4KiB> = Page::containing_address(VirtAddr::new(guard));
+        if let Ok((_, flush)) = mapper.unmap(page) {
+            flush.flush();
+        }
+    }
+
     let page_range = {
         // convert the HEAP_START pointer to a VirtAddr type.
         let heap_start = VirtAddr::new(HEAP_START as u64);
@@ -56,7 +139,7 @@ pub fn init_heap(
 
     // initialize the allocator after creating the heap
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        ALLOCATOR.0.lock().init(HEAP_START, HEAP_SIZE);
     }
 
     Ok(())
@@ -0,0 +1,191 @@
+// Minimal driver for the local APIC.
+//
+// The legacy 8259 PIC still delivers the timer and keyboard interrupts, the local APIC
+// is only used for the interrupt sources the PIC doesn't have, like the performance
+// counter overflow that drives the NMI watchdog. The APIC registers are memory mapped
+// at the physical address found in the IA32_APIC_BASE MSR, we map that frame to a
+// fixed virtual address (like the heap) and access the registers through volatile reads
+// and writes. The base is kept in an atomic instead of a Mutex, because the registers
+// are also accessed from the NMI handler which can interrupt any lock holder.
+//
+// When CPUID advertises x2APIC, `init` switches the APIC to x2APIC mode instead, where
+// the registers are MSRs and nothing needs to be mapped. Some hypervisors only offer this
+// mode. `read` and `write` take the same offsets in both modes, the register at offset
+// `reg` is the MSR 0x800 + reg / 16. The ID register and the ICR differ between the
+// modes, `id` and `send_ipi` hide that.
+
+use core::{
+    arch::x86_64::__cpuid,
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+use x86_64::{
+    registers::model_specific::Msr,
+    structures::paging::{
+        mapper::MapToError,
+        FrameAllocator,
+        Mapper,
+        Page,
+        PageTableFlags,
+        PhysFrame,
+        Size4KiB,
+    },
+    PhysAddr,
+    VirtAddr,
+};
+
+pub const LAPIC_START: usize = 0x4444_5555_0000;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ADDR_MASK: u64 = 0xf_ffff_f000;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+const CPUID_X2APIC: u32 = 1 << 21;
+const X2APIC_MSR_BASE: u32 = 0x800;
+
+// register offsets, relative to the APIC base address.
+pub const ID: usize = 0x20;
+pub const EOI: usize = 0xb0;
+pub const SPURIOUS_VECTOR: usize = 0xf0;
+pub const ICR_LOW: usize = 0x300;
+pub const ICR_HIGH: usize = 0x310;
+pub const LVT_TIMER: usize = 0x320;
+pub const LVT_PERF: usize = 0x340;
+pub const LVT_LINT0: usize = 0x350;
+pub const LVT_LINT1: usize = 0x360;
+
+// bits of the local vector table entries.
+pub const LVT_MASKED: u32 = 1 << 16;
+pub const DELIVERY_NMI: u32 = 0b100 << 8;
+pub const DELIVERY_EXTINT: u32 = 0b111 << 8;
+
+// xAPIC only, the ICR of the x2APIC has no delivery status.
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+
+const APIC_SOFTWARE_ENABLE: u32 = 1 << 8;
+const SPURIOUS_INTERRUPT_VECTOR: u32 = 0xff;
+
+// virtual base address of the APIC registers, 0 until `init` mapped them.
+static BASE: AtomicU64 = AtomicU64::new(0);
+// set by `init` once the APIC runs in x2APIC mode.
+static X2APIC: AtomicBool = AtomicBool::new(false);
+
+/// Returns whether the CPU supports x2APIC mode.
+pub fn has_x2apic() -> bool {
+    __cpuid(1).ecx & CPUID_X2APIC != 0
+}
+
+/// Returns whether `init` switched the APIC to x2APIC mode.
+pub fn is_x2apic() -> bool {
+    X2APIC.load(Ordering::SeqCst)
+}
+
+/// Maps the local APIC registers, or switches to x2APIC mode where the CPU supports it,
+/// and software-enables the APIC.
+///
+/// LINT0 is configured for external interrupts and LINT1 for NMIs (the "virtual wire"
+/// mode set up by the BIOS), so the 8259 PIC keeps delivering its interrupts.
+pub fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let mut apic_base = Msr::new(IA32_APIC_BASE);
+    if has_x2apic() {
+        // xAPIC to x2APIC is a valid transition, the register contents are kept.
+        unsafe {
+            let value = apic_base.read();
+            apic_base.write(value | APIC_BASE_ENABLE | APIC_BASE_X2APIC);
+        }
+        X2APIC.store(true, Ordering::SeqCst);
+    } else {
+        let phys = unsafe { apic_base.read() } & APIC_BASE_ADDR_MASK;
+        let frame = PhysFrame::<Size4KiB>::containing_address(PhysAddr::new(phys));
+        let page = Page::containing_address(VirtAddr::new(LAPIC_START as u64));
+
+        // the registers must not be cached, every read and write has to reach the APIC.
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+        unsafe {
+            mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+        }
+        BASE.store(LAPIC_START as u64, Ordering::SeqCst);
+    }
+
+    unsafe {
+        write(LVT_LINT0, DELIVERY_EXTINT);
+        write(LVT_LINT1, DELIVERY_NMI);
+        write(SPURIOUS_VECTOR, APIC_SOFTWARE_ENABLE | SPURIOUS_INTERRUPT_VECTOR);
+    }
+
+    Ok(())
+}
+
+/// Returns whether `init` mapped the APIC registers or switched to x2APIC mode.
+pub fn is_initialized() -> bool {
+    BASE.load(Ordering::SeqCst) != 0 || is_x2apic()
+}
+
+/// Reads the APIC register at the given offset.
+///
+/// # Safety
+///
+/// The APIC must have been initialized and `reg` must be a valid register offset.
+pub unsafe fn read(reg: usize) -> u32 {
+    if is_x2apic() {
+        // the registers that read_volatile would access are 32 bits wide.
+        return Msr::new(x2apic_msr(reg)).read() as u32;
+    }
+    let base = BASE.load(Ordering::SeqCst) as usize;
+    core::ptr::read_volatile((base + reg) as *const u32)
+}
+
+/// Writes the APIC register at the given offset.
+///
+/// # Safety
+///
+/// The APIC must have been initialized and `reg` must be a valid register offset.
+/// Writing the local vector table can redirect or mask interrupts.
+pub unsafe fn write(reg: usize, value: u32) {
+    if is_x2apic() {
+        Msr::new(x2apic_msr(reg)).write(u64::from(value));
+        return;
+    }
+    let base = BASE.load(Ordering::SeqCst) as usize;
+    core::ptr::write_volatile((base + reg) as *mut u32, value);
+}
+
+fn x2apic_msr(reg: usize) -> u32 {
+    X2APIC_MSR_BASE + (reg >> 4) as u32
+}
+
+/// Returns the APIC ID of the CPU we run on.
+///
+/// # Safety
+///
+/// The APIC must have been initialized.
+pub unsafe fn id() -> u32 {
+    // the xAPIC has an 8 bit ID in the top byte, the x2APIC a 32 bit one.
+    if is_x2apic() {
+        read(ID)
+    } else {
+        read(ID) >> 24
+    }
+}
+
+/// Sends the interrupt described by the low half of the ICR to the APIC with the ID
+/// `destination`, after the previous one was delivered.
+///
+/// # Safety
+///
+/// The APIC must have been initialized, and interrupts must be disabled, an interrupt
+/// handler that sends an IPI must not get between the two ICR writes of the xAPIC.
+pub unsafe fn send_ipi(destination: u32, command: u32) {
+    if is_x2apic() {
+        // the x2APIC's ICR is a single 64 bit MSR, written at once.
+        Msr::new(x2apic_msr(ICR_LOW)).write(u64::from(destination) << 32 | u64::from(command));
+        return;
+    }
+    while read(ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+        core::hint::spin_loop();
+    }
+    write(ICR_HIGH, destination << 24);
+    write(ICR_LOW, command);
+}
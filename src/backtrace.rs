@@ -0,0 +1,95 @@
+// Best-effort stack backtraces by walking the frame pointer chain.
+//
+// The kernel is built with `-C force-frame-pointers=yes` (see .cargo/config.toml), so
+// every function starts by pushing the caller's rbp and then sets rbp to its own frame.
+// Starting from the current rbp we can therefore follow the saved rbp values up the call
+// chain and find the return address stored right above each of them. Every frame is
+// checked with `memory::translate` before it is read, which keeps a corrupted chain from
+// faulting inside a fault handler.
+
+use core::{arch::asm, fmt};
+use x86_64::VirtAddr;
+use crate::memory;
+
+pub const MAX_FRAMES: usize = 16;
+
+/// The return addresses of up to MAX_FRAMES stack frames, innermost first.
+#[derive(Debug, Clone, Copy)]
+pub struct Backtrace {
+    frames: [u64; MAX_FRAMES],
+    len: usize,
+}
+
+impl Backtrace {
+    /// Captures a backtrace of the calling code.
+    #[inline(always)]
+    pub fn capture() -> Self {
+        let rbp: u64;
+        unsafe { asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+        Self::from_frame_pointer(rbp)
+    }
+
+    /// Walks the frame pointer chain starting at the given rbp value.
+    pub fn from_frame_pointer(rbp: u64) -> Self {
+        let mut backtrace = Backtrace { frames: [0; MAX_FRAMES], len: 0 };
+        backtrace.walk(rbp);
+        backtrace
+    }
+
+    /// The backtrace of interrupted code, from the rip and rbp saved in its trap frame. The
+    /// interrupted instruction is the first frame.
+    pub fn from_registers(rip: u64, rbp: u64) -> Self {
+        let mut backtrace = Backtrace { frames: [0; MAX_FRAMES], len: 1 };
+        backtrace.frames[0] = rip;
+        backtrace.walk(rbp);
+        backtrace
+    }
+
+    fn walk(&mut self, mut rbp: u64) {
+        while self.len < MAX_FRAMES && is_readable_frame(rbp) {
+            // [rbp] holds the caller's rbp and [rbp + 8] the return address.
+            let (caller_rbp, return_address) = unsafe {
+                let frame = rbp as *const u64;
+                (frame.read(), frame.add(1).read())
+            };
+            if return_address == 0 {
+                break;
+            }
+            self.frames[self.len] = return_address;
+            self.len += 1;
+
+            // the stack grows down, so the callers' frames are at higher addresses.
+            if caller_rbp <= rbp {
+                break;
+            }
+            rbp = caller_rbp;
+        }
+    }
+
+    pub fn frames(&self) -> &[u64] {
+        &self.frames[..self.len]
+    }
+}
+
+fn is_readable_frame(rbp: u64) -> bool {
+    if rbp == 0 || !rbp.is_multiple_of(8) {
+        return false;
+    }
+    // both the saved rbp and the return address have to be mapped, they may
+    // straddle a page boundary.
+    match (VirtAddr::try_new(rbp), VirtAddr::try_new(rbp + 15)) {
+        (Ok(start), Ok(end)) => memory::translate(start).is_some() && memory::translate(end).is_some(),
+        _ => false,
+    }
+}
+
+impl fmt::Display for Backtrace {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Backtrace:")?;
+        for (i, address) in self.frames().iter().enumerate() {
+            writeln!(f, "  #{:<2} {:#018x}", i, address)?;
+        }
+        Ok(())
+    }
+}
+
@@ -0,0 +1,76 @@
+// Cycle-count benchmarks.
+//
+// `run` times a closure that performs a number of operations with the TSC, and keeps the
+// fastest of `ROUNDS` runs, which leaves out the runs that were hit by a timer interrupt.
+// The TSC reads are fenced with lfence, so the measured code can't move across them. The
+// TSC counts at a constant rate, not in core cycles, and under QEMU without KVM it is the
+// host's, so the numbers are good for comparing the runs of one boot, not across machines.
+//
+// The benchmarks themselves are integration tests, e.g. tests/allocator_bench.rs, that
+// print their results over serial.
+
+use core::{
+    arch::x86_64::{_mm_lfence, _rdtsc},
+    fmt,
+};
+
+/// How often `run` repeats the closure.
+pub const ROUNDS: usize = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BenchResult {
+    pub name: &'static str,
+    /// Operations per round.
+    pub operations: u64,
+    /// Cycles of the fastest round.
+    pub cycles: u64,
+}
+
+impl BenchResult {
+    pub fn cycles_per_operation(&self) -> u64 {
+        self.cycles / self.operations.max(1)
+    }
+}
+
+impl fmt::Display for BenchResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:<32} {:>8} cycles/op ({} ops)", self.name, self.cycles_per_operation(), self.operations)
+    }
+}
+
+/// Reads the TSC, after everything before has completed.
+pub fn cycles() -> u64 {
+    unsafe {
+        _mm_lfence();
+        let tsc = _rdtsc();
+        _mm_lfence();
+        tsc
+    }
+}
+
+/// Times `f`, which performs `operations` operations per call, over `ROUNDS` calls.
+pub fn run(name: &'static str, operations: u64, mut f: impl FnMut()) -> BenchResult {
+    let mut fastest = u64::MAX;
+    for _ in 0..ROUNDS {
+        let start = cycles();
+        f();
+        fastest = fastest.min(cycles().saturating_sub(start));
+    }
+    BenchResult { name, operations, cycles: fastest }
+}
+
+#[test_case]
+fn test_run_keeps_the_fastest_round() {
+    let mut round = 0;
+    let result = run("spin", 10, || {
+        round += 1;
+        // only the first round is slow.
+        let start = cycles();
+        while round == 1 && cycles() - start < 1_000_000 {
+            core::hint::spin_loop();
+        }
+    });
+    assert_eq!(round, ROUNDS);
+    assert!(result.cycles < 1_000_000);
+    assert_eq!(result.cycles_per_operation(), result.cycles / 10);
+}
@@ -0,0 +1,275 @@
+// Boot information, independent of the bootloader.
+//
+// Each bootloader version hands over its own BootInfo type: bootloader 0.9 gives the
+// memory map with its own region types and the offset of the physical memory mapping, and
+// always boots through BIOS, with the VGA text buffer. bootloader_api 0.11 describes memory
+// regions differently and gives a framebuffer instead. The kernel only ever sees `BootData`,
+// built by an adapter at the entry point, so `memory`, `memtest` and the VGA writer don't
+// depend on which bootloader started us.
+//
+// Only the adapter for bootloader 0.9, which the kernel is built with, exists so far. An
+// adapter for bootloader_api fills `BootData` the same way, with `text_buffer` left `None`:
+// the writer then stays uninitialized and the output goes to serial only.
+//
+// A memory map that marks memory in use as usable, or two regions that overlap, only shows
+// up much later, when the frame allocator hands out a frame that something else writes to.
+// `validate` checks the map before the frame allocator is built from it.
+
+use bootloader::bootinfo::{BootInfo, MemoryRegionType};
+use core::fmt;
+use x86_64::{
+    structures::paging::{mapper::Translate, PhysFrame},
+    PhysAddr, VirtAddr,
+};
+
+/// Maximum number of memory regions, the memory map of bootloader 0.9 has as many entries.
+pub const MAX_MEMORY_REGIONS: usize = 64;
+
+/// Physical address of the VGA text buffer.
+pub const VGA_TEXT_BUFFER: u64 = 0xb8000;
+
+const FRAME_SIZE: u64 = 4096;
+
+/// What a region of physical memory is used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryKind {
+    /// Free RAM, for the frame allocator.
+    Usable,
+    /// The kernel image.
+    Kernel,
+    /// The page tables set up by the bootloader.
+    PageTable,
+    /// RAM in use by the bootloader, the boot information, the kernel stack or the firmware.
+    InUse,
+    /// Memory that isn't RAM, or can't be used as RAM.
+    Unavailable,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryRegion {
+    pub start: PhysAddr,
+    /// The first address after the region.
+    pub end: PhysAddr,
+    pub kind: MemoryKind,
+}
+
+impl MemoryRegion {
+    pub fn size(&self) -> u64 {
+        self.end - self.start
+    }
+
+    pub fn contains(&self, address: PhysAddr) -> bool {
+        (self.start..self.end).contains(&address)
+    }
+}
+
+impl fmt::Display for MemoryRegion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#012x}..{:#012x} {:?}", self.start.as_u64(), self.end.as_u64(), self.kind)
+    }
+}
+
+/// All `MAX_MEMORY_REGIONS` entries are in use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TooManyRegions;
+
+/// A memory map the frame allocator can't be trusted with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemoryMapError {
+    /// The region ends before it starts.
+    InvalidRegion(MemoryRegion),
+    /// A usable region overlaps another region.
+    Overlap(MemoryRegion, MemoryRegion),
+    /// Memory in use by the kernel or the bootloader lies in a usable region.
+    InUseMarkedUsable { what: &'static str, address: PhysAddr },
+}
+
+impl fmt::Display for MemoryMapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MemoryMapError::InvalidRegion(region) => write!(f, "region {} ends before it starts", region),
+            MemoryMapError::Overlap(a, b) => write!(f, "region {} overlaps {}", a, b),
+            MemoryMapError::InUseMarkedUsable { what, address } => {
+                write!(f, "the {} at {:#x} is in usable memory", what, address.as_u64())
+            }
+        }
+    }
+}
+
+/// What the kernel needs to know from the bootloader.
+#[derive(Clone)]
+pub struct BootData {
+    /// The virtual address at which the complete physical memory is mapped.
+    pub physical_memory_offset: VirtAddr,
+    /// The virtual address of the VGA text buffer, `None` if the machine has none.
+    pub text_buffer: Option<VirtAddr>,
+    /// The virtual address of the bootloader's own boot information, which must stay intact.
+    pub boot_info: Option<VirtAddr>,
+    regions: [MemoryRegion; MAX_MEMORY_REGIONS],
+    len: usize,
+}
+
+impl BootData {
+    /// Creates boot data without memory regions.
+    pub fn new(physical_memory_offset: VirtAddr, text_buffer: Option<VirtAddr>) -> Self {
+        let empty = MemoryRegion {
+            start: PhysAddr::new(0),
+            end: PhysAddr::new(0),
+            kind: MemoryKind::Unavailable,
+        };
+        BootData {
+            physical_memory_offset,
+            text_buffer,
+            boot_info: None,
+            regions: [empty; MAX_MEMORY_REGIONS],
+            len: 0,
+        }
+    }
+
+    /// Adds a memory region, called by the adapters in the order of the bootloader's map.
+    pub fn add_region(&mut self, region: MemoryRegion) -> Result<(), TooManyRegions> {
+        let slot = self.regions.get_mut(self.len).ok_or(TooManyRegions)?;
+        *slot = region;
+        self.len += 1;
+        Ok(())
+    }
+
+    pub fn memory_regions(&self) -> &[MemoryRegion] {
+        &self.regions[..self.len]
+    }
+
+    /// Returns the frames of the usable regions, in the order of the memory map.
+    pub fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> + '_ {
+        self.memory_regions().iter()
+            .filter(|r| r.kind == MemoryKind::Usable)
+            .flat_map(|r| (r.start.as_u64()..r.end.as_u64()).step_by(FRAME_SIZE as usize))
+            .map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+    }
+
+    /// Returns the total size of the regions of the given kind, in bytes.
+    pub fn size_of(&self, kind: MemoryKind) -> u64 {
+        self.memory_regions().iter().filter(|r| r.kind == kind).map(MemoryRegion::size).sum()
+    }
+
+    /// Checks the memory map, and that the kernel image, the kernel stack and the boot
+    /// information, found through `mapper`, aren't in usable memory.
+    pub fn validate(&self, mapper: &impl Translate) -> Result<(), MemoryMapError> {
+        self.validate_regions()?;
+        // any function and any local variable will do to find the kernel image and stack.
+        let code: fn(&BootData) -> Result<(), MemoryMapError> = BootData::validate_regions;
+        let stack_variable = 0u8;
+        let in_use = [
+            ("kernel image", Some(VirtAddr::new(code as usize as u64))),
+            ("kernel stack", Some(VirtAddr::from_ptr(&stack_variable))),
+            ("boot information", self.boot_info),
+        ];
+        for (what, address) in in_use {
+            if let Some(address) = address.and_then(|address| mapper.translate_addr(address)) {
+                self.check_not_usable(what, address)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that the regions are valid and that no usable region overlaps another one.
+    /// Overlapping regions that are both unusable don't matter to the frame allocator.
+    pub fn validate_regions(&self) -> Result<(), MemoryMapError> {
+        if let Some(&region) = self.memory_regions().iter().find(|r| r.end < r.start) {
+            return Err(MemoryMapError::InvalidRegion(region));
+        }
+        let mut sorted = self.regions;
+        let sorted = &mut sorted[..self.len];
+        sorted.sort_unstable_by_key(|r| r.start);
+        // a usable region that overlaps a later one may reach past the next few regions.
+        for (i, &region) in sorted.iter().enumerate() {
+            let overlapping = sorted[i + 1..].iter()
+                .take_while(|next| next.start < region.end)
+                .find(|next| region.kind == MemoryKind::Usable || next.kind == MemoryKind::Usable);
+            if let Some(&next) = overlapping {
+                return Err(MemoryMapError::Overlap(region, next));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that `address`, in use for `what`, isn't in a usable region.
+    pub fn check_not_usable(&self, what: &'static str, address: PhysAddr) -> Result<(), MemoryMapError> {
+        let usable = self.memory_regions().iter().any(|r| r.kind == MemoryKind::Usable && r.contains(address));
+        if usable {
+            return Err(MemoryMapError::InUseMarkedUsable { what, address });
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for BootData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("BootData")
+            .field("physical_memory_offset", &self.physical_memory_offset)
+            .field("text_buffer", &self.text_buffer)
+            .field("memory_regions", &self.memory_regions())
+            .finish()
+    }
+}
+
+impl From<&BootInfo> for BootData {
+    fn from(boot_info: &BootInfo) -> Self {
+        let offset = VirtAddr::new(boot_info.physical_memory_offset);
+        // bootloader 0.9 maps all of physical memory, the text buffer included.
+        let mut data = BootData::new(offset, Some(offset + VGA_TEXT_BUFFER));
+        data.boot_info = Some(VirtAddr::from_ptr(boot_info));
+        for region in boot_info.memory_map.iter() {
+            let kind = match region.region_type {
+                MemoryRegionType::Usable => MemoryKind::Usable,
+                MemoryRegionType::Kernel => MemoryKind::Kernel,
+                MemoryRegionType::PageTable => MemoryKind::PageTable,
+                MemoryRegionType::Reserved | MemoryRegionType::AcpiNvs
+                | MemoryRegionType::BadMemory | MemoryRegionType::Empty => MemoryKind::Unavailable,
+                _ => MemoryKind::InUse,
+            };
+            // the memory map has at most MAX_MEMORY_REGIONS entries.
+            let _ = data.add_region(MemoryRegion {
+                start: PhysAddr::new(region.range.start_addr()),
+                end: PhysAddr::new(region.range.end_addr()),
+                kind,
+            });
+        }
+        data
+    }
+}
+
+#[test_case]
+fn test_usable_frames() {
+    let mut data = BootData::new(VirtAddr::new(0), None);
+    let region = |start, end, kind| MemoryRegion { start: PhysAddr::new(start), end: PhysAddr::new(end), kind };
+    data.add_region(region(0, 0x1000, MemoryKind::InUse)).unwrap();
+    data.add_region(region(0x1000, 0x3000, MemoryKind::Usable)).unwrap();
+    data.add_region(region(0x3000, 0x4000, MemoryKind::Kernel)).unwrap();
+    data.add_region(region(0x8000, 0x9000, MemoryKind::Usable)).unwrap();
+    let mut frames = data.usable_frames().map(|frame| frame.start_address().as_u64());
+    assert_eq!(frames.next(), Some(0x1000));
+    assert_eq!(frames.next(), Some(0x2000));
+    assert_eq!(frames.next(), Some(0x8000));
+    assert_eq!(frames.next(), None);
+    assert_eq!(data.size_of(MemoryKind::Usable), 0x3000);
+}
+
+#[test_case]
+fn test_validate_regions() {
+    let region = |start, end, kind| MemoryRegion { start: PhysAddr::new(start), end: PhysAddr::new(end), kind };
+    let mut data = BootData::new(VirtAddr::new(0), None);
+    data.add_region(region(0x4000, 0x8000, MemoryKind::Usable)).unwrap();
+    data.add_region(region(0, 0x1000, MemoryKind::Unavailable)).unwrap();
+    // unusable regions may overlap each other.
+    data.add_region(region(0x800, 0x2000, MemoryKind::InUse)).unwrap();
+    assert_eq!(data.validate_regions(), Ok(()));
+    assert_eq!(data.check_not_usable("test", PhysAddr::new(0x3fff)), Ok(()));
+    assert_eq!(
+        data.check_not_usable("test", PhysAddr::new(0x4000)),
+        Err(MemoryMapError::InUseMarkedUsable { what: "test", address: PhysAddr::new(0x4000) }),
+    );
+
+    let kernel = region(0x7000, 0x9000, MemoryKind::Kernel);
+    data.add_region(kernel).unwrap();
+    assert_eq!(data.validate_regions(), Err(MemoryMapError::Overlap(region(0x4000, 0x8000, MemoryKind::Usable), kernel)));
+}
@@ -0,0 +1,109 @@
+// Allocation-free collections.
+//
+// `SpscQueue` is a fixed-capacity ring for handing values from one producer to one
+// consumer without a lock, e.g. from an interrupt handler to the task that processes the
+// values. The producer only writes `tail` and the consumer only writes `head`, each reads
+// the other's index to know how far it may go. Both count up forever and are taken modulo
+// the capacity, so a full queue and an empty one can be told apart without a flag.
+//
+// Nothing stops two producers or two consumers from using a queue at the same time, the
+// users have to make sure there is only one of each. A producer running both in an
+// interrupt handler and in a task, for example, has to push with interrupts disabled.
+// That is why the trace buffers don't use it: every context on a CPU records events, and
+// they overwrite the oldest events instead of failing when the ring is full.
+
+use core::{
+    cell::UnsafeCell,
+    mem::MaybeUninit,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+pub struct SpscQueue<T, const N: usize> {
+    // number of values ever popped, written by the consumer.
+    head: AtomicUsize,
+    // number of values ever pushed, written by the producer.
+    tail: AtomicUsize,
+    slots: [UnsafeCell<MaybeUninit<T>>; N],
+}
+
+// a slot is only accessed by the producer before it's published with `tail`, and by the
+// consumer after that, until it's handed back with `head`.
+unsafe impl<T: Send, const N: usize> Sync for SpscQueue<T, N> {}
+
+impl<T, const N: usize> SpscQueue<T, N> {
+    /// Creates an empty queue, so it can be a static.
+    pub const fn new() -> Self {
+        // with no slots, a queue would be full and empty at once.
+        const { assert!(N > 0, "an SpscQueue needs at least one slot") };
+        SpscQueue {
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            slots: [const { UnsafeCell::new(MaybeUninit::uninit()) }; N],
+        }
+    }
+
+    /// Appends a value, or hands it back if the queue is full. Only called by the producer.
+    pub fn push(&self, value: T) -> Result<(), T> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        if tail.wrapping_sub(self.head.load(Ordering::Acquire)) == N {
+            return Err(value);
+        }
+        unsafe { (*self.slots[tail % N].get()).write(value) };
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+
+    /// Takes the oldest value. Only called by the consumer.
+    pub fn pop(&self) -> Option<T> {
+        let head = self.head.load(Ordering::Relaxed);
+        if head == self.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let value = unsafe { (*self.slots[head % N].get()).assume_init_read() };
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(value)
+    }
+
+    /// Number of values in the queue, it may change as soon as it's read.
+    pub fn len(&self) -> usize {
+        let head = self.head.load(Ordering::Acquire);
+        self.tail.load(Ordering::Acquire).wrapping_sub(head)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+}
+
+impl<T, const N: usize> Default for SpscQueue<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Drop for SpscQueue<T, N> {
+    fn drop(&mut self) {
+        while self.pop().is_some() {}
+    }
+}
+
+#[test_case]
+fn test_spsc_queue_wraps_around() {
+    let queue = SpscQueue::<u8, 4>::new();
+    assert_eq!(queue.pop(), None);
+    for round in 0..3 {
+        for i in 0..4 {
+            assert_eq!(queue.push(round * 4 + i), Ok(()));
+        }
+        assert_eq!(queue.push(0xff), Err(0xff));
+        assert_eq!(queue.len(), 4);
+        for i in 0..4 {
+            assert_eq!(queue.pop(), Some(round * 4 + i));
+        }
+        assert!(queue.is_empty());
+    }
+}
@@ -0,0 +1,332 @@
+// Console output routing.
+//
+// `print!` and `println!` write to every enabled console sink. The VGA text buffer and the
+// first serial interface are registered from the start, other outputs, e.g. a framebuffer
+// console, implement `Console` and register themselves. Each sink can be switched on and
+// off at runtime, e.g. to keep a noisy screen out of a serial log.
+//
+// `serial_print!` still writes only to the serial interface, for output that is meant for
+// the host and would just clutter the screen. The `vga` and `serial-console` features
+// decide which of the two default sinks are there at all, `serial_print!` works without.
+//
+// Printing never waits for a lock. An exception or NMI handler may interrupt the very code
+// that holds the WRITER lock, spinning on it then deadlocks. Sinks that are busy instead
+// report `Busy` and the output is kept in a deferred buffer of that sink, which is written
+// out by the next print that finds the sink free, or by `flush` from task context. The
+// deferred buffers are claimed with an atomic flag and never waited for either: when a
+// nested context finds one claimed, its output is dropped and counted.
+
+use core::{
+    cell::UnsafeCell,
+    fmt::{self, Write},
+    str,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+};
+use x86_64::instructions::interrupts;
+use crate::sync::Mutex;
+#[cfg(feature = "serial-console")]
+use crate::serial::SERIAL1;
+#[cfg(feature = "vga")]
+use crate::vga_buffer::WRITER;
+
+pub const MAX_CONSOLES: usize = 8;
+
+/// Bytes of output that a busy sink can defer.
+pub const DEFERRED_SIZE: usize = 1024;
+
+/// The console device is in use, e.g. by the code an interrupt handler interrupted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Busy;
+
+/// An output device for the kernel console.
+pub trait Console: Sync {
+    /// Writes the string, the implementation does its own locking, but must
+    /// return `Busy` instead of waiting for a lock.
+    fn write_str(&self, s: &str) -> Result<(), Busy>;
+}
+
+#[cfg(feature = "vga")]
+pub struct VgaConsole;
+
+#[cfg(feature = "vga")]
+impl Console for VgaConsole {
+    fn write_str(&self, s: &str) -> Result<(), Busy> {
+        // before `vga_buffer::init` there is nothing to write to, nor to defer for.
+        if let Some(writer) = WRITER.try_lock().ok_or(Busy)?.as_mut() {
+            // writes to the VGA buffer never fail, see Writer::write_str.
+            let _ = writer.write_str(s);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "serial-console")]
+pub struct SerialConsole;
+
+#[cfg(feature = "serial-console")]
+impl Console for SerialConsole {
+    fn write_str(&self, s: &str) -> Result<(), Busy> {
+        SERIAL1.try_lock().ok_or(Busy)?.write_str(s).expect("Printing to serial failed");
+        Ok(())
+    }
+}
+
+/// Index of a registered console.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConsoleId(usize);
+
+impl ConsoleId {
+    pub const VGA: ConsoleId = ConsoleId(0);
+    pub const SERIAL: ConsoleId = ConsoleId(1);
+}
+
+/// All `MAX_CONSOLES` slots are in use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TooManyConsoles;
+
+#[derive(Clone, Copy)]
+struct Sink {
+    console: &'static dyn Console,
+    enabled: bool,
+}
+
+const fn default_sinks() -> [Option<Sink>; MAX_CONSOLES] {
+    // the slots of the compiled out sinks stay empty, `set_enabled` ignores them.
+    #[allow(unused_mut)]
+    let mut sinks = [None; MAX_CONSOLES];
+    #[cfg(feature = "vga")]
+    {
+        sinks[ConsoleId::VGA.0] = Some(Sink { console: &VgaConsole, enabled: true });
+    }
+    #[cfg(feature = "serial-console")]
+    {
+        sinks[ConsoleId::SERIAL.0] = Some(Sink { console: &SerialConsole, enabled: true });
+    }
+    sinks
+}
+
+static SINKS: Mutex<[Option<Sink>; MAX_CONSOLES]> = Mutex::new(default_sinks());
+
+/// Adds an output to the console, it is enabled right away.
+pub fn register(console: &'static dyn Console) -> Result<ConsoleId, TooManyConsoles> {
+    interrupts::without_interrupts(|| {
+        let mut sinks = SINKS.lock();
+        let index = sinks.iter().position(|sink| sink.is_none()).ok_or(TooManyConsoles)?;
+        sinks[index] = Some(Sink { console, enabled: true });
+        Ok(ConsoleId(index))
+    })
+}
+
+/// Removes a console output and discards the output deferred for it.
+pub fn unregister(id: ConsoleId) {
+    interrupts::without_interrupts(|| {
+        SINKS.lock()[id.0] = None;
+        DEFERRED[id.0].clear();
+    });
+}
+
+/// Switches a console output on or off.
+pub fn set_enabled(id: ConsoleId, enabled: bool) {
+    interrupts::without_interrupts(|| {
+        if let Some(sink) = SINKS.lock()[id.0].as_mut() {
+            sink.enabled = enabled;
+        }
+    });
+}
+
+// Output of a busy sink.
+struct Deferred {
+    claimed: AtomicBool,
+    len: UnsafeCell<usize>,
+    bytes: UnsafeCell<[u8; DEFERRED_SIZE]>,
+    dropped: AtomicUsize,
+}
+
+// the cells are only accessed by whoever claimed the buffer.
+unsafe impl Sync for Deferred {}
+
+impl Deferred {
+    const fn new() -> Self {
+        Deferred {
+            claimed: AtomicBool::new(false),
+            len: UnsafeCell::new(0),
+            bytes: UnsafeCell::new([0; DEFERRED_SIZE]),
+            dropped: AtomicUsize::new(0),
+        }
+    }
+
+    fn append(&self, s: &str) {
+        if self.claimed.swap(true, Ordering::Acquire) {
+            self.dropped.fetch_add(s.len(), Ordering::Relaxed);
+            return;
+        }
+        let (len, bytes) = unsafe { (&mut *self.len.get(), &mut *self.bytes.get()) };
+        let count = s.len().min(DEFERRED_SIZE - *len);
+        bytes[*len..*len + count].copy_from_slice(&s.as_bytes()[..count]);
+        *len += count;
+        self.dropped.fetch_add(s.len() - count, Ordering::Relaxed);
+        self.claimed.store(false, Ordering::Release);
+    }
+
+    // Discards the deferred output, unless a nested context claimed the buffer right now.
+    fn clear(&self) {
+        if self.claimed.swap(true, Ordering::Acquire) {
+            return;
+        }
+        unsafe { *self.len.get() = 0 };
+        self.dropped.store(0, Ordering::Relaxed);
+        self.claimed.store(false, Ordering::Release);
+    }
+
+    // Writes the deferred output to the console, returns false if it is still busy.
+    fn flush(&self, console: &dyn Console) -> bool {
+        if self.claimed.swap(true, Ordering::Acquire) {
+            return false;
+        }
+        let (len, bytes) = unsafe { (&mut *self.len.get(), &*self.bytes.get()) };
+        let mut flushed = true;
+        if *len > 0 {
+            // the buffer may end in the middle of a truncated character.
+            let text = match str::from_utf8(&bytes[..*len]) {
+                Ok(text) => text,
+                Err(err) => str::from_utf8(&bytes[..err.valid_up_to()]).unwrap_or(""),
+            };
+            flushed = console.write_str(text).is_ok();
+            if flushed {
+                *len = 0;
+            }
+        }
+        if flushed {
+            let dropped = self.dropped.swap(0, Ordering::Relaxed);
+            if dropped > 0 {
+                let _ = writeln!(SinkWriter { console, deferred: self }, "[{} bytes of output dropped]", dropped);
+            }
+        }
+        self.claimed.store(false, Ordering::Release);
+        flushed
+    }
+}
+
+static DEFERRED: [Deferred; MAX_CONSOLES] = [const { Deferred::new() }; MAX_CONSOLES];
+
+// Adapter to format straight into a sink, deferring the output while it is busy.
+struct SinkWriter<'a> {
+    console: &'a dyn Console,
+    deferred: &'a Deferred,
+}
+
+impl Write for SinkWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.console.write_str(s).is_err() {
+            self.deferred.append(s);
+        }
+        Ok(())
+    }
+}
+
+/// Writes out the output that was deferred while the sinks were busy.
+pub fn flush() {
+    interrupts::without_interrupts(|| {
+        let Some(sinks) = SINKS.try_lock().map(|sinks| *sinks) else {
+            return;
+        };
+        for (index, sink) in sinks.iter().enumerate() {
+            if let Some(sink) = sink.filter(|sink| sink.enabled) {
+                DEFERRED[index].flush(sink.console);
+            }
+        }
+    });
+}
+
+// A sink that is always busy, to append to a deferred buffer through SinkWriter.
+struct Deferring;
+
+impl Console for Deferring {
+    fn write_str(&self, _: &str) -> Result<(), Busy> {
+        Err(Busy)
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    // The without_interrupts function takes a closure and executes it in an interrupt-free
+    // environment. We use it to ensure that no interrupt can occur as long as a Mutex is locked.
+    // This helps avoid a deadlock from the interrupt handler trying to acquire the same lock.
+    interrupts::without_interrupts(|| {
+        // the sinks are copied, so the registry is only locked for a moment. If it is
+        // locked anyway, we interrupted a registration with an NMI or panicked inside
+        // one, the default sinks are the best bet then.
+        let sinks = match SINKS.try_lock() {
+            Some(sinks) => *sinks,
+            None => default_sinks(),
+        };
+        for (index, sink) in sinks.iter().enumerate() {
+            let Some(sink) = sink.filter(|sink| sink.enabled) else {
+                continue;
+            };
+            let deferred = &DEFERRED[index];
+            // keep the order: while older output is waiting, the new output waits too.
+            if deferred.flush(sink.console) {
+                // SinkWriter never fails, busy sinks defer the output.
+                let _ = SinkWriter { console: sink.console, deferred }.write_fmt(args);
+            } else {
+                let _ = SinkWriter { console: &Deferring, deferred }.write_fmt(args);
+            }
+        }
+    });
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::console::_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+}
+
+#[test_case]
+fn test_registered_console_receives_output() {
+    static BYTES: AtomicUsize = AtomicUsize::new(0);
+    struct CountingConsole;
+    impl Console for CountingConsole {
+        fn write_str(&self, s: &str) -> Result<(), Busy> {
+            BYTES.fetch_add(s.len(), Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    let id = register(&CountingConsole).expect("no free console slot");
+    println!("hello");
+    set_enabled(id, false);
+    println!("hello");
+    assert_eq!(BYTES.load(Ordering::Relaxed), "hello\n".len());
+    unregister(id);
+}
+
+#[test_case]
+fn test_busy_console_gets_deferred_output() {
+    static BUSY: AtomicBool = AtomicBool::new(true);
+    static BYTES: AtomicUsize = AtomicUsize::new(0);
+    struct SometimesBusyConsole;
+    impl Console for SometimesBusyConsole {
+        fn write_str(&self, s: &str) -> Result<(), Busy> {
+            if BUSY.load(Ordering::Relaxed) {
+                return Err(Busy);
+            }
+            BYTES.fetch_add(s.len(), Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    let id = register(&SometimesBusyConsole).expect("no free console slot");
+    println!("first");
+    assert_eq!(BYTES.load(Ordering::Relaxed), 0);
+    BUSY.store(false, Ordering::Relaxed);
+    println!("second");
+    unregister(id);
+    println!("third");
+    assert_eq!(BYTES.load(Ordering::Relaxed), "first\nsecond\n".len());
+}
@@ -0,0 +1,244 @@
+// CPU frequency scaling through the P-state MSRs.
+//
+// A P-state is a frequency, with the voltage that goes with it, that the OS can ask the
+// CPU to run at. On Intel CPUs with Enhanced SpeedStep the P-states are the bus ratios
+// between the maximum efficiency ratio and the maximum non-turbo ratio of
+// MSR_PLATFORM_INFO, requested through IA32_PERF_CTL, at 100MHz per ratio step. On AMD
+// CPUs of families 17h and 19h (Zen to Zen 4), up to eight P-states are defined in MSRs of
+// their own and requested by number. Family 1Ah (Zen 5) encodes the frequency in those MSRs
+// differently and isn't supported. The ACPI _PSS tables describe the same states, but the
+// kernel has no ACPI interpreter.
+//
+// A policy picks a P-state: Performance the fastest allowed one, Powersave the slowest.
+// Turbo frequencies above the P-states are left to the CPU. Only the CPU we run on is
+// changed, the other CPUs don't run yet.
+//
+// Hypervisors rarely expose these MSRs, `pstates` then returns `Unsupported`.
+
+use core::{arch::x86_64::__cpuid, fmt};
+use x86_64::registers::model_specific::Msr;
+
+/// Maximum number of P-states `pstates` reports.
+pub const MAX_PSTATES: usize = 32;
+
+const CPUID_EIST: u32 = 1 << 7;
+const CPUID_AMD_POWER_MANAGEMENT: u32 = 0x8000_0007;
+const CPUID_AMD_HW_PSTATE: u32 = 1 << 7;
+
+const MSR_PLATFORM_INFO: u32 = 0xce;
+const IA32_PERF_STATUS: u32 = 0x198;
+const IA32_PERF_CTL: u32 = 0x199;
+const IA32_MISC_ENABLE: u32 = 0x1a0;
+const MISC_ENABLE_EIST: u64 = 1 << 16;
+const INTEL_BUS_MHZ: u32 = 100;
+
+// the families with the Zen P-state definitions `amd_pstate_mhz` decodes.
+const AMD_FAMILIES: [u32; 2] = [0x17, 0x19];
+const AMD_PSTATE_LIMIT: u32 = 0xc001_0061;
+const AMD_PSTATE_CONTROL: u32 = 0xc001_0062;
+const AMD_PSTATE_STATUS: u32 = 0xc001_0063;
+const AMD_PSTATE_DEF_0: u32 = 0xc001_0064;
+const AMD_PSTATE_COUNT: u32 = 8;
+const AMD_PSTATE_ENABLED: u64 = 1 << 63;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CpuFreqError {
+    /// The CPU has no P-states we know how to control.
+    Unsupported,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+    Performance,
+    Powersave,
+}
+
+impl Policy {
+    pub fn parse(s: &str) -> Option<Policy> {
+        match s {
+            "performance" => Some(Policy::Performance),
+            "powersave" => Some(Policy::Powersave),
+            _ => None,
+        }
+    }
+}
+
+/// A P-state: the bus ratio on Intel CPUs, the P-state number on AMD CPUs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PState {
+    pub id: u32,
+    pub mhz: u32,
+}
+
+impl fmt::Display for PState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "P-state {}: {} MHz", self.id, self.mhz)
+    }
+}
+
+/// The P-states of the CPU, fastest first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PStates {
+    states: [PState; MAX_PSTATES],
+    len: usize,
+}
+
+impl PStates {
+    fn new() -> Self {
+        PStates { states: [PState { id: 0, mhz: 0 }; MAX_PSTATES], len: 0 }
+    }
+
+    fn push(&mut self, state: PState) {
+        if let Some(slot) = self.states.get_mut(self.len) {
+            *slot = state;
+            self.len += 1;
+        }
+    }
+
+    pub fn as_slice(&self) -> &[PState] {
+        &self.states[..self.len]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Driver {
+    Intel,
+    Amd,
+}
+
+fn driver() -> Result<Driver, CpuFreqError> {
+    let leaf = __cpuid(0);
+    let mut vendor = [0; 12];
+    vendor[0..4].copy_from_slice(&leaf.ebx.to_le_bytes());
+    vendor[4..8].copy_from_slice(&leaf.edx.to_le_bytes());
+    vendor[8..12].copy_from_slice(&leaf.ecx.to_le_bytes());
+    match &vendor {
+        b"GenuineIntel" if __cpuid(1).ecx & CPUID_EIST != 0 => {
+            // the firmware may have left SpeedStep disabled, PERF_CTL is ignored then.
+            let misc_enable = unsafe { Msr::new(IA32_MISC_ENABLE).read() };
+            if misc_enable & MISC_ENABLE_EIST == 0 {
+                return Err(CpuFreqError::Unsupported);
+            }
+            Ok(Driver::Intel)
+        }
+        b"AuthenticAMD" if AMD_FAMILIES.contains(&family()) && amd_has_hw_pstate() => {
+            Ok(Driver::Amd)
+        }
+        _ => Err(CpuFreqError::Unsupported),
+    }
+}
+
+fn family() -> u32 {
+    let eax = __cpuid(1).eax;
+    let family = (eax >> 8) & 0xf;
+    match family {
+        0xf => family + ((eax >> 20) & 0xff),
+        family => family,
+    }
+}
+
+fn amd_has_hw_pstate() -> bool {
+    __cpuid(0x8000_0000).eax >= CPUID_AMD_POWER_MANAGEMENT
+        && __cpuid(CPUID_AMD_POWER_MANAGEMENT).edx & CPUID_AMD_HW_PSTATE != 0
+}
+
+// Zen: the core frequency is FID / DFS ID * 200MHz, 0 for an invalid divisor.
+fn amd_pstate_mhz(definition: u64) -> u32 {
+    let fid = (definition & 0xff) as u32;
+    let dfs_id = ((definition >> 8) & 0x3f) as u32;
+    (fid * 200).checked_div(dfs_id).unwrap_or(0)
+}
+
+fn amd_pstate(id: u32) -> Option<PState> {
+    let definition = unsafe { Msr::new(AMD_PSTATE_DEF_0 + id).read() };
+    let mhz = amd_pstate_mhz(definition);
+    (definition & AMD_PSTATE_ENABLED != 0 && mhz != 0).then_some(PState { id, mhz })
+}
+
+// the fastest and the slowest P-state numbers the firmware allows.
+fn amd_limits() -> (u32, u32) {
+    let limit = unsafe { Msr::new(AMD_PSTATE_LIMIT).read() };
+    ((limit & 0x7) as u32, ((limit >> 4) & 0x7) as u32)
+}
+
+// the maximum non-turbo and the maximum efficiency ratio.
+fn intel_ratios() -> (u32, u32) {
+    let platform_info = unsafe { Msr::new(MSR_PLATFORM_INFO).read() };
+    (((platform_info >> 8) & 0xff) as u32, ((platform_info >> 40) & 0xff) as u32)
+}
+
+/// Returns the P-states the CPU supports, fastest first.
+pub fn pstates() -> Result<PStates, CpuFreqError> {
+    let mut states = PStates::new();
+    match driver()? {
+        Driver::Intel => {
+            let (max, min) = intel_ratios();
+            for ratio in (min.max(1)..=max).rev() {
+                states.push(PState { id: ratio, mhz: ratio * INTEL_BUS_MHZ });
+            }
+        }
+        Driver::Amd => {
+            // P0 is the fastest.
+            (0..AMD_PSTATE_COUNT).filter_map(amd_pstate).for_each(|state| states.push(state));
+        }
+    }
+    if states.len == 0 {
+        return Err(CpuFreqError::Unsupported);
+    }
+    Ok(states)
+}
+
+/// Returns the P-state the CPU currently runs at.
+pub fn current() -> Result<PState, CpuFreqError> {
+    match driver()? {
+        Driver::Intel => {
+            let ratio = ((unsafe { Msr::new(IA32_PERF_STATUS).read() } >> 8) & 0xff) as u32;
+            Ok(PState { id: ratio, mhz: ratio * INTEL_BUS_MHZ })
+        }
+        Driver::Amd => {
+            let id = (unsafe { Msr::new(AMD_PSTATE_STATUS).read() } & 0x7) as u32;
+            amd_pstate(id).ok_or(CpuFreqError::Unsupported)
+        }
+    }
+}
+
+/// Requests the given P-state, one of `pstates`.
+pub fn set_pstate(state: PState) -> Result<(), CpuFreqError> {
+    let (register, value) = match driver()? {
+        Driver::Intel => (IA32_PERF_CTL, u64::from(state.id) << 8),
+        Driver::Amd => (AMD_PSTATE_CONTROL, u64::from(state.id)),
+    };
+    unsafe { Msr::new(register).write(value) };
+    Ok(())
+}
+
+/// Requests the P-state the policy calls for, and returns it.
+pub fn set_policy(policy: Policy) -> Result<PState, CpuFreqError> {
+    let states = pstates()?;
+    // the firmware may limit the range of the AMD P-states, e.g. on battery.
+    let limits = match driver()? {
+        Driver::Intel => None,
+        Driver::Amd => Some(amd_limits()),
+    };
+    let allowed = |state: &&PState| match limits {
+        Some((fastest, slowest)) => (fastest..=slowest).contains(&state.id),
+        None => true,
+    };
+    let mut allowed_states = states.as_slice().iter().filter(allowed);
+    let state = match policy {
+        Policy::Performance => allowed_states.next(),
+        Policy::Powersave => allowed_states.next_back(),
+    };
+    let state = *state.ok_or(CpuFreqError::Unsupported)?;
+    set_pstate(state)?;
+    Ok(state)
+}
+
+#[test_case]
+fn test_amd_pstate_mhz() {
+    // FID 0x8c, DFS ID 8: 3500MHz.
+    assert_eq!(amd_pstate_mhz(0x808c), 3500);
+    assert_eq!(amd_pstate_mhz(0x8c), 0);
+    assert_eq!(Policy::parse("powersave"), Some(Policy::Powersave));
+    assert_eq!(Policy::parse("ondemand"), None);
+}
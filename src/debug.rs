@@ -0,0 +1,191 @@
+// Hardware breakpoints through the debug registers.
+//
+// The CPU has four breakpoint address registers (DR0-DR3). DR7 enables each of them and
+// selects whether it triggers on instruction execution, on writes or on reads and writes,
+// and how many bytes it covers. When a breakpoint triggers, the CPU raises a debug
+// exception (#DB) and sets the matching bit in DR6. This makes it possible to trap the
+// exact instruction that scribbles over some memory, e.g. the IDT, instead of finding out
+// much later when the corrupted data is used.
+
+use core::{arch::asm, sync::atomic::{AtomicUsize, Ordering}};
+use x86_64::{instructions::interrupts, VirtAddr};
+use crate::{backtrace::Backtrace, println, trap::TrapFrame};
+
+pub const WATCHPOINTS: usize = 4;
+
+// DR6 bit set for single-step traps.
+const DR6_SINGLE_STEP: u64 = 1 << 14;
+// RFLAGS.RF suppresses instruction breakpoints for the next instruction.
+const RFLAGS_RESUME: u64 = 1 << 16;
+
+// number of watchpoint hits reported so far.
+static HITS: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    /// Triggers before the instruction at the address is executed.
+    Execute,
+    /// Triggers after an instruction wrote to the watched bytes.
+    Write,
+    /// Triggers after an instruction read or wrote the watched bytes.
+    ReadWrite,
+}
+
+impl WatchKind {
+    // the R/W bits of the breakpoint in DR7.
+    fn bits(self) -> u64 {
+        match self {
+            WatchKind::Execute => 0b00,
+            WatchKind::Write => 0b01,
+            WatchKind::ReadWrite => 0b11,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchpointError {
+    /// All four debug address registers are in use.
+    NoFreeSlot,
+    /// Data watchpoints cover 1, 2, 4 or 8 bytes, execution breakpoints 1 byte.
+    InvalidLength(usize),
+    /// The address has to be aligned to the watched length.
+    Unaligned,
+}
+
+/// A watchpoint installed with `set_watchpoint`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    index: usize,
+}
+
+/// Installs a hardware watchpoint of `len` bytes at `addr`.
+pub fn set_watchpoint(addr: VirtAddr, len: usize, kind: WatchKind) -> Result<Watchpoint, WatchpointError> {
+    let len_bits = match (kind, len) {
+        (WatchKind::Execute, 1) => 0b00,
+        (WatchKind::Execute, _) => return Err(WatchpointError::InvalidLength(len)),
+        (_, 1) => 0b00,
+        (_, 2) => 0b01,
+        (_, 4) => 0b11,
+        (_, 8) => 0b10,
+        _ => return Err(WatchpointError::InvalidLength(len)),
+    };
+    if !addr.is_aligned(len as u64) {
+        return Err(WatchpointError::Unaligned);
+    }
+
+    interrupts::without_interrupts(|| {
+        let dr7 = unsafe { read_dr7() };
+        let index = (0..WATCHPOINTS)
+            .find(|index| dr7 & local_enable_bit(*index) == 0)
+            .ok_or(WatchpointError::NoFreeSlot)?;
+
+        let control_shift = 16 + 4 * index;
+        let dr7 = (dr7 & !(0xf << control_shift))
+            | (kind.bits() | len_bits << 2) << control_shift
+            | local_enable_bit(index);
+
+        unsafe {
+            write_address(index, addr.as_u64());
+            write_dr7(dr7);
+        }
+        Ok(Watchpoint { index })
+    })
+}
+
+/// Removes a watchpoint.
+pub fn clear_watchpoint(watchpoint: Watchpoint) {
+    interrupts::without_interrupts(|| unsafe {
+        let dr7 = read_dr7();
+        write_dr7(dr7 & !local_enable_bit(watchpoint.index));
+    });
+}
+
+/// Reports the watchpoints that triggered the debug exception, called by the #DB handler.
+pub fn handle_debug_exception(frame: &mut TrapFrame) {
+    let dr6 = unsafe { read_dr6() };
+    let dr7 = unsafe { read_dr7() };
+
+    println!("EXCEPTION: DEBUG at {:?}", frame.stack_frame.instruction_pointer);
+    for index in (0..WATCHPOINTS).filter(|index| dr6 & (1 << index) != 0) {
+        let address = unsafe { read_address(index) };
+        println!("Watchpoint {} hit, watched address {:#x}", index, address);
+        HITS.fetch_add(1, Ordering::Relaxed);
+
+        let kind_bits = (dr7 >> (16 + 4 * index)) & 0b11;
+        if kind_bits == WatchKind::Execute.bits() {
+            // execution breakpoints are faults, without RF we'd hit it again right away.
+            frame.stack_frame.cpu_flags |= RFLAGS_RESUME;
+        }
+    }
+    if dr6 & DR6_SINGLE_STEP != 0 {
+        println!("Single step");
+    }
+    // of the code that hit the watchpoint, not of this handler.
+    let rip = frame.stack_frame.instruction_pointer.as_u64();
+    println!("{}", Backtrace::from_registers(rip, frame.registers.rbp));
+
+    // the CPU never clears DR6 itself.
+    unsafe { write_dr6(0) };
+}
+
+/// Returns the number of watchpoint hits since boot.
+pub fn hits() -> usize {
+    HITS.load(Ordering::Relaxed)
+}
+
+fn local_enable_bit(index: usize) -> u64 {
+    1 << (2 * index)
+}
+
+unsafe fn read_address(index: usize) -> u64 {
+    let value: u64;
+    match index {
+        0 => asm!("mov {}, dr0", out(reg) value, options(nomem, nostack)),
+        1 => asm!("mov {}, dr1", out(reg) value, options(nomem, nostack)),
+        2 => asm!("mov {}, dr2", out(reg) value, options(nomem, nostack)),
+        _ => asm!("mov {}, dr3", out(reg) value, options(nomem, nostack)),
+    }
+    value
+}
+
+unsafe fn write_address(index: usize, value: u64) {
+    match index {
+        0 => asm!("mov dr0, {}", in(reg) value, options(nomem, nostack)),
+        1 => asm!("mov dr1, {}", in(reg) value, options(nomem, nostack)),
+        2 => asm!("mov dr2, {}", in(reg) value, options(nomem, nostack)),
+        _ => asm!("mov dr3, {}", in(reg) value, options(nomem, nostack)),
+    }
+}
+
+unsafe fn read_dr6() -> u64 {
+    let value: u64;
+    asm!("mov {}, dr6", out(reg) value, options(nomem, nostack));
+    value
+}
+
+unsafe fn write_dr6(value: u64) {
+    asm!("mov dr6, {}", in(reg) value, options(nomem, nostack));
+}
+
+unsafe fn read_dr7() -> u64 {
+    let value: u64;
+    asm!("mov {}, dr7", out(reg) value, options(nomem, nostack));
+    value
+}
+
+unsafe fn write_dr7(value: u64) {
+    asm!("mov dr7, {}", in(reg) value, options(nomem, nostack));
+}
+
+#[test_case]
+fn test_write_watchpoint_triggers() {
+    static mut WATCHED: u64 = 0;
+
+    let addr = VirtAddr::from_ptr(core::ptr::addr_of!(WATCHED));
+    let hits_before = hits();
+    let watchpoint = set_watchpoint(addr, 8, WatchKind::Write).expect("no free watchpoint");
+    // the debug handler reports the hit and returns, execution continues.
+    unsafe { core::ptr::addr_of_mut!(WATCHED).write_volatile(1) };
+    clear_watchpoint(watchpoint);
+    assert_eq!(hits(), hits_before + 1);
+}
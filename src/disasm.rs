@@ -0,0 +1,206 @@
+// Faulting instruction bytes and a best-effort decoder.
+//
+// When an exception points at a wild RIP or at bad code, the bytes at RIP are usually the
+// fastest way to see what happened: garbage, a stray `ud2` emitted by the compiler for an
+// unreachable path, a privileged instruction, and so on. The exception handlers print the
+// bytes as a hexdump together with the mnemonic of the instruction, if it's one of the
+// common opcodes known to `decode`. This is not a disassembler: operands are not decoded
+// and unknown opcodes are simply reported as such.
+
+use core::fmt;
+use x86_64::{
+    registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags},
+    VirtAddr,
+};
+use crate::{memory, println};
+
+/// x86_64 instructions are at most 15 bytes long.
+pub const MAX_INSTRUCTION_LEN: usize = 15;
+
+/// The bytes at an instruction pointer.
+#[derive(Debug, Clone, Copy)]
+pub struct InstructionBytes {
+    pub address: VirtAddr,
+    bytes: [u8; MAX_INSTRUCTION_LEN],
+    len: usize,
+}
+
+impl InstructionBytes {
+    /// Reads up to MAX_INSTRUCTION_LEN bytes at the given address, stopping at
+    /// the first byte that isn't mapped. Returns `None` if not even the first
+    /// byte is mapped.
+    pub fn read(address: VirtAddr) -> Option<Self> {
+        let mut instruction = InstructionBytes { address, bytes: [0; MAX_INSTRUCTION_LEN], len: 0 };
+
+        for (offset, byte) in instruction.bytes.iter_mut().enumerate() {
+            let addr = match VirtAddr::try_new(address.as_u64().wrapping_add(offset as u64)) {
+                Ok(addr) => addr,
+                Err(_) => break,
+            };
+            // the instruction may cross into an unmapped page, so every byte is checked.
+            if memory::translate(addr).is_none() {
+                break;
+            }
+            *byte = unsafe { addr.as_ptr::<u8>().read_volatile() };
+            instruction.len += 1;
+        }
+
+        if instruction.len == 0 {
+            return None;
+        }
+        Some(instruction)
+    }
+
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+}
+
+impl fmt::Display for InstructionBytes {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:#018x}:", self.address.as_u64())?;
+        for byte in self.bytes() {
+            write!(f, " {:02x}", byte)?;
+        }
+        match decode(self.bytes()) {
+            Some(mnemonic) => write!(f, "  ({})", mnemonic),
+            None => write!(f, "  (unknown instruction)"),
+        }
+    }
+}
+
+/// Prints the instruction bytes at the given address, or a note that the
+/// address is not mapped.
+pub fn print_faulting_instruction(address: VirtAddr) {
+    match InstructionBytes::read(address) {
+        Some(instruction) => println!("Instruction: {}", instruction),
+        None => println!("Instruction: {:?} is not mapped", address),
+    }
+}
+
+/// Returns the mnemonic of the instruction at the start of `bytes`, if it's
+/// one of the opcodes this decoder knows about.
+pub fn decode(bytes: &[u8]) -> Option<&'static str> {
+    let (rest, rex_w) = skip_prefixes(bytes);
+    let (&opcode, operands) = rest.split_first()?;
+    // the reg field of a ModRM byte selects the operation for the group opcodes.
+    let modrm_reg = operands.first().map(|modrm| (modrm >> 3) & 0x7);
+
+    let mnemonic = match (opcode, operands.first().copied()) {
+        (0x0f, Some(0x0b)) => "ud2",
+        (0x0f, Some(0x05)) => "syscall",
+        (0x0f, Some(0x07)) => "sysret",
+        (0x0f, Some(0x20)) | (0x0f, Some(0x22)) => "mov (control register)",
+        (0x0f, Some(0x21)) | (0x0f, Some(0x23)) => "mov (debug register)",
+        (0x0f, Some(0x30)) => "wrmsr",
+        (0x0f, Some(0x31)) => "rdtsc",
+        (0x0f, Some(0x32)) => "rdmsr",
+        (0x0f, Some(0x33)) => "rdpmc",
+        (0x0f, Some(0xa2)) => "cpuid",
+        (0x0f, Some(0x01)) => "system instruction (lgdt/lidt/invlpg/...)",
+        (0x0f, Some(0xff)) => "ud0",
+        (0x0f, Some(0xb9)) => "ud1",
+        (0x0f, Some(second)) if (0x10..=0x17).contains(&second) || (0x28..=0x2f).contains(&second) => {
+            "SSE move/convert"
+        }
+        (0x0f, Some(second)) if (0x80..=0x8f).contains(&second) => "jcc (rel32)",
+        (0x0f, _) => return None,
+        (0x00, _) => "add r/m8, r8 (zeroed memory?)",
+        (0x70..=0x7f, _) => "jcc (rel8)",
+        (0x88..=0x8b, _) => "mov",
+        (0x8d, _) => "lea",
+        (0x90, _) => "nop",
+        (0xc3, _) => "ret",
+        (0xc7, _) => "mov (immediate)",
+        (0xcc, _) => "int3",
+        (0xcd, _) => "int",
+        (0xcf, _) => if rex_w { "iretq" } else { "iret" },
+        (0xe4..=0xe7, _) | (0xec..=0xef, _) => "in/out",
+        (0xe8, _) => "call (rel32)",
+        (0xe9, _) => "jmp (rel32)",
+        (0xeb, _) => "jmp (rel8)",
+        (0xf4, _) => "hlt",
+        (0xf6, _) | (0xf7, _) => match modrm_reg {
+            Some(6) => "div",
+            Some(7) => "idiv",
+            Some(4) | Some(5) => "mul",
+            _ => "test/not/neg",
+        },
+        (0xfa, _) => "cli",
+        (0xfb, _) => "sti",
+        (0xff, _) => match modrm_reg {
+            Some(2) | Some(3) => "call (indirect)",
+            Some(4) | Some(5) => "jmp (indirect)",
+            Some(6) => "push",
+            _ => "inc/dec",
+        },
+        _ => return None,
+    };
+    Some(mnemonic)
+}
+
+// Skips legacy prefixes and a REX prefix, returns the rest and whether REX.W was set.
+fn skip_prefixes(bytes: &[u8]) -> (&[u8], bool) {
+    let mut rest = bytes;
+    let mut rex_w = false;
+    while let Some((&byte, tail)) = rest.split_first() {
+        match byte {
+            0x66 | 0x67 | 0xf0 | 0xf2 | 0xf3 | 0x2e | 0x36 | 0x3e | 0x26 | 0x64 | 0x65 => rest = tail,
+            0x40..=0x4f => {
+                rex_w = byte & 0x8 != 0;
+                rest = tail;
+                break;
+            }
+            _ => break,
+        }
+    }
+    (rest, rex_w)
+}
+
+/// Explains the usual causes of an invalid opcode exception at an instruction,
+/// if it's one of the known cases.
+pub fn invalid_opcode_hint(bytes: &[u8]) -> Option<&'static str> {
+    let sse_enabled = !Cr0::read().contains(Cr0Flags::EMULATE_COPROCESSOR)
+        && Cr4::read().contains(Cr4Flags::OSFXSR);
+    let avx_enabled = Cr4::read().contains(Cr4Flags::OSXSAVE);
+    hint(bytes, sse_enabled, avx_enabled)
+}
+
+fn hint(bytes: &[u8], sse_enabled: bool, avx_enabled: bool) -> Option<&'static str> {
+    // SSE and SSE2 opcodes after 0x0f, including the 0x38 and 0x3a escapes of SSSE3/SSE4
+    // and 0xae for ldmxcsr/fxsave.
+    let is_sse = |second: u8| matches!(second,
+        0x10..=0x17 | 0x28..=0x2f | 0x38 | 0x3a | 0x50..=0x7f | 0xae | 0xc2 | 0xc4..=0xc6 | 0xd0..=0xff);
+
+    match skip_prefixes(bytes).0 {
+        [0x0f, 0x0b, ..] => Some("ud2 is emitted by the compiler for code it considers unreachable, \
+            e.g. core::intrinsics::abort or a reached unreachable_unchecked"),
+        // c4/c5 are always VEX prefixes in 64-bit mode.
+        [0xc4 | 0xc5, ..] if !avx_enabled => Some("AVX instruction, but AVX isn't enabled (CR4.OSXSAVE is clear)"),
+        [0x0f, second, ..] if is_sse(*second) && !sse_enabled => {
+            Some("SSE instruction, but SSE isn't enabled (CR4.OSFXSR is clear or CR0.EM is set)")
+        }
+        _ => None,
+    }
+}
+
+#[test_case]
+fn test_invalid_opcode_hints() {
+    assert!(hint(&[0x0f, 0x0b], true, true).unwrap().starts_with("ud2"));
+    // movaps xmm0, xmm1
+    assert!(hint(&[0x0f, 0x28, 0xc1], false, false).unwrap().starts_with("SSE"));
+    assert_eq!(hint(&[0x0f, 0x28, 0xc1], true, false), None);
+    // vzeroupper
+    assert!(hint(&[0xc5, 0xf8, 0x77], true, false).unwrap().starts_with("AVX"));
+}
+
+#[test_case]
+fn test_decode_known_instructions() {
+    assert_eq!(decode(&[0x0f, 0x0b]), Some("ud2"));
+    assert_eq!(decode(&[0xcc]), Some("int3"));
+    // div rcx with a REX.W prefix.
+    assert_eq!(decode(&[0x48, 0xf7, 0xf1]), Some("div"));
+    assert_eq!(decode(&[0x48, 0xcf]), Some("iretq"));
+    assert_eq!(decode(&[0x0f, 0x0e]), None);
+    assert_eq!(decode(&[]), None);
+}
@@ -0,0 +1,154 @@
+// Decoders for exception error codes.
+//
+// The CPU pushes an error code for some exceptions. Printed as a number, it means little
+// without the manual at hand, so the handlers print these decoded versions instead.
+
+use core::fmt;
+use x86_64::{structures::idt::PageFaultErrorCode, VirtAddr};
+
+/// The error code of exceptions that refer to a segment selector or an IDT entry:
+/// #GP, #NP, #SS, #TS.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelectorErrorCode(pub u64);
+
+/// The table a selector error code refers to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DescriptorTable {
+    Gdt,
+    Idt,
+    Ldt,
+}
+
+impl SelectorErrorCode {
+    /// The exception happened while delivering an external interrupt.
+    pub fn external(self) -> bool {
+        self.0 & 1 != 0
+    }
+
+    pub fn table(self) -> DescriptorTable {
+        match (self.0 >> 1) & 0b11 {
+            0b00 => DescriptorTable::Gdt,
+            0b10 => DescriptorTable::Ldt,
+            // bit 1 set means IDT, bit 2 is meaningless then.
+            _ => DescriptorTable::Idt,
+        }
+    }
+
+    /// The index of the entry in the table.
+    pub fn index(self) -> u64 {
+        (self.0 >> 3) & 0x1fff
+    }
+}
+
+impl fmt::Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 == 0 {
+            // the fault isn't about a particular selector, e.g. a non-canonical address.
+            return write!(f, "not caused by a segment selector");
+        }
+        write!(f, "caused by {:?} entry {}", self.table(), self.index())?;
+        if self.table() == DescriptorTable::Idt {
+            write!(f, " (vector {})", self.index())?;
+        }
+        if self.external() {
+            write!(f, " while delivering an external interrupt")?;
+        }
+        Ok(())
+    }
+}
+
+/// The cause of a page fault, from its error code and the accessed address in CR2.
+#[derive(Debug, Clone, Copy)]
+pub struct PageFaultCause {
+    pub error_code: PageFaultErrorCode,
+    pub address: VirtAddr,
+}
+
+/// What kind of access faulted, the distinction a page fault handler acts on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageFaultKind {
+    /// The page isn't mapped, or a page table on the way isn't present.
+    NotPresent,
+    /// A write to a present page that isn't writable.
+    WriteToReadOnly,
+    /// An instruction fetch from a present page that isn't executable.
+    ExecuteDisabled,
+    /// Any other protection violation: a user mode access to a kernel page, a protection
+    /// key, a shadow stack access or a malformed page table.
+    ProtectionViolation,
+}
+
+impl PageFaultCause {
+    pub fn kind(&self) -> PageFaultKind {
+        let code = self.error_code;
+        let other = PageFaultErrorCode::USER_MODE | PageFaultErrorCode::MALFORMED_TABLE
+            | PageFaultErrorCode::PROTECTION_KEY | PageFaultErrorCode::SHADOW_STACK;
+        if !code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            PageFaultKind::NotPresent
+        } else if code.intersects(other) {
+            PageFaultKind::ProtectionViolation
+        } else if code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            PageFaultKind::ExecuteDisabled
+        } else if code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            PageFaultKind::WriteToReadOnly
+        } else {
+            PageFaultKind::ProtectionViolation
+        }
+    }
+}
+
+impl fmt::Display for PageFaultCause {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let code = self.error_code;
+        let present = if code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            "protection violation"
+        } else {
+            "non-present"
+        };
+        let access = if code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            "instruction fetch"
+        } else if code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            "write"
+        } else {
+            "read"
+        };
+        let mode = if code.contains(PageFaultErrorCode::USER_MODE) { "user" } else { "kernel" };
+
+        write!(f, "caused by a {} {} from {} mode to {:#x}", present, access, mode, self.address.as_u64())?;
+        if code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+            write!(f, ", a page table has reserved bits set")?;
+        }
+        if code.contains(PageFaultErrorCode::PROTECTION_KEY) {
+            write!(f, ", denied by a protection key")?;
+        }
+        if code.contains(PageFaultErrorCode::SHADOW_STACK) {
+            write!(f, ", on a shadow stack access")?;
+        }
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_page_fault_kind() {
+    let kind = |bits| PageFaultCause { error_code: PageFaultErrorCode::from_bits_truncate(bits), address: VirtAddr::new(0) }.kind();
+    assert_eq!(kind(0b000), PageFaultKind::NotPresent);
+    assert_eq!(kind(0b010), PageFaultKind::NotPresent);
+    assert_eq!(kind(0b011), PageFaultKind::WriteToReadOnly);
+    assert_eq!(kind(0b1_0001), PageFaultKind::ExecuteDisabled);
+    // a user mode write to a kernel page.
+    assert_eq!(kind(0b111), PageFaultKind::ProtectionViolation);
+    assert_eq!(kind(0b001), PageFaultKind::ProtectionViolation);
+}
+
+#[test_case]
+fn test_selector_error_code() {
+    // GDT entry 2, while delivering an external interrupt.
+    let code = SelectorErrorCode(2 << 3 | 1);
+    assert_eq!(code.table(), DescriptorTable::Gdt);
+    assert_eq!(code.index(), 2);
+    assert!(code.external());
+    // IDT entry 0x80.
+    let code = SelectorErrorCode(0x80 << 3 | 0b010);
+    assert_eq!(code.table(), DescriptorTable::Idt);
+    assert_eq!(code.index(), 0x80);
+}
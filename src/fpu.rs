@@ -0,0 +1,103 @@
+// x87 and SSE floating point exceptions.
+//
+// The kernel itself is built with soft-float, but the CPU state is still there for
+// assembly and, later, user code. `init` makes the CPU report unmasked floating point
+// exceptions through #MF and #XM: without CR0.NE an x87 error is signalled through the
+// legacy FERR# pin and the PIC, and without CR4.OSXMMEXCPT an SSE error raises #UD,
+// which looks like a completely different bug. The handlers report the exception from
+// the x87 status word or MXCSR and clear it, a pending exception would otherwise
+// fault again on the next floating point instruction.
+
+use core::{arch::asm, fmt};
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+
+// the exception flags, the same bits in the x87 status word and in MXCSR.
+const EXCEPTION_FLAGS: u32 = 0x3f;
+const EXCEPTION_NAMES: [&str; 6] = [
+    "invalid operation", "denormal operand", "divide by zero", "overflow", "underflow", "precision",
+];
+// x87 only, an invalid operation that was a stack overflow or underflow.
+const X87_STACK_FAULT: u16 = 1 << 6;
+
+/// Enables the x87 and SSE units and native reporting of their exceptions.
+pub fn init() {
+    unsafe {
+        Cr0::update(|flags| {
+            flags.remove(Cr0Flags::EMULATE_COPROCESSOR);
+            flags.insert(Cr0Flags::MONITOR_COPROCESSOR | Cr0Flags::NUMERIC_ERROR);
+        });
+        Cr4::update(|flags| flags.insert(Cr4Flags::OSFXSR | Cr4Flags::OSXMMEXCPT_ENABLE));
+    }
+}
+
+/// The exception flags of the x87 status word or of MXCSR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FloatExceptions(pub u32);
+
+impl fmt::Display for FloatExceptions {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut names = EXCEPTION_NAMES.iter().enumerate().filter(|(bit, _)| self.0 & (1 << bit) != 0);
+        match names.next() {
+            Some((_, name)) => write!(f, "{}", name)?,
+            None => return write!(f, "no exception flags set"),
+        }
+        names.try_for_each(|(_, name)| write!(f, ", {}", name))
+    }
+}
+
+/// The x87 status word.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct X87Status(pub u16);
+
+impl X87Status {
+    pub fn read() -> Self {
+        let status: u16;
+        unsafe { asm!("fnstsw ax", out("ax") status, options(nomem, nostack, preserves_flags)) };
+        X87Status(status)
+    }
+
+    pub fn exceptions(self) -> FloatExceptions {
+        FloatExceptions(u32::from(self.0) & EXCEPTION_FLAGS)
+    }
+
+    pub fn stack_fault(self) -> bool {
+        self.0 & X87_STACK_FAULT != 0
+    }
+}
+
+/// Clears the pending x87 exceptions.
+pub fn clear_x87_exceptions() {
+    unsafe { asm!("fnclex", options(nomem, nostack, preserves_flags)) };
+}
+
+/// The SSE control and status register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mxcsr(pub u32);
+
+impl Mxcsr {
+    pub fn read() -> Self {
+        let mut mxcsr = 0u32;
+        unsafe { asm!("stmxcsr [{}]", in(reg) &mut mxcsr, options(nostack, preserves_flags)) };
+        Mxcsr(mxcsr)
+    }
+
+    pub fn exceptions(self) -> FloatExceptions {
+        FloatExceptions(self.0 & EXCEPTION_FLAGS)
+    }
+}
+
+/// Clears the SSE exception flags in MXCSR.
+pub fn clear_simd_exceptions() {
+    let mxcsr = Mxcsr::read().0 & !EXCEPTION_FLAGS;
+    unsafe { asm!("ldmxcsr [{}]", in(reg) &mxcsr, options(nostack, preserves_flags)) };
+}
+
+#[test_case]
+fn test_exception_flags() {
+    // a stack underflow: invalid operation plus the stack fault bit, C1 is clear for an underflow.
+    let status = X87Status(0x0041);
+    assert_eq!(status.exceptions(), FloatExceptions(0b1));
+    assert!(status.stack_fault());
+    // the default MXCSR has all exceptions masked and none flagged.
+    assert_eq!(Mxcsr(0x1f80).exceptions(), FloatExceptions(0));
+}
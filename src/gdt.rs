@@ -1,28 +1,44 @@
+use core::ptr::addr_of;
 use x86_64::VirtAddr;
 use x86_64::structures::tss::TaskStateSegment;
 use lazy_static::lazy_static;
 use x86_64::structures::gdt::{GlobalDescriptorTable, Descriptor, SegmentSelector};
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+// NMIs can arrive at any instruction, also right after a syscall or interrupt entry
+// switched to a stack that isn't set up yet, so they get their own stack as well.
+pub const NMI_IST_INDEX: u16 = 1;
+// a machine check may report a corrupted stack.
+pub const MACHINE_CHECK_IST_INDEX: u16 = 2;
+
+// 20KB stack size (4096 - 4KB)
+const STACK_SIZE: usize = 4096 * 5;
 
 lazy_static! {
     static ref TSS: TaskStateSegment = {
         let mut tss = TaskStateSegment::new();
         tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            // 20KB stack size (4096 - 4KB)
-            const STACK_SIZE: usize = 4096 * 5;
             static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            // The unsafe is required because the compiler can’t guarantee race
-            // freedom when mutable statics are accessed.
-            let stack_start = VirtAddr::from_ptr( unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
+            // addr_of! takes the address without creating a reference to the mutable static.
+            stack_end(VirtAddr::from_ptr(addr_of!(STACK)))
+        };
+        tss.interrupt_stack_table[NMI_IST_INDEX as usize] = {
+            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+            stack_end(VirtAddr::from_ptr(addr_of!(STACK)))
+        };
+        tss.interrupt_stack_table[MACHINE_CHECK_IST_INDEX as usize] = {
+            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+            stack_end(VirtAddr::from_ptr(addr_of!(STACK)))
         };
         tss
     };
 }
 
+// stacks grow downwards, the IST entries point to the end of the stacks.
+fn stack_end(stack_start: VirtAddr) -> VirtAddr {
+    stack_start + STACK_SIZE
+}
+
 lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
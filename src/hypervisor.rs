@@ -0,0 +1,195 @@
+// Hypervisor detection and the KVM paravirtual clock.
+//
+// CPUID leaf 1 sets ECX bit 31 when running under a hypervisor, and the hypervisor then
+// identifies itself with a vendor string in leaf 0x4000_0000. Under KVM, leaf 0x4000_0001
+// lists the paravirtual features. One of them is kvmclock: we give the host the physical
+// address of a `TimeInfo` structure through an MSR, and the host keeps it updated with the
+// guest's system time at some TSC value plus the factors to scale later TSC deltas to
+// nanoseconds. Unlike counting PIT ticks, which get lost and delayed whenever QEMU doesn't
+// schedule the vCPU in time, this follows the host's clock.
+
+use core::{
+    arch::x86_64::{__cpuid, _rdtsc},
+    ptr::addr_of,
+    sync::atomic::{fence, AtomicU64, Ordering},
+};
+use x86_64::{registers::model_specific::Msr, VirtAddr};
+use crate::{memory, time};
+
+// CPUID leaf 1, ECX: running under a hypervisor.
+const CPUID_HYPERVISOR: u32 = 1 << 31;
+const CPUID_HYPERVISOR_VENDOR: u32 = 0x4000_0000;
+const CPUID_KVM_FEATURES: u32 = 0x4000_0001;
+// EAX of the KVM features leaf, the new clock MSRs.
+const KVM_FEATURE_CLOCKSOURCE2: u32 = 1 << 3;
+
+const MSR_KVM_WALL_CLOCK_NEW: u32 = 0x4b56_4d00;
+const MSR_KVM_SYSTEM_TIME_NEW: u32 = 0x4b56_4d01;
+// bit 0 of the system time MSR enables the updates.
+const KVM_SYSTEM_TIME_ENABLE: u64 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hypervisor {
+    Kvm,
+    HyperV,
+    VMware,
+    /// QEMU without KVM, the Tiny Code Generator.
+    Tcg,
+    /// A hypervisor we don't know, with its vendor string.
+    Other([u8; 12]),
+}
+
+/// Returns the hypervisor we run under, or `None` on bare metal.
+pub fn detect() -> Option<Hypervisor> {
+    if __cpuid(1).ecx & CPUID_HYPERVISOR == 0 {
+        return None;
+    }
+    let leaf = __cpuid(CPUID_HYPERVISOR_VENDOR);
+    let mut vendor = [0; 12];
+    vendor[0..4].copy_from_slice(&leaf.ebx.to_le_bytes());
+    vendor[4..8].copy_from_slice(&leaf.ecx.to_le_bytes());
+    vendor[8..12].copy_from_slice(&leaf.edx.to_le_bytes());
+
+    Some(match &vendor {
+        b"KVMKVMKVM\0\0\0" => Hypervisor::Kvm,
+        b"Microsoft Hv" => Hypervisor::HyperV,
+        b"VMwareVMware" => Hypervisor::VMware,
+        b"TCGTCGTCGTCG" => Hypervisor::Tcg,
+        _ => Hypervisor::Other(vendor),
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KvmClockError {
+    /// We don't run under KVM.
+    NotKvm,
+    /// KVM doesn't offer the clock MSRs.
+    Unsupported,
+}
+
+// pvclock_vcpu_time_info, updated by the host. Its 32 bytes must not cross a page.
+#[repr(C, align(32))]
+struct TimeInfo {
+    // odd while the host updates the structure.
+    version: u32,
+    pad0: u32,
+    tsc_timestamp: u64,
+    system_time: u64,
+    tsc_to_system_mul: u32,
+    tsc_shift: i8,
+    flags: u8,
+    pad: [u8; 2],
+}
+
+// pvclock_wall_clock, written by the host when we write its address to the wall clock MSR.
+#[repr(C, align(4))]
+struct WallClock {
+    version: u32,
+    sec: u32,
+    nsec: u32,
+}
+
+static mut TIME_INFO: TimeInfo = TimeInfo {
+    version: 0, pad0: 0, tsc_timestamp: 0, system_time: 0,
+    tsc_to_system_mul: 0, tsc_shift: 0, flags: 0, pad: [0; 2],
+};
+static mut WALL_CLOCK: WallClock = WallClock { version: 0, sec: 0, nsec: 0 };
+
+// the kvmclock time when `init` ran, 0 while kvmclock isn't used.
+static BOOT_TIME_NS: AtomicU64 = AtomicU64::new(0);
+// the wall clock time at kvmclock time 0, in nanoseconds since the Unix epoch.
+static WALL_CLOCK_BASE_NS: AtomicU64 = AtomicU64::new(0);
+
+/// Registers the kvmclock structures with the host, must run after `memory::init`.
+pub fn init() -> Result<(), KvmClockError> {
+    if detect() != Some(Hypervisor::Kvm) {
+        return Err(KvmClockError::NotKvm);
+    }
+    if __cpuid(CPUID_KVM_FEATURES).eax & KVM_FEATURE_CLOCKSOURCE2 == 0 {
+        return Err(KvmClockError::Unsupported);
+    }
+
+    // the structures are statics, they are mapped as long as the kernel runs.
+    let time_info = memory::translate(VirtAddr::from_ptr(addr_of!(TIME_INFO)))
+        .expect("kernel statics are mapped");
+    let wall_clock = memory::translate(VirtAddr::from_ptr(addr_of!(WALL_CLOCK)))
+        .expect("kernel statics are mapped");
+    unsafe {
+        Msr::new(MSR_KVM_SYSTEM_TIME_NEW).write(time_info.as_u64() | KVM_SYSTEM_TIME_ENABLE);
+        Msr::new(MSR_KVM_WALL_CLOCK_NEW).write(wall_clock.as_u64());
+    }
+
+    let (sec, nsec) = read_consistent(
+        || unsafe { addr_of!(WALL_CLOCK.version).read_volatile() },
+        || unsafe { (addr_of!(WALL_CLOCK.sec).read_volatile(), addr_of!(WALL_CLOCK.nsec).read_volatile()) },
+    );
+    WALL_CLOCK_BASE_NS.store(u64::from(sec) * 1_000_000_000 + u64::from(nsec), Ordering::Relaxed);
+    // continue from the uptime counted with the PIT so far.
+    let boot_time = system_time_ns().saturating_sub(time::uptime_ms() * 1_000_000);
+    BOOT_TIME_NS.store(boot_time.max(1), Ordering::Relaxed);
+    Ok(())
+}
+
+/// Nanoseconds since boot, or `None` if kvmclock isn't used.
+pub fn uptime_ns() -> Option<u64> {
+    match BOOT_TIME_NS.load(Ordering::Relaxed) {
+        0 => None,
+        boot_time => Some(system_time_ns().saturating_sub(boot_time)),
+    }
+}
+
+/// Nanoseconds since the Unix epoch according to the host, or `None` if kvmclock isn't used.
+pub fn unix_time_ns() -> Option<u64> {
+    if BOOT_TIME_NS.load(Ordering::Relaxed) == 0 {
+        return None;
+    }
+    Some(WALL_CLOCK_BASE_NS.load(Ordering::Relaxed) + system_time_ns())
+}
+
+// the guest's system time in nanoseconds.
+fn system_time_ns() -> u64 {
+    let info = addr_of!(TIME_INFO);
+    let (tsc_timestamp, system_time, mul, shift) = read_consistent(
+        || unsafe { addr_of!((*info).version).read_volatile() },
+        || unsafe {
+            (
+                addr_of!((*info).tsc_timestamp).read_volatile(),
+                addr_of!((*info).system_time).read_volatile(),
+                addr_of!((*info).tsc_to_system_mul).read_volatile(),
+                addr_of!((*info).tsc_shift).read_volatile(),
+            )
+        },
+    );
+    let tsc = unsafe { _rdtsc() };
+    system_time + scale_tsc(tsc.wrapping_sub(tsc_timestamp), mul, shift)
+}
+
+// Converts a TSC delta to nanoseconds like the host specifies: shift first, then
+// multiply with the 32.32 fixed point factor.
+fn scale_tsc(delta: u64, mul: u32, shift: i8) -> u64 {
+    let delta = if shift >= 0 { delta << shift } else { delta >> -shift };
+    ((u128::from(delta) * u128::from(mul)) >> 32) as u64
+}
+
+// Reads fields the host updates under a version counter, retrying while an update is
+// in progress (odd version) or happened during the read.
+fn read_consistent<T>(version: impl Fn() -> u32, read: impl Fn() -> T) -> T {
+    loop {
+        let before = version();
+        fence(Ordering::Acquire);
+        let value = read();
+        fence(Ordering::Acquire);
+        if before.is_multiple_of(2) && version() == before {
+            return value;
+        }
+        core::hint::spin_loop();
+    }
+}
+
+#[test_case]
+fn test_scale_tsc() {
+    // a 2 GHz TSC: half a nanosecond per cycle, as 0x8000_0000 / 2^32.
+    assert_eq!(scale_tsc(2_000, 0x8000_0000, 0), 1_000);
+    assert_eq!(scale_tsc(1_000, 0x8000_0000, 1), 1_000);
+    assert_eq!(scale_tsc(4_000, 0x8000_0000, -1), 1_000);
+}
@@ -0,0 +1,122 @@
+// CPU time accounting.
+//
+// Tells where the CPU time goes: halted while idle, polling tasks, or handling
+// interrupts. Everything else, e.g. the boot code before the executor starts, counts
+// as "other". The times are measured in TSC cycles since `start`.
+//
+// Interrupts wake the CPU from `hlt` and their handlers run before `hlt` returns, so
+// the interrupt time that accumulated while halted is not counted as idle time.
+
+use core::{
+    arch::x86_64::_rdtsc,
+    fmt,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+};
+use crate::irqstat;
+
+static START: AtomicU64 = AtomicU64::new(0);
+static IDLE_CYCLES: AtomicU64 = AtomicU64::new(0);
+static TASK_CYCLES: AtomicU64 = AtomicU64::new(0);
+static INTERRUPT_CYCLES: AtomicU64 = AtomicU64::new(0);
+// interrupt handlers running right now, nested ones included.
+static INTERRUPT_DEPTH: AtomicU32 = AtomicU32::new(0);
+
+fn now() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// Starts the accounting, called by `init`.
+pub fn start() {
+    START.store(now(), Ordering::Relaxed);
+}
+
+/// Halts the CPU until the next interrupt and accounts the time as idle.
+pub fn hlt() {
+    account_idle(x86_64::instructions::hlt);
+}
+
+/// Like `hlt`, but enables interrupts atomically right before halting.
+pub fn enable_and_hlt() {
+    account_idle(x86_64::instructions::interrupts::enable_and_hlt);
+}
+
+fn account_idle(halt: fn()) {
+    let interrupts_before = INTERRUPT_CYCLES.load(Ordering::Relaxed);
+    let start = now();
+    halt();
+    let halted = now().saturating_sub(start);
+    let interrupts = INTERRUPT_CYCLES.load(Ordering::Relaxed) - interrupts_before;
+    IDLE_CYCLES.fetch_add(halted.saturating_sub(interrupts), Ordering::Relaxed);
+}
+
+/// Runs `poll`, a poll of a task by the executor, accounts the time as task time and returns
+/// the cycles. Interrupts taken during the poll are accounted as interrupt time only.
+pub fn account_task(poll: impl FnOnce()) -> u64 {
+    let interrupts_before = INTERRUPT_CYCLES.load(Ordering::Relaxed);
+    let start = now();
+    poll();
+    let polled = now().saturating_sub(start);
+    let interrupts = INTERRUPT_CYCLES.load(Ordering::Relaxed) - interrupts_before;
+    let cycles = polled.saturating_sub(interrupts);
+    TASK_CYCLES.fetch_add(cycles, Ordering::Relaxed);
+    cycles
+}
+
+/// Measures the handler of `vector` until the returned guard is dropped, also for the
+/// duration statistics of `irqstat`.
+pub fn interrupt(vector: u8) -> InterruptTimer {
+    INTERRUPT_DEPTH.fetch_add(1, Ordering::Relaxed);
+    InterruptTimer { vector, start: now() }
+}
+
+/// Returns whether an interrupt handler measured by `interrupt` is running, i.e. the code
+/// running now interrupted other code.
+pub fn in_interrupt() -> bool {
+    INTERRUPT_DEPTH.load(Ordering::Relaxed) != 0
+}
+
+#[must_use = "the interrupt time is measured until the timer is dropped"]
+pub struct InterruptTimer {
+    vector: u8,
+    start: u64,
+}
+
+impl Drop for InterruptTimer {
+    fn drop(&mut self) {
+        let cycles = now().saturating_sub(self.start);
+        INTERRUPT_CYCLES.fetch_add(cycles, Ordering::Relaxed);
+        INTERRUPT_DEPTH.fetch_sub(1, Ordering::Relaxed);
+        irqstat::record_duration(self.vector, cycles);
+    }
+}
+
+/// CPU time since `start`, in percent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Utilization {
+    pub idle: u64,
+    pub tasks: u64,
+    pub interrupts: u64,
+    pub other: u64,
+}
+
+impl fmt::Display for Utilization {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "cpu: {}% idle, {}% tasks, {}% interrupts, {}% other",
+            self.idle, self.tasks, self.interrupts, self.other)
+    }
+}
+
+pub fn utilization() -> Utilization {
+    let total = now().saturating_sub(START.load(Ordering::Relaxed)).max(1);
+    let percent = |cycles: u64| (u128::from(cycles) * 100 / u128::from(total)).min(100) as u64;
+
+    let idle = percent(IDLE_CYCLES.load(Ordering::Relaxed));
+    let tasks = percent(TASK_CYCLES.load(Ordering::Relaxed));
+    let interrupts = percent(INTERRUPT_CYCLES.load(Ordering::Relaxed));
+    Utilization {
+        idle,
+        tasks,
+        interrupts,
+        other: 100u64.saturating_sub(idle + tasks + interrupts),
+    }
+}
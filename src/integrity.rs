@@ -0,0 +1,133 @@
+// Kernel image integrity checks.
+//
+// Nothing should ever write to the kernel's code and read-only data, but a driver that
+// DMAs to the wrong frame, or writes through a bad physical memory offset, doesn't go
+// through the page table permissions and can scribble over them. The damage then shows up
+// as a crash in unrelated code, much later. `init` hashes the read-only part of the image
+// page by page, `verify` compares the pages with those hashes: once after the drivers were
+// initialized, and then periodically from the `run` task.
+//
+// The reference hashes are taken at boot, before the drivers stage. A hash embedded at
+// build time would also catch corruption before that, but needs a post-link step that
+// patches the linked image, which the bootimage build doesn't have. The image hash is
+// printed over serial at boot instead, so the host can compare it between runs of the same
+// image.
+//
+// The read-only part is everything from the ELF header to the end of .text: lld places
+// the headers, .rodata and .text there, and puts the writable sections after it.
+
+use alloc::vec::Vec;
+use core::{fmt, ptr::addr_of};
+use x86_64::VirtAddr;
+use crate::{memory, serial_println, sync::Mutex, time};
+
+/// Milliseconds between the checks of the `run` task.
+pub const CHECK_INTERVAL_MS: u64 = 10_000;
+
+const PAGE_SIZE: u64 = 4096;
+
+extern "C" {
+    // defined by the linker.
+    static __ehdr_start: u8;
+    static etext: u8;
+}
+
+// hashes of the pages of the read-only image, 0 for pages that aren't mapped.
+static PAGE_HASHES: Mutex<Vec<u64>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IntegrityError {
+    /// `init` wasn't called.
+    NotInitialized,
+    /// The page at `address` has changed, and with it the image hash.
+    Modified { address: VirtAddr, expected: u64, actual: u64 },
+}
+
+impl fmt::Display for IntegrityError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            IntegrityError::NotInitialized => write!(f, "no reference hashes"),
+            IntegrityError::Modified { address, expected, actual } => write!(
+                f, "kernel image page {:#x} modified, hash {:#018x} instead of {:#018x}",
+                address.as_u64(), actual, expected,
+            ),
+        }
+    }
+}
+
+/// Returns the start and end of the read-only part of the kernel image.
+pub fn image() -> (VirtAddr, VirtAddr) {
+    (VirtAddr::from_ptr(addr_of!(__ehdr_start)), VirtAddr::from_ptr(addr_of!(etext)))
+}
+
+fn pages() -> impl Iterator<Item = VirtAddr> {
+    let (start, end) = image();
+    (start.align_down(PAGE_SIZE).as_u64()..end.as_u64())
+        .step_by(PAGE_SIZE as usize)
+        .map(VirtAddr::new)
+}
+
+// FNV-1a over 64 bit words, a page at a time.
+fn hash_words(words: impl Iterator<Item = u64>) -> u64 {
+    words.fold(0xcbf2_9ce4_8422_2325, |hash, word| (hash ^ word).wrapping_mul(0x0000_0100_0000_01b3))
+}
+
+fn hash_page(page: VirtAddr) -> u64 {
+    if memory::translate(page).is_none() {
+        return 0;
+    }
+    let words = page.as_ptr::<u64>();
+    // the page is mapped, and only read.
+    hash_words((0..PAGE_SIZE as usize / 8).map(|i| unsafe { words.add(i).read_volatile() }))
+}
+
+/// Takes the reference hashes and returns the hash of the whole read-only image.
+/// Needs the heap.
+pub fn init() -> u64 {
+    let hashes: Vec<u64> = pages().map(hash_page).collect();
+    let image_hash = hash_words(hashes.iter().copied());
+    *PAGE_HASHES.lock() = hashes;
+    image_hash
+}
+
+/// Checks the read-only image against the reference hashes.
+pub fn verify() -> Result<(), IntegrityError> {
+    let hashes = PAGE_HASHES.lock();
+    if hashes.is_empty() {
+        return Err(IntegrityError::NotInitialized);
+    }
+    for (address, &expected) in pages().zip(hashes.iter()) {
+        let actual = hash_page(address);
+        if actual != expected {
+            return Err(IntegrityError::Modified { address, expected, actual });
+        }
+    }
+    Ok(())
+}
+
+/// Verifies the image every `CHECK_INTERVAL_MS`, panics when it was modified: the code
+/// that runs next can't be trusted anymore.
+pub async fn run() {
+    loop {
+        time::sleep(CHECK_INTERVAL_MS).await;
+        match verify() {
+            Ok(()) => {}
+            Err(IntegrityError::NotInitialized) => {
+                serial_println!("integrity: no reference hashes, stopping the checks");
+                return;
+            }
+            Err(err) => panic!("{}", err),
+        }
+    }
+}
+
+#[test_case]
+fn test_hash_words() {
+    assert_eq!(hash_words(core::iter::empty()), 0xcbf2_9ce4_8422_2325);
+    assert_ne!(hash_words([1, 2].into_iter()), hash_words([2, 1].into_iter()));
+    let (start, end) = image();
+    assert!(start < end);
+    // the code is part of the image.
+    let code: fn() -> (VirtAddr, VirtAddr) = image;
+    assert!((start..end).contains(&VirtAddr::new(code as usize as u64)));
+}
@@ -1,8 +1,17 @@
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
-use crate::{gdt, print, println};
+use crate::{gdt, println, profiler, task::keyboard, time, trace, watchdog};
+use crate::trap::{trap_stub, TrapFrame};
+use crate::{allocator, debug, disasm, fpu, idle, irqstat, mce, task::executor};
+#[cfg(feature = "vga")]
+use crate::monitor;
+#[cfg(feature = "smp")]
+use crate::{apic, smp::{self, IpiKind}};
+use crate::exceptions::{PageFaultCause, SelectorErrorCode};
+use core::sync::atomic::{AtomicPtr, AtomicU64, Ordering};
+use x86_64::VirtAddr;
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
-use spin::Mutex;
+use crate::sync::Mutex;
 use x86_64::instructions::port::Port;
 
 lazy_static! {
@@ -17,13 +26,82 @@ lazy_static! {
                 .set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_usize()]
                 .set_handler_fn(keyboard_interrupt_handler);
+        for (line, handler) in DEVICE_IRQ_ENTRIES {
+            idt[usize::from(PIC_1_OFFSET + line)].set_handler_fn(handler);
+        }
+        #[cfg(feature = "smp")]
+        for (kind, handler) in IPI_ENTRIES {
+            idt[usize::from(kind.vector())].set_handler_fn(handler);
+        }
         idt.page_fault.set_handler_fn(page_fault_handler);
+        unsafe {
+            idt.non_maskable_interrupt
+                .set_handler_fn(nmi_handler)
+                .set_stack_index(gdt::NMI_IST_INDEX);
+            idt.machine_check
+                .set_handler_fn(machine_check_handler)
+                .set_stack_index(gdt::MACHINE_CHECK_IST_INDEX);
+        }
+        idt.segment_not_present.set_handler_fn(segment_not_present_handler);
+        idt.stack_segment_fault.set_handler_fn(stack_segment_fault_handler);
+        // these enter through the trap stubs, so the handlers see all registers.
+        unsafe {
+            idt.general_protection_fault
+                .set_handler_addr(VirtAddr::new(general_protection_fault_entry as *const () as u64));
+            idt.divide_error
+                .set_handler_addr(VirtAddr::new(divide_error_entry as *const () as u64));
+            idt.invalid_opcode
+                .set_handler_addr(VirtAddr::new(invalid_opcode_entry as *const () as u64));
+            idt.debug
+                .set_handler_addr(VirtAddr::new(debug_entry as *const () as u64));
+            idt.x87_floating_point
+                .set_handler_addr(VirtAddr::new(x87_floating_point_entry as *const () as u64));
+            idt.simd_floating_point
+                .set_handler_addr(VirtAddr::new(simd_floating_point_entry as *const () as u64));
+        }
 
         idt
     };
 }
 
 
+// Number of times each vector fired since boot.
+static INTERRUPT_COUNTS: [AtomicU64; 256] = [const { AtomicU64::new(0) }; 256];
+
+fn count(vector: u8) {
+    INTERRUPT_COUNTS[usize::from(vector)].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns how often the given interrupt vector fired since boot.
+pub fn interrupt_count(vector: u8) -> u64 {
+    INTERRUPT_COUNTS[usize::from(vector)].load(Ordering::Relaxed)
+}
+
+/// Returns a human-readable name for the interrupt vectors we handle.
+pub fn vector_name(vector: u8) -> &'static str {
+    match vector {
+        0 => "divide error",
+        1 => "debug",
+        2 => "nmi",
+        3 => "breakpoint",
+        6 => "invalid opcode",
+        8 => "double fault",
+        11 => "segment not present",
+        12 => "stack segment fault",
+        13 => "general protection",
+        14 => "page fault",
+        16 => "x87 floating point",
+        18 => "machine check",
+        19 => "simd floating point",
+        v if v == InterruptIndex::Timer.as_u8() => "timer",
+        v if v == InterruptIndex::Keyboard.as_u8() => "keyboard",
+        v if (PIC_1_OFFSET..PIC_2_OFFSET + 8).contains(&v) => "device irq",
+        #[cfg(feature = "smp")]
+        v if IpiKind::from_vector(v).is_some() => "ipi",
+        _ => "unknown",
+    }
+}
+
 pub fn init_idt() {
     // In order that the CPU uses our new interrupt descriptor table,
     // we need to load it using the lidt instruction.
@@ -31,6 +109,7 @@ pub fn init_idt() {
 }
 
 extern "x86-interrupt" fn breaking_handler(stack_frame: InterruptStackFrame) {
+    count(3);
     println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
@@ -38,18 +117,49 @@ extern "x86-interrupt" fn breaking_handler(stack_frame: InterruptStackFrame) {
 // The reason is that the x86_64 architecture does not permit returning from a double
 // fault exception, so, we don't return to the caller from this handler.
 extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame, _error_code: u64) -> ! {
+    stack_frame: InterruptStackFrame, error_code: u64) -> ! {
+    count(8);
+    let hook = DOUBLE_FAULT_HOOK.load(Ordering::SeqCst);
+    if !hook.is_null() {
+        // only set_double_fault_hook stores into DOUBLE_FAULT_HOOK.
+        let hook: DoubleFaultHook = unsafe { core::mem::transmute(hook) };
+        hook(&stack_frame, error_code);
+    }
     panic!("EXCEPTION DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
+/// A function that handles double faults instead of the kernel's handler.
+pub type DoubleFaultHook = fn(&InterruptStackFrame, u64) -> !;
+
+// the hook as a raw pointer, so that the handler can read it without taking a lock.
+static DOUBLE_FAULT_HOOK: AtomicPtr<()> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Makes the double fault handler call `hook` instead of panicking, e.g. for tests
+/// that expect a double fault. The hook runs on the double fault stack.
+pub fn set_double_fault_hook(hook: DoubleFaultHook) {
+    DOUBLE_FAULT_HOOK.store(hook as *mut (), Ordering::SeqCst);
+}
+
+/// A function that gets to resolve page faults before the kernel's handler reports them,
+/// e.g. by mapping the page. Returns whether it did, the faulting instruction is then retried.
+pub type PageFaultHook = fn(&PageFaultCause) -> bool;
+
+static PAGE_FAULT_HOOK: AtomicPtr<()> = AtomicPtr::new(core::ptr::null_mut());
+
+/// Makes the page fault handler call `hook` first, for demand paging, copy on write, or
+/// tests that fault on purpose.
+pub fn set_page_fault_hook(hook: PageFaultHook) {
+    PAGE_FAULT_HOOK.store(hook as *mut (), Ordering::SeqCst);
+}
+
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
 // By wrapping the ChainedPics struct in a Mutex we are able to get safe
 // mutable access (through the lock method).
 // The ChainedPics::new function is unsafe because wrong offsets could cause undefined behavior.
-pub static PICS: spin::Mutex<ChainedPics> =
-    spin::Mutex::new( unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) } );
+pub static PICS: Mutex<ChainedPics> =
+    Mutex::new( unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) } );
 
 // The enum is a C-like enum so that we can directly specify the index for each variant.
 // The repr(u8) attribute specifies that each variant is represented as an u8.
@@ -70,8 +180,15 @@ impl InterruptIndex {
     }
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    print!(".");
+extern "x86-interrupt" fn timer_interrupt_handler(stack_frame: InterruptStackFrame) {
+    count(InterruptIndex::Timer.as_u8());
+    let _timer = idle::interrupt(InterruptIndex::Timer.as_u8());
+    if let Some(latency) = time::interrupt_latency_ns() {
+        irqstat::record_timer_latency(latency);
+    }
+    time::tick();
+    watchdog::pet();
+    profiler::sample(&stack_frame);
 
     // The notify_end_of_interrupt figures out whether the primary or secondary PIC
     // sent the interrupt and then uses the command and data ports to send an EOI signal
@@ -84,30 +201,29 @@ extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFr
     unsafe { PICS.lock().notify_end_of_interrupt(InterruptIndex::Timer.as_u8()) }
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
+// the stack frame is only for the monitor.
+#[cfg_attr(not(feature = "vga"), allow(unused_variables))]
+extern "x86-interrupt" fn keyboard_interrupt_handler(stack_frame: InterruptStackFrame) {
+    count(InterruptIndex::Keyboard.as_u8());
+    let _timer = idle::interrupt(InterruptIndex::Keyboard.as_u8());
     // the keyboard controller won’t send another interrupt until we have read the
     // so-called scancode of the pressed key.
     // We use the Port type of the x86_64 crate to read a byte from the keyboard’s data port.
     // This byte is called the scancode and is a number that represents the key press/release.
-    use pc_keyboard::{ layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1 };
-
-    lazy_static! {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-            Mutex::new(Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore));
-    }
-
-    let mut keyboard = KEYBOARD.lock();
     let mut port = Port::new(0x60);
 
     let scancode: u8 = unsafe { port.read() };
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key {
-                DecodedKey::Unicode(character) => print!("{}", character),
-                DecodedKey::RawKey(key) => print!("{:?}", key),
-            }
-        }
+    trace!(Interrupt, "keyboard scancode {:#x}", scancode);
+    // the monitor hotkey is handled right here, so that it works even if the
+    // keyboard task never runs. Decoding the key is left to the keyboard task.
+    #[cfg(feature = "vga")]
+    if monitor::is_hotkey(scancode) {
+        monitor::enter(Some(&stack_frame));
+    } else {
+        keyboard::add_scancode(scancode);
     }
+    #[cfg(not(feature = "vga"))]
+    keyboard::add_scancode(scancode);
 
     unsafe {
         PICS.lock()
@@ -115,6 +231,113 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
     }
 }
 
+/// Handles the interrupt of a device, registered with `register_irq`.
+pub type IrqHandler = fn();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// The line is used by the kernel itself (timer, keyboard, cascade) or doesn't exist.
+    Reserved(u8),
+    /// Another handler is registered for the line, we don't share lines yet.
+    InUse(u8),
+}
+
+// the registered handlers by PIC line, as raw pointers so the interrupt handlers don't need a lock.
+static IRQ_HANDLERS: [AtomicPtr<()>; 16] = [const { AtomicPtr::new(core::ptr::null_mut()) }; 16];
+
+/// Calls `handler` for interrupts on the given PIC line and unmasks the line.
+pub fn register_irq(line: u8, handler: IrqHandler) -> Result<(), IrqError> {
+    if !DEVICE_IRQ_ENTRIES.iter().any(|&(device_line, _)| device_line == line) {
+        return Err(IrqError::Reserved(line));
+    }
+    IRQ_HANDLERS[usize::from(line)]
+        .compare_exchange(core::ptr::null_mut(), handler as *mut (), Ordering::SeqCst, Ordering::SeqCst)
+        .map_err(|_| IrqError::InUse(line))?;
+
+    x86_64::instructions::interrupts::without_interrupts(|| unsafe {
+        let mut pics = PICS.lock();
+        let [mut primary, mut secondary] = pics.read_masks();
+        if line < 8 {
+            primary &= !(1 << line);
+        } else {
+            secondary &= !(1 << (line - 8));
+            // the secondary PIC is connected to line 2.
+            primary &= !(1 << 2);
+        }
+        pics.write_masks(primary, secondary);
+    });
+    Ok(())
+}
+
+fn device_interrupt(line: u8) {
+    let vector = PIC_1_OFFSET + line;
+    count(vector);
+    let _timer = idle::interrupt(vector);
+    let handler = IRQ_HANDLERS[usize::from(line)].load(Ordering::SeqCst);
+    if !handler.is_null() {
+        // only register_irq stores into IRQ_HANDLERS.
+        let handler: IrqHandler = unsafe { core::mem::transmute(handler) };
+        handler();
+    }
+    unsafe { PICS.lock().notify_end_of_interrupt(vector) };
+}
+
+#[cfg(feature = "smp")]
+fn inter_processor_interrupt(kind: IpiKind) {
+    count(kind.vector());
+    let _timer = idle::interrupt(kind.vector());
+    smp::handle_ipi(kind);
+    unsafe { apic::write(apic::EOI, 0) };
+}
+
+#[cfg(feature = "smp")]
+extern "x86-interrupt" fn reschedule_ipi_handler(_stack_frame: InterruptStackFrame) {
+    inter_processor_interrupt(IpiKind::Reschedule);
+}
+
+#[cfg(feature = "smp")]
+extern "x86-interrupt" fn tlb_shootdown_ipi_handler(_stack_frame: InterruptStackFrame) {
+    inter_processor_interrupt(IpiKind::TlbShootdown);
+}
+
+#[cfg(feature = "smp")]
+extern "x86-interrupt" fn halt_ipi_handler(_stack_frame: InterruptStackFrame) {
+    inter_processor_interrupt(IpiKind::Halt);
+}
+
+#[cfg(feature = "smp")]
+extern "x86-interrupt" fn call_function_ipi_handler(_stack_frame: InterruptStackFrame) {
+    inter_processor_interrupt(IpiKind::CallFunction);
+}
+
+#[cfg(feature = "smp")]
+const IPI_ENTRIES: [(IpiKind, extern "x86-interrupt" fn(InterruptStackFrame)); 4] = [
+    (IpiKind::Reschedule, reschedule_ipi_handler),
+    (IpiKind::TlbShootdown, tlb_shootdown_ipi_handler),
+    (IpiKind::Halt, halt_ipi_handler),
+    (IpiKind::CallFunction, call_function_ipi_handler),
+];
+
+macro_rules! device_irq_handlers {
+    ($($line:literal => $name:ident),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $name(_stack_frame: InterruptStackFrame) {
+                device_interrupt($line);
+            }
+        )*
+        const DEVICE_IRQ_ENTRIES: [(u8, extern "x86-interrupt" fn(InterruptStackFrame)); [$($line),*].len()] =
+            [$(($line, $name)),*];
+    };
+}
+
+// every PIC line except the timer, the keyboard and the cascade to the secondary PIC.
+device_irq_handlers! {
+    3 => irq3_handler, 4 => irq4_handler, 5 => irq5_handler, 6 => irq6_handler,
+    7 => irq7_handler, 8 => irq8_handler, 9 => irq9_handler, 10 => irq10_handler,
+    11 => irq11_handler, 12 => irq12_handler, 13 => irq13_handler, 14 => irq14_handler,
+    15 => irq15_handler,
+}
+
 use x86_64::structures::idt::PageFaultErrorCode;
 use crate::hlt_loop;
 
@@ -122,14 +345,162 @@ extern "x86-interrupt" fn page_fault_handler(
     stack_frame: InterruptStackFrame,
     error_code: PageFaultErrorCode) {
     use x86_64::registers::control::Cr2;
+    count(14);
+
+    let cause = PageFaultCause { error_code, address: Cr2::read() };
+    let hook = PAGE_FAULT_HOOK.load(Ordering::SeqCst);
+    if !hook.is_null() {
+        // only set_page_fault_hook stores into PAGE_FAULT_HOOK, always a PageFaultHook.
+        let hook: PageFaultHook = unsafe { core::mem::transmute(hook) };
+        if hook(&cause) {
+            // returning retries the faulting instruction.
+            return;
+        }
+    }
 
     println!("EXCEPTION: PAGE FAULT");
     println!("Accessed Address: {:?}", Cr2::read());
+    println!("{} ({:?})", cause, cause.kind());
+    if allocator::is_guard_page(Cr2::read()) {
+        println!("The address is in a heap guard page, something overflowed a heap object");
+    }
     println!("Error code: {:?}", error_code);
     println!("Stack frame: {:#?}", stack_frame);
+    disasm::print_faulting_instruction(stack_frame.instruction_pointer);
     hlt_loop();
 }
 
+// Machine checks are aborts, the interrupted code can't be resumed reliably.
+extern "x86-interrupt" fn machine_check_handler(stack_frame: InterruptStackFrame) -> ! {
+    count(18);
+    mce::report(&stack_frame);
+    panic!("machine check");
+}
+
+// System control port B, reports the legacy hardware error NMIs.
+const SYSTEM_CONTROL_PORT_B: u16 = 0x61;
+const NMI_MEMORY_PARITY_ERROR: u8 = 1 << 7;
+const NMI_IO_CHANNEL_CHECK: u8 = 1 << 6;
+// setting these disables the error checks, clearing them again resets the error bits.
+const NMI_CHECKS_DISABLE: u8 = 0b1100;
+
+// NMIs can't be masked, so this handler may run while any lock is held.
+extern "x86-interrupt" fn nmi_handler(stack_frame: InterruptStackFrame) {
+    count(2);
+    if watchdog::handle_nmi(&stack_frame) {
+        return;
+    }
+
+    let mut port: Port<u8> = Port::new(SYSTEM_CONTROL_PORT_B);
+    let status = unsafe { port.read() };
+    if status & (NMI_MEMORY_PARITY_ERROR | NMI_IO_CHANNEL_CHECK) == 0 {
+        // nothing we know of caused it, e.g. an NMI button in a VM, nothing is broken.
+        println!("EXCEPTION: NMI of unknown origin, continuing\n{:#?}", stack_frame);
+        return;
+    }
+
+    // only the low 4 bits are writable.
+    let control = status & 0x0f;
+    unsafe {
+        port.write(control | NMI_CHECKS_DISABLE);
+        port.write(control & !NMI_CHECKS_DISABLE);
+    }
+    println!("EXCEPTION: NMI\n{:#?}", stack_frame);
+    // memory or a device is broken, continuing would work with corrupted data.
+    if status & NMI_MEMORY_PARITY_ERROR != 0 {
+        panic!("NMI: memory parity error");
+    }
+    panic!("NMI: I/O channel check, a device reported a fatal error");
+}
+
+trap_stub!(general_protection_fault_entry, general_protection_fault_handler, error_code);
+
+extern "C" fn general_protection_fault_handler(frame: &mut TrapFrame) {
+    count(13);
+    // the requested privilege level of the code segment is the CPL of the faulting code.
+    let mode = if frame.stack_frame.code_segment & 3 == 0 { "kernel" } else { "user" };
+    println!("EXCEPTION: GENERAL PROTECTION FAULT in {} mode\n{}", mode, frame);
+    println!("{}", SelectorErrorCode(frame.error_code));
+    disasm::print_faulting_instruction(frame.stack_frame.instruction_pointer);
+    if let Err(reason) = executor::kill_current_task(frame) {
+        panic!("general protection fault, {}", reason);
+    }
+}
+
+extern "x86-interrupt" fn segment_not_present_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    count(11);
+    println!("EXCEPTION: SEGMENT NOT PRESENT, {}\n{:#?}", SelectorErrorCode(error_code), stack_frame);
+    panic!("segment not present");
+}
+
+extern "x86-interrupt" fn stack_segment_fault_handler(stack_frame: InterruptStackFrame, error_code: u64) {
+    count(12);
+    println!("EXCEPTION: STACK SEGMENT FAULT, {}\n{:#?}", SelectorErrorCode(error_code), stack_frame);
+    panic!("stack segment fault");
+}
+
+trap_stub!(divide_error_entry, divide_error_handler);
+
+// raised for a division by zero and for a quotient that doesn't fit the destination.
+extern "C" fn divide_error_handler(frame: &mut TrapFrame) {
+    count(0);
+    println!("EXCEPTION: DIVIDE ERROR\n{}", frame);
+    disasm::print_faulting_instruction(frame.stack_frame.instruction_pointer);
+    if let Err(reason) = executor::kill_current_task(frame) {
+        panic!("divide error, {}", reason);
+    }
+}
+
+trap_stub!(invalid_opcode_entry, invalid_opcode_handler);
+
+extern "C" fn invalid_opcode_handler(frame: &mut TrapFrame) {
+    count(6);
+    println!("EXCEPTION: INVALID OPCODE\n{}", frame);
+    disasm::print_faulting_instruction(frame.stack_frame.instruction_pointer);
+    let instruction = disasm::InstructionBytes::read(frame.stack_frame.instruction_pointer);
+    if let Some(hint) = instruction.and_then(|instruction| disasm::invalid_opcode_hint(instruction.bytes())) {
+        println!("Hint: {}", hint);
+    }
+    if let Err(reason) = executor::kill_current_task(frame) {
+        panic!("invalid opcode, {}", reason);
+    }
+}
+
+trap_stub!(x87_floating_point_entry, x87_floating_point_handler);
+
+// the exception is reported on the x87 instruction after the one that caused it.
+extern "C" fn x87_floating_point_handler(frame: &mut TrapFrame) {
+    count(16);
+    let status = fpu::X87Status::read();
+    fpu::clear_x87_exceptions();
+    let stack = if status.stack_fault() { ", x87 stack fault" } else { "" };
+    println!("EXCEPTION: X87 FLOATING POINT, {}{} (status {:#06x})\n{}", status.exceptions(), stack, status.0, frame);
+    if let Err(reason) = executor::kill_current_task(frame) {
+        panic!("x87 floating point exception, {}", reason);
+    }
+}
+
+trap_stub!(simd_floating_point_entry, simd_floating_point_handler);
+
+extern "C" fn simd_floating_point_handler(frame: &mut TrapFrame) {
+    count(19);
+    let mxcsr = fpu::Mxcsr::read();
+    fpu::clear_simd_exceptions();
+    println!("EXCEPTION: SIMD FLOATING POINT, {} (mxcsr {:#010x})\n{}", mxcsr.exceptions(), mxcsr.0, frame);
+    disasm::print_faulting_instruction(frame.stack_frame.instruction_pointer);
+    // #XM is a fault, returning would run the instruction and fault again.
+    if let Err(reason) = executor::kill_current_task(frame) {
+        panic!("simd floating point exception, {}", reason);
+    }
+}
+
+trap_stub!(debug_entry, debug_handler);
+
+extern "C" fn debug_handler(frame: &mut TrapFrame) {
+    count(1);
+    debug::handle_debug_exception(frame);
+}
+
 #[test_case]
 fn test_breakpoint_exception_handler () {
     // invokes the int3 function to trigger a breakpoint exception.
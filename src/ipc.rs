@@ -0,0 +1,222 @@
+// Message queues between kernel tasks.
+//
+// A queue carries typed messages instead of the bytes of a pipe, and holds at most
+// `capacity` of them, so a fast sender can't exhaust the heap. Any number of senders can
+// feed one receiver. Receiving either awaits a message in a task or, for code that runs
+// outside the executor, halts the CPU until one arrives. Once all senders are dropped,
+// the receiver gets the queued messages and then `None`.
+//
+// Senders may run in interrupt handlers, so the queue is only locked with interrupts disabled.
+//
+// Without processes there are no syscalls to expose the queues to, they are only used
+// inside the kernel for now.
+
+use alloc::{collections::VecDeque, sync::Arc, vec::Vec};
+use core::{
+    future::poll_fn,
+    task::{Poll, Waker},
+};
+use x86_64::instructions::interrupts;
+use crate::{idle, sync::Mutex};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendError<T> {
+    /// The queue is full, the message is handed back.
+    Full(T),
+    /// The receiver was dropped, the message is handed back.
+    Closed(T),
+}
+
+struct Queue<T> {
+    messages: VecDeque<T>,
+    capacity: usize,
+    senders: usize,
+    receiver_closed: bool,
+    receiver: Option<Waker>,
+    // senders waiting for room, all of them are woken when a message is taken out.
+    blocked_senders: Vec<Waker>,
+}
+
+pub struct Sender<T> {
+    queue: Arc<Mutex<Queue<T>>>,
+}
+
+pub struct Receiver<T> {
+    queue: Arc<Mutex<Queue<T>>>,
+}
+
+// Runs `f` on the locked queue, with interrupts disabled.
+fn with_queue<T, R>(queue: &Mutex<Queue<T>>, f: impl FnOnce(&mut Queue<T>) -> R) -> R {
+    interrupts::without_interrupts(|| f(&mut queue.lock()))
+}
+
+/// Creates a message queue with room for `capacity` messages.
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "a message queue needs room for at least one message");
+    let queue = Arc::new(Mutex::new(Queue {
+        messages: VecDeque::with_capacity(capacity),
+        capacity,
+        senders: 1,
+        receiver_closed: false,
+        receiver: None,
+        blocked_senders: Vec::new(),
+    }));
+    (Sender { queue: queue.clone() }, Receiver { queue })
+}
+
+impl<T> Sender<T> {
+    pub fn try_send(&self, message: T) -> Result<(), SendError<T>> {
+        with_queue(&self.queue, |queue| {
+            if queue.receiver_closed {
+                return Err(SendError::Closed(message));
+            }
+            if queue.messages.len() == queue.capacity {
+                return Err(SendError::Full(message));
+            }
+            queue.messages.push_back(message);
+            if let Some(receiver) = queue.receiver.take() {
+                receiver.wake();
+            }
+            Ok(())
+        })
+    }
+
+    /// Sends the message, waiting for room if the queue is full. Fails with
+    /// the message if the receiver is gone.
+    pub async fn send(&self, message: T) -> Result<(), T> {
+        let mut message = Some(message);
+        poll_fn(|context| {
+            match self.try_send(message.take().expect("polled after completion")) {
+                Ok(()) => Poll::Ready(Ok(())),
+                Err(SendError::Closed(m)) => Poll::Ready(Err(m)),
+                Err(SendError::Full(m)) => {
+                    with_queue(&self.queue, |queue| {
+                        queue.blocked_senders.push(context.waker().clone());
+                        // the receiver may have made room before we registered the waker.
+                        if queue.messages.len() < queue.capacity {
+                            context.waker().wake_by_ref();
+                        }
+                    });
+                    message = Some(m);
+                    Poll::Pending
+                }
+            }
+        }).await
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        with_queue(&self.queue, |queue| queue.senders += 1);
+        Sender { queue: self.queue.clone() }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        with_queue(&self.queue, |queue| {
+            queue.senders -= 1;
+            if queue.senders == 0 {
+                if let Some(receiver) = queue.receiver.take() {
+                    receiver.wake();
+                }
+            }
+        });
+    }
+}
+
+impl<T> Receiver<T> {
+    /// Takes the next message without waiting. `Err(())` means the queue is
+    /// empty but still open, `Ok(None)` that all senders are gone.
+    #[allow(clippy::result_unit_err)]
+    pub fn try_recv(&self) -> Result<Option<T>, ()> {
+        with_queue(&self.queue, |queue| match queue.messages.pop_front() {
+            Some(message) => {
+                for sender in queue.blocked_senders.drain(..) {
+                    sender.wake();
+                }
+                Ok(Some(message))
+            }
+            None if queue.senders == 0 => Ok(None),
+            None => Err(()),
+        })
+    }
+
+    /// Waits for the next message, `None` once all senders are gone.
+    pub async fn recv(&self) -> Option<T> {
+        poll_fn(|context| {
+            if let Ok(message) = self.try_recv() {
+                return Poll::Ready(message);
+            }
+            with_queue(&self.queue, |queue| {
+                queue.receiver = Some(context.waker().clone());
+                // a sender may have sent or gone away before we registered the waker.
+                if !queue.messages.is_empty() || queue.senders == 0 {
+                    context.waker().wake_by_ref();
+                }
+            });
+            Poll::Pending
+        }).await
+    }
+
+    /// Like `recv`, for code that doesn't run in a task: halts the CPU until a
+    /// message arrives, which requires the sender to run in an interrupt handler.
+    /// Interrupts are enabled while halting, and are back as they were on return.
+    pub fn recv_blocking(&self) -> Option<T> {
+        let were_enabled = interrupts::are_enabled();
+        loop {
+            // check and halt atomically, see the executor's sleep_if_idle.
+            interrupts::disable();
+            match self.try_recv() {
+                Ok(message) => {
+                    if were_enabled {
+                        interrupts::enable();
+                    }
+                    return message;
+                }
+                Err(()) => idle::enable_and_hlt(),
+            }
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        with_queue(&self.queue, |queue| {
+            queue.receiver_closed = true;
+            for sender in queue.blocked_senders.drain(..) {
+                sender.wake();
+            }
+        });
+    }
+}
+
+#[test_case]
+fn test_send_and_receive() {
+    let (sender, receiver) = channel(2);
+    assert_eq!(receiver.try_recv(), Err(()));
+    sender.try_send(1).unwrap();
+    sender.clone().try_send(2).unwrap();
+    assert_eq!(sender.try_send(3), Err(SendError::Full(3)));
+    assert_eq!(receiver.try_recv(), Ok(Some(1)));
+    assert_eq!(receiver.recv_blocking(), Some(2));
+    drop(sender);
+    assert_eq!(receiver.try_recv(), Ok(None));
+}
+
+#[test_case]
+fn test_send_to_dropped_receiver() {
+    let (sender, receiver) = channel(1);
+    drop(receiver);
+    assert_eq!(sender.try_send(1), Err(SendError::Closed(1)));
+}
+
+#[test_case]
+fn test_recv_blocking_keeps_interrupts_disabled() {
+    let (sender, receiver) = channel(1);
+    sender.try_send(1).unwrap();
+    interrupts::without_interrupts(|| {
+        assert_eq!(receiver.recv_blocking(), Some(1));
+        assert!(!interrupts::are_enabled());
+    });
+}
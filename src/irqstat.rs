@@ -0,0 +1,145 @@
+// Interrupt latency and handler duration statistics.
+//
+// Every device interrupt handler is timed with the TSC from its entry to its exit, which
+// includes the end of interrupt, see `idle::interrupt`. The durations go into a histogram
+// per vector, with power of two buckets, next to the count, the mean and the maximum.
+//
+// The latency, the time from the interrupt request to the handler, is only known for the
+// PIT timer: its counter tells how far it has counted down since it raised the interrupt.
+// Code that runs with interrupts disabled, e.g. a busy wait for the serial port to send,
+// shows up there as long latencies. The latency is measured in nanoseconds, the TSC rate
+// isn't known, while durations are in TSC cycles like the rest of the accounting.
+//
+// `reset` clears everything, so the effect of a change can be measured over a workload.
+
+use core::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// Number of histogram buckets, the first one also counts everything below
+/// 2^MIN_SHIFT, the last one everything above.
+pub const BUCKETS: usize = 16;
+const MIN_SHIFT: u32 = 6;
+
+struct Histogram {
+    buckets: [AtomicU64; BUCKETS],
+    count: AtomicU64,
+    sum: AtomicU64,
+    max: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Histogram {
+            buckets: [const { AtomicU64::new(0) }; BUCKETS],
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+            max: AtomicU64::new(0),
+        }
+    }
+
+    fn record(&self, value: u64) {
+        self.buckets[bucket(value)].fetch_add(1, Ordering::Relaxed);
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+        self.max.fetch_max(value, Ordering::Relaxed);
+    }
+
+    fn stats(&self) -> Stats {
+        let count = self.count.load(Ordering::Relaxed);
+        Stats {
+            count,
+            mean: self.sum.load(Ordering::Relaxed) / count.max(1),
+            max: self.max.load(Ordering::Relaxed),
+            buckets: core::array::from_fn(|i| self.buckets[i].load(Ordering::Relaxed)),
+        }
+    }
+
+    fn reset(&self) {
+        for counter in self.buckets.iter().chain([&self.count, &self.sum, &self.max]) {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+// bucket i counts the values from 2^(i + MIN_SHIFT) up to the next power of two.
+fn bucket(value: u64) -> usize {
+    let log2 = (u64::BITS - 1).saturating_sub(value.leading_zeros());
+    (log2.saturating_sub(MIN_SHIFT) as usize).min(BUCKETS - 1)
+}
+
+/// The lower bound of histogram bucket `i`.
+pub fn bucket_start(i: usize) -> u64 {
+    match i {
+        0 => 0,
+        i => 1 << (i as u32 + MIN_SHIFT),
+    }
+}
+
+/// A snapshot of a histogram.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    pub count: u64,
+    pub mean: u64,
+    pub max: u64,
+    pub buckets: [u64; BUCKETS],
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} times, mean {}, max {}", self.count, self.mean, self.max)?;
+        for (i, &count) in self.buckets.iter().enumerate().filter(|&(_, &count)| count != 0) {
+            write!(f, "\n  >= {:>8}: {}", bucket_start(i), count)?;
+        }
+        Ok(())
+    }
+}
+
+static DURATIONS: [Histogram; 256] = [const { Histogram::new() }; 256];
+static TIMER_LATENCY: Histogram = Histogram::new();
+
+/// Records the duration of a handler of `vector`, in TSC cycles.
+pub fn record_duration(vector: u8, cycles: u64) {
+    DURATIONS[usize::from(vector)].record(cycles);
+}
+
+/// Records the latency of a timer interrupt, in nanoseconds.
+pub fn record_timer_latency(ns: u64) {
+    TIMER_LATENCY.record(ns);
+}
+
+/// Returns the handler durations of `vector` in TSC cycles, only device interrupts are
+/// measured.
+pub fn duration_stats(vector: u8) -> Stats {
+    DURATIONS[usize::from(vector)].stats()
+}
+
+/// Returns the latencies of the timer interrupt in nanoseconds, the one-shot intervals
+/// of the tickless idle aren't measured.
+pub fn timer_latency_stats() -> Stats {
+    TIMER_LATENCY.stats()
+}
+
+pub fn reset() {
+    DURATIONS.iter().chain([&TIMER_LATENCY]).for_each(Histogram::reset);
+}
+
+#[test_case]
+fn test_histogram() {
+    assert_eq!(bucket(0), 0);
+    assert_eq!(bucket(127), 0);
+    assert_eq!(bucket(128), 1);
+    assert_eq!(bucket(u64::MAX), BUCKETS - 1);
+    assert_eq!(bucket(bucket_start(5)), 5);
+
+    let histogram = Histogram::new();
+    for value in [100, 200, 240] {
+        histogram.record(value);
+    }
+    let stats = histogram.stats();
+    assert_eq!((stats.count, stats.mean, stats.max), (3, 180, 240));
+    assert_eq!(&stats.buckets[..3], &[1, 2, 0]);
+    histogram.reset();
+    assert_eq!(histogram.stats().count, 0);
+}
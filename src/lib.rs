@@ -19,15 +19,69 @@
 extern crate alloc;
 
 use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
 #[cfg(test)]
 use bootloader::{entry_point, BootInfo};
+use x86_64::structures::paging::{mapper::MapToError, OffsetPageTable, Size4KiB};
+use boot::BootData;
+use memory::BootFrameAllocator;
 
+pub mod boot;
+#[cfg(feature = "vga")]
 pub mod vga_buffer;
 pub mod serial;
+pub mod console;
+pub mod tty;
 pub mod interrupts;
+pub mod exceptions;
+pub mod mce;
+pub mod fpu;
 pub mod gdt;
 pub mod memory;
 pub mod allocator;
+pub mod shrinker;
+pub mod sync;
+pub mod collections;
+pub mod rcu;
+pub mod apic;
+#[cfg(feature = "smp")]
+pub mod smp;
+pub mod backtrace;
+pub mod watchdog;
+pub mod profiler;
+pub mod perf;
+pub mod bench;
+pub mod trace;
+pub mod registers;
+pub mod trap;
+pub mod disasm;
+pub mod debug;
+#[cfg(feature = "vga")]
+pub mod monitor;
+pub mod memtest;
+pub mod integrity;
+pub mod panic_screen;
+pub mod panic_policy;
+pub mod task;
+pub mod idle;
+pub mod irqstat;
+pub mod time;
+pub mod rtc;
+pub mod hypervisor;
+pub mod pci;
+pub mod random;
+pub mod virtio;
+pub mod power;
+pub mod cpufreq;
+pub mod thermal;
+pub mod speaker;
+#[cfg(feature = "sound")]
+pub mod sound;
+#[cfg(feature = "usb")]
+pub mod usb;
+pub mod pipe;
+pub mod ipc;
+pub mod workqueue;
 
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
@@ -44,6 +98,8 @@ pub enum QemuExitCode {
 pub fn exit_qemu(exit_code: QemuExitCode) {
     use x86_64::instructions::port::Port;
 
+    power::run_shutdown_hooks();
+
     // We use u32 because we specified the iosize of the isa-debug-exit device as 4 bytes.
     // Both operations are unsafe, because writing to an I/O port can generally result in
     // arbitrary behavior.
@@ -65,14 +121,14 @@ impl<T> Testable for T
     fn run(&self) -> () {
         // We implement the run function by first printing the function name using
         // the any::type_name function.
-        serial_print!("{}...\t", core::any::type_name::<T>());
+        test_print!("{}...\t", core::any::type_name::<T>());
         self(); // invoke the test function
-        serial_println!("[ok]");
+        test_println!("[ok]");
     }
 }
 
 pub fn test_runner(tests: &[&dyn Testable]) {
-    serial_println!("Running {} tests", tests.len());
+    test_println!("Running {} tests", tests.len());
     for test in tests {
         test.run();
     }
@@ -89,14 +145,24 @@ pub fn test_runner(tests: &[&dyn Testable]) {
 // The hlt instruction does exactly that.
 pub fn hlt_loop() -> ! {
     loop {
-        x86_64::instructions::hlt();
+        idle::hlt();
     }
 }
 
+/// The tone of the beep on panics and failed tests.
+pub const PANIC_BEEP_HZ: u32 = 880;
+pub const PANIC_BEEP_MS: u64 = 300;
+
 // Panic handler in test mode.
 pub fn test_panic_handler(info: &PanicInfo) -> ! {
+    test_println!("[failed]\n");
+    test_println!("Error: {}", info);
+    // also on COM1, where someone reading the log looks for what went wrong. The test may
+    // have panicked while printing, nothing else runs after us.
+    unsafe { serial::SERIAL1.force_unlock() };
     serial_println!("[failed]\n");
     serial_println!("Error: {}", info);
+    speaker::beep(PANIC_BEEP_HZ, PANIC_BEEP_MS);
     exit_qemu(QemuExitCode::Failed);
     hlt_loop();
 }
@@ -106,8 +172,11 @@ entry_point!(test_kernel_main);
 
 /// Entry point for `cargo test`
 #[cfg(test)]
-fn test_kernel_main(_boot_info: &'static BootInfo) -> ! {
-    init();
+fn test_kernel_main(boot_info: &'static BootInfo) -> ! {
+    let boot_data = BootData::from(boot_info);
+    init(&boot_data);
+    // for the tests of the code that allocates, like the message queues and pipes.
+    init_memory(&boot_data, InitStage::Heap).expect("heap initialization failed");
     test_main();
     hlt_loop();
 }
@@ -118,15 +187,175 @@ fn panic(info: &PanicInfo) -> ! {
     test_panic_handler(info)
 }
 
-// Initializing the IDT.
-pub fn init() {
+/// The stages of the kernel initialization, in the order they run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum InitStage {
+    Gdt,
+    Idt,
+    Pic,
+    Memory,
+    Heap,
+    Drivers,
+}
+
+#[derive(Debug)]
+pub enum InitError {
+    /// Mapping the heap pages failed.
+    Heap(MapToError<Size4KiB>),
+    /// Mapping the local APIC registers failed.
+    Apic(MapToError<Size4KiB>),
+    /// A driver wrote to the kernel's code or read-only data.
+    Integrity(integrity::IntegrityError),
+    /// `init_memory` was already called, a second mapper would alias the page tables.
+    AlreadyInitialized,
+}
+
+// set by the first init_memory call.
+static MEMORY_INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+/// The paging state set up by the memory stage, for the callers that need to map more pages.
+pub struct KernelMemory {
+    pub mapper: OffsetPageTable<'static>,
+    pub frame_allocator: BootFrameAllocator,
+}
+
+// Initializes the VGA writer and the CPU stages: the GDT, the IDT and the PIC. None of them
+// can fail. Output printed before, or without a text buffer, only goes to serial.
+#[cfg_attr(not(feature = "vga"), allow(unused_variables))]
+pub fn init(boot_data: &BootData) {
+    #[cfg(feature = "vga")]
+    if let Some(text_buffer) = boot_data.text_buffer {
+        unsafe { vga_buffer::init(text_buffer) };
+    }
+    idle::start();
     gdt::init();
+    log_stage(InitStage::Gdt);
+    random::init();
+
     interrupts::init_idt();
+    // hardware errors are reported through #MC from here on, the kernel works without.
+    if mce::init().is_err() {
+        serial_println!("init: no machine check architecture");
+    }
+    fpu::init();
+    log_stage(InitStage::Idt);
+
     //  initialize the 8259 PIC. It is unsafe because it can cause undefined
     // behavior if the PIC is misconfigured.
     unsafe { interrupts::PICS.lock().initialize() };
+    time::init();
+    time::init_wall_clock();
 
     // The interrupts::enable function of the x86_64 crate executes the special
     // sti instruction (“set interrupts”) to enable external interrupts.
     x86_64::instructions::interrupts::enable();
+    log_stage(InitStage::Pic);
+}
+
+/// Runs the stages after `init`, from the memory stage up to and including `last`.
+///
+/// Tests that only need paging pass `InitStage::Memory`, tests that need the heap
+/// `InitStage::Heap`. A failing stage is logged and returned, the later stages don't run.
+/// Only the first call does anything, later ones return `InitError::AlreadyInitialized`.
+pub fn init_memory(boot_data: &BootData, last: InitStage) -> Result<KernelMemory, InitError> {
+    if MEMORY_INITIALIZED.swap(true, Ordering::AcqRel) {
+        return Err(InitError::AlreadyInitialized);
+    }
+    // only once, as checked above.
+    let mapper = unsafe { memory::init(boot_data.physical_memory_offset) };
+    // a bad map corrupts memory much later and far away, stop right here instead.
+    if let Err(err) = boot_data.validate(&mapper) {
+        serial_println!("init: memory map:");
+        for region in boot_data.memory_regions() {
+            serial_println!("  {}", region);
+        }
+        panic!("invalid memory map: {}", err);
+    }
+
+    // must run before the frame allocator hands out the first frame.
+    #[cfg(feature = "memtest")]
+    {
+        let report = unsafe { memtest::run(boot_data) };
+        println!("memtest: {} frames tested, {} bad", report.tested_frames, report.bad_frames);
+    }
+    let frame_allocator = unsafe { BootFrameAllocator::init(boot_data) };
+    let mut kernel_memory = KernelMemory { mapper, frame_allocator };
+    log_stage(InitStage::Memory);
+
+    if last >= InitStage::Heap {
+        run_stage(InitStage::Heap, || {
+            allocator::init_heap(&mut kernel_memory.mapper, &mut kernel_memory.frame_allocator)
+                .map_err(InitError::Heap)
+        })?;
+        // before the drivers, which are what might scribble over the image.
+        serial_println!("init: kernel image hash {:#018x}", integrity::init());
+    }
+    if last >= InitStage::Drivers {
+        run_stage(InitStage::Drivers, || init_drivers(&mut kernel_memory))?;
+    }
+    Ok(kernel_memory)
+}
+
+fn init_drivers(kernel_memory: &mut KernelMemory) -> Result<(), InitError> {
+    apic::init(&mut kernel_memory.mapper, &mut kernel_memory.frame_allocator)
+        .map_err(InitError::Apic)?;
+    serial_println!("init: local APIC in {} mode", if apic::is_x2apic() { "x2APIC" } else { "xAPIC" });
+    if let Err(err) = task::keyboard::init_serial_input() {
+        serial_println!("init: no serial input for the keyboard mirror: {:?}", err);
+    }
+    // the kernel works fine without the watchdog, e.g. in VMs without a PMU.
+    if let Err(err) = watchdog::init() {
+        println!("NMI watchdog disabled: {:?}", err);
+    }
+    match virtio::console::init(&mut kernel_memory.frame_allocator) {
+        Ok(_) => {
+            serial_println!("init: virtio console registered");
+        }
+        Err(err) => {
+            serial_println!("init: no virtio console: {:?}", err);
+        }
+    }
+    if let Err(err) = virtio::rng::init(&mut kernel_memory.frame_allocator) {
+        serial_println!("init: no virtio-rng: {:?}", err);
+    }
+    if !random::is_seeded() {
+        serial_println!("init: the entropy pool is not seeded");
+    }
+    #[cfg(feature = "sound")]
+    if let Err(err) = sound::ac97::init(&mut kernel_memory.frame_allocator) {
+        serial_println!("init: no AC'97 audio: {:?}", err);
+    }
+    #[cfg(feature = "usb")]
+    match usb::xhci::init(&mut kernel_memory.mapper, &mut kernel_memory.frame_allocator) {
+        Ok(()) => {
+            usb::hid::init(&mut kernel_memory.frame_allocator);
+        }
+        Err(err) => {
+            serial_println!("init: no xHCI controller: {:?}", err);
+        }
+    }
+    match hypervisor::init() {
+        Ok(()) => {
+            serial_println!("init: using kvmclock");
+        }
+        Err(err) => {
+            serial_println!("init: no kvmclock: {:?}, hypervisor {:?}", err, hypervisor::detect());
+        }
+    }
+    integrity::verify().map_err(InitError::Integrity)
+}
+
+fn run_stage(stage: InitStage, f: impl FnOnce() -> Result<(), InitError>) -> Result<(), InitError> {
+    let result = f();
+    match &result {
+        Ok(()) => log_stage(stage),
+        Err(err) => {
+            serial_println!("init: {:?} failed: {:?}", stage, err);
+        }
+    }
+    result
+}
+
+fn log_stage(stage: InitStage) {
+    serial_println!("init: {:?} done", stage);
 }
@@ -15,13 +15,11 @@ use x86_64::{
     VirtAddr
 };
 
-use rust_os::{hlt_loop, println};
-use rust_os::{
-    allocator,
-    memory::{ // self means the memory crate, we can access public values
-        self, BootFrameAllocator,
-    }
-};
+use rust_os::{boot::BootData, println, InitStage, KernelMemory};
+use rust_os::task::{executor::Executor, keyboard, Task};
+use rust_os::{integrity, memory, shrinker, thermal, workqueue};
+#[cfg(feature = "usb")]
+use rust_os::usb;
 use bootloader::{BootInfo, entry_point};
 
 // function to handle panic, `!` means a function
@@ -36,8 +34,7 @@ use bootloader::{BootInfo, entry_point};
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    println!("{}", info);
-    hlt_loop();
+    rust_os::panic_screen::show(info);
 }
 
 entry_point!(kernel_main);
@@ -45,17 +42,17 @@ entry_point!(kernel_main);
 fn kernel_main(boot_info: &'static BootInfo) -> ! {
     // this function is the entry point, since the linker looks for a function
     // named `_start` by default.
+    // initialize the VGA writer and the IDT to be used by the CPU.
+    // everything after the entry point works with the bootloader independent boot data.
+    let boot_data = BootData::from(boot_info);
+    rust_os::init(&boot_data);
+
     println!("Welcome to LumexOS {}\
          Current year - {}", "😎", 2022);
 
-    // initialize the IDT to be used by the CPU.
-    rust_os::init();
-
-    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe {
-        BootFrameAllocator::init(&boot_info.memory_map)
-    };
+    let KernelMemory { mut mapper, mut frame_allocator } =
+        rust_os::init_memory(&boot_data, InitStage::Drivers)
+            .expect("kernel initialization failed");
 
     // map an unused page.
     let page = Page::containing_address(VirtAddr::new(0));
@@ -87,9 +84,7 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
         println!("{:?} -> {:?}", virt, phys);
     }
 
-    // initialize the heap memory.
-    allocator::init_heap(&mut mapper, &mut frame_allocator)
-        .expect("heap initialization failed");
+    println!("{}", memory::MemorySummary::new(&boot_data, &frame_allocator));
 
     let x = Box::new(41);
     println!("value {:} allocated on the heap!", *x);
@@ -112,7 +107,26 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     test_main();
 
     println!("It did not crash!");
-    hlt_loop();
+
+    let mut executor = Executor::new();
+    executor.spawn(Task::new(example_task()));
+    executor.spawn(Task::new(workqueue::run()));
+    executor.spawn(Task::new(shrinker::run()));
+    executor.spawn(Task::new(integrity::run()));
+    executor.spawn(Task::new(thermal::run()));
+    executor.spawn(Task::new(keyboard::process_keypresses()));
+    #[cfg(feature = "usb")]
+    executor.spawn(Task::new(usb::hid::poll_devices()));
+    executor.run();
+}
+
+async fn async_number() -> u32 {
+    42
+}
+
+async fn example_task() {
+    let number = async_number().await;
+    println!("async number: {}", number);
 }
 
 #[cfg(test)]
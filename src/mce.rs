@@ -0,0 +1,126 @@
+// Machine check architecture.
+//
+// The CPU reports hardware errors it detects, e.g. uncorrectable ECC errors or bus errors,
+// through a number of MCA banks, each with a control, status, address and misc MSR.
+// Without CR4.MCE set, a machine check shuts the CPU down as if it triple faulted and the
+// error is lost. With it, the CPU raises #MC and the handler dumps the banks, so the
+// error is at least visible before the kernel gives up.
+
+use core::{arch::x86_64::__cpuid, fmt};
+use x86_64::{
+    registers::{
+        control::{Cr4, Cr4Flags},
+        model_specific::Msr,
+    },
+    structures::idt::InterruptStackFrame,
+};
+use crate::{println, serial_println};
+
+const IA32_MCG_CAP: u32 = 0x179;
+const IA32_MCG_STATUS: u32 = 0x17a;
+const IA32_MCG_CTL: u32 = 0x17b;
+// the bank MSRs start at IA32_MC0_CTL, 4 per bank: CTL, STATUS, ADDR, MISC.
+const IA32_MC0_CTL: u32 = 0x400;
+
+const MCG_CAP_COUNT: u64 = 0xff;
+const MCG_CAP_CTL_PRESENT: u64 = 1 << 8;
+
+const STATUS_VALID: u64 = 1 << 63;
+const STATUS_OVERFLOW: u64 = 1 << 62;
+const STATUS_UNCORRECTED: u64 = 1 << 61;
+const STATUS_MISC_VALID: u64 = 1 << 59;
+const STATUS_ADDR_VALID: u64 = 1 << 58;
+const STATUS_CONTEXT_CORRUPT: u64 = 1 << 57;
+
+// CPUID.1:EDX bits for the machine check exception and architecture.
+const CPUID_MCE: u32 = 1 << 7;
+const CPUID_MCA: u32 = 1 << 14;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MceUnsupported;
+
+/// The error logged in an MCA bank.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BankError {
+    pub bank: u32,
+    pub status: u64,
+    pub address: Option<u64>,
+    pub misc: Option<u64>,
+}
+
+impl fmt::Display for BankError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "bank {}: status={:#018x}", self.bank, self.status)?;
+        if let Some(address) = self.address {
+            write!(f, " addr={:#x}", address)?;
+        }
+        if let Some(misc) = self.misc {
+            write!(f, " misc={:#x}", misc)?;
+        }
+        // the MCA error code in the low 16 bits is model specific beyond this.
+        write!(f, " code={:#06x}", self.status & 0xffff)?;
+        if self.status & STATUS_UNCORRECTED != 0 {
+            write!(f, " uncorrected")?;
+        }
+        if self.status & STATUS_CONTEXT_CORRUPT != 0 {
+            write!(f, " context-corrupt")?;
+        }
+        if self.status & STATUS_OVERFLOW != 0 {
+            write!(f, " overflow")?;
+        }
+        Ok(())
+    }
+}
+
+fn bank_count() -> u32 {
+    (unsafe { Msr::new(IA32_MCG_CAP).read() } & MCG_CAP_COUNT) as u32
+}
+
+fn read_bank(bank: u32) -> Option<BankError> {
+    let msr = |offset| unsafe { Msr::new(IA32_MC0_CTL + 4 * bank + offset).read() };
+    let status = msr(1);
+    if status & STATUS_VALID == 0 {
+        return None;
+    }
+    Some(BankError {
+        bank,
+        status,
+        address: (status & STATUS_ADDR_VALID != 0).then(|| msr(2)),
+        misc: (status & STATUS_MISC_VALID != 0).then(|| msr(3)),
+    })
+}
+
+/// Enables the error reporting of all banks and machine check exceptions. Errors that
+/// are still logged in the banks, e.g. from before a reset, are printed and cleared.
+pub fn init() -> Result<(), MceUnsupported> {
+    let features = __cpuid(1).edx;
+    if features & (CPUID_MCE | CPUID_MCA) != CPUID_MCE | CPUID_MCA {
+        return Err(MceUnsupported);
+    }
+
+    unsafe {
+        let capabilities = Msr::new(IA32_MCG_CAP).read();
+        if capabilities & MCG_CAP_CTL_PRESENT != 0 {
+            Msr::new(IA32_MCG_CTL).write(u64::MAX);
+        }
+        for bank in 0..bank_count() {
+            if let Some(error) = read_bank(bank) {
+                serial_println!("mce: error logged before boot, {}", error);
+            }
+            Msr::new(IA32_MC0_CTL + 4 * bank).write(u64::MAX);
+            Msr::new(IA32_MC0_CTL + 4 * bank + 1).write(0);
+        }
+        Cr4::update(|flags| flags.insert(Cr4Flags::MACHINE_CHECK_EXCEPTION));
+    }
+    Ok(())
+}
+
+/// Prints all banks with a valid error, called by the #MC handler.
+pub fn report(stack_frame: &InterruptStackFrame) {
+    let global_status = unsafe { Msr::new(IA32_MCG_STATUS).read() };
+    println!("EXCEPTION: MACHINE CHECK, MCG_STATUS={:#x}\n{:#?}", global_status, stack_frame);
+    for error in (0..bank_count()).filter_map(read_bank) {
+        println!("{}", error);
+        serial_println!("mce: {}", error);
+    }
+}
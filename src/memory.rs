@@ -8,12 +8,38 @@ use x86_64::{
         Size4KiB,
         FrameAllocator,
         PageTableFlags as Flags,
+        page_table::PageTableEntry,
     },
     VirtAddr,
     PhysAddr,
-    registers::control::Cr3
+    registers::control::{ Cr3, Cr4, Cr4Flags }
 };
-use bootloader::bootinfo::{ MemoryMap, MemoryRegionType };
+use core::{ fmt, sync::atomic::{ AtomicU64, AtomicUsize, Ordering } };
+use crate::{ allocator, boot::{ BootData, MemoryKind }, memtest };
+
+// The offset passed to `init`, kept around for code that can't get hold of the mapper,
+// like exception handlers that want to know if an address is safe to read.
+// 0 means `init` has not been called yet.
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+// the frame counts of the BootFrameAllocator, for `frame_stats`.
+static USABLE_FRAMES: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATED_FRAMES: AtomicUsize = AtomicUsize::new(0);
+
+/// The physical frames the BootFrameAllocator can hand out and has handed out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameStats {
+    pub usable: usize,
+    pub allocated: usize,
+}
+
+/// Returns the frame usage of the BootFrameAllocator.
+pub fn frame_stats() -> FrameStats {
+    FrameStats {
+        usable: USABLE_FRAMES.load(Ordering::Relaxed),
+        allocated: ALLOCATED_FRAMES.load(Ordering::Relaxed),
+    }
+}
 
 /// A FrameAllocator that always returns `None`.
 pub struct EmptyFrameAllocator;
@@ -25,24 +51,27 @@ unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator  {
 }
 
 /// A FrameAllocator that returns usable frames from the bootloader's memory map.
-///  A 'static reference to the memory map passed by the bootloader and a next field
+///  A copy of the boot data with the memory regions and a next field
 /// that keeps track of number of the next frame that the allocator should return.
 pub struct BootFrameAllocator {
-    memory_map: &'static MemoryMap,
+    boot_data: BootData,
     next: usize,
 }
 
 impl BootFrameAllocator {
-    /// Create a FrameAllocator from the passed memory map.
+    /// Create a FrameAllocator from the memory regions of the boot data.
     ///
     /// This function is unsafe because the caller must guarantee that the passed
-    /// memory map is valid. The main requirement is that all frames that are marked
-    /// as `USABLE` in it are really unused.
-    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
-        BootFrameAllocator{
-            memory_map,
+    /// memory regions are valid. The main requirement is that all frames that are marked
+    /// as `Usable` in them are really unused.
+    pub unsafe fn init(boot_data: &BootData) -> Self {
+        let allocator = BootFrameAllocator{
+            boot_data: boot_data.clone(),
             next: 0,
-        }
+        };
+        USABLE_FRAMES.store(allocator.usable_frames().count(), Ordering::Relaxed);
+        ALLOCATED_FRAMES.store(0, Ordering::Relaxed);
+        allocator
     }
 
     /// Returns an iterator over the usable frames specified in the memory map.
@@ -51,25 +80,64 @@ impl BootFrameAllocator {
     /// trait with item type PhysFrame, but don’t need to name the concrete return type.
     /// This is important here because we can’t name the concrete type since it
     /// depends on unnamable closure types.
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        // get the usable regions from the memory map.
-        let regions = self.memory_map.iter();
-        // map each region to its address range,
-        // transform to an iterator of frame start addresses
-        let frame_addresses = regions
-            .filter(|r| r.region_type == MemoryRegionType::Usable)
-            .map(|r| r.range.start_addr()..r.range.end_addr())
-            .flat_map(|r| r.step_by(4096));
-
-        // create `PhysFrame` types from the start addresses
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> + '_ {
+        // the frames of the usable regions, leaving out the
+        // frames that failed the boot-time memory test.
+        self.boot_data.usable_frames()
+            .filter(|&frame| !memtest::is_bad(frame))
+    }
+}
+
+impl BootFrameAllocator {
+    /// Returns the number of frames handed out so far.
+    pub fn allocated_frames(&self) -> usize {
+        self.next
+    }
+
+    /// Gives back the frames handed out since `allocated_frames` returned `count`.
+    ///
+    /// # Safety
+    ///
+    /// None of these frames may be in use anymore, they are handed out again.
+    pub unsafe fn free_since(&mut self, count: usize) {
+        assert!(count <= self.next, "frames that were never allocated can't be freed");
+        self.next = count;
+        ALLOCATED_FRAMES.store(count, Ordering::Relaxed);
     }
+
+    /// Allocates `count` physically contiguous frames, for devices that access
+    /// memory with DMA, and returns the first one.
+    ///
+    /// Frames that are skipped because the following ones are not contiguous are lost.
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrame> {
+        let mut start = self.allocate_frame()?;
+        let mut len = 1;
+        while len < count {
+            let frame = self.allocate_frame()?;
+            if frame == start + len as u64 {
+                len += 1;
+            } else {
+                start = frame;
+                len = 1;
+            }
+        }
+        Some(start)
+    }
+}
+
+/// Returns the virtual address at which the complete physical memory mapping makes
+/// the physical address accessible, panics if `init` has not been called yet.
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    let offset = PHYSICAL_MEMORY_OFFSET.load(Ordering::SeqCst);
+    assert!(offset != 0, "memory::init has not been called yet");
+    VirtAddr::new(offset + addr.as_u64())
 }
 
 unsafe impl FrameAllocator<Size4KiB> for BootFrameAllocator {
     fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
         let frame = self.usable_frames().nth(self.next);
         self.next += 1;
+        ALLOCATED_FRAMES.store(self.next, Ordering::Relaxed);
         frame
     }
 }
@@ -91,8 +159,29 @@ pub fn create_example_mapping(
     map_to_result.expect("map_to failed").flush();
 }
 
+/// Returns whether the CPU uses 5-level paging (LA57), in which CR3 points to a level 5
+/// table whose entries each cover a 256TiB level 4 table.
+///
+/// Paging mode can only change outside of long mode, so this is decided by the bootloader.
+pub fn la57_enabled() -> bool {
+    Cr4::read().contains(Cr4Flags::L5_PAGING)
+}
+
+// The level 5 index of an address. VirtAddr only holds 48 bit canonical addresses, their
+// bits 48 to 56 are copies of bit 47: 0 in the lower half, 511 in the upper half.
+fn p5_index(addr: VirtAddr) -> usize {
+    ((addr.as_u64() >> 48) & 0x1ff) as usize
+}
+
 /// Initialize a new OffsetPageTable.
 ///
+/// With 5-level paging, the OffsetPageTable of the x86_64 crate, which only knows 4 levels,
+/// manages the level 4 table of the lower half, the one the kernel and all of its mappings
+/// live in. It can then only map addresses in the lower half, whose level 5 index is 0: it
+/// takes an upper half address for one in the second half of that level 4 table, and maps
+/// the wrong page without an error. All the kernel's mappings (the heap, the APIC, the
+/// device buffers) are in the lower half.
+///
 /// This function is unsafe because the caller must guarantee that the
 /// complete physical memory is mapped to virtual memory at the passed
 /// `physical_memory_offset`. Also, this function must be only called once
@@ -101,6 +190,7 @@ pub fn create_example_mapping(
 /// returns a new OffsetPageTable instance with a 'static lifetime.
 /// This means that the instance stays valid for the complete runtime of our kernel.
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::SeqCst);
     let level_4_table = active_level_4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
@@ -113,10 +203,17 @@ pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static>
 /// to avoid aliasing `&mut` references (which is undefined behavior).
 unsafe fn active_level_4_table(physical_memory_offset: VirtAddr)
     -> &'static mut PageTable {
-    // read the physical frame of the active level 4 table from the CR3 register.
-    let (level_4_table_frame, _) = Cr3::read();
+    // read the physical frame of the active level 4 table from the CR3 register,
+    // or of the level 5 table above it.
+    let (top_level_table_frame, _) = Cr3::read();
 
-    let phys = level_4_table_frame.start_address();
+    let mut phys = top_level_table_frame.start_address();
+    if la57_enabled() {
+        let level_5_table: &PageTable = &*(physical_memory_offset + phys.as_u64()).as_ptr();
+        let entry = &level_5_table[0];
+        assert!(entry.flags().contains(Flags::PRESENT), "5-level paging without a lower half level 4 table");
+        phys = entry.addr();
+    }
     let virt = physical_memory_offset + phys.as_u64();
     let page_table_ptr: *mut PageTable = virt.as_mut_ptr();
 
@@ -124,3 +221,121 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr)
     // return a mutable reference to that value.
     &mut *page_table_ptr
 }
+
+/// Translates the given virtual address to the mapped physical address, or
+/// `None` if the address is not mapped or `init` has not been called yet.
+///
+/// Unlike the `Translate` implementation of the mapper, this only reads the
+/// active page tables, so it can be used from interrupt handlers that must not
+/// touch the mapper, e.g. to check that an address is safe to dereference.
+pub fn translate(addr: VirtAddr) -> Option<PhysAddr> {
+    walk_page_tables(addr, |_, _| {})
+}
+
+/// Translates the given virtual address like `translate`, calling `visit` with
+/// the level (5 or 4 down to 1) and the entry of every page table on the way.
+pub fn walk_page_tables(
+    addr: VirtAddr,
+    mut visit: impl FnMut(u8, &PageTableEntry),
+) -> Option<PhysAddr> {
+    let offset = PHYSICAL_MEMORY_OFFSET.load(Ordering::SeqCst);
+    if offset == 0 {
+        return None;
+    }
+
+    let (top_level_table_frame, _) = Cr3::read();
+    let mut frame_addr = top_level_table_frame.start_address();
+    let table_indexes = [
+        p5_index(addr),
+        usize::from(addr.p4_index()),
+        usize::from(addr.p3_index()),
+        usize::from(addr.p2_index()),
+        usize::from(addr.p1_index()),
+    ];
+    // without LA57, CR3 points to the level 4 table.
+    let first_level = if la57_enabled() { 5 } else { 4 };
+
+    // traverse the multi-level page table.
+    for level in (1..=first_level).rev() {
+        let virt = VirtAddr::new(offset + frame_addr.as_u64());
+        let table: &PageTable = unsafe { &*virt.as_ptr() };
+        let entry = &table[table_indexes[5 - level]];
+        visit(level as u8, entry);
+
+        if !entry.flags().contains(Flags::PRESENT) {
+            return None;
+        }
+        if entry.flags().contains(Flags::HUGE_PAGE) {
+            // a huge page entry in the level 3 table maps 1GiB, in the level 2 table 2MiB.
+            let page_offset_mask = match level {
+                3 => 0x3fff_ffff,
+                2 => 0x1f_ffff,
+                _ => return None,
+            };
+            return Some(entry.addr() + (addr.as_u64() & page_offset_mask));
+        }
+        frame_addr = entry.addr();
+    }
+
+    // calculate the physical address by adding the page offset.
+    Some(frame_addr + u64::from(addr.page_offset()))
+}
+
+/// How the physical memory is used, in bytes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemorySummary {
+    /// All RAM reported by the memory map, i.e. everything but `MemoryKind::Unavailable`.
+    pub total: u64,
+    pub kernel_image: u64,
+    /// The page tables set up by the bootloader.
+    pub boot_page_tables: u64,
+    /// Frames allocated by the kernel for its own page tables.
+    pub kernel_page_tables: u64,
+    pub heap: u64,
+    /// Usable memory that has not been handed out by the frame allocator yet.
+    pub free: u64,
+}
+
+impl MemorySummary {
+    /// Summarizes the memory usage after `allocator::init_heap`.
+    ///
+    /// Every frame allocated so far either backs the heap or holds a page table,
+    /// the mappings created by the kernel only point at existing frames otherwise.
+    pub fn new(boot_data: &BootData, frame_allocator: &BootFrameAllocator) -> Self {
+        let total = boot_data.memory_regions().iter()
+            .filter(|r| r.kind != MemoryKind::Unavailable)
+            .map(|r| r.size())
+            .sum();
+
+        let allocated = frame_allocator.allocated_frames() as u64 * 4096;
+        let heap = allocator::HEAP_SIZE as u64;
+
+        MemorySummary {
+            total,
+            kernel_image: boot_data.size_of(MemoryKind::Kernel),
+            boot_page_tables: boot_data.size_of(MemoryKind::PageTable),
+            kernel_page_tables: allocated.saturating_sub(heap),
+            heap,
+            free: boot_data.size_of(MemoryKind::Usable).saturating_sub(allocated),
+        }
+    }
+}
+
+impl fmt::Display for MemorySummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        const KIB: u64 = 1024;
+        writeln!(f, "memory: {} KiB total", self.total / KIB)?;
+        writeln!(f, "  kernel image:  {:>8} KiB", self.kernel_image / KIB)?;
+        writeln!(f, "  page tables:   {:>8} KiB ({} KiB bootloader, {} KiB kernel)",
+                 (self.boot_page_tables + self.kernel_page_tables) / KIB,
+                 self.boot_page_tables / KIB, self.kernel_page_tables / KIB)?;
+        writeln!(f, "  heap:          {:>8} KiB", self.heap / KIB)?;
+        write!(f, "  free:          {:>8} KiB", self.free / KIB)
+    }
+}
+
+#[test_case]
+fn test_p5_index() {
+    assert_eq!(p5_index(VirtAddr::new(0x0000_7fff_ffff_f000)), 0);
+    assert_eq!(p5_index(VirtAddr::new(0xffff_8000_0000_0000)), 511);
+}
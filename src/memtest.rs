@@ -0,0 +1,98 @@
+// Boot-time memory test.
+//
+// With the `memtest` feature, the kernel tests all usable frames before the frame allocator
+// hands out the first one. Every frame is filled with a few bit patterns which are read
+// back right away, which finds stuck bits. Afterwards, every word of usable memory is set
+// to its own physical address and verified in a second pass over all frames, which finds
+// address lines that make two frames alias each other. Frames that fail are recorded here
+// and skipped by the BootFrameAllocator, just like reserved memory.
+//
+// The physical memory is accessed through the complete mapping set up by the bootloader
+// (the `map_physical_memory` feature), so this runs before paging is touched at all.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::structures::paging::PhysFrame;
+use crate::{boot::BootData, sync::Mutex};
+
+/// Maximum number of bad frames that can be recorded. Memory that has more bad
+/// frames than this should not be used anyway, `run` panics in that case.
+pub const MAX_BAD_FRAMES: usize = 128;
+
+const FRAME_SIZE: u64 = 4096;
+const WORDS_PER_FRAME: usize = FRAME_SIZE as usize / 8;
+const PATTERNS: [u64; 4] = [0, u64::MAX, 0xaaaa_aaaa_aaaa_aaaa, 0x5555_5555_5555_5555];
+
+static BAD_FRAMES: Mutex<[Option<PhysFrame>; MAX_BAD_FRAMES]> = Mutex::new([None; MAX_BAD_FRAMES]);
+// number of entries in BAD_FRAMES, lets `is_bad` skip the lock in the common case.
+static BAD_FRAME_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// The result of a memory test run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemtestReport {
+    pub tested_frames: usize,
+    pub bad_frames: usize,
+}
+
+/// Tests all usable frames of the boot data and records the bad ones.
+///
+/// # Safety
+///
+/// The complete physical memory must be mapped at `physical_memory_offset`, and
+/// none of the usable frames may be in use yet, their contents are destroyed.
+pub unsafe fn run(boot_data: &BootData) -> MemtestReport {
+    let frames = || boot_data.usable_frames();
+    let words = |frame: PhysFrame| {
+        let start = boot_data.physical_memory_offset + frame.start_address().as_u64();
+        start.as_mut_ptr::<u64>()
+    };
+
+    let mut tested_frames = 0;
+    for frame in frames() {
+        tested_frames += 1;
+        let ptr = words(frame);
+        let stuck_bits = PATTERNS.iter().any(|&pattern| {
+            (0..WORDS_PER_FRAME).for_each(|i| ptr.add(i).write_volatile(pattern));
+            (0..WORDS_PER_FRAME).any(|i| ptr.add(i).read_volatile() != pattern)
+        });
+        if stuck_bits {
+            mark_bad(frame);
+        }
+    }
+
+    // write the address pattern to all frames first, so that aliasing frames
+    // overwrite each other before anything is verified.
+    for frame in frames() {
+        let ptr = words(frame);
+        for i in 0..WORDS_PER_FRAME {
+            ptr.add(i).write_volatile(frame.start_address().as_u64() + 8 * i as u64);
+        }
+    }
+    for frame in frames().filter(|&frame| !is_bad(frame)) {
+        let ptr = words(frame);
+        let aliased = (0..WORDS_PER_FRAME)
+            .any(|i| ptr.add(i).read_volatile() != frame.start_address().as_u64() + 8 * i as u64);
+        if aliased {
+            mark_bad(frame);
+        }
+    }
+
+    MemtestReport { tested_frames, bad_frames: BAD_FRAME_COUNT.load(Ordering::SeqCst) }
+}
+
+/// Returns whether the memory test found the frame to be bad.
+pub fn is_bad(frame: PhysFrame) -> bool {
+    if BAD_FRAME_COUNT.load(Ordering::Relaxed) == 0 {
+        return false;
+    }
+    BAD_FRAMES.lock().contains(&Some(frame))
+}
+
+fn mark_bad(frame: PhysFrame) {
+    let mut bad_frames = BAD_FRAMES.lock();
+    let count = BAD_FRAME_COUNT.load(Ordering::SeqCst);
+    if count == MAX_BAD_FRAMES {
+        panic!("memtest: more than {} bad frames, the memory is broken", MAX_BAD_FRAMES);
+    }
+    bad_frames[count] = Some(frame);
+    BAD_FRAME_COUNT.store(count + 1, Ordering::SeqCst);
+}
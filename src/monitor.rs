@@ -0,0 +1,641 @@
+// In-kernel debug monitor.
+//
+// Pressing Ctrl+Alt+F12 drops into a small synchronous command loop that runs right inside
+// the keyboard interrupt handler, with interrupts disabled. The hotkey is recognized from the
+// raw scancodes, and the monitor polls the keyboard controller and decodes the keys itself
+// instead of relying on interrupts and the keyboard task, so it keeps working when the
+// interrupt handling, the timer or anything built on top of them is broken. From the monitor
+// we can look at memory, walk the page tables, see interrupt counts and the interrupted
+// state, and then resume the kernel where it was interrupted.
+//
+// The command line can be edited with the arrow keys, Home, End, Backspace, Delete, Ctrl+U
+// and Ctrl+W, and Up and Down go through the last commands, also those of earlier visits.
+// The line is redrawn on the screen only, the serial console gets each entered command.
+//
+// `set NAME=value` defines a variable, `$NAME` in a command line is replaced by its value
+// before the command runs. The variables live as long as the kernel, so addresses found in
+// one visit can be used in the next. There is no command substitution: the commands print
+// straight to the console instead of producing output that could be put in a command line.
+
+use core::{
+    str,
+    sync::atomic::{AtomicBool, Ordering},
+};
+use pc_keyboard::{
+    layouts, DecodedKey, HandleControl, KeyCode, Keyboard, KeyboardLayout, ScancodeSet,
+    ScancodeSet1,
+};
+use x86_64::{instructions::port::Port, structures::idt::InterruptStackFrame, VirtAddr};
+use crate::{
+    allocator, backtrace::Backtrace, collections::SpscQueue, cpufreq, rtc::DateTime, idle,
+    interrupts, irqstat, memory, thermal, print, println, serial_println, sync::Mutex,
+    task::{executor, keyboard}, time, trace, usb::hid, vga_buffer::{self, WRITER},
+};
+
+const MAX_LINE: usize = 64;
+// more than the codes of the 6 keys and 8 modifiers of a boot keyboard report.
+const USB_SCANCODES: usize = 64;
+/// Number of commands kept for Up and Down.
+pub const HISTORY_SIZE: usize = 16;
+pub const MAX_VARIABLES: usize = 8;
+const MAX_NAME: usize = 16;
+/// Maximum number of bytes dumped by the `mem` command.
+const MAX_DUMP: u64 = 256;
+
+// scancode set 1 make codes, the right Ctrl and Alt keys send the same ones after 0xe0.
+const SCANCODE_CTRL: u8 = 0x1d;
+const SCANCODE_ALT: u8 = 0x38;
+const SCANCODE_F12: u8 = 0x58;
+// break codes have the top bit set.
+const SCANCODE_RELEASED: u8 = 0x80;
+
+// control characters of HandleControl::MapLettersToUnicode and the Delete key.
+const CTRL_U: char = '\u{15}';
+const CTRL_W: char = '\u{17}';
+const BACKSPACE: char = '\u{8}';
+const DELETE: char = '\u{7f}';
+
+static CTRL_DOWN: AtomicBool = AtomicBool::new(false);
+static ALT_DOWN: AtomicBool = AtomicBool::new(false);
+static HISTORY: Mutex<History> =
+    Mutex::new(History { lines: [([0; MAX_LINE], 0); HISTORY_SIZE], count: 0 });
+static VARIABLES: Mutex<Variables> = Mutex::new(Variables([None; MAX_VARIABLES]));
+
+/// Tracks the modifier keys, returns whether the scancode completes the
+/// Ctrl+Alt+F12 combination that enters the monitor.
+pub fn is_hotkey(scancode: u8) -> bool {
+    let down = scancode & SCANCODE_RELEASED == 0;
+    match scancode & !SCANCODE_RELEASED {
+        SCANCODE_CTRL => CTRL_DOWN.store(down, Ordering::Relaxed),
+        SCANCODE_ALT => ALT_DOWN.store(down, Ordering::Relaxed),
+        SCANCODE_F12 => {
+            return down && CTRL_DOWN.load(Ordering::Relaxed) && ALT_DOWN.load(Ordering::Relaxed)
+        }
+        _ => {}
+    }
+    false
+}
+
+/// Runs the monitor until the `continue` command, must be called with interrupts disabled.
+/// `stack_frame` is that of the interrupted code, `None` when entered from a task, e.g. by
+/// the USB keyboard driver.
+pub fn enter(stack_frame: Option<&InterruptStackFrame>) {
+    println!("\nentering debug monitor, type `help` for the commands");
+    let mut keyboard =
+        Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::MapLettersToUnicode);
+
+    loop {
+        print!("monitor> ");
+        let editor = read_line(&mut keyboard);
+        HISTORY.lock().add(editor.text());
+        let mut expanded = [0; MAX_LINE];
+        let len = VARIABLES.lock().expand(editor.text(), &mut expanded);
+        // the editor only stores printable ASCII characters, and so do the variables.
+        let command = str::from_utf8(&expanded[..len]).unwrap_or("");
+        let mut args = command.split_whitespace();
+
+        match args.next() {
+            Some("help") => help(),
+            Some("set") => set_variable(command.trim_start()["set".len()..].trim()),
+            Some("mem") => dump_memory(args.next(), args.next()),
+            Some("pt") => dump_page_tables(args.next()),
+            Some("irq") | Some("irqstat") => dump_interrupt_counts(),
+            Some("irqlat") => dump_interrupt_latency(args.next()),
+            Some("ps") => list_tasks(),
+            Some("free") => show_memory_usage(),
+            Some("date") => show_date(args.next()),
+            Some("uptime") => println!("up {} ms, {} ticks", time::uptime_ms(), time::ticks()),
+            Some("cpu") => println!("{}", idle::utilization()),
+            Some("freq") => cpu_frequency(args.next()),
+            Some("temp") => match thermal::read() {
+                Ok(reading) => println!("{}", reading),
+                Err(err) => println!("no temperature: {:?}", err),
+            },
+            Some("frame") => match stack_frame {
+                Some(stack_frame) => println!("{:#?}", stack_frame),
+                None => println!("entered from a task, nothing was interrupted"),
+            },
+            Some("bt") => println!("{}", Backtrace::capture()),
+            Some("trace") => trace::dump(),
+            Some("screen") => {
+                if let Err(err) = vga_buffer::dump() {
+                    println!("can't dump the screen: {:?}", err);
+                }
+            }
+            Some("c") | Some("continue") => break,
+            Some(other) => println!("unknown command `{}`", other),
+            None => {}
+        }
+    }
+
+    // the modifiers were released while we polled the keyboard ourselves, the
+    // keyboard task still thinks they are held down.
+    CTRL_DOWN.store(false, Ordering::Relaxed);
+    ALT_DOWN.store(false, Ordering::Relaxed);
+    for modifier in [SCANCODE_CTRL, SCANCODE_ALT] {
+        keyboard::add_scancode(modifier | SCANCODE_RELEASED);
+        keyboard::add_scancode(0xe0);
+        keyboard::add_scancode(modifier | SCANCODE_RELEASED);
+    }
+    println!("leaving debug monitor");
+}
+
+fn help() {
+    println!("mem <addr> [len]  hexdump memory (len in bytes, default 64)");
+    println!("pt <addr>         walk the page tables for a virtual address");
+    println!("irq, irqstat      interrupt counts since boot");
+    println!("irqlat [reset]    timer latency and handler durations, or clear them");
+    println!("ps                tasks and the CPU time they used");
+    println!("free              heap and physical frame usage");
+    println!("uptime            time since boot");
+    println!("date [+HH:MM]     date and time in UTC, or at the offset");
+    println!("cpu               idle, task and interrupt time since boot");
+    println!("freq [POLICY]     P-states, or set the performance or powersave policy");
+    println!("temp              CPU temperature");
+    println!("frame             interrupted stack frame");
+    println!("bt                backtrace");
+    println!("trace             dump the trace buffers over serial");
+    println!("screen            dump the screen contents over serial");
+    println!("set [NAME=value]  define a variable for $NAME, or list them");
+    println!("c, continue       resume the kernel");
+}
+
+fn dump_memory(addr: Option<&str>, len: Option<&str>) {
+    let Some(start) = addr.and_then(parse_number) else {
+        println!("usage: mem <addr> [len]");
+        return;
+    };
+    let len = len.and_then(parse_number).unwrap_or(64).min(MAX_DUMP);
+
+    for line_start in (start..start.saturating_add(len)).step_by(16) {
+        print!("{:#018x}:", line_start);
+        for addr in line_start..line_start.saturating_add(16).min(start.saturating_add(len)) {
+            match VirtAddr::try_new(addr).ok().filter(|&virt| memory::translate(virt).is_some()) {
+                Some(virt) => print!(" {:02x}", unsafe { virt.as_ptr::<u8>().read_volatile() }),
+                None => {
+                    println!(" <not mapped>");
+                    return;
+                }
+            }
+        }
+        println!();
+    }
+}
+
+fn dump_page_tables(addr: Option<&str>) {
+    let addr = addr.and_then(parse_number).and_then(|addr| VirtAddr::try_new(addr).ok());
+    let Some(addr) = addr else {
+        println!("usage: pt <addr>");
+        return;
+    };
+
+    println!("{}-level paging", if memory::la57_enabled() { 5 } else { 4 });
+    let phys = memory::walk_page_tables(addr, |level, entry| {
+        println!("L{} {:#014x} {:?}", level, entry.addr().as_u64(), entry.flags());
+    });
+    println!("{:?} -> {:?}", addr, phys);
+}
+
+fn dump_interrupt_counts() {
+    for vector in 0..=u8::MAX {
+        let count = interrupts::interrupt_count(vector);
+        if count != 0 {
+            println!("{:>3} {:<20} {}", vector, interrupts::vector_name(vector), count);
+        }
+    }
+}
+
+fn dump_interrupt_latency(arg: Option<&str>) {
+    if arg == Some("reset") {
+        irqstat::reset();
+        return;
+    }
+    println!("timer latency (ns): {}", irqstat::timer_latency_stats());
+    println!("handler durations (cycles):");
+    for vector in 0..=u8::MAX {
+        let stats = irqstat::duration_stats(vector);
+        if stats.count != 0 {
+            println!("{:>3} {:<20} {} times, mean {}, max {}",
+                vector, interrupts::vector_name(vector), stats.count, stats.mean, stats.max);
+        }
+    }
+}
+
+fn cpu_frequency(policy: Option<&str>) {
+    if let Some(policy) = policy {
+        let Some(policy) = cpufreq::Policy::parse(policy) else {
+            println!("usage: freq [performance|powersave]");
+            return;
+        };
+        match cpufreq::set_policy(policy) {
+            Ok(state) => println!("requested {}", state),
+            Err(err) => println!("can't set the policy: {:?}", err),
+        }
+        return;
+    }
+    match cpufreq::pstates() {
+        Ok(states) => states.as_slice().iter().for_each(|state| println!("{}", state)),
+        Err(err) => println!("no P-states: {:?}", err),
+    }
+    if let Ok(state) = cpufreq::current() {
+        println!("current: {}", state);
+    }
+}
+
+fn list_tasks() {
+    let Some(tasks) = executor::tasks() else {
+        println!("the task table is locked by the interrupted executor");
+        return;
+    };
+    println!("{:>6} {:>10} {:>16}", "id", "polls", "cycles");
+    for task in tasks.iter().flatten() {
+        let current = if executor::current_task() == Some(task.id) { " (interrupted)" } else { "" };
+        println!("{:>6} {:>10} {:>16}{}", task.id, task.polls, task.cycles, current);
+    }
+}
+
+fn show_memory_usage() {
+    match allocator::stats() {
+        Some(heap) => {
+            println!("heap:   {} of {} bytes used, {} free", heap.used, heap.size, heap.free)
+        }
+        None => println!("heap:   locked by the interrupted code"),
+    }
+    let frames = memory::frame_stats();
+    println!("frames: {} of {} usable frames allocated ({} KiB free)",
+        frames.allocated, frames.usable, frames.usable.saturating_sub(frames.allocated) * 4);
+}
+
+fn show_date(offset: Option<&str>) {
+    let offset = match offset.map(parse_utc_offset) {
+        Some(Some(offset)) => offset,
+        Some(None) => {
+            println!("usage: date [+HH:MM]");
+            return;
+        }
+        None => 0,
+    };
+    let seconds = (time::unix_time_ms() / 1000).saturating_add_signed(offset);
+    let sign = if offset < 0 { '-' } else { '+' };
+    let minutes = offset.unsigned_abs() / 60;
+    println!("{} {}{:02}:{:02}", DateTime::from_unix(seconds), sign, minutes / 60, minutes % 60);
+}
+
+// Parses an offset from UTC like +02:00, -0530 or +1 into seconds.
+fn parse_utc_offset(s: &str) -> Option<i64> {
+    let (sign, rest) = match s.as_bytes().first()? {
+        b'+' => (1, &s[1..]),
+        b'-' => (-1, &s[1..]),
+        _ => return None,
+    };
+    let (hours, minutes) = match rest.split_once(':') {
+        Some(parts) => parts,
+        None if rest.len() == 4 => rest.split_at(2),
+        None => (rest, "0"),
+    };
+    let (hours, minutes): (i64, i64) = (hours.parse().ok()?, minutes.parse().ok()?);
+    if hours > 14 || minutes >= 60 {
+        return None;
+    }
+    Some(sign * (hours * 3600 + minutes * 60))
+}
+
+fn parse_number(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+fn set_variable(assignment: &str) {
+    let mut variables = VARIABLES.lock();
+    if assignment.is_empty() {
+        for variable in variables.0.iter().flatten() {
+            println!("{}={}", variable.name(), variable.value());
+        }
+        return;
+    }
+    let result = match assignment.split_once('=') {
+        Some((name, value)) => variables.set(name.trim(), value.trim()),
+        None => Err("usage: set NAME=value"),
+    };
+    if let Err(message) = result {
+        println!("{}", message);
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Variable {
+    name: ([u8; MAX_NAME], usize),
+    value: ([u8; MAX_LINE], usize),
+}
+
+impl Variable {
+    // names and values are checked to be ASCII when they are set.
+    fn name(&self) -> &str {
+        str::from_utf8(&self.name.0[..self.name.1]).unwrap_or("")
+    }
+
+    fn value(&self) -> &str {
+        str::from_utf8(&self.value.0[..self.value.1]).unwrap_or("")
+    }
+}
+
+struct Variables([Option<Variable>; MAX_VARIABLES]);
+
+fn is_name_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || byte == b'_'
+}
+
+impl Variables {
+    // Defines or replaces `name`, an empty value removes it.
+    fn set(&mut self, name: &str, value: &str) -> Result<(), &'static str> {
+        if name.is_empty() || name.len() > MAX_NAME || !name.bytes().all(is_name_byte) {
+            return Err("variable names are letters, digits and _, at most 16 of them");
+        }
+        let existing = self.0.iter().position(|v| v.is_some_and(|v| v.name() == name));
+        if value.is_empty() {
+            if let Some(index) = existing {
+                self.0[index] = None;
+            }
+            return Ok(());
+        }
+        let index = existing
+            .or_else(|| self.0.iter().position(Option::is_none))
+            .ok_or("too many variables")?;
+        let mut variable =
+            Variable { name: ([0; MAX_NAME], name.len()), value: ([0; MAX_LINE], value.len()) };
+        variable.name.0[..name.len()].copy_from_slice(name.as_bytes());
+        variable.value.0[..value.len()].copy_from_slice(value.as_bytes());
+        self.0[index] = Some(variable);
+        Ok(())
+    }
+
+    fn get(&self, name: &[u8]) -> Option<&Variable> {
+        self.0.iter().flatten().find(|v| v.name().as_bytes() == name)
+    }
+
+    // Copies `line` to `out` with every `$NAME` replaced by the value, undefined variables
+    // by nothing. Returns the length, what doesn't fit into `out` is cut off.
+    fn expand(&self, line: &[u8], out: &mut [u8]) -> usize {
+        let mut len = 0;
+        let mut push = |bytes: &[u8]| {
+            let n = bytes.len().min(out.len() - len);
+            out[len..len + n].copy_from_slice(&bytes[..n]);
+            len += n;
+        };
+        let mut rest = line;
+        while let Some(dollar) = rest.iter().position(|&b| b == b'$') {
+            push(&rest[..dollar]);
+            let name_len = rest[dollar + 1..].iter().take_while(|&&b| is_name_byte(b)).count();
+            let name = &rest[dollar + 1..dollar + 1 + name_len];
+            match self.get(name) {
+                Some(variable) => push(variable.value().as_bytes()),
+                // a lone `$` stays.
+                None if name.is_empty() => push(b"$"),
+                None => {}
+            }
+            rest = &rest[dollar + 1 + name_len..];
+        }
+        push(rest);
+        len
+    }
+}
+
+// The commands entered last, oldest first in a ring.
+struct History {
+    lines: [([u8; MAX_LINE], usize); HISTORY_SIZE],
+    // commands added so far, the next goes to `count % HISTORY_SIZE`.
+    count: usize,
+}
+
+impl History {
+    fn add(&mut self, line: &[u8]) {
+        if line.is_empty() || self.get(0) == Some(line) {
+            return;
+        }
+        let entry = &mut self.lines[self.count % HISTORY_SIZE];
+        entry.0[..line.len()].copy_from_slice(line);
+        entry.1 = line.len();
+        self.count += 1;
+    }
+
+    // The command entered `age` commands ago, 0 is the last one.
+    fn get(&self, age: usize) -> Option<&[u8]> {
+        if age >= self.count.min(HISTORY_SIZE) {
+            return None;
+        }
+        let (line, len) = &self.lines[(self.count - 1 - age) % HISTORY_SIZE];
+        Some(&line[..*len])
+    }
+}
+
+// The line being edited and the cursor position in it.
+struct LineEditor {
+    line: [u8; MAX_LINE],
+    len: usize,
+    cursor: usize,
+}
+
+impl LineEditor {
+    fn new() -> Self {
+        LineEditor { line: [0; MAX_LINE], len: 0, cursor: 0 }
+    }
+
+    fn text(&self) -> &[u8] {
+        &self.line[..self.len]
+    }
+
+    fn set(&mut self, text: &[u8]) {
+        self.len = text.len().min(MAX_LINE);
+        self.line[..self.len].copy_from_slice(&text[..self.len]);
+        self.cursor = self.len;
+    }
+
+    fn insert(&mut self, byte: u8) {
+        if self.len == MAX_LINE {
+            return;
+        }
+        self.line.copy_within(self.cursor..self.len, self.cursor + 1);
+        self.line[self.cursor] = byte;
+        self.len += 1;
+        self.cursor += 1;
+    }
+
+    // Removes the characters from `start` up to the cursor.
+    fn remove_before_cursor(&mut self, start: usize) {
+        self.line.copy_within(self.cursor..self.len, start);
+        self.len -= self.cursor - start;
+        self.cursor = start;
+    }
+
+    fn backspace(&mut self) {
+        if self.cursor > 0 {
+            self.remove_before_cursor(self.cursor - 1);
+        }
+    }
+
+    fn delete(&mut self) {
+        if self.cursor < self.len {
+            self.cursor += 1;
+            self.backspace();
+        }
+    }
+
+    // Ctrl+W: removes the word before the cursor and the spaces after it.
+    fn delete_word(&mut self) {
+        let before = &self.line[..self.cursor];
+        let word_end = before.iter().rposition(|&b| b != b' ').map_or(0, |i| i + 1);
+        let word_start = before[..word_end].iter().rposition(|&b| b == b' ').map_or(0, |i| i + 1);
+        self.remove_before_cursor(word_start);
+    }
+
+    fn move_left(&mut self) {
+        self.cursor = self.cursor.saturating_sub(1);
+    }
+
+    fn move_right(&mut self) {
+        self.cursor = (self.cursor + 1).min(self.len);
+    }
+}
+
+// Redraws the line after the prompt at `start`, blanking what's left of a longer line before.
+fn draw(editor: &LineEditor, start: usize, drawn: usize) {
+    let mut writer = WRITER.lock();
+    let Some(writer) = writer.as_mut() else {
+        return;
+    };
+    writer.set_column(start);
+    for &byte in editor.text() {
+        writer.write_byte(byte);
+    }
+    for _ in editor.len..drawn {
+        writer.write_byte(b' ');
+    }
+    writer.set_column(start + editor.cursor);
+}
+
+// Reads and edits a line by polling the keyboard controller and the USB keyboards.
+fn read_line<L: KeyboardLayout, S: ScancodeSet>(keyboard: &mut Keyboard<L, S>) -> LineEditor {
+    let mut status_port: Port<u8> = Port::new(0x64);
+    let mut data_port: Port<u8> = Port::new(0x60);
+    // a USB keyboard report can carry the codes of several keys.
+    let usb_scancodes: SpscQueue<u8, USB_SCANCODES> = SpscQueue::new();
+    let mut editor = LineEditor::new();
+    // how far back Up went into the history, 0 for the line being typed.
+    let mut age = 0;
+    let mut draft = LineEditor::new();
+    let start = WRITER.lock().as_ref().map_or(0, |writer| writer.column());
+
+    loop {
+        let scancode = if let Some(scancode) = usb_scancodes.pop() {
+            scancode
+        // bit 0 of the status register is set when a scancode is waiting.
+        } else if unsafe { status_port.read() } & 1 != 0 {
+            unsafe { data_port.read() }
+        } else {
+            hid::poll_keyboards(|scancode| {
+                let _ = usb_scancodes.push(scancode);
+            });
+            core::hint::spin_loop();
+            continue;
+        };
+
+        let key = match keyboard.add_byte(scancode) {
+            Ok(Some(event)) => keyboard.process_keyevent(event),
+            _ => None,
+        };
+        let drawn = editor.len;
+        match key {
+            Some(DecodedKey::Unicode('\n')) => {
+                editor.cursor = editor.len;
+                draw(&editor, start, drawn);
+                if let Some(writer) = WRITER.lock().as_mut() {
+                    writer.write_byte(b'\n');
+                }
+                serial_println!("{}", str::from_utf8(editor.text()).unwrap_or(""));
+                return editor;
+            }
+            Some(DecodedKey::Unicode(BACKSPACE)) => editor.backspace(),
+            Some(DecodedKey::Unicode(DELETE)) => editor.delete(),
+            Some(DecodedKey::Unicode(CTRL_U)) => editor.remove_before_cursor(0),
+            Some(DecodedKey::Unicode(CTRL_W)) => editor.delete_word(),
+            Some(DecodedKey::Unicode(c)) if c.is_ascii_graphic() || c == ' ' => {
+                editor.insert(c as u8)
+            }
+            Some(DecodedKey::RawKey(KeyCode::ArrowLeft)) => editor.move_left(),
+            Some(DecodedKey::RawKey(KeyCode::ArrowRight)) => editor.move_right(),
+            Some(DecodedKey::RawKey(KeyCode::Home)) => editor.cursor = 0,
+            Some(DecodedKey::RawKey(KeyCode::End)) => editor.cursor = editor.len,
+            Some(DecodedKey::RawKey(KeyCode::ArrowUp)) => {
+                if let Some(line) = HISTORY.lock().get(age) {
+                    if age == 0 {
+                        draft.set(editor.text());
+                    }
+                    editor.set(line);
+                    age += 1;
+                }
+            }
+            Some(DecodedKey::RawKey(KeyCode::ArrowDown)) if age > 0 => {
+                age -= 1;
+                match age {
+                    0 => editor.set(draft.text()),
+                    _ => editor.set(HISTORY.lock().get(age - 1).unwrap_or(&[])),
+                }
+            }
+            _ => continue,
+        }
+        draw(&editor, start, drawn);
+    }
+}
+
+#[test_case]
+fn test_line_editing() {
+    let mut editor = LineEditor::new();
+    editor.set(b"mem 0x1000");
+    editor.cursor = 4;
+    editor.insert(b'x');
+    assert_eq!(editor.text(), b"mem x0x1000");
+    editor.backspace();
+    editor.delete();
+    assert_eq!(editor.text(), b"mem x1000");
+    editor.cursor = editor.len;
+    editor.delete_word();
+    assert_eq!(editor.text(), b"mem ");
+    editor.delete_word();
+    assert_eq!((editor.text(), editor.cursor), (&b""[..], 0));
+
+    let mut history = History { lines: [([0; MAX_LINE], 0); HISTORY_SIZE], count: 0 };
+    for _ in 0..HISTORY_SIZE {
+        history.add(b"irq");
+        history.add(b"bt");
+    }
+    assert_eq!(history.count, 2 * HISTORY_SIZE);
+    assert_eq!(history.get(0), Some(&b"bt"[..]));
+    assert_eq!(history.get(1), Some(&b"irq"[..]));
+    assert_eq!(history.get(HISTORY_SIZE), None);
+}
+
+#[test_case]
+fn test_parse_utc_offset() {
+    assert_eq!(parse_utc_offset("+02:00"), Some(7200));
+    assert_eq!(parse_utc_offset("-0530"), Some(-19_800));
+    assert_eq!(parse_utc_offset("+1"), Some(3600));
+    assert_eq!(parse_utc_offset("2"), None);
+    assert_eq!(parse_utc_offset("+02:75"), None);
+}
+
+#[test_case]
+fn test_variable_expansion() {
+    let mut variables = Variables([None; MAX_VARIABLES]);
+    variables.set("addr", "0xb8000").unwrap();
+    variables.set("LEN", "32").unwrap();
+    assert!(variables.set("no-dash", "1").is_err());
+
+    let mut out = [0; MAX_LINE];
+    let len = variables.expand(b"mem $addr $LEN $unset$", &mut out);
+    assert_eq!(&out[..len], b"mem 0xb8000 32 $");
+    variables.set("addr", "").unwrap();
+    let len = variables.expand(b"mem $addr", &mut out);
+    assert_eq!(&out[..len], b"mem ");
+}
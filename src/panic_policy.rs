@@ -0,0 +1,119 @@
+// What the kernel does after a panic was reported.
+//
+// Halting forever keeps the report on the screen for whoever sits in front of it, but
+// leaves an unattended test rig hanging until its timeout. The policy can instead reboot
+// the machine after a delay, or exit QEMU with the failure code through the isa-debug-exit
+// device. It is chosen at build time with the `PANIC_POLICY` environment variable, e.g.
+// `PANIC_POLICY=reboot=10 cargo build`, and can be changed at runtime with `set_policy`,
+// for a kernel command line once the bootloader passes one. Test kernels have their own
+// panic handler and always exit QEMU.
+//
+// The policy is kept in an atomic, the panic handler must not wait for a lock.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use crate::{exit_qemu, hlt_loop, power, serial_println, time, QemuExitCode};
+
+/// Seconds before the reboot when `PANIC_POLICY=reboot` gives no delay.
+pub const DEFAULT_REBOOT_DELAY_S: u64 = 5;
+/// Longest reboot delay, longer ones given to `PanicPolicy::parse` or `set_policy` are cut
+/// to it.
+pub const MAX_REBOOT_DELAY_S: u64 = 3600;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PanicPolicy {
+    Halt,
+    /// Reboots after the given number of seconds.
+    Reboot(u64),
+    /// Exits QEMU with `QemuExitCode::Failed`, halts if not running in QEMU.
+    ExitQemu,
+}
+
+impl PanicPolicy {
+    /// Parses `halt`, `reboot`, `reboot=<seconds>` or `exit-qemu`.
+    pub fn parse(s: &str) -> Option<PanicPolicy> {
+        match s.split_once('=') {
+            Some(("reboot", delay)) => {
+                let delay: u64 = delay.parse().ok()?;
+                Some(PanicPolicy::Reboot(delay.min(MAX_REBOOT_DELAY_S)))
+            }
+            Some(_) => None,
+            None => match s {
+                "halt" => Some(PanicPolicy::Halt),
+                "reboot" => Some(PanicPolicy::Reboot(DEFAULT_REBOOT_DELAY_S)),
+                "exit-qemu" => Some(PanicPolicy::ExitQemu),
+                _ => None,
+            },
+        }
+    }
+
+    // 0 for halt, 1 for exit-qemu, 2 + the delay for reboot.
+    fn encode(self) -> u64 {
+        match self {
+            PanicPolicy::Halt => 0,
+            PanicPolicy::ExitQemu => 1,
+            PanicPolicy::Reboot(delay) => 2 + delay.min(MAX_REBOOT_DELAY_S),
+        }
+    }
+
+    fn decode(value: u64) -> PanicPolicy {
+        match value {
+            0 => PanicPolicy::Halt,
+            1 => PanicPolicy::ExitQemu,
+            delay => PanicPolicy::Reboot(delay - 2),
+        }
+    }
+}
+
+// the policy set with `set_policy`, `UNSET` until then.
+const UNSET: u64 = u64::MAX;
+static POLICY: AtomicU64 = AtomicU64::new(UNSET);
+
+/// The policy the kernel was built with, `Halt` if `PANIC_POLICY` isn't set or invalid.
+pub fn build_policy() -> PanicPolicy {
+    option_env!("PANIC_POLICY").and_then(PanicPolicy::parse).unwrap_or(PanicPolicy::Halt)
+}
+
+/// Returns the current policy.
+pub fn policy() -> PanicPolicy {
+    match POLICY.load(Ordering::Relaxed) {
+        UNSET => build_policy(),
+        value => PanicPolicy::decode(value),
+    }
+}
+
+/// Overrides the policy the kernel was built with, a reboot delay is cut to
+/// `MAX_REBOOT_DELAY_S`.
+pub fn set_policy(policy: PanicPolicy) {
+    POLICY.store(policy.encode(), Ordering::Relaxed);
+}
+
+/// Carries out the current policy, called at the end of the panic handler.
+pub fn apply() -> ! {
+    match policy() {
+        PanicPolicy::Halt => {}
+        PanicPolicy::Reboot(delay) => {
+            serial_println!("rebooting in {} seconds", delay);
+            time::delay_ms(delay * 1000);
+            power::reboot();
+        }
+        PanicPolicy::ExitQemu => exit_qemu(QemuExitCode::Failed),
+    }
+    hlt_loop();
+}
+
+#[test_case]
+fn test_parse_panic_policy() {
+    assert_eq!(PanicPolicy::parse("halt"), Some(PanicPolicy::Halt));
+    assert_eq!(PanicPolicy::parse("reboot"), Some(PanicPolicy::Reboot(DEFAULT_REBOOT_DELAY_S)));
+    assert_eq!(PanicPolicy::parse("reboot=30"), Some(PanicPolicy::Reboot(30)));
+    assert_eq!(PanicPolicy::parse("exit-qemu"), Some(PanicPolicy::ExitQemu));
+    let too_long = PanicPolicy::Reboot(MAX_REBOOT_DELAY_S);
+    assert_eq!(PanicPolicy::parse("reboot=99999999999999999"), Some(too_long));
+    assert_eq!(PanicPolicy::parse("reboot=soon"), None);
+    assert_eq!(PanicPolicy::parse("halt=1"), None);
+    for policy in [PanicPolicy::Halt, PanicPolicy::Reboot(0), PanicPolicy::Reboot(7)] {
+        assert_eq!(PanicPolicy::decode(policy.encode()), policy);
+    }
+    assert_eq!(PanicPolicy::decode(PanicPolicy::ExitQemu.encode()), PanicPolicy::ExitQemu);
+    assert_eq!(PanicPolicy::decode(PanicPolicy::Reboot(u64::MAX).encode()), too_long);
+}
@@ -0,0 +1,106 @@
+// Full-screen panic report.
+//
+// A single println of the panic message is easily scrolled away by later output, and it
+// tells nothing about the state the kernel was in.
+// Instead, the panic handler takes over the screen and renders a report with the panic
+// message and location, the registers, the control registers used for paging, the task
+// that was running, the heap usage and the last lines that were on the screen before the panic.
+// The same information (plus a backtrace) is written to the serial port for headless runs.
+// What happens afterwards is up to the panic policy, see `panic_policy`.
+
+use core::{fmt::Write, panic::PanicInfo};
+use x86_64::{
+    instructions::interrupts,
+    registers::control::{Cr2, Cr3},
+};
+use crate::{
+    allocator,
+    backtrace::Backtrace,
+    panic_policy,
+    registers::GeneralRegisters,
+    serial::SERIAL1,
+    speaker,
+    task::executor,
+};
+#[cfg(feature = "vga")]
+use crate::vga_buffer::{Colors, BUFFER_HEIGHT, BUFFER_WIDTH, WRITER};
+
+/// Number of lines of the previous screen contents shown in the report.
+pub const LOG_LINES: usize = 8;
+
+/// Renders the panic report and carries out the panic policy.
+pub fn show(info: &PanicInfo) -> ! {
+    let registers = GeneralRegisters::capture();
+    let backtrace = Backtrace::capture();
+    interrupts::disable();
+
+    // The panic may have happened while the writers were locked. Nothing else is
+    // going to run after us, so we can take them over.
+    unsafe {
+        #[cfg(feature = "vga")]
+        WRITER.force_unlock();
+        SERIAL1.force_unlock();
+    }
+
+    let (level_4_table, _) = Cr3::read();
+    let page_fault_address = Cr2::read();
+    let heap = allocator::stats();
+    let task = executor::current_task();
+
+    // nothing to draw on before `vga_buffer::init` or without `vga`, the serial report still goes out.
+    #[cfg(feature = "vga")]
+    if let Some(writer) = WRITER.lock().as_mut() {
+        // save the bottom of the screen before we clear it.
+        let mut log = [[b' '; BUFFER_WIDTH]; LOG_LINES];
+        for (i, line) in log.iter_mut().enumerate() {
+            *line = writer.row_text(BUFFER_HEIGHT - LOG_LINES + i);
+        }
+
+        // writing to the VGA buffer never fails, see Writer::write_str.
+        writer.set_color(Colors::White, Colors::Red);
+        writer.clear_screen();
+        let _ = writeln!(writer, "KERNEL PANIC");
+        let _ = writeln!(writer, "{}", info.message());
+        if let Some(location) = info.location() {
+            let _ = writeln!(writer, "at {}", location);
+        }
+        let _ = writeln!(writer);
+        let _ = writeln!(writer, "{}", registers);
+        let _ = writeln!(writer, "CR2={:?} CR3={:?}", page_fault_address, level_4_table.start_address());
+        match task {
+            Some(task) => {
+                let _ = writeln!(writer, "task: {}", task);
+            }
+            None => {
+                let _ = writeln!(writer, "task: none");
+            }
+        }
+        match heap {
+            Some(heap) => {
+                let _ = writeln!(writer, "heap: {} of {} bytes used, {} free", heap.used, heap.size, heap.free);
+            }
+            None => {
+                let _ = writeln!(writer, "heap: locked");
+            }
+        }
+
+        let _ = writeln!(writer, "last screen lines:");
+        writer.set_color(Colors::LightGray, Colors::Red);
+        for line in log.iter() {
+            for &character in line.iter() {
+                writer.write_byte(character);
+            }
+        }
+    }
+
+    let mut serial = SERIAL1.lock();
+    let _ = writeln!(serial, "KERNEL PANIC: {}", info);
+    let _ = writeln!(serial, "{}", registers);
+    let _ = writeln!(serial, "CR2={:?} CR3={:?}", page_fault_address, level_4_table.start_address());
+    let _ = writeln!(serial, "task: {:?}", task);
+    let _ = writeln!(serial, "heap: {:?}", heap);
+    let _ = write!(serial, "{}", backtrace);
+
+    speaker::beep(crate::PANIC_BEEP_HZ, crate::PANIC_BEEP_MS);
+    panic_policy::apply();
+}
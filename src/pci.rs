@@ -0,0 +1,183 @@
+// PCI configuration space access and device enumeration.
+//
+// The configuration space of every PCI function is reached through the legacy I/O port
+// mechanism: the bus, device, function and register offset are written to CONFIG_ADDRESS
+// and the register is then read or written through CONFIG_DATA. Enumeration brute-forces
+// all buses and devices, functions beyond 0 are only probed on multifunction devices.
+
+use core::fmt;
+use x86_64::instructions::{interrupts, port::Port};
+use crate::sync::Mutex;
+
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+// bit 31 of CONFIG_ADDRESS enables the configuration space access.
+const CONFIG_ENABLE: u32 = 1 << 31;
+
+const VENDOR_NONE: u16 = 0xffff;
+// bit 7 of the header type marks multifunction devices.
+const HEADER_MULTIFUNCTION: u8 = 0x80;
+
+// configuration space offsets of the type 0 header.
+const REG_VENDOR_DEVICE: u8 = 0x00;
+const REG_COMMAND: u8 = 0x04;
+const REG_CLASS: u8 = 0x08;
+const REG_HEADER_TYPE: u8 = 0x0c;
+const REG_BAR0: u8 = 0x10;
+const REG_SUBSYSTEM: u8 = 0x2c;
+const REG_INTERRUPT_LINE: u8 = 0x3c;
+
+const COMMAND_IO_SPACE: u16 = 1 << 0;
+const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+
+// the CONFIG_ADDRESS/CONFIG_DATA pair must not be interleaved.
+static CONFIG_LOCK: Mutex<()> = Mutex::new(());
+
+/// A PCI function.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciDevice {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+}
+
+/// A base address register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Io(u16),
+    Memory(u64),
+}
+
+impl PciDevice {
+    fn probe(bus: u8, device: u8, function: u8) -> Option<PciDevice> {
+        let ids = read_config(bus, device, function, REG_VENDOR_DEVICE);
+        if ids as u16 == VENDOR_NONE {
+            return None;
+        }
+        let class = read_config(bus, device, function, REG_CLASS);
+        Some(PciDevice {
+            bus,
+            device,
+            function,
+            vendor_id: ids as u16,
+            device_id: (ids >> 16) as u16,
+            class: (class >> 24) as u8,
+            subclass: (class >> 16) as u8,
+            prog_if: (class >> 8) as u8,
+        })
+    }
+
+    pub fn read(&self, offset: u8) -> u32 {
+        read_config(self.bus, self.device, self.function, offset)
+    }
+
+    pub fn write(&self, offset: u8, value: u32) {
+        write_config(self.bus, self.device, self.function, offset, value)
+    }
+
+    /// The subsystem ID, which e.g. identifies the virtio device type.
+    pub fn subsystem_id(&self) -> u16 {
+        (self.read(REG_SUBSYSTEM) >> 16) as u16
+    }
+
+    /// The legacy IRQ line the firmware routed the device's interrupt pin to.
+    pub fn interrupt_line(&self) -> u8 {
+        self.read(REG_INTERRUPT_LINE) as u8
+    }
+
+    /// Returns the base address register `index`, `None` if it is unused.
+    pub fn bar(&self, index: u8) -> Option<Bar> {
+        let offset = REG_BAR0 + 4 * index;
+        let low = self.read(offset);
+        if low & 1 != 0 {
+            return Some(Bar::Io((low & !0b11) as u16));
+        }
+        // bits 1-2 select the type, 0b10 is a 64 bit BAR that continues in the next one.
+        let high = if (low >> 1) & 0b11 == 0b10 { self.read(offset + 4) } else { 0 };
+        let address = u64::from(high) << 32 | u64::from(low & !0xf);
+        (address != 0).then_some(Bar::Memory(address))
+    }
+
+    /// Enables the device's I/O and memory decoding and lets it master DMA.
+    pub fn enable(&self) {
+        let command = self.read(REG_COMMAND);
+        let flags = u32::from(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE | COMMAND_BUS_MASTER);
+        // the upper half is the status register, writing ones there clears its bits.
+        self.write(REG_COMMAND, (command & 0xffff) | flags);
+    }
+}
+
+impl fmt::Display for PciDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{} {:04x}:{:04x} class {:02x}.{:02x}.{:02x}",
+            self.bus, self.device, self.function, self.vendor_id, self.device_id,
+            self.class, self.subclass, self.prog_if)
+    }
+}
+
+/// Calls `f` for every PCI function.
+pub fn for_each_device(mut f: impl FnMut(PciDevice)) {
+    for bus in 0..=u8::MAX {
+        for device in 0..32 {
+            let Some(first) = PciDevice::probe(bus, device, 0) else { continue };
+            f(first);
+            let header_type = (first.read(REG_HEADER_TYPE) >> 16) as u8;
+            if header_type & HEADER_MULTIFUNCTION != 0 {
+                (1..8).filter_map(|function| PciDevice::probe(bus, device, function)).for_each(&mut f);
+            }
+        }
+    }
+}
+
+/// Returns the first function with the given vendor and device ID.
+pub fn find(vendor_id: u16, device_id: u16) -> Option<PciDevice> {
+    let mut found = None;
+    for_each_device(|device| {
+        if found.is_none() && device.vendor_id == vendor_id && device.device_id == device_id {
+            found = Some(device);
+        }
+    });
+    found
+}
+
+fn config_address(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+    CONFIG_ENABLE
+        | u32::from(bus) << 16
+        | u32::from(device) << 11
+        | u32::from(function) << 8
+        | u32::from(offset & !0b11)
+}
+
+fn read_config(bus: u8, device: u8, function: u8, offset: u8) -> u32 {
+    interrupts::without_interrupts(|| {
+        let _guard = CONFIG_LOCK.lock();
+        unsafe {
+            Port::new(CONFIG_ADDRESS).write(config_address(bus, device, function, offset));
+            Port::new(CONFIG_DATA).read()
+        }
+    })
+}
+
+fn write_config(bus: u8, device: u8, function: u8, offset: u8, value: u32) {
+    interrupts::without_interrupts(|| {
+        let _guard = CONFIG_LOCK.lock();
+        unsafe {
+            Port::new(CONFIG_ADDRESS).write(config_address(bus, device, function, offset));
+            Port::new(CONFIG_DATA).write(value);
+        }
+    })
+}
+
+#[test_case]
+fn test_host_bridge_is_found() {
+    // QEMU's machines have an Intel host bridge at 00:00.0.
+    let bridge = PciDevice::probe(0, 0, 0).expect("no PCI host bridge");
+    assert_eq!(bridge.vendor_id, 0x8086);
+    assert_eq!(bridge.class, 0x06);
+}
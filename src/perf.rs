@@ -0,0 +1,247 @@
+// Access to the architectural performance monitoring counters.
+//
+// Intel CPUs (and most hypervisors that expose a virtual PMU) provide a few fixed function
+// counters for instructions retired and cycles, plus general purpose counters that can be
+// programmed with an event selector. CPUID leaf 0xA tells us how many counters there are,
+// how wide they are and which of the architectural events are supported.
+//
+// The first general purpose counter is reserved for the NMI watchdog, `Counter::new`
+// hands out the remaining ones and returns them when the counter is dropped. A fixed counter
+// also belongs to one `Counter` at a time, further counters for its event get a general
+// purpose counter.
+
+use core::{
+    arch::x86_64::__cpuid,
+    sync::atomic::{AtomicU32, Ordering},
+};
+use x86_64::registers::model_specific::Msr;
+
+pub const IA32_PMC0: u32 = 0xc1;
+pub const IA32_PERFEVTSEL0: u32 = 0x186;
+pub const IA32_FIXED_CTR0: u32 = 0x309;
+pub const IA32_FIXED_CTR_CTRL: u32 = 0x38d;
+pub const IA32_PERF_GLOBAL_CTRL: u32 = 0x38f;
+
+pub const PERFEVTSEL_USR: u64 = 1 << 16;
+pub const PERFEVTSEL_OS: u64 = 1 << 17;
+pub const PERFEVTSEL_INT: u64 = 1 << 20;
+pub const PERFEVTSEL_EN: u64 = 1 << 22;
+
+/// The general purpose counter used by the NMI watchdog.
+pub const WATCHDOG_COUNTER: u32 = 0;
+
+// count in both kernel and user mode, 4 control bits per fixed counter.
+const FIXED_CTRL_OS_USR: u64 = 0b11;
+
+/// The performance monitoring capabilities reported by CPUID leaf 0xA.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PmuInfo {
+    pub version: u32,
+    pub general_counters: u32,
+    pub general_width: u32,
+    pub fixed_counters: u32,
+    pub fixed_width: u32,
+    // bit i set means architectural event i is NOT available.
+    unavailable_events: u32,
+}
+
+/// Returns the PMU capabilities, or `None` if there is no architectural PMU.
+pub fn info() -> Option<PmuInfo> {
+    if __cpuid(0).eax < 0xa {
+        return None;
+    }
+
+    let leaf = __cpuid(0xa);
+    let info = PmuInfo {
+        version: leaf.eax & 0xff,
+        general_counters: (leaf.eax >> 8) & 0xff,
+        general_width: (leaf.eax >> 16) & 0xff,
+        // the fixed counters are only described from version 2 on.
+        fixed_counters: if leaf.eax & 0xff >= 2 { leaf.edx & 0x1f } else { 0 },
+        fixed_width: if leaf.eax & 0xff >= 2 { (leaf.edx >> 5) & 0xff } else { 0 },
+        unavailable_events: leaf.ebx,
+    };
+
+    if info.version == 0 || info.general_counters == 0 || info.general_width == 0 {
+        return None;
+    }
+    Some(info)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    InstructionsRetired,
+    CoreCycles,
+    ReferenceCycles,
+    LlcMisses,
+    BranchMisses,
+}
+
+impl Event {
+    // (bit in CPUID.0xA:EBX, event select, unit mask) of the architectural event.
+    fn architectural(self) -> (u32, u64, u64) {
+        match self {
+            Event::CoreCycles => (0, 0x3c, 0x00),
+            Event::InstructionsRetired => (1, 0xc0, 0x00),
+            Event::ReferenceCycles => (2, 0x3c, 0x01),
+            Event::LlcMisses => (4, 0x2e, 0x41),
+            Event::BranchMisses => (6, 0xc5, 0x00),
+        }
+    }
+
+    // index of the fixed function counter counting this event, if any.
+    fn fixed_counter(self) -> Option<u32> {
+        match self {
+            Event::InstructionsRetired => Some(0),
+            Event::CoreCycles => Some(1),
+            Event::ReferenceCycles => Some(2),
+            Event::LlcMisses | Event::BranchMisses => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PerfError {
+    NoPmu,
+    EventUnavailable(Event),
+    NoFreeCounter,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Slot {
+    Fixed(u32),
+    General(u32),
+}
+
+// bit i set means general purpose counter i is in use.
+static USED_COUNTERS: AtomicU32 = AtomicU32::new(1 << WATCHDOG_COUNTER);
+// bit i set means fixed function counter i is in use.
+static USED_FIXED_COUNTERS: AtomicU32 = AtomicU32::new(0);
+
+/// A performance counter programmed to count one event.
+#[derive(Debug)]
+pub struct Counter {
+    event: Event,
+    slot: Slot,
+    width: u32,
+}
+
+impl Counter {
+    /// Allocates a counter for the event. The counter starts stopped at zero.
+    pub fn new(event: Event) -> Result<Counter, PerfError> {
+        let info = info().ok_or(PerfError::NoPmu)?;
+        let (bit, _, _) = event.architectural();
+        if info.unavailable_events & (1 << bit) != 0 {
+            return Err(PerfError::EventUnavailable(event));
+        }
+
+        // prefer the fixed counters, they don't take away a general purpose one.
+        let (slot, width) = match event.fixed_counter() {
+            Some(index) if index < info.fixed_counters && allocate_fixed(index) => {
+                (Slot::Fixed(index), info.fixed_width)
+            }
+            _ => (Slot::General(allocate_general(info.general_counters)?), info.general_width),
+        };
+
+        let counter = Counter { event, slot, width };
+        counter.stop();
+        counter.reset();
+        Ok(counter)
+    }
+
+    pub fn event(&self) -> Event {
+        self.event
+    }
+
+    pub fn start(&self) {
+        unsafe {
+            match self.slot {
+                Slot::Fixed(index) => {
+                    let mut ctrl = Msr::new(IA32_FIXED_CTR_CTRL);
+                    let value = ctrl.read() | FIXED_CTRL_OS_USR << (index * 4);
+                    ctrl.write(value);
+                    enable_global(1 << (32 + index));
+                }
+                Slot::General(index) => {
+                    let (_, event_select, unit_mask) = self.event.architectural();
+                    Msr::new(IA32_PERFEVTSEL0 + index).write(
+                        event_select | unit_mask << 8 | PERFEVTSEL_USR | PERFEVTSEL_OS | PERFEVTSEL_EN,
+                    );
+                    enable_global(1 << index);
+                }
+            }
+        }
+    }
+
+    pub fn stop(&self) {
+        unsafe {
+            match self.slot {
+                Slot::Fixed(index) => {
+                    let mut ctrl = Msr::new(IA32_FIXED_CTR_CTRL);
+                    let value = ctrl.read() & !(0xf << (index * 4));
+                    ctrl.write(value);
+                }
+                Slot::General(index) => Msr::new(IA32_PERFEVTSEL0 + index).write(0),
+            }
+        }
+    }
+
+    pub fn reset(&self) {
+        unsafe { Msr::new(self.counter_msr()).write(0) };
+    }
+
+    /// Returns the number of events counted since the last reset.
+    pub fn read(&self) -> u64 {
+        let value = unsafe { Msr::new(self.counter_msr()).read() };
+        value & ((1 << self.width) - 1)
+    }
+
+    fn counter_msr(&self) -> u32 {
+        match self.slot {
+            Slot::Fixed(index) => IA32_FIXED_CTR0 + index,
+            Slot::General(index) => IA32_PMC0 + index,
+        }
+    }
+}
+
+impl Drop for Counter {
+    fn drop(&mut self) {
+        self.stop();
+        let (used, index) = match self.slot {
+            Slot::Fixed(index) => (&USED_FIXED_COUNTERS, index),
+            Slot::General(index) => (&USED_COUNTERS, index),
+        };
+        used.fetch_and(!(1 << index), Ordering::SeqCst);
+    }
+}
+
+fn allocate_fixed(index: u32) -> bool {
+    let bit = 1 << index;
+    USED_FIXED_COUNTERS.fetch_or(bit, Ordering::SeqCst) & bit == 0
+}
+
+fn allocate_general(counters: u32) -> Result<u32, PerfError> {
+    for index in 0..counters.min(32) {
+        let bit = 1 << index;
+        if USED_COUNTERS.fetch_or(bit, Ordering::SeqCst) & bit == 0 {
+            return Ok(index);
+        }
+    }
+    Err(PerfError::NoFreeCounter)
+}
+
+/// Sets the given bits in IA32_PERF_GLOBAL_CTRL.
+///
+/// From PMU version 2 on, a counter only counts if its bit in the global control
+/// register is set as well. Earlier versions don't have the register.
+///
+/// # Safety
+///
+/// The PMU must be present.
+pub unsafe fn enable_global(bits: u64) {
+    if info().is_some_and(|info| info.version >= 2) {
+        let mut global_ctrl = Msr::new(IA32_PERF_GLOBAL_CTRL);
+        let value = global_ctrl.read() | bits;
+        global_ctrl.write(value);
+    }
+}
@@ -0,0 +1,169 @@
+// Anonymous pipes between tasks.
+//
+// A pipe is a bounded ring buffer of bytes with a writing and a reading end. Reads wait
+// while the pipe is empty and writes wait while it is full, by registering the task's
+// waker and returning `Pending`, so neither end ever spins. Dropping the write end makes
+// reads return 0 (end of file) once the buffer is drained, dropping the read end makes
+// writes fail with `BrokenPipe`.
+//
+// There are no processes and file descriptor tables yet, so the ends are plain kernel
+// objects handed to the tasks.
+
+use alloc::{collections::VecDeque, sync::Arc};
+use core::{
+    future::poll_fn,
+    task::{Poll, Waker},
+};
+use crate::sync::Mutex;
+
+/// The read end was dropped, nobody is going to read the data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BrokenPipe;
+
+struct Buffer {
+    data: VecDeque<u8>,
+    capacity: usize,
+    reader_closed: bool,
+    writer_closed: bool,
+    // the task waiting for data, respectively for free space.
+    reader: Option<Waker>,
+    writer: Option<Waker>,
+}
+
+pub struct PipeReader {
+    buffer: Arc<Mutex<Buffer>>,
+}
+
+pub struct PipeWriter {
+    buffer: Arc<Mutex<Buffer>>,
+}
+
+/// Creates a pipe that buffers up to `capacity` bytes.
+pub fn pipe(capacity: usize) -> (PipeWriter, PipeReader) {
+    assert!(capacity > 0, "a pipe needs room for at least one byte");
+    let buffer = Arc::new(Mutex::new(Buffer {
+        data: VecDeque::with_capacity(capacity),
+        capacity,
+        reader_closed: false,
+        writer_closed: false,
+        reader: None,
+        writer: None,
+    }));
+    (PipeWriter { buffer: buffer.clone() }, PipeReader { buffer })
+}
+
+impl PipeReader {
+    /// Reads the available bytes without waiting. Returns `None` if the pipe is
+    /// empty, but still open, and `Some(0)` at the end of file.
+    pub fn try_read(&self, buf: &mut [u8]) -> Option<usize> {
+        let mut buffer = self.buffer.lock();
+        if buffer.data.is_empty() && !buffer.writer_closed && !buf.is_empty() {
+            return None;
+        }
+        let len = buf.len().min(buffer.data.len());
+        for (dst, src) in buf.iter_mut().zip(buffer.data.drain(..len)) {
+            *dst = src;
+        }
+        if let Some(writer) = buffer.writer.take() {
+            writer.wake();
+        }
+        Some(len)
+    }
+
+    /// Waits until data is available and reads it, returns 0 at the end of file.
+    pub async fn read(&self, buf: &mut [u8]) -> usize {
+        poll_fn(|context| {
+            if let Some(len) = self.try_read(buf) {
+                return Poll::Ready(len);
+            }
+            let mut buffer = self.buffer.lock();
+            buffer.reader = Some(context.waker().clone());
+            // the writer may have written or closed before we registered the waker.
+            if buffer.data.is_empty() && !buffer.writer_closed {
+                Poll::Pending
+            } else {
+                drop(buffer);
+                Poll::Ready(self.try_read(buf).unwrap_or(0))
+            }
+        }).await
+    }
+}
+
+impl PipeWriter {
+    /// Writes as many bytes as fit without waiting. Returns `Ok(0)` if the pipe is full.
+    pub fn try_write(&self, buf: &[u8]) -> Result<usize, BrokenPipe> {
+        let mut buffer = self.buffer.lock();
+        if buffer.reader_closed {
+            return Err(BrokenPipe);
+        }
+        let len = buf.len().min(buffer.capacity - buffer.data.len());
+        buffer.data.extend(&buf[..len]);
+        if len > 0 {
+            if let Some(reader) = buffer.reader.take() {
+                reader.wake();
+            }
+        }
+        Ok(len)
+    }
+
+    /// Writes all of `buf`, waiting for the reader to make room as often as needed.
+    pub async fn write_all(&self, mut buf: &[u8]) -> Result<(), BrokenPipe> {
+        poll_fn(|context| {
+            loop {
+                let len = self.try_write(buf)?;
+                buf = &buf[len..];
+                if buf.is_empty() {
+                    return Poll::Ready(Ok(()));
+                }
+                let mut buffer = self.buffer.lock();
+                buffer.writer = Some(context.waker().clone());
+                // the reader may have made room before we registered the waker.
+                if buffer.data.len() == buffer.capacity && !buffer.reader_closed {
+                    return Poll::Pending;
+                }
+            }
+        }).await
+    }
+}
+
+impl Drop for PipeReader {
+    fn drop(&mut self) {
+        let mut buffer = self.buffer.lock();
+        buffer.reader_closed = true;
+        if let Some(writer) = buffer.writer.take() {
+            writer.wake();
+        }
+    }
+}
+
+impl Drop for PipeWriter {
+    fn drop(&mut self) {
+        let mut buffer = self.buffer.lock();
+        buffer.writer_closed = true;
+        if let Some(reader) = buffer.reader.take() {
+            reader.wake();
+        }
+    }
+}
+
+#[test_case]
+fn test_write_and_read() {
+    let (writer, reader) = pipe(4);
+    let mut buf = [0; 8];
+    assert_eq!(reader.try_read(&mut buf), None);
+    assert_eq!(writer.try_write(b"hello"), Ok(4));
+    assert_eq!(writer.try_write(b"o"), Ok(0));
+    assert_eq!(reader.try_read(&mut buf[..2]), Some(2));
+    assert_eq!(writer.try_write(b"o"), Ok(1));
+    assert_eq!(reader.try_read(&mut buf), Some(3));
+    assert_eq!(&buf[..3], b"llo");
+    drop(writer);
+    assert_eq!(reader.try_read(&mut buf), Some(0));
+}
+
+#[test_case]
+fn test_write_to_dropped_reader() {
+    let (writer, reader) = pipe(4);
+    drop(reader);
+    assert_eq!(writer.try_write(b"x"), Err(BrokenPipe));
+}
@@ -0,0 +1,82 @@
+// Shutdown, reboot and the cleanup hooks that run before them.
+//
+// Subsystems that keep state which must reach the hardware before the machine goes away,
+// like a block cache or a NIC, register a hook with `on_shutdown`. The hooks run once, in
+// reverse registration order, so later subsystems are cleaned up before the ones they
+// were built on. The registry is a fixed-size array, so hooks can be registered before
+// the heap exists.
+
+use x86_64::{
+    instructions::{interrupts, port::Port, tables::lidt},
+    structures::DescriptorTablePointer,
+    VirtAddr,
+};
+use crate::{exit_qemu, hlt_loop, sync::Mutex, QemuExitCode};
+use core::sync::atomic::{AtomicBool, Ordering};
+
+pub const MAX_HOOKS: usize = 16;
+
+// QEMU's ACPI PM1a control register (PIIX4 and ICH9) and the value that enters the S5
+// (soft off) sleep state. There is no ACPI table parsing yet, so this only works in QEMU.
+const QEMU_PM1A_CONTROL: u16 = 0x604;
+const QEMU_SLEEP_S5: u16 = 0x2000;
+// the reset line of the 8042 keyboard controller.
+const KEYBOARD_CONTROLLER: u16 = 0x64;
+const KEYBOARD_CONTROLLER_RESET: u8 = 0xfe;
+
+type Hooks = [Option<fn()>; MAX_HOOKS];
+
+static HOOKS: Mutex<Hooks> = Mutex::new([None; MAX_HOOKS]);
+static HOOKS_RAN: AtomicBool = AtomicBool::new(false);
+
+/// All `MAX_HOOKS` slots are in use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TooManyHooks;
+
+/// Registers a hook that runs before the machine powers off, reboots or exits QEMU.
+pub fn on_shutdown(hook: fn()) -> Result<(), TooManyHooks> {
+    let mut hooks = HOOKS.lock();
+    let slot = hooks.iter_mut().find(|slot| slot.is_none()).ok_or(TooManyHooks)?;
+    *slot = Some(hook);
+    Ok(())
+}
+
+/// Runs the registered hooks, only the first call does anything.
+pub fn run_shutdown_hooks() {
+    if HOOKS_RAN.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    // copy the hooks, so that a hook can't deadlock by registering another one.
+    // try_lock, because we may be shutting down from a panic while the lock is held.
+    let Some(hooks) = HOOKS.try_lock().map(|hooks| *hooks) else {
+        return;
+    };
+    for hook in hooks.iter().rev().flatten() {
+        hook();
+    }
+}
+
+/// Runs the hooks and powers the machine off.
+pub fn poweroff() -> ! {
+    run_shutdown_hooks();
+    interrupts::disable();
+    unsafe { Port::new(QEMU_PM1A_CONTROL).write(QEMU_SLEEP_S5) };
+    // not running in QEMU, the isa-debug-exit device still gets us out of test runs.
+    exit_qemu(QemuExitCode::Success);
+    hlt_loop();
+}
+
+/// Runs the hooks and resets the machine.
+pub fn reboot() -> ! {
+    run_shutdown_hooks();
+    interrupts::disable();
+    unsafe {
+        Port::new(KEYBOARD_CONTROLLER).write(KEYBOARD_CONTROLLER_RESET);
+
+        // no keyboard controller, force a triple fault: with an empty IDT the breakpoint
+        // exception can't be delivered, neither can the double fault that follows.
+        lidt(&DescriptorTablePointer { limit: 0, base: VirtAddr::new(0) });
+        core::arch::asm!("int3");
+    }
+    hlt_loop();
+}
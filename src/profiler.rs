@@ -0,0 +1,128 @@
+// Timer-based sampling profiler.
+//
+// While the profiler is running, every timer interrupt records the interrupted instruction
+// pointer and a few of its callers into a fixed-size ring buffer. Once the buffer is full
+// the oldest samples are overwritten, so it always holds the most recent window of
+// activity. `dump` aggregates the samples into a histogram of the hottest addresses and
+// prints it over serial, where the addresses can be resolved with addr2line against the
+// kernel binary.
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::{cmp::Reverse, sync::atomic::{AtomicBool, Ordering}};
+use x86_64::{instructions::interrupts, structures::idt::InterruptStackFrame};
+use crate::{backtrace::Backtrace, serial_println, sync::Mutex};
+
+pub const MAX_SAMPLES: usize = 512;
+/// Number of callers recorded for every sample.
+pub const CALLER_DEPTH: usize = 4;
+/// Number of entries printed by `dump`.
+pub const HISTOGRAM_ENTRIES: usize = 10;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Sample {
+    pub instruction_pointer: u64,
+    pub callers: [u64; CALLER_DEPTH],
+}
+
+struct SampleBuffer {
+    samples: [Sample; MAX_SAMPLES],
+    // index of the next sample to write.
+    next: usize,
+    len: usize,
+}
+
+static RUNNING: AtomicBool = AtomicBool::new(false);
+
+// The timer interrupt is the only writer; everybody else reads with interrupts
+// disabled, so the interrupt handler never finds the lock taken.
+static SAMPLES: Mutex<SampleBuffer> = Mutex::new(SampleBuffer {
+    samples: [Sample { instruction_pointer: 0, callers: [0; CALLER_DEPTH] }; MAX_SAMPLES],
+    next: 0,
+    len: 0,
+});
+
+/// Starts sampling, discarding the samples of a previous run.
+pub fn start() {
+    interrupts::without_interrupts(|| {
+        let mut buffer = SAMPLES.lock();
+        buffer.next = 0;
+        buffer.len = 0;
+    });
+    RUNNING.store(true, Ordering::SeqCst);
+}
+
+/// Stops sampling, the recorded samples are kept until the next `start`.
+pub fn stop() {
+    RUNNING.store(false, Ordering::SeqCst);
+}
+
+pub fn is_running() -> bool {
+    RUNNING.load(Ordering::SeqCst)
+}
+
+/// Records a sample of the interrupted code, called from the timer interrupt.
+pub fn sample(stack_frame: &InterruptStackFrame) {
+    if !is_running() {
+        return;
+    }
+
+    let instruction_pointer = stack_frame.instruction_pointer.as_u64();
+    let mut sample = Sample { instruction_pointer, callers: [0; CALLER_DEPTH] };
+
+    // The frames of the interrupt handler come first in the backtrace, the frame
+    // of the interrupted code starts with its instruction pointer.
+    let backtrace = Backtrace::capture();
+    let callers = backtrace.frames()
+        .iter()
+        .skip_while(|&&address| address != instruction_pointer)
+        .skip(1);
+    for (slot, &caller) in sample.callers.iter_mut().zip(callers) {
+        *slot = caller;
+    }
+
+    let mut buffer = SAMPLES.lock();
+    let next = buffer.next;
+    buffer.samples[next] = sample;
+    buffer.next = (next + 1) % MAX_SAMPLES;
+    buffer.len = usize::min(buffer.len + 1, MAX_SAMPLES);
+}
+
+/// Returns a copy of the recorded samples, oldest first.
+pub fn samples() -> Vec<Sample> {
+    interrupts::without_interrupts(|| {
+        let buffer = SAMPLES.lock();
+        let start = (buffer.next + MAX_SAMPLES - buffer.len) % MAX_SAMPLES;
+        (0..buffer.len)
+            .map(|i| buffer.samples[(start + i) % MAX_SAMPLES])
+            .collect()
+    })
+}
+
+/// Returns the sampled instruction pointers with their sample count, hottest first.
+pub fn histogram() -> Vec<(u64, usize)> {
+    let mut counts = BTreeMap::new();
+    for sample in samples() {
+        *counts.entry(sample.instruction_pointer).or_insert(0) += 1;
+    }
+
+    let mut histogram: Vec<_> = counts.into_iter().collect();
+    histogram.sort_by_key(|&(_, count)| Reverse(count));
+    histogram
+}
+
+/// Prints the hottest addresses and one of their call chains over serial.
+pub fn dump() {
+    let samples = samples();
+    serial_println!("profile: {} samples", samples.len());
+
+    for (address, count) in histogram().into_iter().take(HISTOGRAM_ENTRIES) {
+        let percent = count * 100 / samples.len();
+        serial_println!("{:#018x} {:>5} {:>3}%", address, count, percent);
+
+        if let Some(sample) = samples.iter().find(|s| s.instruction_pointer == address) {
+            for &caller in sample.callers.iter().take_while(|&&caller| caller != 0) {
+                serial_println!("    <- {:#018x}", caller);
+            }
+        }
+    }
+}
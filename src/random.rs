@@ -0,0 +1,117 @@
+// The kernel entropy pool.
+//
+// Entropy sources mix their bytes into a 256 bit pool, random numbers are generated from it
+// with xoshiro256**. Every source credits the pool with the number of bits it vouches for,
+// `is_seeded` tells whether that reached the pool size. At boot the pool gets the TSC
+// (no credit, it is barely unpredictable right after reset) and, if the CPU has it, RDRAND.
+// virtio-rng adds bytes from the host, which covers CPUs without RDRAND.
+//
+// This is a statistical generator over a well-seeded state, not a cryptographic one:
+// its output reveals the pool. It is good for randomizing layouts and IDs, not for keys.
+
+use core::{
+    arch::{asm, x86_64::{__cpuid, _rdtsc}},
+    sync::atomic::{AtomicU32, Ordering},
+};
+use x86_64::instructions::interrupts;
+use crate::sync::Mutex;
+
+/// Bits of credited entropy after which the pool counts as seeded.
+pub const POOL_BITS: u32 = 256;
+
+// CPUID leaf 1, ECX bit 30.
+const CPUID_RDRAND: u32 = 1 << 30;
+// RDRAND may fail transiently, Intel recommends 10 retries.
+const RDRAND_RETRIES: usize = 10;
+
+static POOL: Mutex<[u64; 4]> = Mutex::new([
+    0x6a09_e667_f3bc_c908, 0xbb67_ae85_84ca_a73b, 0x3c6e_f372_fe94_f82b, 0xa54f_f53a_5f1d_36f1,
+]);
+static CREDITED_BITS: AtomicU32 = AtomicU32::new(0);
+
+/// Seeds the pool from the CPU.
+pub fn init() {
+    let tsc = unsafe { _rdtsc() };
+    add_entropy(&tsc.to_le_bytes(), 0);
+
+    if __cpuid(1).ecx & CPUID_RDRAND != 0 {
+        for _ in 0..POOL_BITS / 64 {
+            if let Some(value) = rdrand() {
+                add_entropy(&value.to_le_bytes(), 64);
+            }
+        }
+    }
+}
+
+fn rdrand() -> Option<u64> {
+    (0..RDRAND_RETRIES).find_map(|_| {
+        let value: u64;
+        let ok: u8;
+        // the carry flag tells whether the value is valid.
+        unsafe { asm!("rdrand {}", "setc {}", out(reg) value, out(reg_byte) ok, options(nomem, nostack)) };
+        (ok == 1).then_some(value)
+    })
+}
+
+/// Mixes `bytes` into the pool, crediting it with `bits` of entropy.
+pub fn add_entropy(bytes: &[u8], bits: u32) {
+    interrupts::without_interrupts(|| {
+        let mut pool = POOL.lock();
+        for (i, chunk) in bytes.chunks(8).enumerate() {
+            let mut word = [0; 8];
+            word[..chunk.len()].copy_from_slice(chunk);
+            pool[i % 4] ^= splitmix64(u64::from_le_bytes(word));
+            // spread every input over the whole state.
+            next(&mut pool);
+        }
+    });
+    let bits = bits.min(8 * bytes.len() as u32);
+    let _ = CREDITED_BITS.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |credited| {
+        Some(credited.saturating_add(bits).min(POOL_BITS))
+    });
+}
+
+/// Returns whether the pool was credited with `POOL_BITS` of entropy.
+pub fn is_seeded() -> bool {
+    CREDITED_BITS.load(Ordering::Relaxed) >= POOL_BITS
+}
+
+pub fn next_u64() -> u64 {
+    interrupts::without_interrupts(|| next(&mut POOL.lock()))
+}
+
+/// Fills `bytes` with random bytes.
+pub fn fill(bytes: &mut [u8]) {
+    for chunk in bytes.chunks_mut(8) {
+        chunk.copy_from_slice(&next_u64().to_le_bytes()[..chunk.len()]);
+    }
+}
+
+fn splitmix64(value: u64) -> u64 {
+    let mut z = value.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+}
+
+// one step of xoshiro256**.
+fn next(state: &mut [u64; 4]) -> u64 {
+    let result = state[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+    let t = state[1] << 17;
+    state[2] ^= state[0];
+    state[3] ^= state[1];
+    state[1] ^= state[2];
+    state[0] ^= state[3];
+    state[2] ^= t;
+    state[3] = state[3].rotate_left(45);
+    result
+}
+
+#[test_case]
+fn test_entropy_changes_output() {
+    let mut a = [1, 2, 3, 4];
+    let mut b = a;
+    assert_eq!(next(&mut a), next(&mut b));
+    b[0] ^= splitmix64(0);
+    assert_ne!(next(&mut a), next(&mut b));
+}
@@ -0,0 +1,104 @@
+// Read-copy-update for read-mostly data.
+//
+// An `RcuCell` holds a pointer to a heap allocated value. Readers follow the pointer without
+// taking a lock, so they can run in interrupt handlers, even ones that interrupted a writer.
+// A writer builds a new value from the old one, switches the pointer over, and frees the
+// old value only after a grace period, once every reader that may still see it is done.
+//
+// Readers announce themselves in one of two counters, picked by the lowest bit of the
+// global epoch. A writer flips the epoch after switching the pointer and waits until the
+// counter of the old epoch drops to zero: readers that started later use the other counter
+// and can only have seen the new pointer. A reader that raced with the flip notices that
+// the epoch changed under it and retries with the new counter.
+//
+// Writers allocate and wait, so they must run in task context and never inside `read`.
+// With only one CPU, the grace period ends right away: a reader is never interrupted by a
+// task, and an interrupted one continues before the task that was interrupted.
+
+use alloc::boxed::Box;
+use core::{
+    ptr,
+    sync::atomic::{AtomicPtr, AtomicUsize, Ordering},
+};
+use crate::sync::Mutex;
+
+static EPOCH: AtomicUsize = AtomicUsize::new(0);
+static READERS: [AtomicUsize; 2] = [AtomicUsize::new(0), AtomicUsize::new(0)];
+
+pub struct RcuCell<T> {
+    value: AtomicPtr<T>,
+    // writers take turns, readers never touch it.
+    writer: Mutex<()>,
+}
+
+// readers on any CPU get a `&T`, writers hand the value over between CPUs.
+unsafe impl<T: Send + Sync> Sync for RcuCell<T> {}
+
+impl<T> RcuCell<T> {
+    /// Creates a cell without a value, so it can be a static.
+    pub const fn new() -> Self {
+        RcuCell { value: AtomicPtr::new(ptr::null_mut()), writer: Mutex::new(()) }
+    }
+
+    /// Calls `f` with the current value, without taking a lock.
+    pub fn read<R>(&self, f: impl FnOnce(Option<&T>) -> R) -> R {
+        let epoch = loop {
+            let epoch = EPOCH.load(Ordering::SeqCst);
+            READERS[epoch & 1].fetch_add(1, Ordering::SeqCst);
+            if EPOCH.load(Ordering::SeqCst) == epoch {
+                break epoch;
+            }
+            // a writer flipped the epoch, it may not wait for this counter anymore.
+            READERS[epoch & 1].fetch_sub(1, Ordering::SeqCst);
+        };
+        // the value stays allocated until READERS[epoch & 1] drops to 0.
+        let result = f(unsafe { self.value.load(Ordering::SeqCst).as_ref() });
+        READERS[epoch & 1].fetch_sub(1, Ordering::SeqCst);
+        result
+    }
+
+    /// Replaces the value with the one `f` builds from the current one, and frees the old
+    /// value after a grace period. Nothing changes if `f` fails.
+    pub fn try_update<E>(&self, f: impl FnOnce(Option<&T>) -> Result<T, E>) -> Result<(), E> {
+        let _writer = self.writer.lock();
+        // only writers replace the value, and we are the only writer.
+        let old = self.value.load(Ordering::SeqCst);
+        let new = Box::into_raw(Box::new(f(unsafe { old.as_ref() })?));
+        self.value.store(new, Ordering::SeqCst);
+        synchronize();
+        if !old.is_null() {
+            drop(unsafe { Box::from_raw(old) });
+        }
+        Ok(())
+    }
+
+    /// Like `try_update`, for updates that can't fail.
+    pub fn update(&self, f: impl FnOnce(Option<&T>) -> T) {
+        let _ = self.try_update::<()>(|old| Ok(f(old)));
+    }
+}
+
+impl<T> Default for RcuCell<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for RcuCell<T> {
+    fn drop(&mut self) {
+        let value = *self.value.get_mut();
+        if !value.is_null() {
+            drop(unsafe { Box::from_raw(value) });
+        }
+    }
+}
+
+/// Waits until every reader that started before the call is done.
+pub fn synchronize() {
+    // writers of different cells may flip concurrently, each waits for the counter it
+    // flipped away from, which covers the readers that started before its flip.
+    let old = EPOCH.fetch_add(1, Ordering::SeqCst);
+    while READERS[old & 1].load(Ordering::SeqCst) != 0 {
+        core::hint::spin_loop();
+    }
+}
@@ -0,0 +1,69 @@
+use core::{arch::asm, fmt};
+
+/// The general purpose registers.
+///
+/// The fields are in the order in which they end up in memory when rax is pushed
+/// first and r15 last, so a pushed register set can be read through this type.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct GeneralRegisters {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+}
+
+impl GeneralRegisters {
+    /// Captures the registers at the call site.
+    ///
+    /// This is best-effort: the compiler is free to use any register to hold the
+    /// pointer to the result, so that register shows the pointer instead of its
+    /// previous value. It is meant for panic reports, not for saving state.
+    #[inline(always)]
+    pub fn capture() -> Self {
+        let mut registers = GeneralRegisters::default();
+        unsafe {
+            asm!(
+                "mov [{0} + 0x00], r15",
+                "mov [{0} + 0x08], r14",
+                "mov [{0} + 0x10], r13",
+                "mov [{0} + 0x18], r12",
+                "mov [{0} + 0x20], r11",
+                "mov [{0} + 0x28], r10",
+                "mov [{0} + 0x30], r9",
+                "mov [{0} + 0x38], r8",
+                "mov [{0} + 0x40], rbp",
+                "mov [{0} + 0x48], rdi",
+                "mov [{0} + 0x50], rsi",
+                "mov [{0} + 0x58], rdx",
+                "mov [{0} + 0x60], rcx",
+                "mov [{0} + 0x68], rbx",
+                "mov [{0} + 0x70], rax",
+                in(reg) &mut registers as *mut GeneralRegisters,
+                options(nostack, preserves_flags),
+            );
+        }
+        registers
+    }
+}
+
+impl fmt::Display for GeneralRegisters {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "RAX={:016x} RBX={:016x} RCX={:016x}", self.rax, self.rbx, self.rcx)?;
+        writeln!(f, "RDX={:016x} RSI={:016x} RDI={:016x}", self.rdx, self.rsi, self.rdi)?;
+        writeln!(f, "RBP={:016x} R8 ={:016x} R9 ={:016x}", self.rbp, self.r8, self.r9)?;
+        writeln!(f, "R10={:016x} R11={:016x} R12={:016x}", self.r10, self.r11, self.r12)?;
+        write!(f, "R13={:016x} R14={:016x} R15={:016x}", self.r13, self.r14, self.r15)
+    }
+}
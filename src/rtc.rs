@@ -0,0 +1,149 @@
+// The CMOS real-time clock.
+//
+// The RTC keeps the date and time while the machine is off, with a resolution of a second.
+// Its registers are read through the CMOS index and data ports. The values may be BCD or
+// binary and the hour 12 or 24 hour based, status register B tells which. While the clock
+// updates its registers, once a second, they may be inconsistent, so we read them until two
+// reads in a row agree. The RTC usually runs in local time on machines that also boot
+// Windows, the kernel assumes UTC like QEMU's default.
+//
+// The CMOS index port also holds the NMI disable bit, we always write it clear.
+
+use core::fmt;
+use x86_64::instructions::{interrupts, port::Port};
+use crate::sync::Mutex;
+
+const CMOS_INDEX: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const REG_SECONDS: u8 = 0x00;
+const REG_MINUTES: u8 = 0x02;
+const REG_HOURS: u8 = 0x04;
+const REG_DAY: u8 = 0x07;
+const REG_MONTH: u8 = 0x08;
+const REG_YEAR: u8 = 0x09;
+const REG_STATUS_A: u8 = 0x0a;
+const REG_STATUS_B: u8 = 0x0b;
+const STATUS_A_UPDATING: u8 = 1 << 7;
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+const STATUS_B_BINARY: u8 = 1 << 2;
+// set in the hours register for PM in 12 hour mode.
+const HOUR_PM: u8 = 1 << 7;
+
+static CMOS: Mutex<()> = Mutex::new(());
+
+/// A date and time in UTC.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// Seconds since the Unix epoch.
+    pub fn to_unix(&self) -> u64 {
+        // days since the epoch of the proleptic Gregorian calendar, years starting in March.
+        let (month, day) = (i64::from(self.month), i64::from(self.day));
+        let year = i64::from(self.year) - i64::from(month <= 2);
+        let era = year.div_euclid(400);
+        let year_of_era = year - era * 400;
+        let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+        let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+        let days = era * 146_097 + day_of_era - 719_468;
+        let seconds = i64::from(self.hour) * 3600 + i64::from(self.minute) * 60 + i64::from(self.second);
+        (days * 86_400 + seconds).max(0) as u64
+    }
+
+    /// The date and time `seconds` after the Unix epoch.
+    pub fn from_unix(seconds: u64) -> DateTime {
+        let days = (seconds / 86_400) as i64 + 719_468;
+        let era = days.div_euclid(146_097);
+        let day_of_era = days - era * 146_097;
+        let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let month_index = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+        let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+        let year = year_of_era + era * 400 + i64::from(month <= 2);
+        let time = seconds % 86_400;
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (time / 3600) as u8,
+            minute: (time / 60 % 60) as u8,
+            second: (time % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second)
+    }
+}
+
+fn read_register(register: u8) -> u8 {
+    unsafe {
+        Port::new(CMOS_INDEX).write(register);
+        Port::new(CMOS_DATA).read()
+    }
+}
+
+fn read_raw() -> [u8; 6] {
+    while read_register(REG_STATUS_A) & STATUS_A_UPDATING != 0 {
+        core::hint::spin_loop();
+    }
+    [REG_SECONDS, REG_MINUTES, REG_HOURS, REG_DAY, REG_MONTH, REG_YEAR].map(read_register)
+}
+
+/// Reads the current date and time from the RTC.
+pub fn read() -> DateTime {
+    let (raw, status) = interrupts::without_interrupts(|| {
+        let _guard = CMOS.lock();
+        let mut raw = read_raw();
+        loop {
+            let again = read_raw();
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+        (raw, read_register(REG_STATUS_B))
+    });
+
+    let binary = status & STATUS_B_BINARY != 0;
+    let decode = |value: u8| if binary { value } else { (value >> 4) * 10 + (value & 0xf) };
+    let [second, minute, hours, day, month, year] = raw;
+    let mut hour = decode(hours & !HOUR_PM);
+    if status & STATUS_B_24_HOUR == 0 {
+        // 12 AM is 0, 12 PM is 12.
+        hour %= 12;
+        if hours & HOUR_PM != 0 {
+            hour += 12;
+        }
+    }
+    DateTime {
+        // the century register isn't at the same place everywhere, assume this one.
+        year: 2000 + u16::from(decode(year)),
+        month: decode(month),
+        day: decode(day),
+        hour,
+        minute: decode(minute),
+        second: decode(second),
+    }
+}
+
+#[test_case]
+fn test_unix_time_conversion() {
+    let date = DateTime { year: 2024, month: 2, day: 29, hour: 13, minute: 5, second: 9 };
+    assert_eq!(date.to_unix(), 1_709_211_909);
+    assert_eq!(DateTime::from_unix(1_709_211_909), date);
+    assert_eq!(DateTime::from_unix(0).to_unix(), 0);
+    assert_eq!(DateTime::from_unix(0).year, 1970);
+}
@@ -1,6 +1,15 @@
 use uart_16550::SerialPort;
-use spin::Mutex;
+use crate::sync::Mutex;
 use lazy_static::lazy_static;
+use x86_64::instructions::{interrupts, port::Port};
+
+/// The PIC line of the first serial interface, it interrupts when a byte was received.
+pub const SERIAL1_IRQ: u8 = 4;
+
+const SERIAL1_PORT: u16 = 0x3F8;
+// the line status register, bit 0 is set while a received byte waits in the data register.
+const LINE_STATUS: u16 = 5;
+const DATA_READY: u8 = 1;
 
 // Like with the VGA text buffer, we use lazy_static and a spinlock to create a
 // static writer instance. By using lazy_static we can ensure that the init method
@@ -11,19 +20,48 @@ lazy_static! {
         // of the UART as argument, from which it can calculate the addresses of all
         // needed ports. We’re passing the port address 0x3F8, which is the standard port
         // number for the first serial interface.
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
+        let mut serial_port = unsafe { SerialPort::new(SERIAL1_PORT) };
         serial_port.init();
         Mutex::new(serial_port)
     };
 }
 
+// The second serial interface (COM2, port 0x2F8) is reserved for the structured
+// test-runner protocol: the `[ok]`/`[failed]` markers and the test names. Keeping it
+// apart from COM1 means debug logs printed by a test can never end up interleaved
+// with the output the host parses to find out which tests passed.
+lazy_static! {
+    pub static ref SERIAL2: Mutex<SerialPort> = {
+        let mut serial_port = unsafe { SerialPort::new(0x2F8) };
+        serial_port.init();
+        Mutex::new(serial_port)
+    };
+}
+
+/// Returns the byte received on the first serial interface, if there is one.
+pub fn try_receive() -> Option<u8> {
+    let mut line_status: Port<u8> = Port::new(SERIAL1_PORT + LINE_STATUS);
+    if unsafe { line_status.read() } & DATA_READY == 0 {
+        return None;
+    }
+    Some(interrupts::without_interrupts(|| SERIAL1.lock().receive()))
+}
+
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
+    print_to(&SERIAL1, args);
+}
+
+#[doc(hidden)]
+pub fn _test_print(args: ::core::fmt::Arguments) {
+    print_to(&SERIAL2, args);
+}
+
+fn print_to(port: &Mutex<SerialPort>, args: ::core::fmt::Arguments) {
     use core::fmt::Write;
-    use x86_64::instructions::interrupts;
 
     interrupts::without_interrupts(|| {
-        SERIAL1.lock().write_fmt(args).expect("Printing to serial failed");
+        port.lock().write_fmt(args).expect("Printing to serial failed");
     });
 }
 
@@ -42,4 +80,22 @@ macro_rules! serial_println {
     ($fmt:expr) => ($crate::serial_print!(concat!($fmt, "\n")));
     ($fmt:expr, $($arg:tt)*) => ($crate::serial_print!(
         concat!($fmt, "\n"), $($arg)*));
-}
\ No newline at end of file
+}
+
+/// Prints test protocol output to the host through the second serial interface.
+#[macro_export]
+macro_rules! test_print {
+    ($($arg:tt)*) => {
+        $crate::serial::_test_print(format_args!($($arg)*));
+    };
+}
+
+/// Prints test protocol output to the host through the second serial interface,
+/// appending a newline.
+#[macro_export]
+macro_rules! test_println {
+    () => ($crate::test_print!("\n"));
+    ($fmt:expr) => ($crate::test_print!(concat!($fmt, "\n")));
+    ($fmt:expr, $($arg:tt)*) => ($crate::test_print!(
+        concat!($fmt, "\n"), $($arg)*));
+}
@@ -0,0 +1,114 @@
+// Memory pressure callbacks.
+//
+// Subsystems that keep memory they could give back, like caches and log buffers, register a
+// shrinker. When an allocation fails, the allocator runs the shrinkers and retries before
+// the alloc error handler gets to panic. When the free heap drops below `LOW_WATERMARK`, the
+// allocator sets a flag that the `run` task polls, and the shrinkers also run from there, so
+// memory is usually freed before it runs out. The allocator itself only sets the flag: it
+// may be called with any lock held, including those of the workqueue and the executor.
+//
+// A shrinker gets the number of bytes wanted and returns how many it freed. It runs in the
+// context of the failed allocation or of the `run` task, with interrupts enabled. A failed
+// allocation may happen with any lock held, also the shrinker's own, e.g. when a cache
+// allocates under its lock, so a shrinker must only `try_lock` and return 0 when a lock is
+// busy. It may allocate itself, an allocation failing inside a shrinker doesn't shrink again.
+//
+// The boot frame allocator can't take frames back, so there is nothing to shrink for
+// physical memory.
+
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use x86_64::instructions::interrupts;
+use crate::{sync::Mutex, time};
+
+pub const MAX_SHRINKERS: usize = 16;
+/// Free heap bytes below which the shrinkers are run in the background.
+pub const LOW_WATERMARK: usize = 8 * 1024;
+/// Milliseconds between the low memory checks of the `run` task.
+pub const POLL_INTERVAL_MS: u64 = 100;
+
+/// Frees up to `wanted` bytes, returns how many it freed. Must not wait for locks: it can be
+/// called from an allocation made while one of its locks is held, it has to `try_lock` them
+/// and return 0 if they are busy.
+pub type Shrinker = fn(wanted: usize) -> usize;
+
+static SHRINKERS: Mutex<[Option<Shrinker>; MAX_SHRINKERS]> = Mutex::new([None; MAX_SHRINKERS]);
+// set while the shrinkers run.
+static SHRINKING: AtomicBool = AtomicBool::new(false);
+// bytes the heap was below the watermark by after the last allocation, 0 if it wasn't.
+static LOW_MEMORY: AtomicUsize = AtomicUsize::new(0);
+
+/// All `MAX_SHRINKERS` slots are in use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TooManyShrinkers;
+
+/// Registers a shrinker that is called under memory pressure.
+pub fn register(shrinker: Shrinker) -> Result<(), TooManyShrinkers> {
+    interrupts::without_interrupts(|| {
+        let mut shrinkers = SHRINKERS.lock();
+        let slot = shrinkers.iter_mut().find(|slot| slot.is_none()).ok_or(TooManyShrinkers)?;
+        *slot = Some(shrinker);
+        Ok(())
+    })
+}
+
+/// Runs the shrinkers until `wanted` bytes were freed, returns how many were. Does nothing
+/// when called from a shrinker or with interrupts disabled, e.g. from an interrupt handler,
+/// where the shrinkers' locks may be held by the interrupted code.
+pub fn shrink(wanted: usize) -> usize {
+    if !interrupts::are_enabled() || SHRINKING.swap(true, Ordering::Acquire) {
+        return 0;
+    }
+    // copy the shrinkers, so that a shrinker can register another one. Like the shrinkers,
+    // don't wait for a lock that the failed allocation's caller may hold.
+    let copy = interrupts::without_interrupts(|| SHRINKERS.try_lock().map(|list| *list));
+    let Some(shrinkers) = copy else {
+        SHRINKING.store(false, Ordering::Release);
+        return 0;
+    };
+    let freed = run_shrinkers(&shrinkers, wanted);
+    SHRINKING.store(false, Ordering::Release);
+    freed
+}
+
+fn run_shrinkers(shrinkers: &[Option<Shrinker>], wanted: usize) -> usize {
+    let mut freed = 0;
+    for shrinker in shrinkers.iter().flatten() {
+        if freed >= wanted {
+            break;
+        }
+        freed += shrinker(wanted - freed);
+    }
+    freed
+}
+
+/// Flags the heap as low on memory if `free` heap bytes are below the watermark, called by
+/// the allocator after every allocation. Only touches an atomic, so it can't deadlock.
+pub fn check_heap(free: usize) {
+    LOW_MEMORY.store(LOW_WATERMARK.saturating_sub(free), Ordering::Relaxed);
+}
+
+/// Runs the shrinkers in the background while the heap is below the watermark.
+pub async fn run() {
+    loop {
+        time::sleep(POLL_INTERVAL_MS).await;
+        let wanted = LOW_MEMORY.swap(0, Ordering::Relaxed);
+        if wanted > 0 {
+            shrink(wanted);
+        }
+    }
+}
+
+#[test_case]
+fn test_shrink_stops_when_enough_was_freed() {
+    use core::sync::atomic::AtomicUsize;
+    static CALLS: AtomicUsize = AtomicUsize::new(0);
+    let shrinker: Shrinker = |wanted| {
+        CALLS.fetch_add(1, Ordering::Relaxed);
+        wanted
+    };
+    // a list of its own, registered shrinkers would stay for the rest of the tests.
+    let shrinkers = [Some(shrinker), None, Some(shrinker)];
+    assert_eq!(run_shrinkers(&shrinkers, 100), 100);
+    assert_eq!(CALLS.load(Ordering::Relaxed), 1);
+    assert_eq!(run_shrinkers(&[None], 100), 0);
+}
@@ -0,0 +1,118 @@
+// Inter-processor interrupts.
+//
+// An IPI is sent by writing the target's APIC ID and the vector to the interrupt command
+// register (ICR) of the local APIC. Every kind of IPI has its own vector, the handlers in
+// `interrupts` pass them to `handle_ipi`:
+//
+// - Reschedule only wakes the target from `hlt`, so its executor looks at the ready queue.
+// - TlbShootdown flushes the target's whole TLB, after page table entries were changed.
+// - Halt stops the target for good, e.g. when another CPU panics.
+// - CallFunction runs the function queued with `call_function` on the target.
+//
+// Only the bootstrap processor runs so far, so the only CPU that can be targeted is the
+// sending one itself. The API is what the code that starts the other CPUs will build on.
+
+use x86_64::instructions::{interrupts, tlb};
+use crate::{apic, hlt_loop, sync::Mutex};
+
+/// The first IPI vector, the kinds follow in the order of `IpiKind`.
+pub const IPI_VECTOR_BASE: u8 = 0xf0;
+
+// ICR: fixed delivery to the physical APIC ID in the high half.
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpiKind {
+    Reschedule,
+    TlbShootdown,
+    Halt,
+    CallFunction,
+}
+
+impl IpiKind {
+    pub const ALL: [IpiKind; 4] = [IpiKind::Reschedule, IpiKind::TlbShootdown, IpiKind::Halt, IpiKind::CallFunction];
+
+    pub fn vector(self) -> u8 {
+        IPI_VECTOR_BASE + self as u8
+    }
+
+    pub fn from_vector(vector: u8) -> Option<IpiKind> {
+        IpiKind::ALL.get(usize::from(vector.checked_sub(IPI_VECTOR_BASE)?)).copied()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpiError {
+    ApicNotInitialized,
+    /// No running CPU has this APIC ID.
+    NoSuchCpu(u32),
+    /// The target hasn't run the function queued before yet.
+    Busy,
+}
+
+type Call = (fn(usize), usize);
+
+// the function for the CallFunction IPI, one slot while there is one CPU.
+static CALL: Mutex<Option<Call>> = Mutex::new(None);
+
+/// Returns the APIC ID of the CPU we run on, or `None` before `apic::init`.
+pub fn current_cpu() -> Option<u32> {
+    apic::is_initialized().then(|| unsafe { apic::id() })
+}
+
+/// Sends an IPI to the CPU with the APIC ID `cpu`.
+pub fn send_ipi(cpu: u32, kind: IpiKind) -> Result<(), IpiError> {
+    let current = current_cpu().ok_or(IpiError::ApicNotInitialized)?;
+    if cpu != current {
+        return Err(IpiError::NoSuchCpu(cpu));
+    }
+    interrupts::without_interrupts(|| unsafe {
+        apic::send_ipi(cpu, ICR_LEVEL_ASSERT | u32::from(kind.vector()));
+    });
+    Ok(())
+}
+
+/// Runs `func(arg)` on the CPU `cpu` from its CallFunction IPI handler, with interrupts
+/// disabled. Returns once the IPI is sent, not when the function ran.
+pub fn call_function(cpu: u32, func: fn(usize), arg: usize) -> Result<(), IpiError> {
+    interrupts::without_interrupts(|| {
+        let mut call = CALL.lock();
+        if call.is_some() {
+            return Err(IpiError::Busy);
+        }
+        *call = Some((func, arg));
+        Ok(())
+    })?;
+    send_ipi(cpu, IpiKind::CallFunction).inspect_err(|_| {
+        interrupts::without_interrupts(|| *CALL.lock() = None);
+    })
+}
+
+/// Handles an IPI of the given kind, called by the interrupt handlers.
+pub fn handle_ipi(kind: IpiKind) {
+    match kind {
+        IpiKind::Reschedule => {}
+        IpiKind::TlbShootdown => tlb::flush_all(),
+        IpiKind::Halt => {
+            // the APIC never gets an EOI, so nothing of a lower priority gets through either.
+            interrupts::disable();
+            hlt_loop();
+        }
+        IpiKind::CallFunction => {
+            // take the function first, so it can queue the next one.
+            let call = CALL.lock().take();
+            if let Some((func, arg)) = call {
+                func(arg);
+            }
+        }
+    }
+}
+
+#[test_case]
+fn test_ipi_vectors() {
+    for kind in IpiKind::ALL {
+        assert_eq!(IpiKind::from_vector(kind.vector()), Some(kind));
+    }
+    assert_eq!(IpiKind::from_vector(IPI_VECTOR_BASE - 1), None);
+    assert_eq!(IpiKind::from_vector(IPI_VECTOR_BASE + 4), None);
+}
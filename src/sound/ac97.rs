@@ -0,0 +1,254 @@
+// AC'97 audio driver.
+//
+// The controller has two I/O BARs: the native audio mixer (NAM), which holds the codec
+// registers like volumes and sample rates, and the native audio bus master (NABM), which
+// runs the DMA engines. The PCM out engine plays from a ring of 32 buffer descriptors, each
+// pointing to a buffer of samples. The driver sets the last valid index (LVI) to the last
+// filled descriptor, and the engine raises an interrupt after every buffer and halts once it
+// finished the one at LVI. Refilling the descriptors it's done with before it gets there
+// keeps the sound going. If the engine halts anyway, playback restarts from the first
+// descriptor, with a short gap.
+
+use core::sync::atomic::{AtomicBool, AtomicU16, Ordering};
+use x86_64::{instructions::{interrupts, port::Port}, PhysAddr};
+use crate::{
+    interrupts::{self as irq, IrqError},
+    memory::{self, BootFrameAllocator},
+    pci::{self, Bar},
+    sync::Mutex,
+};
+use super::SoundError;
+
+const VENDOR_INTEL: u16 = 0x8086;
+// the 82801AA AC'97 controller QEMU emulates.
+const DEVICE_82801AA: u16 = 0x2415;
+
+// NAM registers.
+const NAM_RESET: u16 = 0x00;
+const NAM_MASTER_VOLUME: u16 = 0x02;
+const NAM_PCM_OUT_VOLUME: u16 = 0x18;
+// 0 dB on both channels, not muted.
+const VOLUME_0DB: u16 = 0x0000;
+const PCM_VOLUME_0DB: u16 = 0x0808;
+
+// NABM registers of the PCM out engine and the global ones.
+const PO_BDBAR: u16 = 0x10;
+const PO_CIV: u16 = 0x14;
+const PO_LVI: u16 = 0x15;
+const PO_SR: u16 = 0x16;
+const PO_CR: u16 = 0x1b;
+const GLOB_CNT: u16 = 0x2c;
+const GLOB_STA: u16 = 0x30;
+
+const CR_RUN: u8 = 1 << 0;
+const CR_RESET: u8 = 1 << 1;
+const CR_LAST_VALID_INTERRUPT: u8 = 1 << 2;
+const CR_COMPLETION_INTERRUPT: u8 = 1 << 4;
+const SR_HALTED: u16 = 1 << 0;
+// last valid buffer completed, buffer completed, FIFO error, cleared by writing ones.
+const SR_INTERRUPTS: u16 = 0b111 << 2;
+// deasserts the cold reset of the codec link.
+const GLOB_CNT_COLD_RESET: u32 = 1 << 1;
+const GLOB_STA_CODEC_READY: u32 = 1 << 8;
+// how often to poll for the codec after the reset.
+const MAX_POLLS: usize = 100_000;
+
+const DESCRIPTORS: usize = 32;
+const BUFFER_SIZE: usize = 4096;
+/// Samples (of one channel each) per DMA buffer.
+pub const SAMPLES_PER_BUFFER: usize = BUFFER_SIZE / 2;
+// interrupt when the engine finished the buffer.
+const DESCRIPTOR_IOC: u16 = 1 << 15;
+// the descriptor list and buffer addresses are 32 bits.
+const DMA_LIMIT: u64 = 1 << 32;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct BufferDescriptor {
+    addr: u32,
+    samples: u16,
+    flags: u16,
+}
+
+struct Controller {
+    nabm: u16,
+    descriptors: PhysAddr,
+    buffers: PhysAddr,
+}
+
+static CONTROLLER: Mutex<Option<Controller>> = Mutex::new(None);
+// the NABM base for the interrupt handler, 0 before `init`.
+static NABM: AtomicU16 = AtomicU16::new(0);
+static PLAYING: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ac97Error {
+    NotFound,
+    /// The BARs are not in I/O space.
+    UnexpectedBars,
+    /// The codec didn't come out of the reset.
+    NoCodec,
+    OutOfMemory,
+    /// The DMA memory is above 4 GiB, out of reach of the 32 bit descriptor addresses.
+    DmaAbove4GiB,
+    Irq(IrqError),
+}
+
+/// Sets up the first AC'97 controller.
+pub fn init(frame_allocator: &mut BootFrameAllocator) -> Result<(), Ac97Error> {
+    let device = pci::find(VENDOR_INTEL, DEVICE_82801AA).ok_or(Ac97Error::NotFound)?;
+    let (Some(Bar::Io(nam)), Some(Bar::Io(nabm))) = (device.bar(0), device.bar(1)) else {
+        return Err(Ac97Error::UnexpectedBars);
+    };
+    device.enable();
+
+    let descriptors = frame_allocator.allocate_contiguous(1).ok_or(Ac97Error::OutOfMemory)?;
+    let buffers = frame_allocator
+        .allocate_contiguous(DESCRIPTORS * BUFFER_SIZE / 4096)
+        .ok_or(Ac97Error::OutOfMemory)?;
+    let buffers_end = buffers.start_address() + (DESCRIPTORS * BUFFER_SIZE) as u64;
+    if descriptors.start_address().as_u64() >= DMA_LIMIT || buffers_end.as_u64() > DMA_LIMIT {
+        return Err(Ac97Error::DmaAbove4GiB);
+    }
+
+    unsafe { Port::new(nabm + GLOB_CNT).write(GLOB_CNT_COLD_RESET) };
+    let mut global_status: Port<u32> = Port::new(nabm + GLOB_STA);
+    if !(0..MAX_POLLS).any(|_| unsafe { global_status.read() } & GLOB_STA_CODEC_READY != 0) {
+        return Err(Ac97Error::NoCodec);
+    }
+    unsafe {
+        Port::new(nam + NAM_RESET).write(0u16);
+        Port::new(nam + NAM_MASTER_VOLUME).write(VOLUME_0DB);
+        Port::new(nam + NAM_PCM_OUT_VOLUME).write(PCM_VOLUME_0DB);
+    }
+    let controller = Controller {
+        nabm,
+        descriptors: descriptors.start_address(),
+        buffers: buffers.start_address(),
+    };
+    controller.reset();
+
+    // without the interrupt, playback would wait for completions forever, so the controller
+    // is only made available once the handler is registered.
+    NABM.store(nabm, Ordering::SeqCst);
+    irq::register_irq(device.interrupt_line(), interrupt_handler).map_err(Ac97Error::Irq)?;
+    interrupts::without_interrupts(|| *CONTROLLER.lock() = Some(controller));
+    Ok(())
+}
+
+fn interrupt_handler() {
+    let nabm = NABM.load(Ordering::SeqCst);
+    let mut status: Port<u16> = Port::new(nabm + PO_SR);
+    let pending = unsafe { status.read() } & SR_INTERRUPTS;
+    if pending != 0 {
+        unsafe { status.write(pending) };
+        super::complete();
+    }
+}
+
+impl Controller {
+    // Stops the engine and resets it to the first descriptor.
+    fn reset(&self) {
+        unsafe {
+            let mut control: Port<u8> = Port::new(self.nabm + PO_CR);
+            control.write(CR_RESET);
+            // the bit clears itself once the reset is done.
+            while control.read() & CR_RESET != 0 {
+                core::hint::spin_loop();
+            }
+            Port::new(self.nabm + PO_BDBAR).write(self.descriptors.as_u64() as u32);
+        }
+    }
+
+    fn is_halted(&self) -> bool {
+        let status = unsafe { Port::<u16>::new(self.nabm + PO_SR).read() };
+        status & SR_HALTED != 0
+    }
+
+    fn current_index(&self) -> u8 {
+        unsafe { Port::<u8>::new(self.nabm + PO_CIV).read() }
+    }
+
+    fn last_valid_index(&self) -> u8 {
+        unsafe { Port::<u8>::new(self.nabm + PO_LVI).read() }
+    }
+
+    fn fill(&self, index: usize, samples: &[i16]) {
+        let buffer = self.buffers + (index * BUFFER_SIZE) as u64;
+        unsafe {
+            let ptr: *mut i16 = memory::phys_to_virt(buffer).as_mut_ptr();
+            ptr.copy_from_nonoverlapping(samples.as_ptr(), samples.len());
+            let descriptors: *mut BufferDescriptor = memory::phys_to_virt(self.descriptors).as_mut_ptr();
+            descriptors.add(index).write_volatile(BufferDescriptor {
+                addr: buffer.as_u64() as u32,
+                samples: samples.len() as u16,
+                flags: DESCRIPTOR_IOC,
+            });
+        }
+    }
+
+    fn start(&self, last_valid: u8) {
+        unsafe {
+            Port::new(self.nabm + PO_LVI).write(last_valid);
+            Port::new(self.nabm + PO_CR).write(CR_RUN | CR_LAST_VALID_INTERRUPT | CR_COMPLETION_INTERRUPT);
+        }
+    }
+}
+
+/// Exclusive use of the PCM out engine, stops it when dropped.
+pub struct Playback(());
+
+impl Playback {
+    pub fn start() -> Result<Playback, SoundError> {
+        if !interrupts::without_interrupts(|| CONTROLLER.lock().is_some()) {
+            return Err(SoundError::NoDevice);
+        }
+        if PLAYING.swap(true, Ordering::SeqCst) {
+            return Err(SoundError::Busy);
+        }
+        Ok(Playback(()))
+    }
+
+    /// Fills the buffers the engine is done with from `chunks` and keeps it running,
+    /// returns whether all chunks were played.
+    pub fn queue<'a>(&mut self, chunks: &mut impl Iterator<Item = &'a [i16]>) -> bool {
+        interrupts::without_interrupts(|| {
+            let guard = CONTROLLER.lock();
+            let controller = guard.as_ref().expect("Playback exists without a controller");
+
+            let halted = controller.is_halted();
+            let (mut next, free) = if halted {
+                controller.reset();
+                (0, DESCRIPTORS)
+            } else {
+                let current = usize::from(controller.current_index());
+                let last = usize::from(controller.last_valid_index());
+                let in_flight = (last + DESCRIPTORS - current) % DESCRIPTORS + 1;
+                ((last + 1) % DESCRIPTORS, DESCRIPTORS - in_flight)
+            };
+
+            let mut queued = 0;
+            for chunk in chunks.take(free) {
+                controller.fill(next, chunk);
+                next = (next + 1) % DESCRIPTORS;
+                queued += 1;
+            }
+            if queued > 0 {
+                controller.start(((next + DESCRIPTORS - 1) % DESCRIPTORS) as u8);
+            }
+            // done once nothing was left to queue and the engine played everything.
+            queued == 0 && halted
+        })
+    }
+}
+
+impl Drop for Playback {
+    fn drop(&mut self) {
+        interrupts::without_interrupts(|| {
+            if let Some(controller) = CONTROLLER.lock().as_ref() {
+                controller.reset();
+            }
+        });
+        PLAYING.store(false, Ordering::SeqCst);
+    }
+}
@@ -0,0 +1,76 @@
+// Sound output.
+//
+// A minimal interface for playing PCM: 16 bit signed samples, two interleaved channels at
+// `SAMPLE_RATE`. `play` streams the samples through the driver's DMA ring and returns once
+// the device played all of them. The only driver so far is for AC'97 codecs, which QEMU
+// provides with `-device AC97` plus an audio backend.
+
+pub mod ac97;
+
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+};
+use x86_64::instructions::interrupts;
+use crate::sync::Mutex;
+
+pub const SAMPLE_RATE: u32 = 48_000;
+pub const CHANNELS: usize = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SoundError {
+    /// No sound device was set up.
+    NoDevice,
+    /// Another task is playing already.
+    Busy,
+}
+
+/// Plays interleaved stereo samples at `SAMPLE_RATE`.
+pub async fn play(samples: &[i16]) -> Result<(), SoundError> {
+    let mut playback = ac97::Playback::start()?;
+    let mut chunks = samples.chunks(ac97::SAMPLES_PER_BUFFER);
+    loop {
+        let seen = completions();
+        if playback.queue(&mut chunks) {
+            return Ok(());
+        }
+        Completion { seen }.await;
+    }
+}
+
+// number of buffer completion interrupts since boot, and the task waiting for the next.
+static COMPLETIONS: AtomicU64 = AtomicU64::new(0);
+static WAKER: Mutex<Option<Waker>> = Mutex::new(None);
+
+fn completions() -> u64 {
+    COMPLETIONS.load(Ordering::SeqCst)
+}
+
+/// Called by the drivers' interrupt handlers when the device finished a buffer.
+fn complete() {
+    COMPLETIONS.fetch_add(1, Ordering::SeqCst);
+    if let Some(waker) = WAKER.lock().take() {
+        waker.wake();
+    }
+}
+
+// Waits for the next completion after `seen`.
+struct Completion {
+    seen: u64,
+}
+
+impl Future for Completion {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        interrupts::without_interrupts(|| {
+            if completions() != self.seen {
+                return Poll::Ready(());
+            }
+            *WAKER.lock() = Some(context.waker().clone());
+            Poll::Pending
+        })
+    }
+}
@@ -0,0 +1,70 @@
+// PC speaker.
+//
+// Channel 2 of the PIT is wired to the speaker: in square wave mode it produces the tone,
+// and bits 0 and 1 of port 0x61 gate the channel and connect its output to the speaker.
+// This makes noise without serial or a screen attached, e.g. when a headless test kernel
+// panics. Under QEMU the speaker needs `-machine pcspk-audiodev=<id>` with an audio backend.
+//
+// `beep` waits with the interrupts in whatever state they are, it must work in the panic
+// handler. It delays with writes to the POST port 0x80, each of which takes about a
+// microsecond on the ISA bus, which is precise enough for a beep.
+
+use x86_64::instructions::port::Port;
+use crate::time;
+
+const PIT_CHANNEL2: u16 = 0x42;
+const PIT_COMMAND: u16 = 0x43;
+// channel 2, low byte then high byte, mode 3 (square wave).
+const PIT_SQUARE_WAVE: u8 = 0xb6;
+const PIT_FREQUENCY: u32 = 1_193_182;
+
+const SPEAKER_PORT: u16 = 0x61;
+// bit 0 gates PIT channel 2, bit 1 enables the speaker output.
+const SPEAKER_ENABLE: u8 = 0b11;
+const POST_PORT: u16 = 0x80;
+
+/// Starts a tone of the given frequency in Hz, until `stop`.
+pub fn start(frequency: u32) {
+    let divisor = (PIT_FREQUENCY / frequency.max(19)).min(u32::from(u16::MAX)) as u16;
+    unsafe {
+        Port::new(PIT_COMMAND).write(PIT_SQUARE_WAVE);
+        let mut data = Port::new(PIT_CHANNEL2);
+        data.write(divisor as u8);
+        data.write((divisor >> 8) as u8);
+
+        let mut speaker: Port<u8> = Port::new(SPEAKER_PORT);
+        let control = speaker.read();
+        // only the low 4 bits are writable, the upper ones report NMI sources.
+        speaker.write((control & 0x0f) | SPEAKER_ENABLE);
+    }
+}
+
+/// Silences the speaker.
+pub fn stop() {
+    unsafe {
+        let mut speaker: Port<u8> = Port::new(SPEAKER_PORT);
+        let control = speaker.read();
+        speaker.write(control & 0x0f & !SPEAKER_ENABLE);
+    }
+}
+
+/// Plays a tone of the given frequency in Hz for `duration_ms` milliseconds.
+pub fn beep(frequency: u32, duration_ms: u64) {
+    start(frequency);
+    delay_ms(duration_ms);
+    stop();
+}
+
+fn delay_ms(ms: u64) {
+    let mut post: Port<u8> = Port::new(POST_PORT);
+    for _ in 0..ms * 1000 {
+        unsafe { post.write(0) };
+    }
+}
+
+/// Plays a tone like `beep`, but lets other tasks run in the meantime.
+pub async fn beep_async(frequency: u32, duration_ms: u64) {
+    start(frequency);
+    time::sleep(duration_ms).await;
+    stop();
+}
@@ -0,0 +1,205 @@
+// The ticket lock that all the kernel statics (WRITER, SERIAL1, PICS, ...) use.
+//
+// Taking the lock draws a ticket, and the lock is handed to the tickets in order, so a
+// CPU can't be starved by others that keep grabbing the lock the moment it's released,
+// which can happen with a plain test-and-set spinlock. While waiting, a CPU only reads the
+// ticket being served, and backs off for longer the more tickets are ahead of it, so the
+// waiters don't keep bouncing the cache line between them while the holder works.
+//
+// With the `lock-debug` feature, every successful lock records the source location that
+// took it, and a lock that spins for more than SPIN_LIMIT iterations is treated as a
+// deadlock: we panic with both the place that holds the lock and the place that tried to
+// take it. A typical example is printing from an interrupt handler while WRITER is held by
+// the interrupted code, which would otherwise just hang the kernel silently.
+
+use core::{
+    cell::UnsafeCell,
+    ops::{Deref, DerefMut},
+    sync::atomic::{AtomicU32, Ordering},
+};
+#[cfg(feature = "lock-debug")]
+use core::{
+    panic::Location,
+    ptr,
+    sync::atomic::AtomicPtr,
+};
+
+/// Number of failed acquire attempts after which a lock is reported as deadlocked.
+#[cfg(feature = "lock-debug")]
+pub const SPIN_LIMIT: usize = 100_000_000;
+
+// pause iterations per ticket ahead of ours, and the most we back off at once.
+const BACKOFF_PER_TICKET: u32 = 16;
+const MAX_BACKOFF: u32 = 1024;
+
+pub struct Mutex<T> {
+    next_ticket: AtomicU32,
+    now_serving: AtomicU32,
+    value: UnsafeCell<T>,
+    // location of the code currently holding the lock, null when unlocked.
+    #[cfg(feature = "lock-debug")]
+    owner: AtomicPtr<Location<'static>>,
+}
+
+// the tickets make sure only one guard accesses the value at a time.
+unsafe impl<T: Send> Sync for Mutex<T> {}
+unsafe impl<T: Send> Send for Mutex<T> {}
+
+pub struct MutexGuard<'a, T> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Mutex {
+            next_ticket: AtomicU32::new(0),
+            now_serving: AtomicU32::new(0),
+            value: UnsafeCell::new(value),
+            #[cfg(feature = "lock-debug")]
+            owner: AtomicPtr::new(ptr::null_mut()),
+        }
+    }
+
+    /// Spins until the lock is acquired.
+    ///
+    /// With the `lock-debug` feature, this panics if the lock can't be taken
+    /// within SPIN_LIMIT attempts.
+    #[track_caller]
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        #[cfg(feature = "lock-debug")]
+        let caller = Location::caller();
+        #[cfg(feature = "lock-debug")]
+        let mut attempts = 0;
+
+        loop {
+            let ahead = ticket.wrapping_sub(self.now_serving.load(Ordering::Acquire));
+            if ahead == 0 {
+                break;
+            }
+            #[cfg(feature = "lock-debug")]
+            {
+                attempts += 1;
+                if attempts == SPIN_LIMIT {
+                    self.report_deadlock(caller);
+                }
+            }
+            for _ in 0..ahead.saturating_mul(BACKOFF_PER_TICKET).min(MAX_BACKOFF) {
+                core::hint::spin_loop();
+            }
+        }
+        #[cfg(feature = "lock-debug")]
+        self.owner.store(caller as *const _ as *mut _, Ordering::Relaxed);
+        MutexGuard { mutex: self }
+    }
+
+    /// Tries to acquire the lock once, returning `None` if it is already held.
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        // only draw a ticket if it would be served right away.
+        let serving = self.now_serving.load(Ordering::Relaxed);
+        self.next_ticket
+            .compare_exchange(serving, serving.wrapping_add(1), Ordering::Acquire, Ordering::Relaxed)
+            .ok()?;
+        #[cfg(feature = "lock-debug")]
+        self.owner.store(Location::caller() as *const _ as *mut _, Ordering::Relaxed);
+        Some(MutexGuard { mutex: self })
+    }
+
+    #[cfg(feature = "lock-debug")]
+    fn report_deadlock(&self, caller: &'static Location<'static>) -> ! {
+        let owner = self.owner.load(Ordering::Relaxed);
+        // the owner may have released the lock in the meantime, the pointer is
+        // either null or one of the 'static locations stored by lock and try_lock.
+        match unsafe { owner.as_ref() } {
+            Some(owner) => panic!("deadlock: lock held at {} could not be acquired at {}",
+                                  owner, caller),
+            None => panic!("deadlock: lock could not be acquired at {}", caller),
+        }
+    }
+
+    // Hands the lock to the next ticket.
+    fn unlock(&self) {
+        // clear the owner before the lock is released.
+        #[cfg(feature = "lock-debug")]
+        self.owner.store(ptr::null_mut(), Ordering::Relaxed);
+        self.now_serving.fetch_add(1, Ordering::Release);
+    }
+
+    /// Forcibly unlocks the mutex.
+    ///
+    /// # Safety
+    ///
+    /// This is only meant for the panic path, where the code holding the lock will never
+    /// run again. Calling it while a guard is still in use gives two `&mut` to the data.
+    pub unsafe fn force_unlock(&self) {
+        #[cfg(feature = "lock-debug")]
+        self.owner.store(ptr::null_mut(), Ordering::Relaxed);
+        // serve the next ticket that will be drawn, which drops the holder's ticket and
+        // those of all the waiters: a waiter may be a CPU that will never release the lock,
+        // or a lock call that panicked in report_deadlock. Their tickets are behind now, so
+        // they keep spinning, but any later lock succeeds.
+        let next = self.next_ticket.load(Ordering::Relaxed);
+        self.now_serving.store(next, Ordering::Release);
+    }
+}
+
+impl<T> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
+
+#[test_case]
+fn test_try_lock_fails_while_held() {
+    let mutex = Mutex::new(0);
+    let mut guard = mutex.lock();
+    *guard += 1;
+    assert!(mutex.try_lock().is_none());
+    drop(guard);
+    assert_eq!(*mutex.try_lock().expect("lock is free"), 1);
+}
+
+#[test_case]
+fn test_tickets_are_served_in_order() {
+    let mutex = Mutex::new(());
+    drop(mutex.lock());
+    drop(mutex.try_lock().expect("lock is free"));
+    assert_eq!(mutex.next_ticket.load(Ordering::Relaxed), 2);
+    assert_eq!(mutex.now_serving.load(Ordering::Relaxed), 2);
+    let guard = mutex.lock();
+    // a waiter's ticket, as if another CPU were spinning in lock.
+    mutex.next_ticket.fetch_add(1, Ordering::Relaxed);
+    drop(guard);
+    // the lock now belongs to the waiter, try_lock must not jump the queue.
+    assert!(mutex.try_lock().is_none());
+}
+
+#[test_case]
+fn test_force_unlock_drops_waiting_tickets() {
+    let mutex = Mutex::new(());
+    let guard = mutex.lock();
+    core::mem::forget(guard);
+    // a waiter's ticket that will never be used.
+    mutex.next_ticket.fetch_add(1, Ordering::Relaxed);
+    unsafe { mutex.force_unlock() };
+    drop(mutex.try_lock().expect("lock is free"));
+    drop(mutex.lock());
+    // force unlocking a free lock leaves it free.
+    unsafe { mutex.force_unlock() };
+    drop(mutex.lock());
+}
@@ -0,0 +1,330 @@
+// Executor that polls the tasks whose wakers were invoked.
+//
+// Wakers are usually invoked from interrupt handlers, so the ready queue is only ever
+// locked with interrupts disabled, otherwise an interrupt arriving while the executor
+// holds the lock would deadlock.
+//
+// When no task is ready, the executor halts the CPU until the next interrupt. Checking
+// the queue and halting has to be atomic: an interrupt that wakes a task right after the
+// check but before the hlt would otherwise leave that task waiting for the next interrupt.
+// This is why the check runs with interrupts disabled and `enable_and_hlt` executes `sti`
+// directly followed by `hlt`, the CPU only delivers interrupts after the instruction
+// following `sti`.
+//
+// A task that causes a fault, e.g. a general protection fault, can be killed instead of
+// taking the whole kernel down: tasks are polled through `call_with_recovery`, which
+// records its stack pointer, and `kill_current_task` makes the fault handler return to the
+// end of `call_with_recovery` instead of the faulting instruction. The frames of the killed
+// task are skipped without running any destructors, so whatever it held is leaked: memory
+// is lost, and a lock it held stays locked, the next lock of it deadlocks. The task's future
+// isn't dropped either, its state is broken. Only faults of the task's own code are
+// recovered from: a fault in an interrupt handler that interrupted the task, or on another
+// stack, goes to the panic path, killing the task would leave the interrupt unacknowledged.
+//
+// By default, ready tasks run round-robin in the order they were woken. With the
+// `fair-scheduler` feature, the executor instead picks the ready task that used the least
+// CPU time so far (its virtual runtime, in TSC cycles), like Linux' CFS. A task that is
+// woken all the time, e.g. a busy loop around `yield_now`, then can't crowd out a task
+// that only runs now and then, like the shell: the latter always has the lower runtime.
+//
+// The executor keeps a table of the tasks and the CPU time they used in a static, so the
+// debug monitor can list them without getting hold of the executor.
+
+use alloc::{collections::{BTreeMap, VecDeque}, sync::Arc, task::Wake};
+use core::{
+    arch::naked_asm,
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+};
+use x86_64::{instructions::interrupts, VirtAddr};
+use crate::{console, idle, println, sync::Mutex, time, trap::TrapFrame};
+use super::{Task, TaskId};
+
+type TaskQueue = Arc<Mutex<VecDeque<TaskId>>>;
+
+// id of the task that is being polled, 0 while the executor is idle.
+static CURRENT_TASK: AtomicU64 = AtomicU64::new(0);
+
+// where a killed task's poll continues, only set while a task is polled.
+static RECOVERY_STACK_POINTER: AtomicU64 = AtomicU64::new(0);
+static RECOVERY_INSTRUCTION_POINTER: AtomicU64 = AtomicU64::new(0);
+
+// RFLAGS.IF, tasks run with interrupts enabled.
+const RFLAGS_INTERRUPTS: u64 = 1 << 9;
+// the size of the kernel stack the tasks are polled on, the bootloader's default.
+const TASK_STACK_SIZE: u64 = 80 * 4096;
+
+/// Number of tasks `tasks` can list, further ones run but are not listed.
+pub const TASK_TABLE_SIZE: usize = 32;
+
+/// What the executor knows about a task.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TaskInfo {
+    pub id: u64,
+    pub polls: u64,
+    /// TSC cycles spent polling the task, without the interrupts taken meanwhile.
+    pub cycles: u64,
+}
+
+static TASK_TABLE: Mutex<[Option<TaskInfo>; TASK_TABLE_SIZE]> = Mutex::new([None; TASK_TABLE_SIZE]);
+
+/// Returns the tasks of the executor, or `None` if the table is locked, which happens when
+/// the caller interrupted the executor while it updated the table.
+pub fn tasks() -> Option<[Option<TaskInfo>; TASK_TABLE_SIZE]> {
+    TASK_TABLE.try_lock().map(|table| *table)
+}
+
+// Calls `f` on the table entry of the task, or on a free one if it has none.
+fn update_task_table(id: TaskId, f: impl FnOnce(&mut Option<TaskInfo>)) {
+    interrupts::without_interrupts(|| {
+        let mut table = TASK_TABLE.lock();
+        let index = table.iter().position(|entry| entry.is_some_and(|info| info.id == id.0))
+            .or_else(|| table.iter().position(Option::is_none));
+        if let Some(index) = index {
+            f(&mut table[index]);
+        }
+    });
+}
+
+/// Returns the id of the task that is being polled, if any.
+pub fn current_task() -> Option<u64> {
+    match CURRENT_TASK.load(Ordering::Relaxed) {
+        0 => None,
+        id => Some(id),
+    }
+}
+
+pub struct Executor {
+    tasks: BTreeMap<TaskId, Task>,
+    task_queue: TaskQueue,
+    // a waker per task, so that a new one isn't allocated on every poll.
+    waker_cache: BTreeMap<TaskId, Waker>,
+    // smallest vruntime of the tasks, new tasks start here instead of at 0, so that
+    // they don't monopolize the CPU until they caught up with the old ones.
+    min_vruntime: u64,
+}
+
+impl Executor {
+    pub fn new() -> Self {
+        Executor {
+            tasks: BTreeMap::new(),
+            task_queue: Arc::new(Mutex::new(VecDeque::new())),
+            waker_cache: BTreeMap::new(),
+            min_vruntime: 0,
+        }
+    }
+
+    pub fn spawn(&mut self, mut task: Task) {
+        let task_id = task.id;
+        task.vruntime = self.min_vruntime;
+        if self.tasks.insert(task.id, task).is_some() {
+            panic!("task with same ID already in tasks");
+        }
+        update_task_table(task_id, |entry| *entry = Some(TaskInfo { id: task_id.0, polls: 0, cycles: 0 }));
+        push(&self.task_queue, task_id);
+    }
+
+    /// Polls the tasks forever, sleeping while none of them is ready.
+    pub fn run(&mut self) -> ! {
+        loop {
+            self.run_ready_tasks();
+            // output deferred by interrupt handlers while the console was busy.
+            console::flush();
+            self.sleep_if_idle();
+        }
+    }
+
+    fn run_ready_tasks(&mut self) {
+        while let Some(task_id) = self.next_task() {
+            let task = match self.tasks.get_mut(&task_id) {
+                Some(task) => task,
+                // the task already finished, it was woken more than once.
+                None => continue,
+            };
+            let waker = self.waker_cache
+                .entry(task_id)
+                .or_insert_with(|| TaskWaker::waker(task_id, self.task_queue.clone()));
+            let mut context = Context::from_waker(waker);
+
+            CURRENT_TASK.store(task_id.0, Ordering::Relaxed);
+            let mut call = PollCall { task, context: &mut context, result: Poll::Pending };
+            let mut killed = false;
+            let cycles = idle::account_task(|| {
+                killed = call_with_recovery(poll_task, &mut call as *mut PollCall as *mut u8) != 0;
+            });
+            let result = call.result;
+            let task = call.task;
+            task.vruntime += cycles;
+            CURRENT_TASK.store(0, Ordering::Relaxed);
+            let done = killed || result == Poll::Ready(());
+            update_task_table(task_id, |entry| match entry {
+                _ if done => *entry = None,
+                Some(info) => {
+                    info.polls += 1;
+                    info.cycles += cycles;
+                }
+                // the table was full when the task was spawned.
+                None => {}
+            });
+
+            if killed {
+                // the future was interrupted in the middle of a poll, dropping it isn't safe.
+                core::mem::forget(self.tasks.remove(&task_id));
+                self.waker_cache.remove(&task_id);
+                println!("task {} killed", task_id.0);
+            } else if result == Poll::Ready(()) {
+                self.tasks.remove(&task_id);
+                self.waker_cache.remove(&task_id);
+            }
+            if let Some(min) = self.tasks.values().map(|task| task.vruntime).min() {
+                self.min_vruntime = self.min_vruntime.max(min);
+            }
+        }
+    }
+
+    // Takes the next task to poll off the ready queue, according to the scheduling policy.
+    fn next_task(&self) -> Option<TaskId> {
+        interrupts::without_interrupts(|| {
+            let mut queue = self.task_queue.lock();
+            if cfg!(feature = "fair-scheduler") {
+                // ids of finished tasks sort first, they are skipped right away.
+                let index = (0..queue.len())
+                    .min_by_key(|&i| self.tasks.get(&queue[i]).map_or(0, |task| task.vruntime))?;
+                queue.remove(index)
+            } else {
+                queue.pop_front()
+            }
+        })
+    }
+
+    fn sleep_if_idle(&self) {
+        interrupts::disable();
+        if self.task_queue.lock().is_empty() {
+            // no tick until the next sleeping task is due.
+            time::enter_idle();
+            idle::enable_and_hlt();
+            time::exit_idle();
+        } else {
+            interrupts::enable();
+        }
+    }
+}
+
+impl Default for Executor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Why `kill_current_task` left a fault to the panic path.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotKilled {
+    /// No task was polled.
+    NoTask,
+    /// The fault happened in an interrupt handler, which interrupted the task.
+    InInterrupt,
+    /// The fault happened on another stack than the one the task is polled on.
+    OtherStack,
+}
+
+impl fmt::Display for NotKilled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            NotKilled::NoTask => "no task was running",
+            NotKilled::InInterrupt => "in an interrupt handler",
+            NotKilled::OtherStack => "not on the task stack",
+        })
+    }
+}
+
+/// Kills the task that caused the fault, called by fault handlers. Makes the handler
+/// return to the executor instead of to the faulting instruction. Fails if the fault
+/// didn't happen in the code of a polled task itself.
+///
+/// The locks the task held stay locked, the next attempt to take one of them deadlocks.
+pub fn kill_current_task(frame: &mut TrapFrame) -> Result<(), NotKilled> {
+    let stack_pointer = RECOVERY_STACK_POINTER.load(Ordering::Relaxed);
+    if stack_pointer == 0 {
+        return Err(NotKilled::NoTask);
+    }
+    if idle::in_interrupt() {
+        return Err(NotKilled::InInterrupt);
+    }
+    // the task's frames are below the ones of `call_with_recovery`.
+    let depth = stack_pointer.wrapping_sub(frame.stack_frame.stack_pointer.as_u64());
+    if depth == 0 || depth > TASK_STACK_SIZE {
+        return Err(NotKilled::OtherStack);
+    }
+    let frame = &mut frame.stack_frame;
+    frame.stack_pointer = VirtAddr::new(stack_pointer);
+    frame.instruction_pointer = VirtAddr::new(RECOVERY_INSTRUCTION_POINTER.load(Ordering::Relaxed));
+    // the task may have faulted with interrupts disabled.
+    frame.cpu_flags |= RFLAGS_INTERRUPTS;
+    Ok(())
+}
+
+struct PollCall<'a, 'b> {
+    task: &'a mut Task,
+    context: &'a mut Context<'b>,
+    result: Poll<()>,
+}
+
+extern "C" fn poll_task(call: *mut u8) {
+    let call = unsafe { &mut *(call as *mut PollCall) };
+    call.result = call.task.poll(call.context);
+}
+
+// Calls `f(arg)` and returns 0, or returns 1 if `kill_current_task` killed it.
+#[unsafe(naked)]
+extern "C" fn call_with_recovery(f: extern "C" fn(*mut u8), arg: *mut u8) -> u64 {
+    naked_asm!(
+        // the registers the callee has to preserve, they are restored from here after a kill.
+        "push rbp", "push rbx", "push r12", "push r13", "push r14", "push r15",
+        "mov [rip + {stack_pointer}], rsp",
+        "lea rax, [rip + 2f]",
+        "mov [rip + {instruction_pointer}], rax",
+        // the return address and the 6 registers leave the stack off by 8.
+        "sub rsp, 8",
+        "mov rax, rdi",
+        "mov rdi, rsi",
+        "call rax",
+        "add rsp, 8",
+        "xor eax, eax",
+        "jmp 3f",
+        // a killed task continues here, with the stack pointer stored above.
+        "2:",
+        "mov eax, 1",
+        "3:",
+        "mov qword ptr [rip + {stack_pointer}], 0",
+        "pop r15", "pop r14", "pop r13", "pop r12", "pop rbx", "pop rbp",
+        "ret",
+        stack_pointer = sym RECOVERY_STACK_POINTER,
+        instruction_pointer = sym RECOVERY_INSTRUCTION_POINTER,
+    );
+}
+
+fn push(queue: &TaskQueue, task_id: TaskId) {
+    interrupts::without_interrupts(|| queue.lock().push_back(task_id));
+}
+
+struct TaskWaker {
+    task_id: TaskId,
+    task_queue: TaskQueue,
+}
+
+impl TaskWaker {
+    fn waker(task_id: TaskId, task_queue: TaskQueue) -> Waker {
+        Waker::from(Arc::new(TaskWaker { task_id, task_queue }))
+    }
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        push(&self.task_queue, self.task_id);
+    }
+}
@@ -0,0 +1,211 @@
+// Keyboard input as a task.
+//
+// The keyboard interrupt handler only reads the scancode from the controller and queues it
+// with `add_scancode`, USB keyboards queue the set 1 codes translated from their reports.
+// Decoding the scancodes into keys, with all the modifier and layout state of pc_keyboard,
+// happens in the `process_keypresses` task, which feeds the characters to the TTY. This
+// keeps the handler short and takes the decoder state, and the locks around it, out of
+// interrupt context.
+//
+// The scancodes go through a lock-free queue, the keyboard task is its only consumer. The
+// interrupt handlers and the USB HID task all produce, `add_scancode` disables interrupts
+// so that they take turns.
+//
+// In mirror mode the keyboard is also driven from the host over the first serial
+// interface: every decoded key is echoed to serial, and every received byte is translated
+// to the scancodes of the US layout that type it, and queued like a keypress. A session can
+// then be scripted, or replayed from a log of the echoed keys. Mirror mode is enabled at
+// build time with the `KEYBOARD_MIRROR` environment variable, there is no kernel command
+// line yet, or at runtime with `set_mirror`.
+
+use core::{
+    future::poll_fn,
+    sync::atomic::{AtomicUsize, Ordering},
+    task::{Poll, Waker},
+};
+use core::sync::atomic::AtomicBool;
+use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+use x86_64::instructions::interrupts;
+use crate::{
+    collections::SpscQueue,
+    interrupts::{register_irq, IrqError},
+    print, serial, serial_print,
+    sync::Mutex,
+    tty,
+};
+
+/// Number of scancodes that can wait for the keyboard task, further ones are dropped.
+pub const SCANCODE_QUEUE_SIZE: usize = 128;
+
+static QUEUE: SpscQueue<u8, SCANCODE_QUEUE_SIZE> = SpscQueue::new();
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+static TASK: Mutex<Option<Waker>> = Mutex::new(None);
+
+/// Queues a scancode for the keyboard task, called by the keyboard interrupt handler.
+pub fn add_scancode(scancode: u8) {
+    interrupts::without_interrupts(|| {
+        if QUEUE.push(scancode).is_err() {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        if let Some(task) = TASK.lock().take() {
+            task.wake();
+        }
+    });
+}
+
+/// Number of scancodes dropped because the keyboard task didn't keep up.
+pub fn dropped_scancodes() -> usize {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+static MIRROR: AtomicBool = AtomicBool::new(option_env!("KEYBOARD_MIRROR").is_some());
+
+/// Returns whether keys are echoed to serial and serial input is typed as keys.
+pub fn mirror_enabled() -> bool {
+    MIRROR.load(Ordering::Relaxed)
+}
+
+pub fn set_mirror(enabled: bool) {
+    MIRROR.store(enabled, Ordering::Relaxed);
+}
+
+/// Takes the input of the first serial interface for mirror mode. Until mirror mode is
+/// enabled, the received bytes are dropped.
+pub fn init_serial_input() -> Result<(), IrqError> {
+    register_irq(serial::SERIAL1_IRQ, serial_interrupt)
+}
+
+fn serial_interrupt() {
+    while let Some(byte) = serial::try_receive() {
+        if mirror_enabled() {
+            type_byte(byte);
+        }
+    }
+}
+
+const SCANCODE_ESCAPE: u8 = 0x01;
+const SCANCODE_BACKSPACE: u8 = 0x0e;
+const SCANCODE_TAB: u8 = 0x0f;
+const SCANCODE_ENTER: u8 = 0x1c;
+const SCANCODE_CTRL: u8 = 0x1d;
+const SCANCODE_SHIFT: u8 = 0x2a;
+const SCANCODE_SPACE: u8 = 0x39;
+// set 1 break codes are the make codes with the top bit set.
+const BREAK: u8 = 0x80;
+
+// the character keys of the US layout by row: the scancode of the first key in the row,
+// the characters of the keys without and with shift.
+const KEY_ROWS: [(u8, &[u8], &[u8]); 4] = [
+    (0x02, b"1234567890-=", b"!@#$%^&*()_+"),
+    (0x10, b"qwertyuiop[]", b"QWERTYUIOP{}"),
+    (0x1e, b"asdfghjkl;'`", b"ASDFGHJKL:\"~"),
+    (0x2b, b"\\zxcvbnm,./", b"|ZXCVBNM<>?"),
+];
+
+/// Returns the scancode of the key that types `byte` on the US layout, and the modifier
+/// that has to be held down with it, if any.
+fn key_for_byte(byte: u8) -> Option<(u8, Option<u8>)> {
+    let key = match byte {
+        b'\n' | b'\r' => SCANCODE_ENTER,
+        b'\t' => SCANCODE_TAB,
+        // terminals send DEL for backspace.
+        0x08 | 0x7f => SCANCODE_BACKSPACE,
+        0x1b => SCANCODE_ESCAPE,
+        b' ' => SCANCODE_SPACE,
+        // the keyboard task maps Ctrl+letter to the control characters.
+        0x01..=0x1a => {
+            let letter = byte - 1 + b'a';
+            return key_for_byte(letter).map(|(key, _)| (key, Some(SCANCODE_CTRL)));
+        }
+        _ => {
+            return KEY_ROWS.iter().find_map(|&(first, plain, shifted)| {
+                let find = |keys: &[u8]| {
+                    keys.iter().position(|&k| k == byte).map(|i| first + i as u8)
+                };
+                find(plain).map(|key| (key, None))
+                    .or_else(|| find(shifted).map(|key| (key, Some(SCANCODE_SHIFT))))
+            });
+        }
+    };
+    Some((key, None))
+}
+
+// queues the keypresses that type `byte`, bytes no key types are dropped.
+fn type_byte(byte: u8) {
+    let Some((key, modifier)) = key_for_byte(byte) else {
+        return;
+    };
+    if let Some(modifier) = modifier {
+        add_scancode(modifier);
+    }
+    add_scancode(key);
+    add_scancode(key | BREAK);
+    if let Some(modifier) = modifier {
+        add_scancode(modifier | BREAK);
+    }
+}
+
+async fn next_scancode() -> u8 {
+    poll_fn(|context| {
+        if let Some(scancode) = QUEUE.pop() {
+            return Poll::Ready(scancode);
+        }
+        interrupts::without_interrupts(|| *TASK.lock() = Some(context.waker().clone()));
+        // a scancode queued before the waker was stored didn't wake us.
+        match QUEUE.pop() {
+            Some(scancode) => Poll::Ready(scancode),
+            None => Poll::Pending,
+        }
+    }).await
+}
+
+/// Decodes the queued scancodes and feeds the typed characters to the TTY.
+pub async fn process_keypresses() {
+    // Ctrl+letter is mapped to the control characters, e.g. Ctrl+C to 0x03 for the TTY.
+    let mut keyboard =
+        Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::MapLettersToUnicode);
+
+    loop {
+        let scancode = next_scancode().await;
+        let Ok(Some(key_event)) = keyboard.add_byte(scancode) else {
+            continue;
+        };
+        let key = keyboard.process_keyevent(key_event);
+        if mirror_enabled() {
+            match key {
+                Some(DecodedKey::Unicode(character)) => {
+                    serial_print!("{}", character);
+                }
+                Some(DecodedKey::RawKey(key)) => {
+                    serial_print!("<{:?}>", key);
+                }
+                None => {}
+            }
+        }
+        match key {
+            Some(DecodedKey::Unicode(character)) => {
+                let mut bytes = [0; 4];
+                for &byte in character.encode_utf8(&mut bytes).as_bytes() {
+                    tty::input(byte);
+                }
+            }
+            Some(DecodedKey::RawKey(key)) => print!("{:?}", key),
+            None => {}
+        }
+    }
+}
+
+#[test_case]
+fn test_key_for_byte() {
+    assert_eq!(key_for_byte(b'q'), Some((0x10, None)));
+    assert_eq!(key_for_byte(b'Q'), Some((0x10, Some(SCANCODE_SHIFT))));
+    assert_eq!(key_for_byte(b'0'), Some((0x0b, None)));
+    assert_eq!(key_for_byte(b'"'), Some((0x28, Some(SCANCODE_SHIFT))));
+    assert_eq!(key_for_byte(b'\\'), Some((0x2b, None)));
+    assert_eq!(key_for_byte(b'/'), Some((0x35, None)));
+    assert_eq!(key_for_byte(b'\n'), Some((SCANCODE_ENTER, None)));
+    // Ctrl+C.
+    assert_eq!(key_for_byte(0x03), Some((0x2e, Some(SCANCODE_CTRL))));
+    assert_eq!(key_for_byte(0x80), None);
+}
@@ -0,0 +1,91 @@
+// Cooperative multitasking with async/await.
+//
+// A task is a pinned, heap allocated future. Tasks are driven by the executor, which
+// polls a task whenever its waker was invoked, e.g. by an interrupt handler.
+
+use alloc::boxed::Box;
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll},
+};
+
+pub mod executor;
+pub mod keyboard;
+pub mod mouse;
+
+/// Unique identifier of a task, used by the executor to find the task that was woken.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TaskId(u64);
+
+impl TaskId {
+    fn new() -> Self {
+        // 0 is never handed out, the executor uses it for "no task running".
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        TaskId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+/// Gives up the CPU to the other ready tasks, for long running work between its `.await`s.
+///
+/// The task is put back at the end of the ready queue, so it runs again once
+/// every task that was ready before it had its turn.
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+/// Future returned by `yield_now`.
+#[must_use = "futures do nothing unless awaited"]
+pub struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        self.yielded = true;
+        // waking ourselves queues the task again right away.
+        context.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+pub struct Task {
+    id: TaskId,
+    // TSC cycles spent polling the task, see the executor's scheduling policy.
+    vruntime: u64,
+    // the future has to be pinned, polling may create self-references.
+    future: Pin<Box<dyn Future<Output = ()>>>,
+}
+
+impl Task {
+    pub fn new(future: impl Future<Output = ()> + 'static) -> Task {
+        Task {
+            id: TaskId::new(),
+            vruntime: 0,
+            future: Box::pin(future),
+        }
+    }
+
+    pub fn id(&self) -> TaskId {
+        self.id
+    }
+
+    /// Returns the TSC cycles spent polling the task, relative to when it was spawned.
+    pub fn vruntime(&self) -> u64 {
+        self.vruntime
+    }
+
+    fn poll(&mut self, context: &mut Context) -> Poll<()> {
+        self.future.as_mut().poll(context)
+    }
+}
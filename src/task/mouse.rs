@@ -0,0 +1,69 @@
+// Mouse input.
+//
+// Mouse drivers queue the movements and button states they receive with `add_event`, from
+// interrupt context or a polling task. Consumers take them in order with `next_event`.
+//
+// The events go through a lock-free queue, which only takes one consumer at a time, and one
+// producer, `add_event` disables interrupts so that drivers in both contexts take turns.
+
+use core::{
+    future::poll_fn,
+    sync::atomic::{AtomicUsize, Ordering},
+    task::{Poll, Waker},
+};
+use x86_64::instructions::interrupts;
+use crate::{collections::SpscQueue, sync::Mutex};
+
+/// Number of events that can wait for a consumer, further ones are dropped.
+pub const EVENT_QUEUE_SIZE: usize = 64;
+
+pub const BUTTON_LEFT: u8 = 1 << 0;
+pub const BUTTON_RIGHT: u8 = 1 << 1;
+pub const BUTTON_MIDDLE: u8 = 1 << 2;
+
+/// A movement relative to the previous event, and the buttons held down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MouseEvent {
+    pub buttons: u8,
+    pub dx: i16,
+    /// Positive is down, like on the screen.
+    pub dy: i16,
+    pub wheel: i8,
+}
+
+static QUEUE: SpscQueue<MouseEvent, EVENT_QUEUE_SIZE> = SpscQueue::new();
+static DROPPED: AtomicUsize = AtomicUsize::new(0);
+static TASK: Mutex<Option<Waker>> = Mutex::new(None);
+
+/// Queues an event for the consumer, called by the mouse drivers.
+pub fn add_event(event: MouseEvent) {
+    interrupts::without_interrupts(|| {
+        if QUEUE.push(event).is_err() {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        if let Some(task) = TASK.lock().take() {
+            task.wake();
+        }
+    });
+}
+
+/// Number of events dropped because nobody took them.
+pub fn dropped_events() -> usize {
+    DROPPED.load(Ordering::Relaxed)
+}
+
+/// Waits for the next mouse event, only one task may wait at a time.
+pub async fn next_event() -> MouseEvent {
+    poll_fn(|context| {
+        if let Some(event) = QUEUE.pop() {
+            return Poll::Ready(event);
+        }
+        interrupts::without_interrupts(|| *TASK.lock() = Some(context.waker().clone()));
+        // an event queued before the waker was stored didn't wake us.
+        match QUEUE.pop() {
+            Some(event) => Poll::Ready(event),
+            None => Poll::Pending,
+        }
+    }).await
+}
@@ -0,0 +1,193 @@
+// CPU temperature from the digital thermal sensor.
+//
+// Intel CPUs with a digital thermal sensor (CPUID leaf 6) report the core temperature in
+// IA32_THERM_STATUS, as the number of degrees below TjMax, the temperature at which the
+// CPU starts to throttle itself. TjMax is in IA32_TEMPERATURE_TARGET, the CPUs that don't
+// have it throttle at 100°C. AMD CPUs report their temperature through the SMN registers
+// of the data fabric instead, which the kernel doesn't access.
+//
+// The `run` task reads the sensor every `POLL_INTERVAL_MS` and warns over serial when the
+// temperature comes within `WARNING_MARGIN` degrees of TjMax, and when the CPU throttled,
+// which is useful to know during long stress tests on bare metal. After a warning it stays
+// quiet until the temperature dropped below the warning threshold again. Only the task clears
+// the throttling log, a `read` from elsewhere, e.g. the monitor, leaves it for the task.
+
+use core::{arch::x86_64::__cpuid, fmt};
+use x86_64::registers::model_specific::Msr;
+use crate::{serial_println, time};
+
+/// Milliseconds between the readings of the `run` task.
+pub const POLL_INTERVAL_MS: u64 = 2_000;
+/// Degrees below TjMax from which the `run` task warns.
+pub const WARNING_MARGIN: u32 = 10;
+
+const CPUID_THERMAL_POWER: u32 = 6;
+const CPUID_DIGITAL_SENSOR: u32 = 1 << 0;
+const CPUID_POWER_LIMIT: u32 = 1 << 4;
+const CPUID_HWP: u32 = 1 << 7;
+
+const IA32_THERM_STATUS: u32 = 0x19c;
+const IA32_TEMPERATURE_TARGET: u32 = 0x1a2;
+// the CPU throttles right now, and it throttled since the log bit was cleared.
+const THERM_STATUS_THROTTLING: u64 = 1 << 0;
+const THERM_STATUS_THROTTLING_LOG: u64 = 1 << 1;
+// the log bits: a log bit is cleared by writing 0 and unchanged by writing 1, the other
+// bits are read-only. The thermal, PROCHOT and critical temperature logs are always there,
+// the others only with the CPUID features they belong to, writing them otherwise faults.
+const THERM_STATUS_BASE_LOGS: u64 = 1 << 1 | 1 << 3 | 1 << 5;
+const THERM_STATUS_THRESHOLD_LOGS: u64 = 1 << 7 | 1 << 9;
+const THERM_STATUS_POWER_LIMIT_LOG: u64 = 1 << 11;
+const THERM_STATUS_HWP_LOGS: u64 = 1 << 13 | 1 << 15;
+const THERM_STATUS_VALID: u64 = 1 << 31;
+const DEFAULT_TJ_MAX: u32 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThermalError {
+    /// The CPU has no digital thermal sensor.
+    Unsupported,
+    /// The sensor has no valid reading.
+    NoReading,
+}
+
+/// A reading of the digital thermal sensor of the CPU we run on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reading {
+    /// The temperature in °C.
+    pub celsius: u32,
+    /// The temperature at which the CPU throttles, in °C.
+    pub tj_max: u32,
+    pub throttling: bool,
+    /// The CPU throttled since the `run` task last cleared the throttling log.
+    pub throttled: bool,
+}
+
+impl Reading {
+    fn from_msrs(status: u64, tj_max: u32) -> Result<Reading, ThermalError> {
+        if status & THERM_STATUS_VALID == 0 {
+            return Err(ThermalError::NoReading);
+        }
+        let below_tj_max = ((status >> 16) & 0x7f) as u32;
+        Ok(Reading {
+            celsius: tj_max.saturating_sub(below_tj_max),
+            tj_max,
+            throttling: status & THERM_STATUS_THROTTLING != 0,
+            throttled: status & THERM_STATUS_THROTTLING_LOG != 0,
+        })
+    }
+
+    /// Returns whether the temperature is within `WARNING_MARGIN` of TjMax.
+    pub fn is_hot(&self) -> bool {
+        self.celsius + WARNING_MARGIN >= self.tj_max
+    }
+}
+
+impl fmt::Display for Reading {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // the VGA text mode has no degree sign in ASCII.
+        write!(f, "{}C, throttles at {}C", self.celsius, self.tj_max)?;
+        if self.throttling {
+            write!(f, ", throttling")?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns whether the CPU has a digital thermal sensor.
+pub fn is_supported() -> bool {
+    __cpuid(0).eax >= CPUID_THERMAL_POWER && __cpuid(CPUID_THERMAL_POWER).eax & CPUID_DIGITAL_SENSOR != 0
+}
+
+fn tj_max() -> u32 {
+    // reading a missing MSR faults, and not every CPU with a sensor has this one. The CPUs
+    // with the package sensor (Sandy Bridge on) all do, the older ones are taken to throttle
+    // at 100°C.
+    const CPUID_PACKAGE_SENSOR: u32 = 1 << 6;
+    if __cpuid(CPUID_THERMAL_POWER).eax & CPUID_PACKAGE_SENSOR == 0 {
+        return DEFAULT_TJ_MAX;
+    }
+    match ((unsafe { Msr::new(IA32_TEMPERATURE_TARGET).read() } >> 16) & 0xff) as u32 {
+        0 => DEFAULT_TJ_MAX,
+        tj_max => tj_max,
+    }
+}
+
+/// Reads the sensor.
+pub fn read() -> Result<Reading, ThermalError> {
+    if !is_supported() {
+        return Err(ThermalError::Unsupported);
+    }
+    let status = unsafe { Msr::new(IA32_THERM_STATUS).read() };
+    Reading::from_msrs(status, tj_max())
+}
+
+// The log bits of IA32_THERM_STATUS for the CPUID leaf 6 EAX `features`, like Linux's
+// therm_throt builds them.
+fn log_mask(features: u32) -> u64 {
+    let mut mask = THERM_STATUS_BASE_LOGS;
+    if features & CPUID_DIGITAL_SENSOR != 0 {
+        mask |= THERM_STATUS_THRESHOLD_LOGS;
+    }
+    if features & CPUID_POWER_LIMIT != 0 {
+        mask |= THERM_STATUS_POWER_LIMIT_LOG;
+    }
+    if features & CPUID_HWP != 0 {
+        mask |= THERM_STATUS_HWP_LOGS;
+    }
+    mask
+}
+
+// Clears the throttling log and leaves the other logs alone.
+fn clear_throttling_log() {
+    let value = log_mask(__cpuid(CPUID_THERMAL_POWER).eax) & !THERM_STATUS_THROTTLING_LOG;
+    unsafe { Msr::new(IA32_THERM_STATUS).write(value) };
+}
+
+/// Watches the temperature and warns when it gets close to TjMax. Returns right away if
+/// the CPU has no sensor.
+pub async fn run() {
+    if !is_supported() {
+        return;
+    }
+    let mut warned = false;
+    let mut had_reading = true;
+    loop {
+        match read() {
+            Ok(reading) => {
+                if reading.is_hot() && !warned {
+                    serial_println!("thermal: warning, CPU at {}", reading);
+                }
+                if reading.throttled {
+                    serial_println!("thermal: the CPU throttled, now at {}", reading);
+                    clear_throttling_log();
+                }
+                warned = reading.is_hot();
+                had_reading = true;
+            }
+            Err(err) => {
+                if had_reading {
+                    serial_println!("thermal: no reading: {:?}", err);
+                }
+                had_reading = false;
+            }
+        }
+        time::sleep(POLL_INTERVAL_MS).await;
+    }
+}
+
+#[test_case]
+fn test_reading_from_msrs() {
+    // valid, 35 degrees below TjMax, throttled.
+    let status = THERM_STATUS_VALID | 35 << 16 | THERM_STATUS_THROTTLING_LOG;
+    let reading = Reading::from_msrs(status, 100).unwrap();
+    assert_eq!(reading.celsius, 65);
+    assert!(reading.throttled && !reading.throttling);
+    assert!(!reading.is_hot());
+    assert!(Reading::from_msrs(THERM_STATUS_VALID | 5 << 16, 100).unwrap().is_hot());
+    assert_eq!(Reading::from_msrs(35 << 16, 100), Err(ThermalError::NoReading));
+    // clearing the log writes none of the status bits, nor the logs the CPU doesn't have.
+    let read_only = THERM_STATUS_THROTTLING | 0x7f << 16 | THERM_STATUS_VALID;
+    assert_eq!(log_mask(u32::MAX) & read_only, 0);
+    assert_eq!(log_mask(u32::MAX), 0xaaaa);
+    assert_eq!(log_mask(CPUID_DIGITAL_SENSOR), 0x2aa);
+    assert_eq!(log_mask(CPUID_DIGITAL_SENSOR | CPUID_POWER_LIMIT) & THERM_STATUS_HWP_LOGS, 0);
+}
@@ -0,0 +1,246 @@
+// System time and sleeping, driven by the PIT.
+//
+// Channel 0 of the programmable interval timer raises IRQ 0 `HZ` times per second while the
+// kernel is busy. When the executor runs out of ready tasks, it calls `enter_idle`, which
+// switches the PIT to one-shot mode and programs the interrupt for the earliest sleeping
+// task instead, so an idle kernel doesn't wake up for ticks nobody is waiting for. The
+// 16 bit PIT counter limits the one-shot interval to about 55ms, the idle kernel still
+// wakes up every `MAX_IDLE_TICKS` ticks, which also keeps the NMI watchdog fed.
+//
+// If another interrupt ends the idle period early, `exit_idle` reads how far the PIT got
+// and accounts the elapsed ticks, the fraction of the current tick is lost.
+//
+// The ticks drive the timers, but drift under QEMU, which delivers them late or not at all
+// when the vCPU doesn't run. Under KVM the uptime comes from kvmclock instead.
+//
+// The wall clock time is the RTC time at boot plus the uptime, or the host's time from
+// kvmclock when available.
+
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
+    task::{Context, Poll, Waker},
+};
+use x86_64::instructions::{interrupts, port::Port};
+use crate::{hypervisor, rtc, sync::Mutex};
+
+// writes to it only take time, for `delay_ms`.
+const POST_PORT: u16 = 0x80;
+
+/// Timer interrupts per second while the kernel is busy.
+pub const HZ: u64 = 100;
+/// Maximum number of tasks that sleep at the same time.
+pub const MAX_TIMERS: usize = 32;
+
+const PIT_FREQUENCY: u64 = 1_193_182;
+const DIVISOR: u64 = PIT_FREQUENCY / HZ;
+// the longest one-shot interval that fits the 16 bit counter.
+const MAX_IDLE_TICKS: u64 = u16::MAX as u64 / DIVISOR;
+
+const PIT_CHANNEL0: u16 = 0x40;
+const PIT_COMMAND: u16 = 0x43;
+// channel 0, low byte then high byte, mode 2 (rate generator) or 0 (one-shot).
+const PIT_PERIODIC: u8 = 0x34;
+const PIT_ONE_SHOT: u8 = 0x30;
+// channel 0, latch the current count.
+const PIT_LATCH: u8 = 0x00;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+// the Unix time at uptime 0 according to the RTC, in milliseconds.
+static BOOT_UNIX_TIME_MS: AtomicU64 = AtomicU64::new(0);
+// number of ticks the programmed one-shot interval covers, 0 in periodic mode.
+static ONE_SHOT_TICKS: AtomicU64 = AtomicU64::new(0);
+
+struct Timer {
+    // identifies the Sleep that registered the timer, the slot may be reused once it expired.
+    id: u64,
+    deadline: u64,
+    waker: Waker,
+}
+
+static TIMERS: Mutex<[Option<Timer>; MAX_TIMERS]> = Mutex::new([const { None }; MAX_TIMERS]);
+static NEXT_TIMER_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Programs the PIT for `HZ` interrupts per second.
+pub fn init() {
+    program(PIT_PERIODIC, DIVISOR as u16);
+}
+
+fn program(mode: u8, count: u16) {
+    unsafe {
+        Port::new(PIT_COMMAND).write(mode);
+        let mut data = Port::new(PIT_CHANNEL0);
+        data.write(count as u8);
+        data.write((count >> 8) as u8);
+    }
+}
+
+fn read_count() -> u64 {
+    unsafe {
+        Port::new(PIT_COMMAND).write(PIT_LATCH);
+        let mut data: Port<u8> = Port::new(PIT_CHANNEL0);
+        let low = u64::from(data.read());
+        let high = u64::from(data.read());
+        high << 8 | low
+    }
+}
+
+/// Returns the nanoseconds since the PIT raised the timer interrupt, called at the start
+/// of its handler. `None` at the end of a one-shot interval, the counter doesn't reload
+/// then. Latencies of more than a tick wrap around.
+pub fn interrupt_latency_ns() -> Option<u64> {
+    if ONE_SHOT_TICKS.load(Ordering::Relaxed) != 0 {
+        return None;
+    }
+    // in mode 2 the counter raises the interrupt and starts again from DIVISOR.
+    let counted = DIVISOR.saturating_sub(read_count());
+    Some(counted * 1_000_000_000 / PIT_FREQUENCY)
+}
+
+/// Number of ticks since boot.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// Milliseconds since boot, from kvmclock when available.
+pub fn uptime_ms() -> u64 {
+    hypervisor::uptime_ns().map_or(ticks() * 1000 / HZ, |ns| ns / 1_000_000)
+}
+
+/// Reads the RTC to start the wall clock.
+pub fn init_wall_clock() {
+    let now_ms = rtc::read().to_unix() * 1000;
+    BOOT_UNIX_TIME_MS.store(now_ms.saturating_sub(uptime_ms()), Ordering::Relaxed);
+}
+
+/// Milliseconds since the Unix epoch.
+pub fn unix_time_ms() -> u64 {
+    hypervisor::unix_time_ns()
+        .map_or_else(|| BOOT_UNIX_TIME_MS.load(Ordering::Relaxed) + uptime_ms(), |ns| ns / 1_000_000)
+}
+
+/// Waits for about `ms` milliseconds without timer interrupts, for code that runs with
+/// interrupts disabled, like the panic handler. Each write to the POST port 0x80 takes
+/// about a microsecond on the ISA bus.
+pub fn delay_ms(ms: u64) {
+    let mut post: Port<u8> = Port::new(POST_PORT);
+    for _ in 0..ms.saturating_mul(1000) {
+        unsafe { post.write(0) };
+    }
+}
+
+/// Converts milliseconds to ticks, rounding up, so sleeps are never too short.
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    (ms * HZ).div_ceil(1000)
+}
+
+/// Advances the time and wakes the tasks whose sleep ended, called by the timer interrupt.
+pub fn tick() {
+    match ONE_SHOT_TICKS.swap(0, Ordering::Relaxed) {
+        0 => TICKS.fetch_add(1, Ordering::Relaxed),
+        // the one-shot interval ended, go back to the periodic tick.
+        idle_ticks => {
+            program(PIT_PERIODIC, DIVISOR as u16);
+            TICKS.fetch_add(idle_ticks, Ordering::Relaxed)
+        }
+    };
+
+    let now = ticks();
+    let mut timers = TIMERS.lock();
+    for slot in timers.iter_mut() {
+        if slot.as_ref().is_some_and(|timer| timer.deadline <= now) {
+            if let Some(timer) = slot.take() {
+                timer.waker.wake();
+            }
+        }
+    }
+}
+
+fn next_deadline() -> Option<u64> {
+    TIMERS.lock().iter().flatten().map(|timer| timer.deadline).min()
+}
+
+/// Stops the periodic tick until the next deadline, called by the executor with
+/// interrupts disabled right before it halts.
+pub fn enter_idle() {
+    let now = ticks();
+    let idle_ticks = next_deadline()
+        .map_or(MAX_IDLE_TICKS, |deadline| deadline.saturating_sub(now))
+        .min(MAX_IDLE_TICKS);
+    // the periodic tick is as good as a one-shot interval of one tick.
+    if idle_ticks > 1 {
+        ONE_SHOT_TICKS.store(idle_ticks, Ordering::Relaxed);
+        program(PIT_ONE_SHOT, (idle_ticks * DIVISOR) as u16);
+    }
+}
+
+/// Restores the periodic tick after the CPU woke up, if the one-shot interval
+/// didn't end yet.
+pub fn exit_idle() {
+    interrupts::without_interrupts(|| {
+        let idle_ticks = ONE_SHOT_TICKS.swap(0, Ordering::Relaxed);
+        if idle_ticks == 0 {
+            return;
+        }
+        let remaining = read_count();
+        let elapsed = (idle_ticks * DIVISOR).saturating_sub(remaining) / DIVISOR;
+        program(PIT_PERIODIC, DIVISOR as u16);
+        TICKS.fetch_add(elapsed, Ordering::Relaxed);
+    });
+}
+
+/// Waits for at least `ms` milliseconds.
+pub fn sleep(ms: u64) -> Sleep {
+    Sleep {
+        id: NEXT_TIMER_ID.fetch_add(1, Ordering::Relaxed),
+        deadline: ticks() + ms_to_ticks(ms),
+        slot: None,
+    }
+}
+
+/// Future returned by `sleep`.
+#[must_use = "futures do nothing unless awaited"]
+pub struct Sleep {
+    id: u64,
+    deadline: u64,
+    slot: Option<usize>,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, context: &mut Context) -> Poll<()> {
+        if ticks() >= self.deadline {
+            return Poll::Ready(());
+        }
+        let (id, deadline) = (self.id, self.deadline);
+        let slot = interrupts::without_interrupts(|| {
+            let mut timers = TIMERS.lock();
+            let own_slot = self.slot
+                .filter(|&slot| timers[slot].as_ref().is_some_and(|timer| timer.id == id));
+            let slot = own_slot.or_else(|| timers.iter().position(|timer| timer.is_none()))?;
+            timers[slot] = Some(Timer { id, deadline, waker: context.waker().clone() });
+            Some(slot)
+        });
+        match slot {
+            Some(slot) => self.slot = Some(slot),
+            // all timers are in use, fall back to polling on every executor round.
+            None => context.waker().wake_by_ref(),
+        }
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot {
+            interrupts::without_interrupts(|| {
+                let mut timers = TIMERS.lock();
+                if timers[slot].as_ref().is_some_and(|timer| timer.id == self.id) {
+                    timers[slot] = None;
+                }
+            });
+        }
+    }
+}
@@ -0,0 +1,232 @@
+// Lightweight event tracing.
+//
+// `trace!(Category, "format", args...)` formats a short message into a fixed-size event,
+// stamps it with the TSC and stores it in the ring buffer of the current CPU. Recording
+// never allocates and never takes a lock, so it can be used in interrupt handlers and in
+// the middle of code that holds WRITER or the allocator lock. The buffers keep the most
+// recent EVENTS_PER_CPU events each; `dump` merges them by timestamp and prints them over
+// serial, which is how interrupt/task ordering bugs can be reconstructed after the fact.
+//
+// Every slot is protected by a sequence number (odd while the slot is being written), so
+// a dump running concurrently with writers skips torn events instead of printing garbage.
+//
+// APIC IDs can be sparse, e.g. only the even ones with hyper-threading off, so a CPU gets
+// the next free buffer when it records its first event and keeps it. The events of the
+// CPUs beyond MAX_CPUS aren't recorded.
+
+use alloc::vec::Vec;
+use core::{
+    arch::x86_64::_rdtsc,
+    cell::UnsafeCell,
+    fmt::{self, Write},
+    sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering},
+};
+use crate::{apic, serial_println};
+
+pub const MAX_CPUS: usize = 4;
+pub const EVENTS_PER_CPU: usize = 256;
+/// Maximum length of the message of an event, longer messages are truncated.
+pub const MESSAGE_LEN: usize = 40;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Category {
+    Interrupt = 0,
+    Task = 1,
+    Memory = 2,
+    Driver = 3,
+    Other = 4,
+}
+
+#[derive(Clone, Copy)]
+pub struct Event {
+    pub timestamp: u64,
+    pub cpu: usize,
+    pub category: Category,
+    len: u8,
+    message: [u8; MESSAGE_LEN],
+}
+
+impl Event {
+    pub fn message(&self) -> &str {
+        // `record` only ever truncates at char boundaries.
+        core::str::from_utf8(&self.message[..usize::from(self.len)]).unwrap_or("<invalid>")
+    }
+}
+
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{:>16}] cpu{} {:?}: {}", self.timestamp, self.cpu, self.category, self.message())
+    }
+}
+
+struct Slot {
+    // 2 * n + 1 while the n-th event of the buffer is written, 2 * n + 2 once it's complete.
+    sequence: AtomicU64,
+    event: UnsafeCell<Event>,
+}
+
+struct RingBuffer {
+    // number of events ever recorded in this buffer.
+    head: AtomicUsize,
+    slots: [Slot; EVENTS_PER_CPU],
+}
+
+// The slots are only accessed through the sequence protocol described above.
+unsafe impl Sync for RingBuffer {}
+
+// only used as array initializers, a fresh copy of the constant is what we want.
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_SLOT: Slot = Slot {
+    sequence: AtomicU64::new(0),
+    event: UnsafeCell::new(Event {
+        timestamp: 0,
+        cpu: 0,
+        category: Category::Other,
+        len: 0,
+        message: [0; MESSAGE_LEN],
+    }),
+};
+#[allow(clippy::declare_interior_mutable_const)]
+const EMPTY_BUFFER: RingBuffer = RingBuffer {
+    head: AtomicUsize::new(0),
+    slots: [EMPTY_SLOT; EVENTS_PER_CPU],
+};
+
+static BUFFERS: [RingBuffer; MAX_CPUS] = [EMPTY_BUFFER; MAX_CPUS];
+
+// the APIC ID of the CPU each buffer belongs to, NO_CPU while it is free.
+const NO_CPU: u32 = u32::MAX;
+static CPU_IDS: [AtomicU32; MAX_CPUS] = [const { AtomicU32::new(NO_CPU) }; MAX_CPUS];
+
+// bit n set means category n is recorded, all categories are enabled by default.
+static ENABLED: AtomicU32 = AtomicU32::new(u32::MAX);
+
+pub fn enable(category: Category) {
+    ENABLED.fetch_or(1 << category as u32, Ordering::Relaxed);
+}
+
+pub fn disable(category: Category) {
+    ENABLED.fetch_and(!(1 << category as u32), Ordering::Relaxed);
+}
+
+pub fn is_enabled(category: Category) -> bool {
+    ENABLED.load(Ordering::Relaxed) & (1 << category as u32) != 0
+}
+
+/// Records an event in the trace buffer of the current CPU.
+#[macro_export]
+macro_rules! trace {
+    ($category:ident, $($arg:tt)*) => {
+        $crate::trace::record($crate::trace::Category::$category, format_args!($($arg)*))
+    };
+}
+
+#[doc(hidden)]
+pub fn record(category: Category, args: fmt::Arguments) {
+    if !is_enabled(category) {
+        return;
+    }
+
+    let Some(cpu) = current_cpu() else { return };
+    let mut message = MessageWriter { buffer: [0; MESSAGE_LEN], len: 0 };
+    // MessageWriter never fails, it only truncates.
+    let _ = message.write_fmt(args);
+
+    let buffer = &BUFFERS[cpu];
+    let index = buffer.head.fetch_add(1, Ordering::Relaxed);
+    let slot = &buffer.slots[index % EVENTS_PER_CPU];
+    let sequence = 2 * index as u64;
+
+    slot.sequence.store(sequence + 1, Ordering::Release);
+    unsafe {
+        slot.event.get().write(Event {
+            timestamp: _rdtsc(),
+            cpu,
+            category,
+            len: message.len as u8,
+            message: message.buffer,
+        });
+    }
+    slot.sequence.store(sequence + 2, Ordering::Release);
+}
+
+/// Returns the recorded events of all CPUs, ordered by timestamp.
+pub fn events() -> Vec<Event> {
+    let mut events = Vec::new();
+
+    for buffer in BUFFERS.iter() {
+        let head = buffer.head.load(Ordering::Acquire);
+        for index in head.saturating_sub(EVENTS_PER_CPU)..head {
+            let slot = &buffer.slots[index % EVENTS_PER_CPU];
+            let complete = 2 * index as u64 + 2;
+
+            if slot.sequence.load(Ordering::Acquire) != complete {
+                continue;
+            }
+            let event = unsafe { slot.event.get().read() };
+            // the event may have been overwritten while we copied it.
+            if slot.sequence.load(Ordering::Acquire) == complete {
+                events.push(event);
+            }
+        }
+    }
+
+    events.sort_by_key(|event| event.timestamp);
+    events
+}
+
+/// Prints all recorded events over serial.
+pub fn dump() {
+    let events = events();
+    serial_println!("trace: {} events", events.len());
+    for event in events {
+        serial_println!("{}", event);
+    }
+}
+
+// Returns the index of the current CPU's buffer, `None` if all of them belong to others.
+fn current_cpu() -> Option<usize> {
+    // before apic::init only the bootstrap processor runs, which claims buffer 0 afterwards.
+    if !apic::is_initialized() {
+        return Some(0);
+    }
+    let id = unsafe { apic::id() };
+    CPU_IDS.iter().position(|owner| {
+        match owner.compare_exchange(NO_CPU, id, Ordering::Relaxed, Ordering::Relaxed) {
+            Ok(_) => true,
+            Err(other) => other == id,
+        }
+    })
+}
+
+struct MessageWriter {
+    buffer: [u8; MESSAGE_LEN],
+    len: usize,
+}
+
+impl fmt::Write for MessageWriter {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for c in s.chars() {
+            let end = self.len + c.len_utf8();
+            if end > MESSAGE_LEN {
+                break;
+            }
+            c.encode_utf8(&mut self.buffer[self.len..end]);
+            self.len = end;
+        }
+        Ok(())
+    }
+}
+
+#[test_case]
+fn test_trace_records_events() {
+    crate::trace!(Other, "test event {}", 42);
+
+    // read the slot directly instead of collecting all the events.
+    let buffer = &BUFFERS[current_cpu().expect("no trace buffer for this CPU")];
+    let index = buffer.head.load(Ordering::Acquire) - 1;
+    let event = unsafe { buffer.slots[index % EVENTS_PER_CPU].event.get().read() };
+    assert_eq!(event.category, Category::Other);
+    assert_eq!(event.message(), "test event 42");
+}
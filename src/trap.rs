@@ -0,0 +1,77 @@
+// Exception entry stubs that save the full register set.
+//
+// The x86-interrupt calling convention only hands the handlers the interrupt stack frame
+// (RIP, CS, RFLAGS, RSP and SS). For faults like #GP or #UD we want to see all general
+// purpose registers of the faulting code, so these exceptions enter through small naked
+// stubs generated by `trap_stub!` instead. A stub pushes a zero error code if the CPU
+// doesn't push one, saves all general purpose registers on the stack and calls the Rust
+// handler with a pointer to the resulting TrapFrame. When the handler returns, the
+// registers are restored from the frame (so a handler may change them) and the stub
+// returns to the interrupted code with iretq.
+
+use core::fmt;
+use x86_64::structures::idt::InterruptStackFrameValue;
+use crate::registers::GeneralRegisters;
+
+/// The state saved by a `trap_stub!` entry stub, laid out like it is on the stack.
+#[derive(Debug)]
+#[repr(C)]
+pub struct TrapFrame {
+    pub registers: GeneralRegisters,
+    /// The error code pushed by the CPU, 0 for exceptions without one.
+    pub error_code: u64,
+    pub stack_frame: InterruptStackFrameValue,
+}
+
+impl fmt::Display for TrapFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let frame = &self.stack_frame;
+        writeln!(f, "RIP={:016x} RSP={:016x} RFLAGS={:016x}",
+                 frame.instruction_pointer.as_u64(), frame.stack_pointer.as_u64(), frame.cpu_flags)?;
+        writeln!(f, "CS={:04x} SS={:04x} ERROR={:016x}",
+                 frame.code_segment, frame.stack_segment, self.error_code)?;
+        write!(f, "{}", self.registers)
+    }
+}
+
+/// Defines a naked exception entry stub `$name` that calls the handler
+/// `extern "C" fn(&mut TrapFrame)`.
+///
+/// Use `trap_stub!(name, handler, error_code)` for exceptions that push an
+/// error code and `trap_stub!(name, handler)` for the others.
+macro_rules! trap_stub {
+    ($name:ident, $handler:path, error_code) => {
+        $crate::trap::trap_stub!(@stub $name, $handler, "");
+    };
+    ($name:ident, $handler:path) => {
+        $crate::trap::trap_stub!(@stub $name, $handler, "push 0");
+    };
+    (@stub $name:ident, $handler:path, $push_error_code:literal) => {
+        #[unsafe(naked)]
+        extern "C" fn $name() {
+            core::arch::naked_asm!(
+                $push_error_code,
+                // the push order matches the field order of GeneralRegisters.
+                "push rax", "push rbx", "push rcx", "push rdx", "push rsi", "push rdi", "push rbp",
+                "push r8", "push r9", "push r10", "push r11", "push r12", "push r13", "push r14",
+                "push r15",
+                "mov rdi, rsp",
+                // The CPU aligned the stack to 16 bytes before pushing its frame and the
+                // error code (6 words), the 15 registers leave it off by 8.
+                "sub rsp, 8",
+                "cld",
+                "call {handler}",
+                "add rsp, 8",
+                "pop r15", "pop r14", "pop r13", "pop r12", "pop r11", "pop r10", "pop r9",
+                "pop r8", "pop rbp", "pop rdi", "pop rsi", "pop rdx", "pop rcx", "pop rbx",
+                "pop rax",
+                // drop the error code.
+                "add rsp, 8",
+                "iretq",
+                handler = sym $handler,
+            );
+        }
+    };
+}
+
+pub(crate) use trap_stub;
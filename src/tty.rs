@@ -0,0 +1,215 @@
+// Terminal line discipline.
+//
+// The TTY sits between the input drivers and whoever reads the input, e.g. the shell.
+// Drivers feed it bytes with `input`, usually from an interrupt handler. In canonical
+// mode, the TTY collects the bytes into a line that can be edited with backspace, and
+// only hands it to the reader once it is completed with Enter. In raw mode, every byte
+// is passed on as it arrives. Echoing the input back to the console can be switched off,
+// e.g. for passwords.
+//
+// Ctrl+C discards the line being edited and makes the pending `read` fail with
+// `Interrupted`, so a reader can abort what it is doing.
+//
+// `input` runs in interrupt context, so the buffers are fixed-size arrays (the allocator
+// may be locked by the interrupted code) and the state is only locked with interrupts
+// disabled.
+
+use core::{
+    future::poll_fn,
+    task::{Poll, Waker},
+};
+use x86_64::instructions::interrupts;
+use crate::{print, sync::Mutex};
+
+/// Maximum length of a line in canonical mode, further input is dropped.
+pub const MAX_LINE: usize = 128;
+/// Number of input bytes that can wait for the reader.
+pub const INPUT_BUFFER: usize = 512;
+
+const CTRL_C: u8 = 0x03;
+const BACKSPACE: u8 = 0x08;
+const DELETE: u8 = 0x7f;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Mode {
+    /// Line editing, the reader only sees complete lines.
+    pub canonical: bool,
+    /// Print the input as it is typed.
+    pub echo: bool,
+}
+
+/// The read was aborted with Ctrl+C.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interrupted;
+
+struct LineDiscipline {
+    mode: Mode,
+    // the line being edited in canonical mode.
+    line: [u8; MAX_LINE],
+    line_len: usize,
+    // the input that is ready for the reader, a ring buffer.
+    ready: [u8; INPUT_BUFFER],
+    ready_start: usize,
+    ready_len: usize,
+    interrupted: bool,
+    reader: Option<Waker>,
+}
+
+static TTY: Mutex<LineDiscipline> = Mutex::new(LineDiscipline {
+    mode: Mode { canonical: true, echo: true },
+    line: [0; MAX_LINE],
+    line_len: 0,
+    ready: [0; INPUT_BUFFER],
+    ready_start: 0,
+    ready_len: 0,
+    interrupted: false,
+    reader: None,
+});
+
+impl LineDiscipline {
+    fn push_ready(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            if self.ready_len == INPUT_BUFFER {
+                // nobody is reading, drop the input instead of blocking the driver.
+                break;
+            }
+            self.ready[(self.ready_start + self.ready_len) % INPUT_BUFFER] = byte;
+            self.ready_len += 1;
+        }
+    }
+
+    fn wake_reader(&mut self) {
+        if let Some(reader) = self.reader.take() {
+            reader.wake();
+        }
+    }
+
+    // Handles an input byte, returns what to echo.
+    fn input(&mut self, byte: u8) -> Echo {
+        if byte == CTRL_C {
+            self.line_len = 0;
+            self.interrupted = true;
+            self.wake_reader();
+            return Echo::Interrupt;
+        }
+        if !self.mode.canonical {
+            self.push_ready(&[byte]);
+            self.wake_reader();
+            return Echo::Byte(byte);
+        }
+
+        match byte {
+            BACKSPACE | DELETE if self.line_len > 0 => {
+                self.line_len -= 1;
+                Echo::Erase
+            }
+            BACKSPACE | DELETE => Echo::None,
+            b'\n' | b'\r' => {
+                let len = self.line_len;
+                let line = self.line;
+                self.push_ready(&line[..len]);
+                self.push_ready(b"\n");
+                self.line_len = 0;
+                self.wake_reader();
+                Echo::Byte(b'\n')
+            }
+            byte if self.line_len < MAX_LINE => {
+                self.line[self.line_len] = byte;
+                self.line_len += 1;
+                Echo::Byte(byte)
+            }
+            _ => Echo::None,
+        }
+    }
+
+    // Copies ready input to `buf`, in canonical mode at most one line.
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        let mut len = 0;
+        while len < buf.len() && self.ready_len > 0 {
+            let byte = self.ready[self.ready_start];
+            self.ready_start = (self.ready_start + 1) % INPUT_BUFFER;
+            self.ready_len -= 1;
+            buf[len] = byte;
+            len += 1;
+            if self.mode.canonical && byte == b'\n' {
+                break;
+            }
+        }
+        len
+    }
+}
+
+enum Echo {
+    None,
+    Byte(u8),
+    Erase,
+    Interrupt,
+}
+
+/// Changes the mode, the line being edited is kept.
+pub fn set_mode(mode: Mode) {
+    interrupts::without_interrupts(|| TTY.lock().mode = mode);
+}
+
+pub fn mode() -> Mode {
+    interrupts::without_interrupts(|| TTY.lock().mode)
+}
+
+/// Feeds an input byte to the TTY, called by the input drivers.
+pub fn input(byte: u8) {
+    let (echo, enabled) = interrupts::without_interrupts(|| {
+        let mut tty = TTY.lock();
+        (tty.input(byte), tty.mode.echo)
+    });
+    // echo without holding the TTY lock.
+    match echo {
+        Echo::Interrupt => print!("^C\n"),
+        _ if !enabled => {}
+        Echo::Byte(byte) if byte.is_ascii() => print!("{}", byte as char),
+        Echo::Erase => print!("\u{8} \u{8}"),
+        Echo::Byte(_) | Echo::None => {}
+    }
+}
+
+/// Waits for input and reads it into `buf`. In canonical mode, this returns one line
+/// including its newline, or the part of it that fits.
+pub async fn read(buf: &mut [u8]) -> Result<usize, Interrupted> {
+    poll_fn(|context| {
+        interrupts::without_interrupts(|| {
+            let mut tty = TTY.lock();
+            if tty.interrupted {
+                tty.interrupted = false;
+                return Poll::Ready(Err(Interrupted));
+            }
+            if tty.ready_len > 0 || buf.is_empty() {
+                return Poll::Ready(Ok(tty.read(buf)));
+            }
+            tty.reader = Some(context.waker().clone());
+            Poll::Pending
+        })
+    }).await
+}
+
+#[test_case]
+fn test_canonical_mode_edits_line() {
+    let mut tty = LineDiscipline {
+        mode: Mode { canonical: true, echo: false },
+        line: [0; MAX_LINE],
+        line_len: 0,
+        ready: [0; INPUT_BUFFER],
+        ready_start: 0,
+        ready_len: 0,
+        interrupted: false,
+        reader: None,
+    };
+    for &byte in b"ab\x08c" {
+        tty.input(byte);
+    }
+    // nothing is readable before the line is complete.
+    assert_eq!(tty.ready_len, 0);
+    tty.input(b'\n');
+
+    let mut buf = [0; 8];
+    let len = tty.read(&mut buf);
+    assert_eq!(&buf[..len], b"ac\n");
+}
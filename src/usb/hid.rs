@@ -0,0 +1,290 @@
+// HID boot protocol keyboards and mice.
+//
+// Devices with a HID boot interface can be switched to the boot protocol, in which they send
+// fixed reports that need no report descriptor: a keyboard reports the modifier keys and up
+// to six pressed keys, a mouse its buttons and movement. The `poll_devices` task reads the
+// reports from the interrupt IN endpoints. Keyboard reports are turned into the make and
+// break codes of scancode set 1 a PS/2 keyboard would send for the same keys, and queued
+// with `keyboard::add_scancode`, so the keyboard task decodes both the same way. They also
+// go through the monitor hotkey check like the PS/2 scancodes, and the monitor polls the
+// keyboards itself while it runs. Mouse reports go to the mouse event queue.
+
+use x86_64::instructions::interrupts;
+use crate::{
+    memory::BootFrameAllocator,
+    println,
+    sync::Mutex,
+    task::{keyboard, mouse::{self, MouseEvent}},
+    time,
+};
+#[cfg(feature = "vga")]
+use crate::monitor;
+use super::{
+    xhci::{self, XhciError}, Descriptor, EndpointDescriptor, SetupPacket, UsbDevice,
+    DESCRIPTOR_CONFIGURATION, REQUEST_SET_CONFIGURATION, REQUEST_TYPE_OUT,
+};
+
+pub const MAX_DEVICES: usize = 4;
+/// How often the task polls the devices, in milliseconds.
+pub const POLL_INTERVAL_MS: u64 = 10;
+
+const CLASS_HID: u8 = 3;
+const SUBCLASS_BOOT: u8 = 1;
+const PROTOCOL_KEYBOARD: u8 = 1;
+const PROTOCOL_MOUSE: u8 = 2;
+// class requests to the interface.
+const REQUEST_TYPE_CLASS_INTERFACE: u8 = 0x21;
+const REQUEST_SET_IDLE: u8 = 0x0a;
+const REQUEST_SET_PROTOCOL: u8 = 0x0b;
+const BOOT_PROTOCOL: u16 = 0;
+
+const REPORT_SIZE: usize = 8;
+// the key usage a keyboard reports in all slots when too many keys are pressed.
+const USAGE_ROLLOVER_ERROR: u8 = 0x01;
+const EXTENDED: u16 = 0xe000;
+
+// set 1 make codes of keyboard usages 0x04 (A) to 0x65 (Application), 0 for keys without
+// one, `EXTENDED` for the ones behind an 0xe0 prefix.
+const USAGE_TO_SCANCODE: [u16; 0x62] = [
+    0x1e, 0x30, 0x2e, 0x20, 0x12, 0x21, 0x22, 0x23, 0x17, 0x24, 0x25, 0x26, 0x32, 0x31, 0x18, 0x19,
+    0x10, 0x13, 0x1f, 0x14, 0x16, 0x2f, 0x11, 0x2d, 0x15, 0x2c, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07,
+    0x08, 0x09, 0x0a, 0x0b, 0x1c, 0x01, 0x0e, 0x0f, 0x39, 0x0c, 0x0d, 0x1a, 0x1b, 0x2b, 0x2b, 0x27,
+    0x28, 0x29, 0x33, 0x34, 0x35, 0x3a, 0x3b, 0x3c, 0x3d, 0x3e, 0x3f, 0x40, 0x41, 0x42, 0x43, 0x44,
+    0x57, 0x58, 0, 0x46, 0, 0xe052, 0xe047, 0xe049, 0xe053, 0xe04f, 0xe051, 0xe04d, 0xe04b, 0xe050,
+    0xe048, 0x45, 0xe035, 0x37, 0x4a, 0x4e, 0xe01c, 0x4f, 0x50, 0x51, 0x4b, 0x4c, 0x4d, 0x47, 0x48,
+    0x49, 0x52, 0x53, 0x56, 0xe05d,
+];
+// set 1 make codes of the modifier bits, left control to right GUI.
+const MODIFIER_TO_SCANCODE: [u16; 8] = [0x1d, 0x2a, 0x38, 0xe05b, 0xe01d, 0x36, 0xe038, 0xe05c];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Keyboard,
+    Mouse,
+}
+
+#[derive(Clone, Copy)]
+struct HidDevice {
+    slot: u8,
+    kind: Kind,
+    last_report: [u8; REPORT_SIZE],
+}
+
+static DEVICES: Mutex<[Option<HidDevice>; MAX_DEVICES]> = Mutex::new([None; MAX_DEVICES]);
+
+/// Switches the enumerated boot keyboards and mice to the boot protocol, returns how many
+/// were set up.
+pub fn init(frame_allocator: &mut BootFrameAllocator) -> usize {
+    let mut found = 0;
+    super::for_each_device(|device| {
+        if found == MAX_DEVICES {
+            return;
+        }
+        match setup_device(device, frame_allocator) {
+            Ok(Some(hid)) => {
+                println!("usb: {:?} at {}", hid.kind, device);
+                interrupts::without_interrupts(|| DEVICES.lock()[found] = Some(hid));
+                found += 1;
+            }
+            Ok(None) => {}
+            Err(err) => println!("usb: slot {}: HID setup failed: {:?}", device.slot, err),
+        }
+    });
+    found
+}
+
+// Looks for a boot interface in the first configuration and sets it up.
+fn setup_device(device: &UsbDevice, frame_allocator: &mut BootFrameAllocator) -> Result<Option<HidDevice>, XhciError> {
+    let mut bytes = [0; 256];
+    let setup = SetupPacket::get_descriptor(DESCRIPTOR_CONFIGURATION, 0, bytes.len() as u16);
+    let len = xhci::control_in(device.slot, setup, &mut bytes)?;
+    let total_length = usize::from(u16::from_le_bytes([bytes[2], bytes[3]])).min(len);
+    let Some((interface, kind, endpoint)) = find_boot_interface(&bytes[..total_length]) else {
+        return Ok(None);
+    };
+
+    let configuration = bytes[5];
+    let class_request = |request, value| SetupPacket {
+        request_type: REQUEST_TYPE_CLASS_INTERFACE,
+        request,
+        value,
+        index: u16::from(interface),
+        length: 0,
+    };
+    xhci::control_out(device.slot, SetupPacket {
+        request_type: REQUEST_TYPE_OUT,
+        request: REQUEST_SET_CONFIGURATION,
+        value: u16::from(configuration),
+        index: 0,
+        length: 0,
+    })?;
+    xhci::configure_interrupt_in(device.slot, endpoint, frame_allocator)?;
+    xhci::control_out(device.slot, class_request(REQUEST_SET_PROTOCOL, BOOT_PROTOCOL))?;
+    if kind == Kind::Keyboard {
+        // only report when the keys change, repeating held keys is up to the decoder.
+        xhci::control_out(device.slot, class_request(REQUEST_SET_IDLE, 0))?;
+    }
+    Ok(Some(HidDevice { slot: device.slot, kind, last_report: [0; REPORT_SIZE] }))
+}
+
+// Returns the interface number, the kind of device and the interrupt IN endpoint of the
+// first boot keyboard or mouse interface.
+fn find_boot_interface(configuration: &[u8]) -> Option<(u8, Kind, EndpointDescriptor)> {
+    let mut interface = None;
+    for descriptor in super::parse_configuration(configuration) {
+        match descriptor {
+            Descriptor::Interface(i) => {
+                interface = match (i.class, i.subclass, i.protocol) {
+                    (CLASS_HID, SUBCLASS_BOOT, PROTOCOL_KEYBOARD) => Some((i.number, Kind::Keyboard)),
+                    (CLASS_HID, SUBCLASS_BOOT, PROTOCOL_MOUSE) => Some((i.number, Kind::Mouse)),
+                    _ => None,
+                };
+            }
+            Descriptor::Endpoint(endpoint) if endpoint.is_interrupt_in() => {
+                if let Some((number, kind)) = interface {
+                    return Some((number, kind, endpoint));
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Polls the HID devices for reports, returns at once if `init` found none.
+pub async fn poll_devices() {
+    let count = interrupts::without_interrupts(|| DEVICES.lock().iter().flatten().count());
+    if count == 0 {
+        return;
+    }
+    loop {
+        for index in 0..count {
+            let Some(mut device) = interrupts::without_interrupts(|| DEVICES.lock()[index]) else {
+                continue;
+            };
+            let mut report = [0; REPORT_SIZE];
+            match xhci::poll_interrupt_in(device.slot, &mut report) {
+                Ok(Some(_)) => match device.kind {
+                    Kind::Keyboard => update_keyboard(&mut device, &report, queue_scancode),
+                    Kind::Mouse => mouse::add_event(MouseEvent {
+                        buttons: report[0] & 0b111,
+                        dx: i16::from(report[1] as i8),
+                        dy: i16::from(report[2] as i8),
+                        wheel: report[3] as i8,
+                    }),
+                },
+                Ok(None) => {}
+                Err(err) => println!("usb: slot {}: HID report failed: {:?}", device.slot, err),
+            }
+            interrupts::without_interrupts(|| DEVICES.lock()[index] = Some(device));
+        }
+        time::sleep(POLL_INTERVAL_MS).await;
+    }
+}
+
+// Queues a scancode for the keyboard task, unless it completes the monitor hotkey.
+fn queue_scancode(scancode: u8) {
+    #[cfg(feature = "vga")]
+    if monitor::is_hotkey(scancode) {
+        interrupts::without_interrupts(|| monitor::enter(None));
+        return;
+    }
+    keyboard::add_scancode(scancode);
+}
+
+/// Polls the keyboards once and emits their scancodes, for the debug monitor, which reads
+/// the keys itself with interrupts disabled. Does nothing while the devices are locked.
+#[cfg(feature = "vga")]
+pub fn poll_keyboards(mut emit: impl FnMut(u8)) {
+    let Some(mut devices) = DEVICES.try_lock() else { return };
+    for device in devices.iter_mut().flatten().filter(|device| device.kind == Kind::Keyboard) {
+        let mut report = [0; REPORT_SIZE];
+        if let Ok(Some(_)) = xhci::poll_interrupt_in(device.slot, &mut report) {
+            update_keyboard(device, &report, &mut emit);
+        }
+    }
+}
+
+// Emits the scancodes of a new report and remembers its keys.
+fn update_keyboard(device: &mut HidDevice, report: &[u8; REPORT_SIZE], emit: impl FnMut(u8)) {
+    keyboard_report_to_scancodes(&device.last_report, report, emit);
+    // keep the old keys while the keyboard reports a rollover.
+    if report[2] != USAGE_ROLLOVER_ERROR {
+        device.last_report = *report;
+    }
+}
+
+// Emits the set 1 codes for the keys released and pressed between two boot keyboard reports.
+fn keyboard_report_to_scancodes(
+    previous: &[u8; REPORT_SIZE],
+    report: &[u8; REPORT_SIZE],
+    mut emit: impl FnMut(u8),
+) {
+    if report[2..].iter().all(|&usage| usage == USAGE_ROLLOVER_ERROR) {
+        return;
+    }
+    let mut send = |code: u16, pressed: bool| {
+        if code == 0 {
+            return;
+        }
+        if code & EXTENDED == EXTENDED {
+            emit(0xe0);
+        }
+        emit(code as u8 | if pressed { 0 } else { 0x80 });
+    };
+    let scancode = |usage: u8| {
+        usize::from(usage).checked_sub(4).and_then(|i| USAGE_TO_SCANCODE.get(i)).copied().unwrap_or(0)
+    };
+
+    for (bit, &code) in MODIFIER_TO_SCANCODE.iter().enumerate() {
+        let (was, is) = (previous[0] >> bit & 1 != 0, report[0] >> bit & 1 != 0);
+        if was != is {
+            send(code, is);
+        }
+    }
+    for &usage in &previous[2..] {
+        if usage != 0 && !report[2..].contains(&usage) {
+            send(scancode(usage), false);
+        }
+    }
+    for &usage in &report[2..] {
+        if usage != 0 && !previous[2..].contains(&usage) {
+            send(scancode(usage), true);
+        }
+    }
+}
+
+#[test_case]
+fn test_keyboard_report_to_scancodes() {
+    fn convert(previous: [u8; REPORT_SIZE], report: [u8; REPORT_SIZE]) -> ([u8; 8], usize) {
+        let (mut codes, mut len) = ([0; 8], 0);
+        keyboard_report_to_scancodes(&previous, &report, |code| {
+            codes[len] = code;
+            len += 1;
+        });
+        (codes, len)
+    }
+    // A pressed, then released.
+    let a = [0, 0, 0x04, 0, 0, 0, 0, 0];
+    assert_eq!(convert([0; 8], a), ([0x1e, 0, 0, 0, 0, 0, 0, 0], 1));
+    assert_eq!(convert(a, [0; 8]), ([0x9e, 0, 0, 0, 0, 0, 0, 0], 1));
+    // left shift and Up pressed together, Up is behind a prefix.
+    assert_eq!(convert([0; 8], [0b10, 0, 0x52, 0, 0, 0, 0, 0]), ([0x2a, 0xe0, 0x48, 0, 0, 0, 0, 0], 3));
+    // a rollover report changes nothing.
+    assert_eq!(convert(a, [0, 0, 1, 1, 1, 1, 1, 1]).1, 0);
+}
+
+#[test_case]
+fn test_find_boot_interface() {
+    // the configuration of QEMU's usb-mouse: configuration, interface, HID and endpoint descriptors.
+    let configuration = [
+        9, 2, 34, 0, 1, 1, 6, 0xa0, 50,
+        9, 4, 0, 0, 1, 3, 1, 2, 0,
+        9, 0x21, 0x01, 0x00, 0, 1, 0x22, 52, 0,
+        7, 5, 0x81, 3, 4, 0, 10,
+    ];
+    let (interface, kind, endpoint) = find_boot_interface(&configuration).unwrap();
+    assert_eq!(interface, 0);
+    assert_eq!(kind, Kind::Mouse);
+    assert_eq!((endpoint.address, endpoint.max_packet_size, endpoint.interval), (0x81, 4, 10));
+}
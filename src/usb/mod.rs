@@ -0,0 +1,243 @@
+// USB devices.
+//
+// The host controller drivers enumerate the devices on their root hub ports: they reset the
+// port, give the device an address and read its device descriptor, then record the device
+// here. Class drivers look up the devices they handle with `for_each_device`. The only host
+// controller driver is for xHCI, which handles USB devices of all speeds, QEMU provides one
+// with `-device qemu-xhci`.
+//
+// Hubs are not supported yet, only devices plugged directly into a root hub port are found.
+
+pub mod hid;
+pub mod xhci;
+
+use core::fmt;
+use crate::rcu::RcuCell;
+
+pub const MAX_DEVICES: usize = 16;
+
+// standard requests and descriptor types from chapter 9 of the USB specification.
+pub const REQUEST_GET_DESCRIPTOR: u8 = 6;
+pub const REQUEST_SET_CONFIGURATION: u8 = 9;
+pub const DESCRIPTOR_DEVICE: u8 = 1;
+pub const DESCRIPTOR_CONFIGURATION: u8 = 2;
+pub const DESCRIPTOR_INTERFACE: u8 = 4;
+pub const DESCRIPTOR_ENDPOINT: u8 = 5;
+// bmRequestType of standard device-to-host and host-to-device requests.
+pub const REQUEST_TYPE_IN: u8 = 0x80;
+pub const REQUEST_TYPE_OUT: u8 = 0x00;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Speed {
+    Full,
+    Low,
+    High,
+    Super,
+    SuperPlus,
+    Unknown(u8),
+}
+
+impl Speed {
+    /// The maximum packet size of the default control endpoint, before we know
+    /// what the device descriptor says.
+    pub fn default_max_packet_size(self) -> u16 {
+        match self {
+            Speed::Low | Speed::Full | Speed::Unknown(_) => 8,
+            Speed::High => 64,
+            Speed::Super | Speed::SuperPlus => 512,
+        }
+    }
+
+    /// The maximum packet size of the default control endpoint from bMaxPacketSize0 of the
+    /// device descriptor, which SuperSpeed devices give as a power of two.
+    pub fn max_packet_size(self, max_packet_size0: u8) -> u16 {
+        match self {
+            Speed::Super | Speed::SuperPlus => 1 << max_packet_size0.min(9),
+            _ => u16::from(max_packet_size0),
+        }
+    }
+}
+
+/// The setup stage of a control transfer.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SetupPacket {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    pub length: u16,
+}
+
+impl SetupPacket {
+    pub fn get_descriptor(descriptor_type: u8, index: u8, length: u16) -> Self {
+        SetupPacket {
+            request_type: REQUEST_TYPE_IN,
+            request: REQUEST_GET_DESCRIPTOR,
+            value: u16::from(descriptor_type) << 8 | u16::from(index),
+            index: 0,
+            length,
+        }
+    }
+
+    /// The packet as the 8 bytes sent on the bus, little endian.
+    pub fn to_u64(self) -> u64 {
+        u64::from(self.request_type)
+            | u64::from(self.request) << 8
+            | u64::from(self.value) << 16
+            | u64::from(self.index) << 32
+            | u64::from(self.length) << 48
+    }
+}
+
+/// The fields of a device descriptor we use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceDescriptor {
+    pub usb_version: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    pub max_packet_size: u8,
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub configurations: u8,
+}
+
+impl DeviceDescriptor {
+    pub const LENGTH: usize = 18;
+
+    pub fn parse(bytes: &[u8; Self::LENGTH]) -> Option<Self> {
+        if bytes[1] != DESCRIPTOR_DEVICE {
+            return None;
+        }
+        let u16_at = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+        Some(DeviceDescriptor {
+            usb_version: u16_at(2),
+            class: bytes[4],
+            subclass: bytes[5],
+            protocol: bytes[6],
+            max_packet_size: bytes[7],
+            vendor_id: u16_at(8),
+            product_id: u16_at(10),
+            configurations: bytes[17],
+        })
+    }
+}
+
+/// The fields of an interface descriptor we use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InterfaceDescriptor {
+    pub number: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+}
+
+/// The fields of an endpoint descriptor we use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EndpointDescriptor {
+    /// The endpoint number, with bit 7 set for IN endpoints.
+    pub address: u8,
+    pub attributes: u8,
+    pub max_packet_size: u16,
+    pub interval: u8,
+}
+
+impl EndpointDescriptor {
+    pub fn is_interrupt_in(&self) -> bool {
+        self.address & 0x80 != 0 && self.attributes & 0b11 == 0b11
+    }
+}
+
+/// A descriptor following the configuration descriptor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Descriptor {
+    Interface(InterfaceDescriptor),
+    Endpoint(EndpointDescriptor),
+    /// Class specific and other descriptors.
+    Other(u8),
+}
+
+/// Splits the bytes returned for a configuration descriptor into the descriptors that follow
+/// it. Stops at the first truncated descriptor.
+pub fn parse_configuration(bytes: &[u8]) -> impl Iterator<Item = Descriptor> + '_ {
+    let mut rest = bytes.get(usize::from(bytes.first().copied().unwrap_or(0))..).unwrap_or(&[]);
+    core::iter::from_fn(move || {
+        let len = usize::from(*rest.first()?);
+        if len < 2 || len > rest.len() {
+            return None;
+        }
+        let (descriptor, tail) = rest.split_at(len);
+        rest = tail;
+        Some(match (descriptor[1], len) {
+            (DESCRIPTOR_INTERFACE, 9..) => Descriptor::Interface(InterfaceDescriptor {
+                number: descriptor[2],
+                class: descriptor[5],
+                subclass: descriptor[6],
+                protocol: descriptor[7],
+            }),
+            (DESCRIPTOR_ENDPOINT, 7..) => Descriptor::Endpoint(EndpointDescriptor {
+                address: descriptor[2],
+                attributes: descriptor[3],
+                max_packet_size: u16::from_le_bytes([descriptor[4], descriptor[5]]) & 0x7ff,
+                interval: descriptor[6],
+            }),
+            (descriptor_type, _) => Descriptor::Other(descriptor_type),
+        })
+    })
+}
+
+/// A device found on a root hub port.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UsbDevice {
+    /// The slot the xHCI controller assigned to the device.
+    pub slot: u8,
+    pub port: u8,
+    pub speed: Speed,
+    pub descriptor: DeviceDescriptor,
+}
+
+impl fmt::Display for UsbDevice {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let d = &self.descriptor;
+        write!(f, "port {} slot {}: {:04x}:{:04x} class {:02x}.{:02x}.{:02x} usb {:x}.{:02x} {:?} speed",
+            self.port, self.slot, d.vendor_id, d.product_id, d.class, d.subclass, d.protocol,
+            d.usb_version >> 8, d.usb_version & 0xff, self.speed)
+    }
+}
+
+/// All `MAX_DEVICES` slots are in use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TooManyDevices;
+
+// read by the class drivers, written only when a device is enumerated.
+static DEVICES: RcuCell<[Option<UsbDevice>; MAX_DEVICES]> = RcuCell::new();
+
+/// Records an enumerated device, called by the host controller drivers in task context.
+pub fn add_device(device: UsbDevice) -> Result<(), TooManyDevices> {
+    DEVICES.try_update(|old| {
+        let mut devices = old.copied().unwrap_or([None; MAX_DEVICES]);
+        let slot = devices.iter_mut().find(|slot| slot.is_none()).ok_or(TooManyDevices)?;
+        *slot = Some(device);
+        Ok(devices)
+    })
+}
+
+/// Calls `f` for every enumerated device, `f` must not add devices.
+pub fn for_each_device(mut f: impl FnMut(&UsbDevice)) {
+    DEVICES.read(|devices| devices.into_iter().flatten().flatten().for_each(&mut f));
+}
+
+#[test_case]
+fn test_parse_device_descriptor() {
+    // QEMU's usb-kbd.
+    let bytes = [18, 1, 0x00, 0x02, 0, 0, 0, 64, 0x27, 0x06, 0x01, 0x00, 0, 0, 1, 4, 11, 1];
+    let descriptor = DeviceDescriptor::parse(&bytes).unwrap();
+    assert_eq!(descriptor.usb_version, 0x0200);
+    assert_eq!(descriptor.vendor_id, 0x0627);
+    assert_eq!(descriptor.product_id, 0x0001);
+    assert_eq!(descriptor.max_packet_size, 64);
+    assert_eq!(descriptor.configurations, 1);
+    assert_eq!(SetupPacket::get_descriptor(DESCRIPTOR_DEVICE, 0, 18).to_u64(), 0x0012_0000_0100_0680);
+    assert_eq!(Speed::Full.max_packet_size(descriptor.max_packet_size), 64);
+    assert_eq!(Speed::Super.max_packet_size(9), 512);
+}
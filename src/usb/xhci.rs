@@ -0,0 +1,764 @@
+// xHCI host controller driver.
+//
+// The controller is programmed through memory mapped registers behind BAR 0, which we map
+// at a fixed virtual address like the local APIC. Everything else is exchanged through
+// rings of 16 byte transfer request blocks (TRBs) in memory: the driver queues commands on
+// the command ring and transfers on a transfer ring per endpoint, and rings a doorbell to
+// make the controller look at them. The controller reports completions and port changes on
+// the event ring. A cycle bit in every TRB tells its producer's current lap around the
+// ring, so the consumer knows where the valid entries end without a shared index.
+//
+// Every device gets a slot with a device context, in which the controller keeps the state
+// of the device and its endpoints. The driver hands in the wanted state as an input context
+// with the Address Device, Evaluate Context and Configure Endpoint commands. The maximum
+// packet size of the control endpoint of a full speed device is only known from the first
+// 8 bytes of its device descriptor, which are read with the minimum packet size first.
+//
+// The driver polls the event ring instead of taking interrupts. It enumerates the devices on
+// the root hub ports at boot and keeps the controller for the class drivers. Those can set
+// up one interrupt IN endpoint per device and poll it: a transfer is kept queued on the
+// endpoint, and its completion is recorded whenever the event ring is read, also while
+// waiting for something else.
+
+use core::{
+    hint::spin_loop,
+    ptr::{read_volatile, write_volatile},
+};
+use x86_64::{
+    instructions::interrupts,
+    structures::paging::{
+        mapper::MapToError, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+use crate::{
+    memory::{self, BootFrameAllocator},
+    pci::{self, Bar},
+    println,
+    sync::Mutex,
+};
+use super::{DeviceDescriptor, EndpointDescriptor, SetupPacket, Speed, UsbDevice, DESCRIPTOR_DEVICE};
+
+/// Where the controller registers are mapped.
+pub const XHCI_START: u64 = 0x4444_6666_0000;
+// the registers we use fit in 64 KiB on every controller we know.
+const MMIO_SIZE: u64 = 0x10000;
+
+// PCI class serial bus, subclass USB, programming interface xHCI.
+const CLASS_SERIAL_BUS: u8 = 0x0c;
+const SUBCLASS_USB: u8 = 0x03;
+const PROG_IF_XHCI: u8 = 0x30;
+
+// capability registers.
+const CAP_LENGTH: usize = 0x00;
+const CAP_HCSPARAMS1: usize = 0x04;
+const CAP_HCSPARAMS2: usize = 0x08;
+const CAP_HCCPARAMS1: usize = 0x10;
+const CAP_DBOFF: usize = 0x14;
+const CAP_RTSOFF: usize = 0x18;
+// HCCPARAMS1: contexts are 64 instead of 32 bytes.
+const HCC_CONTEXT_64: u32 = 1 << 2;
+
+// operational registers, relative to CAP_LENGTH.
+const OP_USBCMD: usize = 0x00;
+const OP_USBSTS: usize = 0x04;
+const OP_CRCR: usize = 0x18;
+const OP_DCBAAP: usize = 0x30;
+const OP_CONFIG: usize = 0x38;
+const OP_PORTSC: usize = 0x400;
+const USBCMD_RUN: u32 = 1 << 0;
+const USBCMD_RESET: u32 = 1 << 1;
+const USBSTS_HALTED: u32 = 1 << 0;
+const USBSTS_NOT_READY: u32 = 1 << 11;
+
+const PORTSC_CONNECTED: u32 = 1 << 0;
+const PORTSC_ENABLED: u32 = 1 << 1;
+const PORTSC_RESET: u32 = 1 << 4;
+const PORTSC_POWER: u32 = 1 << 9;
+const PORTSC_RESET_CHANGE: u32 = 1 << 21;
+// the change bits are cleared by writing ones, and writing one to PED disables the port,
+// so a read-modify-write has to mask them.
+const PORTSC_WRITE_MASK: u32 = !(PORTSC_ENABLED | 0x7f << 17);
+
+// interrupter 0 in the runtime registers.
+const RT_IR0: usize = 0x20;
+const IR_ERSTSZ: usize = 0x08;
+const IR_ERSTBA: usize = 0x10;
+const IR_ERDP: usize = 0x18;
+// ERDP: event handler busy, cleared by writing one.
+const ERDP_BUSY: u64 = 1 << 3;
+
+const TRB_NORMAL: u32 = 1;
+const TRB_SETUP: u32 = 2;
+const TRB_DATA: u32 = 3;
+const TRB_STATUS: u32 = 4;
+const TRB_LINK: u32 = 6;
+const TRB_ENABLE_SLOT: u32 = 9;
+const TRB_ADDRESS_DEVICE: u32 = 11;
+const TRB_CONFIGURE_ENDPOINT: u32 = 12;
+const TRB_EVALUATE_CONTEXT: u32 = 13;
+const TRB_TRANSFER_EVENT: u32 = 32;
+const TRB_COMMAND_COMPLETION: u32 = 33;
+
+const TRB_CYCLE: u32 = 1 << 0;
+// link TRBs: toggle the cycle state when following the link.
+const TRB_TOGGLE_CYCLE: u32 = 1 << 1;
+const TRB_INTERRUPT_ON_SHORT_PACKET: u32 = 1 << 2;
+const TRB_INTERRUPT_ON_COMPLETION: u32 = 1 << 5;
+const TRB_IMMEDIATE_DATA: u32 = 1 << 6;
+// setup TRBs: an IN data stage follows.
+const TRB_TRANSFER_TYPE_IN: u32 = 3 << 16;
+// data and status TRBs: the direction is device to host.
+const TRB_DIRECTION_IN: u32 = 1 << 16;
+
+const COMPLETION_SUCCESS: u8 = 1;
+const COMPLETION_SHORT_PACKET: u8 = 13;
+
+// one page of TRBs per ring, the last one links back to the start.
+const RING_SIZE: usize = 4096 / 16;
+const MAX_SLOTS: u8 = 16;
+// how often to poll for a register change or an event before giving up.
+const MAX_POLLS: usize = 10_000_000;
+
+// endpoint types in the endpoint context.
+const EP_TYPE_CONTROL: u32 = 4;
+const EP_TYPE_INTERRUPT_IN: u32 = 7;
+
+#[derive(Debug)]
+pub enum XhciError {
+    NotFound,
+    /// BAR 0 is not a memory BAR.
+    UnexpectedBar,
+    Map(MapToError<Size4KiB>),
+    OutOfMemory,
+    /// The controller didn't react in time.
+    Timeout,
+    /// A command or transfer failed with the completion code.
+    Failed(u8),
+    /// A device didn't return a valid device descriptor.
+    InvalidDescriptor,
+    /// `init` found no controller, or the slot or endpoint is not set up.
+    NotInitialized,
+}
+
+#[derive(Clone, Copy, Default)]
+#[repr(C, align(16))]
+struct Trb {
+    parameter: u64,
+    status: u32,
+    control: u32,
+}
+
+impl Trb {
+    fn trb_type(&self) -> u32 {
+        (self.control >> 10) & 0x3f
+    }
+
+    fn completion_code(&self) -> u8 {
+        (self.status >> 24) as u8
+    }
+
+    fn slot(&self) -> u8 {
+        (self.control >> 24) as u8
+    }
+
+    // transfer events: the bytes of the transfer that were not transferred.
+    fn residual_length(&self) -> usize {
+        (self.status & 0xff_ffff) as usize
+    }
+}
+
+// A ring the driver produces TRBs on.
+struct Ring {
+    phys: PhysAddr,
+    trbs: *mut Trb,
+    enqueue: usize,
+    cycle: u32,
+}
+
+impl Ring {
+    fn new(frame_allocator: &mut BootFrameAllocator) -> Result<Ring, XhciError> {
+        let (phys, trbs) = allocate_page(frame_allocator)?;
+        let trbs = trbs as *mut Trb;
+        // the last TRB links back to the first one.
+        let link = Trb { parameter: phys.as_u64(), status: 0, control: TRB_LINK << 10 | TRB_TOGGLE_CYCLE };
+        unsafe { write_volatile(trbs.add(RING_SIZE - 1), link) };
+        Ok(Ring { phys, trbs, enqueue: 0, cycle: TRB_CYCLE })
+    }
+
+    // Queues a TRB, returns its physical address.
+    fn push(&mut self, mut trb: Trb) -> PhysAddr {
+        trb.control = (trb.control & !TRB_CYCLE) | self.cycle;
+        let address = self.phys + (self.enqueue * 16) as u64;
+        unsafe { write_volatile(self.trbs.add(self.enqueue), trb) };
+        self.enqueue += 1;
+        if self.enqueue == RING_SIZE - 1 {
+            // hand the link TRB to the controller and start the next lap.
+            unsafe {
+                let link = self.trbs.add(RING_SIZE - 1);
+                let control = read_volatile(&(*link).control);
+                write_volatile(&mut (*link).control, (control & !TRB_CYCLE) | self.cycle);
+            }
+            self.enqueue = 0;
+            self.cycle ^= TRB_CYCLE;
+        }
+        address
+    }
+}
+
+// The ring the controller produces events on.
+struct EventRing {
+    phys: PhysAddr,
+    trbs: *const Trb,
+    dequeue: usize,
+    cycle: u32,
+}
+
+struct Slot {
+    port: u8,
+    speed: Speed,
+    control: Ring,
+    // a page for the data stage of control transfers.
+    data: (PhysAddr, *mut u8),
+    interrupt_in: Option<InterruptEndpoint>,
+}
+
+struct InterruptEndpoint {
+    // the device context index, also the doorbell target.
+    index: u8,
+    ring: Ring,
+    buffer: (PhysAddr, *mut u8),
+    max_packet_size: u16,
+    // the transfer queued on the endpoint and, once it's done, its result.
+    pending: Option<PhysAddr>,
+    completed: Option<Result<usize, u8>>,
+}
+
+struct Controller {
+    operational: u64,
+    runtime: u64,
+    doorbells: u64,
+    ports: u8,
+    context_size: usize,
+    commands: Ring,
+    events: EventRing,
+    device_contexts: *mut u64,
+    slots: [Option<Slot>; MAX_SLOTS as usize + 1],
+}
+
+// the raw pointers point to memory only the controller and this driver use.
+unsafe impl Send for Controller {}
+
+static CONTROLLER: Mutex<Option<Controller>> = Mutex::new(None);
+
+fn allocate_page(frame_allocator: &mut BootFrameAllocator) -> Result<(PhysAddr, *mut u8), XhciError> {
+    let frame = frame_allocator.allocate_contiguous(1).ok_or(XhciError::OutOfMemory)?;
+    let virt: *mut u8 = memory::phys_to_virt(frame.start_address()).as_mut_ptr();
+    unsafe { virt.write_bytes(0, 4096) };
+    Ok((frame.start_address(), virt))
+}
+
+// the port speed IDs of the slot context.
+fn speed_id(speed: Speed) -> u32 {
+    match speed {
+        Speed::Full => 1,
+        Speed::Low => 2,
+        Speed::High => 3,
+        Speed::Super => 4,
+        Speed::SuperPlus => 5,
+        Speed::Unknown(id) => u32::from(id),
+    }
+}
+
+fn poll(mut condition: impl FnMut() -> bool) -> Result<(), XhciError> {
+    for _ in 0..MAX_POLLS {
+        if condition() {
+            return Ok(());
+        }
+        spin_loop();
+    }
+    Err(XhciError::Timeout)
+}
+
+/// Sets up the first xHCI controller and enumerates the devices on its root hub.
+pub fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut BootFrameAllocator,
+) -> Result<(), XhciError> {
+    let mut found = None;
+    pci::for_each_device(|device| {
+        if found.is_none()
+            && (device.class, device.subclass, device.prog_if) == (CLASS_SERIAL_BUS, SUBCLASS_USB, PROG_IF_XHCI)
+        {
+            found = Some(device);
+        }
+    });
+    let device = found.ok_or(XhciError::NotFound)?;
+    let Some(Bar::Memory(bar)) = device.bar(0) else {
+        return Err(XhciError::UnexpectedBar);
+    };
+    device.enable();
+
+    // the registers must not be cached, every access has to reach the controller.
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+    for offset in (0..MMIO_SIZE).step_by(4096) {
+        let page = Page::containing_address(VirtAddr::new(XHCI_START + offset));
+        let frame = PhysFrame::containing_address(PhysAddr::new(bar + offset));
+        unsafe { mapper.map_to(page, frame, flags, frame_allocator).map_err(XhciError::Map)?.flush() };
+    }
+
+    let mut controller = Controller::reset(XHCI_START, frame_allocator)?;
+    for port in 1..=controller.ports {
+        match controller.enumerate_port(port, frame_allocator) {
+            Ok(Some(device)) => {
+                println!("usb: {}", device);
+                if super::add_device(device).is_err() {
+                    println!("usb: too many devices, ignoring the one at port {}", port);
+                }
+            }
+            Ok(None) => {}
+            Err(err) => println!("usb: port {} failed: {:?}", port, err),
+        }
+    }
+    interrupts::without_interrupts(|| *CONTROLLER.lock() = Some(controller));
+    Ok(())
+}
+
+impl Controller {
+    fn reset(base: u64, frame_allocator: &mut BootFrameAllocator) -> Result<Controller, XhciError> {
+        let read_cap = |offset: usize| unsafe { read_volatile((base + offset as u64) as *const u32) };
+        let cap_length = read_cap(CAP_LENGTH) & 0xff;
+        let params1 = read_cap(CAP_HCSPARAMS1);
+        let params2 = read_cap(CAP_HCSPARAMS2);
+        let context_size = if read_cap(CAP_HCCPARAMS1) & HCC_CONTEXT_64 != 0 { 64 } else { 32 };
+        let max_slots = (params1 & 0xff).min(u32::from(MAX_SLOTS));
+
+        let (commands, events_page, dcbaa) = (
+            Ring::new(frame_allocator)?,
+            allocate_page(frame_allocator)?,
+            allocate_page(frame_allocator)?,
+        );
+        let controller = Controller {
+            operational: base + u64::from(cap_length),
+            runtime: base + u64::from(read_cap(CAP_RTSOFF) & !0x1f),
+            doorbells: base + u64::from(read_cap(CAP_DBOFF) & !0x3),
+            ports: (params1 >> 24) as u8,
+            context_size,
+            commands,
+            events: EventRing { phys: events_page.0, trbs: events_page.1 as *const Trb, dequeue: 0, cycle: TRB_CYCLE },
+            device_contexts: dcbaa.1 as *mut u64,
+            slots: [const { None }; MAX_SLOTS as usize + 1],
+        };
+
+        // stop and reset the controller, the firmware may have used it.
+        let command = controller.read_op(OP_USBCMD);
+        controller.write_op(OP_USBCMD, command & !USBCMD_RUN);
+        poll(|| controller.read_op(OP_USBSTS) & USBSTS_HALTED != 0)?;
+        controller.write_op(OP_USBCMD, USBCMD_RESET);
+        poll(|| controller.read_op(OP_USBCMD) & USBCMD_RESET == 0)?;
+        poll(|| controller.read_op(OP_USBSTS) & USBSTS_NOT_READY == 0)?;
+
+        controller.write_op(OP_CONFIG, max_slots);
+        // the controller may want scratchpad pages for itself, their array goes into entry 0.
+        let scratchpads = ((params2 >> 21) & 0x1f) << 5 | (params2 >> 27) & 0x1f;
+        if scratchpads > 0 {
+            let (array_phys, array) = allocate_page(frame_allocator)?;
+            for i in 0..scratchpads as usize {
+                let (page, _) = allocate_page(frame_allocator)?;
+                unsafe { write_volatile((array as *mut u64).add(i), page.as_u64()) };
+            }
+            unsafe { write_volatile(controller.device_contexts, array_phys.as_u64()) };
+        }
+        controller.write_op64(OP_DCBAAP, dcbaa.0.as_u64());
+        controller.write_op64(OP_CRCR, controller.commands.phys.as_u64() | u64::from(TRB_CYCLE));
+
+        // a single event ring segment, described by a one entry segment table.
+        let (erst_phys, erst) = allocate_page(frame_allocator)?;
+        unsafe {
+            write_volatile(erst as *mut u64, controller.events.phys.as_u64());
+            write_volatile(erst.add(8) as *mut u32, RING_SIZE as u32);
+        }
+        controller.write_runtime(RT_IR0 + IR_ERSTSZ, 1);
+        controller.write_runtime64(RT_IR0 + IR_ERDP, controller.events.phys.as_u64());
+        controller.write_runtime64(RT_IR0 + IR_ERSTBA, erst_phys.as_u64());
+
+        controller.write_op(OP_USBCMD, USBCMD_RUN);
+        poll(|| controller.read_op(OP_USBSTS) & USBSTS_HALTED == 0)?;
+        Ok(controller)
+    }
+
+    fn read_op(&self, offset: usize) -> u32 {
+        unsafe { read_volatile((self.operational + offset as u64) as *const u32) }
+    }
+
+    fn write_op(&self, offset: usize, value: u32) {
+        unsafe { write_volatile((self.operational + offset as u64) as *mut u32, value) }
+    }
+
+    // 64 bit registers may be written as two halves, the low half first.
+    fn write_op64(&self, offset: usize, value: u64) {
+        self.write_op(offset, value as u32);
+        self.write_op(offset + 4, (value >> 32) as u32);
+    }
+
+    fn write_runtime(&self, offset: usize, value: u32) {
+        unsafe { write_volatile((self.runtime + offset as u64) as *mut u32, value) }
+    }
+
+    fn write_runtime64(&self, offset: usize, value: u64) {
+        self.write_runtime(offset, value as u32);
+        self.write_runtime(offset + 4, (value >> 32) as u32);
+    }
+
+    fn ring_doorbell(&self, slot: u8, target: u32) {
+        unsafe { write_volatile((self.doorbells + 4 * u64::from(slot)) as *mut u32, target) }
+    }
+
+    fn portsc(&self, port: u8) -> usize {
+        OP_PORTSC + 0x10 * usize::from(port - 1)
+    }
+
+    // Takes the next event, if the controller produced one.
+    fn next_event(&mut self) -> Option<Trb> {
+        let trb = unsafe { read_volatile(self.events.trbs.add(self.events.dequeue)) };
+        if trb.control & TRB_CYCLE != self.events.cycle {
+            return None;
+        }
+        self.events.dequeue += 1;
+        if self.events.dequeue == RING_SIZE {
+            self.events.dequeue = 0;
+            self.events.cycle ^= TRB_CYCLE;
+        }
+        let dequeue = self.events.phys + (self.events.dequeue * 16) as u64;
+        self.write_runtime64(RT_IR0 + IR_ERDP, dequeue.as_u64() | ERDP_BUSY);
+        Some(trb)
+    }
+
+    // Waits for the event reporting the completion of the TRB at `trb`.
+    fn wait_for_completion(&mut self, event_type: u32, trb: PhysAddr) -> Result<Trb, XhciError> {
+        for _ in 0..MAX_POLLS {
+            match self.next_event() {
+                // port status changes and stale completions are not interesting here.
+                Some(event) if event.trb_type() == event_type && event.parameter == trb.as_u64() => {
+                    return match event.completion_code() {
+                        COMPLETION_SUCCESS | COMPLETION_SHORT_PACKET => Ok(event),
+                        code => Err(XhciError::Failed(code)),
+                    };
+                }
+                Some(event) => self.record_transfer(event),
+                None => spin_loop(),
+            }
+        }
+        Err(XhciError::Timeout)
+    }
+
+    // Records the completion of a transfer on an interrupt endpoint.
+    fn record_transfer(&mut self, event: Trb) {
+        if event.trb_type() != TRB_TRANSFER_EVENT {
+            return;
+        }
+        let endpoint = self.slots
+            .get_mut(usize::from(event.slot()))
+            .and_then(|slot| slot.as_mut())
+            .and_then(|slot| slot.interrupt_in.as_mut());
+        if let Some(endpoint) = endpoint.filter(|endpoint| endpoint.pending == Some(PhysAddr::new(event.parameter))) {
+            endpoint.pending = None;
+            let requested = usize::from(endpoint.max_packet_size);
+            endpoint.completed = Some(match event.completion_code() {
+                COMPLETION_SUCCESS | COMPLETION_SHORT_PACKET => {
+                    Ok(requested.saturating_sub(event.residual_length()))
+                }
+                code => Err(code),
+            });
+        }
+    }
+
+    fn command(&mut self, trb: Trb) -> Result<Trb, XhciError> {
+        let address = self.commands.push(trb);
+        self.ring_doorbell(0, 0);
+        self.wait_for_completion(TRB_COMMAND_COMPLETION, address)
+    }
+
+    // Resets the port and addresses the device connected to it, returns `None` for empty ports.
+    fn enumerate_port(
+        &mut self,
+        port: u8,
+        frame_allocator: &mut BootFrameAllocator,
+    ) -> Result<Option<UsbDevice>, XhciError> {
+        let portsc = self.portsc(port);
+        let status = self.read_op(portsc);
+        if status & PORTSC_POWER == 0 {
+            self.write_op(portsc, (status & PORTSC_WRITE_MASK) | PORTSC_POWER);
+        }
+        if self.read_op(portsc) & PORTSC_CONNECTED == 0 {
+            return Ok(None);
+        }
+        // USB 3 ports enable themselves, USB 2 ports after a reset.
+        if self.read_op(portsc) & PORTSC_ENABLED == 0 {
+            let status = self.read_op(portsc);
+            self.write_op(portsc, (status & PORTSC_WRITE_MASK) | PORTSC_RESET);
+            poll(|| self.read_op(portsc) & PORTSC_RESET_CHANGE != 0)?;
+            let status = self.read_op(portsc);
+            self.write_op(portsc, (status & PORTSC_WRITE_MASK) | PORTSC_RESET_CHANGE);
+            poll(|| self.read_op(portsc) & PORTSC_ENABLED != 0)?;
+        }
+        let speed = match (self.read_op(portsc) >> 10) & 0xf {
+            1 => Speed::Full,
+            2 => Speed::Low,
+            3 => Speed::High,
+            4 => Speed::Super,
+            5 => Speed::SuperPlus,
+            other => Speed::Unknown(other as u8),
+        };
+
+        let event = self.command(Trb { control: TRB_ENABLE_SLOT << 10, ..Trb::default() })?;
+        let slot = event.slot();
+        if slot == 0 || slot > MAX_SLOTS {
+            return Err(XhciError::Failed(0));
+        }
+        self.address_device(slot, port, speed, frame_allocator)?;
+
+        // the first 8 bytes of the descriptor fit into the smallest packet size.
+        let mut head = [0; 8];
+        let setup = SetupPacket::get_descriptor(DESCRIPTOR_DEVICE, 0, head.len() as u16);
+        self.control_in(slot, setup, &mut head)?;
+        if head[1] != DESCRIPTOR_DEVICE || head[7] == 0 {
+            return Err(XhciError::InvalidDescriptor);
+        }
+        let max_packet_size = speed.max_packet_size(head[7]);
+        if max_packet_size != speed.default_max_packet_size() {
+            self.set_control_max_packet_size(slot, max_packet_size, frame_allocator)?;
+        }
+
+        let mut bytes = [0; DeviceDescriptor::LENGTH];
+        let setup = SetupPacket::get_descriptor(DESCRIPTOR_DEVICE, 0, bytes.len() as u16);
+        self.control_in(slot, setup, &mut bytes)?;
+        let descriptor = DeviceDescriptor::parse(&bytes).ok_or(XhciError::InvalidDescriptor)?;
+        Ok(Some(UsbDevice { slot, port, speed, descriptor }))
+    }
+
+    fn context(&self, base: *mut u8, index: usize) -> *mut u32 {
+        unsafe { base.add(index * self.context_size) as *mut u32 }
+    }
+
+    fn address_device(
+        &mut self,
+        slot: u8,
+        port: u8,
+        speed: Speed,
+        frame_allocator: &mut BootFrameAllocator,
+    ) -> Result<(), XhciError> {
+        let (device_context, _) = allocate_page(frame_allocator)?;
+        let input_context = allocate_page(frame_allocator)?;
+        let control = Ring::new(frame_allocator)?;
+        unsafe { write_volatile(self.device_contexts.add(usize::from(slot)), device_context.as_u64()) };
+
+        let (input_phys, input) = input_context;
+        unsafe {
+            // input control context: add the slot context and endpoint 0.
+            write_volatile(self.context(input, 0).add(1), 0b11);
+            // slot context: speed, one context entry, the root hub port.
+            let slot_context = self.context(input, 1);
+            write_volatile(slot_context, speed_id(speed) << 20 | 1 << 27);
+            write_volatile(slot_context.add(1), u32::from(port) << 16);
+            // endpoint 0: control, 3 retries, the default packet size.
+            let endpoint = self.context(input, 2);
+            let max_packet_size = u32::from(speed.default_max_packet_size());
+            write_volatile(endpoint.add(1), 3 << 1 | EP_TYPE_CONTROL << 3 | max_packet_size << 16);
+            write_volatile(endpoint.add(2), control.phys.as_u64() as u32 | TRB_CYCLE);
+            write_volatile(endpoint.add(3), (control.phys.as_u64() >> 32) as u32);
+            // the average TRB length, 8 for control endpoints.
+            write_volatile(endpoint.add(4), 8);
+        }
+
+        self.command(Trb {
+            parameter: input_phys.as_u64(),
+            control: TRB_ADDRESS_DEVICE << 10 | u32::from(slot) << 24,
+            ..Trb::default()
+        })?;
+        let data = allocate_page(frame_allocator)?;
+        self.slots[usize::from(slot)] = Some(Slot { port, speed, control, data, interrupt_in: None });
+        Ok(())
+    }
+
+    // Tells the controller the real maximum packet size of the control endpoint.
+    fn set_control_max_packet_size(
+        &mut self,
+        slot: u8,
+        max_packet_size: u16,
+        frame_allocator: &mut BootFrameAllocator,
+    ) -> Result<(), XhciError> {
+        let (input_phys, input) = allocate_page(frame_allocator)?;
+        unsafe {
+            // input control context: evaluate endpoint 0, only its maximum packet size is.
+            write_volatile(self.context(input, 0).add(1), 0b10);
+            let endpoint = self.context(input, 2);
+            write_volatile(endpoint.add(1), 3 << 1 | EP_TYPE_CONTROL << 3 | u32::from(max_packet_size) << 16);
+        }
+        self.command(Trb {
+            parameter: input_phys.as_u64(),
+            control: TRB_EVALUATE_CONTEXT << 10 | u32::from(slot) << 24,
+            ..Trb::default()
+        })
+        .map(|_| ())
+    }
+
+    fn configure_interrupt_in(
+        &mut self,
+        slot: u8,
+        endpoint: EndpointDescriptor,
+        frame_allocator: &mut BootFrameAllocator,
+    ) -> Result<(), XhciError> {
+        let (port, speed) = match &self.slots[usize::from(slot)] {
+            Some(slot) if slot.interrupt_in.is_none() => (slot.port, slot.speed),
+            _ => return Err(XhciError::NotInitialized),
+        };
+        let index = 2 * (endpoint.address & 0xf) + 1;
+        let ring = Ring::new(frame_allocator)?;
+        let buffer = allocate_page(frame_allocator)?;
+        let (input_phys, input) = allocate_page(frame_allocator)?;
+
+        // the interval is 2^n * 125 us, full and low speed devices give it in ms, the
+        // faster ones as n + 1.
+        let interval = match speed {
+            Speed::Low | Speed::Full => (u32::from(endpoint.interval.max(1)) * 8).ilog2().clamp(3, 10),
+            _ => u32::from(endpoint.interval.clamp(1, 16)) - 1,
+        };
+        let max_packet_size = u32::from(endpoint.max_packet_size);
+        unsafe {
+            // input control context: add the endpoint, and the slot context for its entry count.
+            write_volatile(self.context(input, 0).add(1), 1 | 1 << index);
+            let slot_context = self.context(input, 1);
+            write_volatile(slot_context, speed_id(speed) << 20 | u32::from(index) << 27);
+            write_volatile(slot_context.add(1), u32::from(port) << 16);
+            let context = self.context(input, 1 + usize::from(index));
+            write_volatile(context, interval << 16);
+            write_volatile(context.add(1), 3 << 1 | EP_TYPE_INTERRUPT_IN << 3 | max_packet_size << 16);
+            write_volatile(context.add(2), ring.phys.as_u64() as u32 | TRB_CYCLE);
+            write_volatile(context.add(3), (ring.phys.as_u64() >> 32) as u32);
+            // average TRB length and the payload per service interval, both one packet.
+            write_volatile(context.add(4), max_packet_size | max_packet_size << 16);
+        }
+        self.command(Trb {
+            parameter: input_phys.as_u64(),
+            control: TRB_CONFIGURE_ENDPOINT << 10 | u32::from(slot) << 24,
+            ..Trb::default()
+        })?;
+
+        let endpoint = InterruptEndpoint {
+            index,
+            ring,
+            buffer,
+            max_packet_size: endpoint.max_packet_size.clamp(1, 4096),
+            pending: None,
+            completed: None,
+        };
+        if let Some(slot) = self.slots[usize::from(slot)].as_mut() {
+            slot.interrupt_in = Some(endpoint);
+        }
+        Ok(())
+    }
+
+    fn poll_interrupt_in(&mut self, slot: u8, buffer: &mut [u8]) -> Result<Option<usize>, XhciError> {
+        while let Some(event) = self.next_event() {
+            self.record_transfer(event);
+        }
+        let endpoint = self.slots[usize::from(slot)]
+            .as_mut()
+            .and_then(|slot| slot.interrupt_in.as_mut())
+            .ok_or(XhciError::NotInitialized)?;
+
+        let received = match endpoint.completed.take() {
+            Some(Ok(len)) => {
+                let len = len.min(buffer.len());
+                unsafe { core::ptr::copy_nonoverlapping(endpoint.buffer.1, buffer.as_mut_ptr(), len) };
+                Some(Ok(len))
+            }
+            Some(Err(code)) => Some(Err(XhciError::Failed(code))),
+            None => None,
+        };
+        if endpoint.pending.is_none() {
+            endpoint.pending = Some(endpoint.ring.push(Trb {
+                parameter: endpoint.buffer.0.as_u64(),
+                status: u32::from(endpoint.max_packet_size),
+                control: TRB_NORMAL << 10 | TRB_INTERRUPT_ON_SHORT_PACKET | TRB_INTERRUPT_ON_COMPLETION,
+            }));
+            let index = endpoint.index;
+            self.ring_doorbell(slot, u32::from(index));
+        }
+        received.transpose()
+    }
+
+    // Runs a control transfer with an IN data stage into `buffer`, returns how much of it was
+    // filled. Short packets are not told apart, the rest of the buffer is left over from the
+    // previous transfer.
+    fn control_in(
+        &mut self,
+        slot: u8,
+        setup: SetupPacket,
+        buffer: &mut [u8],
+    ) -> Result<usize, XhciError> {
+        let len = buffer.len().min(4096);
+        let Slot { control: ring, data: (data_phys, data), .. } =
+            self.slots[usize::from(slot)].as_mut().ok_or(XhciError::NotInitialized)?;
+        let (data_phys, data) = (*data_phys, *data);
+        ring.push(Trb {
+            parameter: setup.to_u64(),
+            status: 8,
+            control: TRB_SETUP << 10 | TRB_IMMEDIATE_DATA | TRB_TRANSFER_TYPE_IN,
+        });
+        ring.push(Trb {
+            parameter: data_phys.as_u64(),
+            status: len as u32,
+            control: TRB_DATA << 10 | TRB_DIRECTION_IN,
+        });
+        let status = ring.push(Trb { control: TRB_STATUS << 10 | TRB_INTERRUPT_ON_COMPLETION, ..Trb::default() });
+        self.ring_doorbell(slot, 1);
+        self.wait_for_completion(TRB_TRANSFER_EVENT, status)?;
+
+        unsafe { core::ptr::copy_nonoverlapping(data, buffer.as_mut_ptr(), len) };
+        Ok(len)
+    }
+
+    // Runs a control transfer without a data stage.
+    fn control_out(&mut self, slot: u8, setup: SetupPacket) -> Result<(), XhciError> {
+        let ring = &mut self.slots[usize::from(slot)].as_mut().ok_or(XhciError::NotInitialized)?.control;
+        ring.push(Trb { parameter: setup.to_u64(), status: 8, control: TRB_SETUP << 10 | TRB_IMMEDIATE_DATA });
+        let status = ring.push(Trb {
+            control: TRB_STATUS << 10 | TRB_DIRECTION_IN | TRB_INTERRUPT_ON_COMPLETION,
+            ..Trb::default()
+        });
+        self.ring_doorbell(slot, 1);
+        self.wait_for_completion(TRB_TRANSFER_EVENT, status).map(|_| ())
+    }
+}
+
+/// Runs a control transfer with an IN data stage on the device in `slot`.
+pub fn control_in(slot: u8, setup: SetupPacket, buffer: &mut [u8]) -> Result<usize, XhciError> {
+    with_controller(|controller| controller.control_in(slot, setup, buffer))
+}
+
+/// Runs a control transfer without a data stage on the device in `slot`.
+pub fn control_out(slot: u8, setup: SetupPacket) -> Result<(), XhciError> {
+    with_controller(|controller| controller.control_out(slot, setup))
+}
+
+/// Sets up the interrupt IN `endpoint` of the device in `slot`, in the configuration the
+/// device was just switched to.
+pub fn configure_interrupt_in(
+    slot: u8,
+    endpoint: EndpointDescriptor,
+    frame_allocator: &mut BootFrameAllocator,
+) -> Result<(), XhciError> {
+    with_controller(|controller| controller.configure_interrupt_in(slot, endpoint, frame_allocator))
+}
+
+/// Returns the length of the packet the interrupt IN endpoint of the device in `slot`
+/// received since the last call, copied into `buffer`, and queues the next transfer.
+pub fn poll_interrupt_in(slot: u8, buffer: &mut [u8]) -> Result<Option<usize>, XhciError> {
+    with_controller(|controller| controller.poll_interrupt_in(slot, buffer))
+}
+
+fn with_controller<T>(f: impl FnOnce(&mut Controller) -> Result<T, XhciError>) -> Result<T, XhciError> {
+    interrupts::without_interrupts(|| f(CONTROLLER.lock().as_mut().ok_or(XhciError::NotInitialized)?))
+}
@@ -1,50 +1,10 @@
-#[allow(dead_code)]
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-#[repr(u8)]
-pub enum Colors {
-    // Because of the repr(u8) attribute each enum variant is stored as an u8
-    Black = 0,
-    Blue = 1,
-    Green = 2,
-    Cyan = 3,
-    Red = 4,
-    Magenta = 5,
-    Brown = 6,
-    LightGray = 7,
-    DarkGray = 8,
-    LightBlue = 9,
-    LightGreen = 10,
-    LightCyan = 11,
-    LightRed = 12,
-    Pink = 13,
-    Yellow = 14,
-    White = 15,
-}
-
-// To ensure that the ColorCode has the exact same data layout as
-// an u8, we use the repr(transparent) attribute.
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-#[repr(transparent)]
-struct ColorCode(u8);
+// The VGA text console, the Writer of the `vga_text` crate drawing into the VGA text buffer.
 
-impl ColorCode {
-    fn new(foreground: Colors, background: Colors) -> ColorCode {
-        ColorCode(( background as u8 ) << 4 | (foreground as u8))
-    }
-}
+pub use vga_text::{ColorCode, Colors, ScreenChar, TextBuffer, BUFFER_HEIGHT, BUFFER_WIDTH};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-#[repr(C)]
-struct ScreenChar {
-    ascii_character: u8,
-    color_code: ColorCode
-}
-
-const BUFFER_HEIGHT: usize = 25;
-const BUFFER_WIDTH: usize = 80;
-
-use core::{ fmt, fmt::Write };
+use core::fmt;
 use volatile::Volatile;
+use x86_64::{instructions::{interrupts, port::Port}, VirtAddr};
 
 // Since the field ordering in default structs is undefined in Rust,
 // we need the repr(C) attribute. It guarantees that the struct’s
@@ -58,134 +18,106 @@ struct Buffer {
     chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
 }
 
-// To actually write to screen, we now create a writer type:
-// The writer will always write to the last line and shift lines up when
-// a line is full (or on \n). The column_position field keeps track of the
-// current position in the last row. The current foreground and background
-// colors are specified by color_code and a reference to the VGA buffer is
-// stored in buffer.
-//
-// Note that we need an explicit lifetime here to tell the
-// compiler how long the reference is valid. The 'static lifetime specifies
-// that the reference is valid for the whole program run time
-// (which is true for the VGA text buffer).
-pub struct Writer {
-    column_position: usize,
-    color_code: ColorCode,
-    buffer: &'static mut Buffer,
-}
+/// The VGA text buffer, mapped somewhere in memory.
+pub struct VgaBuffer(&'static mut Buffer);
 
-impl Writer {
-    pub fn write_byte(&mut self, byte: u8) {
-        match byte {
-            b'\n' => self.new_line(),
-            byte => {
-                if self.column_position >= BUFFER_WIDTH { // we reached end of the screen
-                    self.new_line();
-                }
-
-                let row = BUFFER_HEIGHT - 1;
-                let col = self.column_position;
-
-                let color_code = self.color_code;
-                self.buffer.chars[row][col].write(ScreenChar {
-                    ascii_character: byte,
-                    color_code,
-                });
-                self.column_position += 1; // move the column position a step to the right
-            }
-        }
+impl TextBuffer for VgaBuffer {
+    fn read(&self, row: usize, col: usize) -> ScreenChar {
+        self.0.chars[row][col].read()
     }
 
-    fn new_line(&mut self) {
-        // We iterate over all screen characters and move each character one row up.
-       for row in 1..BUFFER_HEIGHT {
-           for col in 0..BUFFER_WIDTH {
-               let character = self.buffer.chars[row][col].read();
-               self.buffer.chars[row - 1][col].write(character);
-           }
-       }
-       self.clear_row(BUFFER_HEIGHT - 1);
-        self.column_position = 0;
-    }
-    // clear_row clears a row by overwriting all of its characters with a space character.
-    fn clear_row(&mut self, row: usize) {
-        let blank = ScreenChar {
-            ascii_character: b' ', // space
-            color_code: self.color_code,
-        };
-
-        for col in 0..BUFFER_WIDTH {
-            self.buffer.chars[row][col].write(blank);
-        }
+    fn write(&mut self, row: usize, col: usize, character: ScreenChar) {
+        self.0.chars[row][col].write(character);
     }
 
-    // To print whole strings, we can convert them to bytes and print them one-by-one:
-    // The VGA text buffer only supports ASCII and the additional bytes of code page 437.
-    // Rust strings are UTF-8 by default, so they might contain bytes that are not supported
-    // by the VGA text buffer. We use a match to differentiate printable ASCII bytes
-    // For unprintable bytes, we print a ■ character, which has the hex code 0xfe on the VGA hardware.
-    fn write_string(&mut self, s: &str) {
-        for byte in s.bytes() {
-            match byte {
-                // printable ASCII byte or newline
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
-                // not part of printable ASCII range
-                _ => self.write_byte(0xfe),
-            }
+    // Moves the hardware cursor through the cursor location registers of the CRT controller.
+    fn set_cursor(&mut self, row: usize, col: usize) {
+        let position = (row * BUFFER_WIDTH + col) as u16;
+        let mut index: Port<u8> = Port::new(0x3d4);
+        let mut data: Port<u8> = Port::new(0x3d5);
+        unsafe {
+            index.write(0x0f);
+            data.write(position as u8);
+            index.write(0x0e);
+            data.write((position >> 8) as u8);
         }
     }
 }
 
-impl fmt::Write for Writer {
-    fn write_str(&mut self, s: &str) -> fmt::Result {
-        self.write_string(s);
-        Ok(())
-    }
+/// Prints to the VGA text buffer, or to a MemoryBuffer of `vga_text`.
+pub type Writer<B = VgaBuffer> = vga_text::Writer<B>;
+
+use crate::sync::Mutex;
+
+// To provide a global writer that can be used as an interface from other modules
+// without carrying a Writer instance around, we create a static WRITER. It stays empty
+// until `init` gets the address of the text buffer from the boot information: machines
+// booted through UEFI only have a framebuffer, and nothing at 0xb8000 to write to.
+pub static WRITER: Mutex<Option<Writer>> = Mutex::new(None);
+
+/// Output before `init` or while the writer is in use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PrintError {
+    NotInitialized,
+    Busy,
 }
 
-use lazy_static::lazy_static;
-use spin::Mutex;
-use x86_64::instructions::interrupts;
-
-// With lazy_static, we can define our static WRITER without problems.
-lazy_static! {
-    // To provide a global writer that can be used as an interface from other modules
-    // without carrying a Writer instance around, we try to create a static WRITER.
-    // It first creates a new Writer that points to the VGA buffer at 0xb8000.
-    // The syntax for this might seem a bit strange: First, we cast the integer
-    // 0xb8000 as an mutable raw pointer. Then we convert it to a mutable
-    // reference by dereferencing it (through *) and immediately borrowing it again
-    // (through &mut). This conversion requires an unsafe block, since the compiler
-    // can’t guarantee that the raw pointer is valid.
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
-        column_position: 0,
-        color_code: ColorCode::new(Colors::White, Colors::LightBlue),
-        buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
-    });
+/// Points the writer to the text buffer mapped at `buffer`.
+///
+/// # Safety
+///
+/// `buffer` must be the virtual address of the VGA text buffer, mapped for the rest of the
+/// kernel's run and not written by anything else.
+pub unsafe fn init(buffer: VirtAddr) {
+    // We convert the raw pointer to a mutable reference by dereferencing it (through *)
+    // and immediately borrowing it again (through &mut). This requires an unsafe block,
+    // since the compiler can't guarantee that the raw pointer is valid.
+    let writer = Writer::new(VgaBuffer(&mut *buffer.as_mut_ptr::<Buffer>()));
+    interrupts::without_interrupts(|| *WRITER.lock() = Some(writer));
 }
 
-#[macro_export]
-macro_rules! print {
-    ($($arg:tt)*) => ($crate::vga_buffer::_print(format_args!($($arg)*)));
+/// Returns whether `init` was called.
+pub fn is_initialized() -> bool {
+    interrupts::without_interrupts(|| WRITER.lock().is_some())
 }
 
-#[macro_export]
-macro_rules! println {
-    () => ($crate::print!("\n"));
-    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+/// Prints to the text buffer, without waiting for the writer.
+pub fn try_print(args: fmt::Arguments) -> Result<(), PrintError> {
+    use fmt::Write;
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.try_lock().ok_or(PrintError::Busy)?;
+        let writer = writer.as_mut().ok_or(PrintError::NotInitialized)?;
+        // writes to the VGA buffer never fail, see Writer::write_str.
+        let _ = writer.write_fmt(args);
+        Ok(())
+    })
 }
 
-#[doc(hidden)]
-pub fn _print(args: fmt::Arguments) {
-    // The without_interrupts function takes a closure and executes it in an interrupt-free
-    // environment. We use it to ensure that no interrupt can occur as long as the Mutex is locked.
-    // This helps avoid a deadlock from the interrupt handler trying to acquire Writer lock.
+/// Writes the screen contents to the first serial interface, for tests and CI runs that
+/// check what is on the screen. After a `vga-dump 80x25 cursor=<row>,<col>` line, each row
+/// is a line with its 80 characters between bars, bytes outside printable ASCII as `.`,
+/// and a line with the 80 color codes as two hex digits each, both prefixed with the row
+/// number. A `vga-dump end` line closes the dump.
+pub fn dump() -> Result<(), PrintError> {
+    use crate::serial::SERIAL1;
     interrupts::without_interrupts(|| {
-        // unwrap panics if an error occurs. This isn’t a problem in our case,
-        // since writes to the VGA buffer never fails. we returned OK() in write_str.
-        WRITER.lock().write_fmt(args).unwrap();
-    });
+        let writer = WRITER.try_lock().ok_or(PrintError::Busy)?;
+        let writer = writer.as_ref().ok_or(PrintError::NotInitialized)?;
+        let mut serial = SERIAL1.try_lock().ok_or(PrintError::Busy)?;
+        // writes to the serial port never fail.
+        let _ = writer.dump_to(&mut *serial);
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+use crate::println;
+
+#[test_case]
+fn test_try_print() {
+    assert_eq!(try_print(format_args!("test_try_print output\n")), Ok(()));
+    let _writer = WRITER.lock();
+    assert_eq!(try_print(format_args!("test_try_print output\n")), Err(PrintError::Busy));
 }
 
 #[test_case]
@@ -202,6 +134,7 @@ fn test_println_many_input() {
 
 #[test_case]
 fn test_println_output() {
+    use core::fmt::Write;
     use x86_64::instructions::interrupts;
 
     let s = "Both operations are unsafe, because writing to an I/O port";
@@ -209,17 +142,14 @@ fn test_println_output() {
 
     interrupts::without_interrupts(|| {
         let mut writer = WRITER.lock();
+        // the test kernel initializes the writer from the boot information.
+        let writer = writer.as_mut().expect("writer not initialized");
         writeln!(writer, "\n{}", s).expect("writeln failed");
 
         for (i, c) in s.chars().enumerate() {
-            let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 2][i].read();
+            let screen_char = writer.buffer().read(BUFFER_HEIGHT - 2, i);
             assert_eq!(char::from(screen_char.ascii_character), c);
         }
     });
 }
 
-// TODO: Tests to be written
-// - a function that tests that no panic occurs when printing very long lines and that
-// they’re wrapped correctly.
-//- a function for testing that newlines, non-printable characters, and non-unicode
-// characters are handled correctly.
@@ -0,0 +1,129 @@
+// virtio-console output.
+//
+// Registers the first port of a virtio console as a console sink. Every descriptor of the
+// transmit queue owns a fixed slot of a DMA buffer, a string is copied into as many slots
+// as it needs and passed to the host in one go. When the host hasn't consumed enough of
+// the earlier output yet, the write reports `Busy` and the console keeps the output in the
+// deferred buffer, so a slow host throttles the kernel's output instead of losing it. A
+// string longer than all the slots together could never be passed, it is cut to what fits
+// and the last slot tells how many bytes were dropped.
+//
+// QEMU provides the device with `-device virtio-serial-pci -device virtconsole,chardev=...`.
+
+use core::fmt::{self, Write};
+use x86_64::PhysAddr;
+use crate::{
+    console::{self, Busy, Console, ConsoleId},
+    memory::{self, BootFrameAllocator},
+    sync::Mutex,
+};
+use super::{Buffer, DeviceType, VirtioDevice, VirtioError, Virtqueue};
+
+// the queues of port 0, without the multiport feature there are no others.
+const TRANSMIT_QUEUE: u16 = 1;
+const SLOT_SIZE: usize = 128;
+const FRAME_SIZE: usize = 4096;
+
+struct Driver {
+    transmit: Virtqueue,
+    buffers: PhysAddr,
+}
+
+impl Driver {
+    fn slot(&self, descriptor: u16) -> PhysAddr {
+        self.buffers + SLOT_SIZE as u64 * u64::from(descriptor)
+    }
+
+    // Copies at most SLOT_SIZE bytes into a free slot and adds it to the queue.
+    fn send(&mut self, bytes: &[u8]) -> Result<(), Busy> {
+        let descriptor = self.transmit.next_free().ok_or(Busy)?;
+        let slot = self.slot(descriptor);
+        unsafe {
+            let ptr: *mut u8 = memory::phys_to_virt(slot).as_mut_ptr();
+            ptr.copy_from_nonoverlapping(bytes.as_ptr(), bytes.len());
+        }
+        let buffer = Buffer { addr: slot, len: bytes.len() as u32, device_writes: false };
+        self.transmit.add(&[buffer]).ok_or(Busy)?;
+        Ok(())
+    }
+}
+
+// The note about dropped output, formatted on the stack.
+struct Note {
+    bytes: [u8; SLOT_SIZE],
+    len: usize,
+}
+
+impl Write for Note {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let count = s.len().min(SLOT_SIZE - self.len);
+        self.bytes[self.len..self.len + count].copy_from_slice(&s.as_bytes()[..count]);
+        self.len += count;
+        Ok(())
+    }
+}
+
+static DRIVER: Mutex<Option<Driver>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtioConsoleError {
+    Device(VirtioError),
+    /// The device is set up, but there was no free console slot for it.
+    TooManyConsoles,
+}
+
+pub struct VirtioConsole;
+
+impl Console for VirtioConsole {
+    fn write_str(&self, s: &str) -> Result<(), Busy> {
+        let mut guard = DRIVER.try_lock().ok_or(Busy)?;
+        let Some(driver) = guard.as_mut() else { return Ok(()) };
+
+        while driver.transmit.pop_used().is_some() {}
+        let mut text = s.as_bytes();
+        let mut dropped = 0;
+        let queue_bytes = usize::from(driver.transmit.size()) * SLOT_SIZE;
+        if text.len() > queue_bytes {
+            // keep the last slot for the note.
+            let kept = queue_bytes - SLOT_SIZE;
+            dropped = text.len() - kept;
+            text = &text[..kept];
+        }
+        let slots = text.chunks(SLOT_SIZE).len() + usize::from(dropped > 0);
+        if slots > usize::from(driver.transmit.free_count()) {
+            return Err(Busy);
+        }
+        for chunk in text.chunks(SLOT_SIZE) {
+            driver.send(chunk)?;
+        }
+        if dropped > 0 {
+            let mut note = Note { bytes: [0; SLOT_SIZE], len: 0 };
+            let _ = write!(note, "\n[{} bytes of output dropped]\n", dropped);
+            driver.send(&note.bytes[..note.len])?;
+        }
+        driver.transmit.notify();
+        Ok(())
+    }
+}
+
+/// Sets up the first virtio console and adds it to the console outputs.
+pub fn init(frame_allocator: &mut BootFrameAllocator) -> Result<ConsoleId, VirtioConsoleError> {
+    let device = VirtioDevice::find(DeviceType::Console).map_err(VirtioConsoleError::Device)?;
+    device.negotiate_features(0);
+    let result = device.setup_queue(TRANSMIT_QUEUE, frame_allocator).and_then(|transmit| {
+        let frames = (usize::from(transmit.size()) * SLOT_SIZE).div_ceil(FRAME_SIZE);
+        let buffers = frame_allocator.allocate_contiguous(frames).ok_or(VirtioError::OutOfMemory)?;
+        Ok(Driver { transmit, buffers: buffers.start_address() })
+    });
+    let driver = match result {
+        Ok(driver) => driver,
+        Err(err) => {
+            device.fail();
+            return Err(VirtioConsoleError::Device(err));
+        }
+    };
+    device.finish_setup();
+
+    x86_64::instructions::interrupts::without_interrupts(|| *DRIVER.lock() = Some(driver));
+    console::register(&VirtioConsole).map_err(|_| VirtioConsoleError::TooManyConsoles)
+}
@@ -0,0 +1,337 @@
+// Virtio devices over the legacy PCI transport.
+//
+// QEMU's virtio devices are transitional by default: next to the modern interface they
+// offer the legacy one, a block of registers in I/O space behind BAR 0, which is all we
+// need. Data is exchanged through virtqueues. A virtqueue is a table of descriptors, each
+// pointing to a buffer in guest memory, an available ring in which the driver passes
+// descriptors to the device, and a used ring in which the device returns them once it is
+// done. The legacy interface has the device pick the queue size and wants the three parts
+// in one physically contiguous, page aligned block.
+//
+// The drivers poll the used rings instead of taking interrupts, this is fine for the
+// simple devices we drive and works the same in every context.
+
+pub mod console;
+pub mod rng;
+
+use core::{
+    ptr::{addr_of, addr_of_mut},
+    sync::atomic::{fence, Ordering},
+};
+use x86_64::{instructions::port::Port, PhysAddr};
+use crate::{memory::{self, BootFrameAllocator}, pci::{self, Bar, PciDevice}};
+
+pub const VENDOR_ID: u16 = 0x1af4;
+// transitional devices use the device IDs 0x1000 plus the device type - 1.
+const TRANSITIONAL_DEVICE_BASE: u16 = 0x1000;
+
+// legacy register offsets from the I/O base.
+const REG_DEVICE_FEATURES: u16 = 0x00;
+const REG_GUEST_FEATURES: u16 = 0x04;
+const REG_QUEUE_ADDRESS: u16 = 0x08;
+const REG_QUEUE_SIZE: u16 = 0x0c;
+const REG_QUEUE_SELECT: u16 = 0x0e;
+const REG_QUEUE_NOTIFY: u16 = 0x10;
+const REG_DEVICE_STATUS: u16 = 0x12;
+/// The device specific configuration, without MSI-X.
+pub const REG_DEVICE_CONFIG: u16 = 0x14;
+
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FAILED: u8 = 0x80;
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+// the legacy interface assumes 4 KiB pages for the queue layout.
+const QUEUE_ALIGN: usize = 4096;
+const FRAME_SIZE: usize = 4096;
+
+/// The device types we have drivers for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u16)]
+pub enum DeviceType {
+    Console = 3,
+    Entropy = 4,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtioError {
+    /// There is no device of the requested type.
+    NotFound,
+    /// BAR 0 of the device is not in I/O space, it's a modern-only device.
+    NoLegacyInterface,
+    /// The device doesn't have the queue.
+    NoQueue(u16),
+    /// No contiguous frames for a virtqueue were left.
+    OutOfMemory,
+    /// The virtqueue frames are beyond the 32 bit page number of the legacy interface.
+    QueueAbove16TiB,
+}
+
+/// A virtio device, accessed through the legacy registers.
+pub struct VirtioDevice {
+    pub pci: PciDevice,
+    io_base: u16,
+}
+
+impl VirtioDevice {
+    /// Finds the first device of the given type, resets it and acknowledges it.
+    pub fn find(device_type: DeviceType) -> Result<VirtioDevice, VirtioError> {
+        let device_id = TRANSITIONAL_DEVICE_BASE + device_type as u16 - 1;
+        let pci = pci::find(VENDOR_ID, device_id).ok_or(VirtioError::NotFound)?;
+        let Some(Bar::Io(io_base)) = pci.bar(0) else {
+            return Err(VirtioError::NoLegacyInterface);
+        };
+        pci.enable();
+
+        let device = VirtioDevice { pci, io_base };
+        device.write_status(0);
+        device.write_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        Ok(device)
+    }
+
+    /// Accepts the features in `wanted` the device offers, returns the accepted ones.
+    pub fn negotiate_features(&self, wanted: u32) -> u32 {
+        let features = unsafe { Port::<u32>::new(self.io_base + REG_DEVICE_FEATURES).read() } & wanted;
+        unsafe { Port::new(self.io_base + REG_GUEST_FEATURES).write(features) };
+        features
+    }
+
+    /// Sets up the queue with the given index.
+    pub fn setup_queue(
+        &self,
+        index: u16,
+        frame_allocator: &mut BootFrameAllocator,
+    ) -> Result<Virtqueue, VirtioError> {
+        unsafe { Port::new(self.io_base + REG_QUEUE_SELECT).write(index) };
+        let size: u16 = unsafe { Port::new(self.io_base + REG_QUEUE_SIZE).read() };
+        if size == 0 {
+            return Err(VirtioError::NoQueue(index));
+        }
+
+        let layout = QueueLayout::new(size);
+        let allocated = frame_allocator.allocated_frames();
+        let frame = frame_allocator
+            .allocate_contiguous(layout.total.div_ceil(FRAME_SIZE))
+            .ok_or(VirtioError::OutOfMemory)?;
+        let Ok(pfn) = u32::try_from(frame.start_address().as_u64() / 4096) else {
+            // the device never saw the frames.
+            unsafe { frame_allocator.free_since(allocated) };
+            return Err(VirtioError::QueueAbove16TiB);
+        };
+        let base: *mut u8 = memory::phys_to_virt(frame.start_address()).as_mut_ptr();
+        unsafe {
+            base.write_bytes(0, layout.total);
+            Port::new(self.io_base + REG_QUEUE_ADDRESS).write(pfn);
+        }
+        Ok(Virtqueue::new(base, layout, size, index, self.io_base + REG_QUEUE_NOTIFY))
+    }
+
+    /// Tells the device that the driver is set up.
+    pub fn finish_setup(&self) {
+        self.write_status(self.read_status() | STATUS_DRIVER_OK);
+    }
+
+    /// Tells the device that the driver gave up on it.
+    pub fn fail(&self) {
+        self.write_status(self.read_status() | STATUS_FAILED);
+    }
+
+    pub fn read_config_u8(&self, offset: u16) -> u8 {
+        unsafe { Port::new(self.io_base + REG_DEVICE_CONFIG + offset).read() }
+    }
+
+    fn read_status(&self) -> u8 {
+        unsafe { Port::new(self.io_base + REG_DEVICE_STATUS).read() }
+    }
+
+    fn write_status(&self, status: u8) {
+        unsafe { Port::new(self.io_base + REG_DEVICE_STATUS).write(status) };
+    }
+}
+
+// Offsets of the parts of a legacy virtqueue.
+#[derive(Debug, Clone, Copy)]
+struct QueueLayout {
+    avail: usize,
+    used: usize,
+    total: usize,
+}
+
+impl QueueLayout {
+    fn new(size: u16) -> Self {
+        let size = usize::from(size);
+        // descriptors are 16 bytes, the rings have flags, index, the entries and an event index.
+        let avail = 16 * size;
+        let used = (avail + 6 + 2 * size).next_multiple_of(QUEUE_ALIGN);
+        let total = (used + 6 + 8 * size).next_multiple_of(QUEUE_ALIGN);
+        QueueLayout { avail, used, total }
+    }
+}
+
+#[repr(C)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+struct UsedElement {
+    id: u32,
+    len: u32,
+}
+
+/// A buffer passed to the device.
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    pub addr: PhysAddr,
+    pub len: u32,
+    /// The device writes to the buffer instead of reading it.
+    pub device_writes: bool,
+}
+
+/// A virtqueue, set up by `VirtioDevice::setup_queue`.
+pub struct Virtqueue {
+    base: *mut u8,
+    layout: QueueLayout,
+    size: u16,
+    index: u16,
+    notify_port: u16,
+    free_head: u16,
+    free_count: u16,
+    // our copy of the available index, and how far we consumed the used ring.
+    avail_index: u16,
+    last_used: u16,
+}
+
+// the queue memory is only accessed through the Virtqueue, which is owned by its driver.
+unsafe impl Send for Virtqueue {}
+
+impl Virtqueue {
+    fn new(base: *mut u8, layout: QueueLayout, size: u16, index: u16, notify_port: u16) -> Self {
+        let queue = Virtqueue {
+            base, layout, size, index, notify_port,
+            free_head: 0, free_count: size, avail_index: 0, last_used: 0,
+        };
+        // chain all descriptors into the free list.
+        for i in 0..size {
+            unsafe { addr_of_mut!((*queue.descriptor(i)).next).write_volatile(i + 1) };
+        }
+        queue
+    }
+
+    /// The number of descriptors in the queue.
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// The number of descriptors that are not passed to the device.
+    pub fn free_count(&self) -> u16 {
+        self.free_count
+    }
+
+    /// The descriptor the next `add` starts its chain with.
+    pub fn next_free(&self) -> Option<u16> {
+        (self.free_count > 0).then_some(self.free_head)
+    }
+
+    /// Passes the buffers to the device as one descriptor chain, returns the index of
+    /// its first descriptor or `None` if there are not enough free descriptors.
+    ///
+    /// The buffers must stay valid until the device returns the chain.
+    pub fn add(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > usize::from(self.free_count) {
+            return None;
+        }
+        let head = self.free_head;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let index = self.free_head;
+            let descriptor = self.descriptor(index);
+            unsafe {
+                self.free_head = addr_of!((*descriptor).next).read_volatile();
+                let mut flags = if buffer.device_writes { DESC_F_WRITE } else { 0 };
+                if i + 1 < buffers.len() {
+                    flags |= DESC_F_NEXT;
+                }
+                addr_of_mut!((*descriptor).addr).write_volatile(buffer.addr.as_u64());
+                addr_of_mut!((*descriptor).len).write_volatile(buffer.len);
+                addr_of_mut!((*descriptor).flags).write_volatile(flags);
+                if i + 1 < buffers.len() {
+                    addr_of_mut!((*descriptor).next).write_volatile(self.free_head);
+                }
+            }
+        }
+        self.free_count -= buffers.len() as u16;
+
+        unsafe {
+            let ring_entry = self.avail_ring().add(2 + usize::from(self.avail_index % self.size));
+            ring_entry.write_volatile(head);
+            // the device must see the descriptors and the entry before the new index.
+            fence(Ordering::SeqCst);
+            self.avail_index = self.avail_index.wrapping_add(1);
+            self.avail_ring().add(1).write_volatile(self.avail_index);
+        }
+        Some(head)
+    }
+
+    /// Tells the device that there are new available buffers.
+    pub fn notify(&self) {
+        fence(Ordering::SeqCst);
+        unsafe { Port::new(self.notify_port).write(self.index) };
+    }
+
+    /// Takes the next chain the device is done with, returns its first descriptor and
+    /// the number of bytes the device wrote, and puts the descriptors back on the free list.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let used = unsafe { self.base.add(self.layout.used) } as *mut u16;
+        let used_index = unsafe { used.add(1).read_volatile() };
+        if used_index == self.last_used {
+            return None;
+        }
+        fence(Ordering::SeqCst);
+        let element = unsafe {
+            let elements = used.add(2) as *const UsedElement;
+            elements.add(usize::from(self.last_used % self.size))
+        };
+        let (head, len) = unsafe {
+            (addr_of!((*element).id).read_volatile() as u16, addr_of!((*element).len).read_volatile())
+        };
+        self.last_used = self.last_used.wrapping_add(1);
+
+        // walk the chain to its end and put all of it in front of the free list.
+        let mut index = head;
+        loop {
+            self.free_count += 1;
+            let descriptor = self.descriptor(index);
+            let flags = unsafe { addr_of!((*descriptor).flags).read_volatile() };
+            if flags & DESC_F_NEXT == 0 {
+                unsafe { addr_of_mut!((*descriptor).next).write_volatile(self.free_head) };
+                break;
+            }
+            index = unsafe { addr_of!((*descriptor).next).read_volatile() };
+        }
+        self.free_head = head;
+        Some((head, len))
+    }
+
+    fn descriptor(&self, index: u16) -> *mut Descriptor {
+        unsafe { (self.base as *mut Descriptor).add(usize::from(index)) }
+    }
+
+    // flags, index, then the ring entries.
+    fn avail_ring(&self) -> *mut u16 {
+        unsafe { self.base.add(self.layout.avail) as *mut u16 }
+    }
+}
+
+#[test_case]
+fn test_queue_layout() {
+    // the layout QEMU's 128 entry queues use, from the legacy interface spec.
+    let layout = QueueLayout::new(128);
+    assert_eq!(layout.avail, 2048);
+    assert_eq!(layout.used, 4096);
+    assert_eq!(layout.total, 8192);
+}
@@ -0,0 +1,87 @@
+// virtio-rng, entropy from the host.
+//
+// The device has a single queue into which the driver puts buffers for the device to fill
+// with random bytes from the host. We request a pool's worth at boot and whenever
+// `reseed` is called, and mix the bytes into the entropy pool.
+//
+// QEMU provides the device with `-device virtio-rng-pci`.
+
+use x86_64::instructions::interrupts;
+use crate::{memory::{self, BootFrameAllocator}, random, sync::Mutex};
+use super::{Buffer, DeviceType, VirtioDevice, VirtioError, Virtqueue};
+
+const REQUEST_QUEUE: u16 = 0;
+const REQUEST_SIZE: usize = random::POOL_BITS as usize / 8;
+// how often to poll for a response before giving up, the host answers within microseconds.
+const MAX_POLLS: usize = 1_000_000;
+
+struct Driver {
+    requests: Virtqueue,
+    buffer: x86_64::PhysAddr,
+    // the buffer was posted and the device hasn't returned it yet.
+    pending: bool,
+}
+
+static DRIVER: Mutex<Option<Driver>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RngError {
+    Device(VirtioError),
+    /// `init` found no device.
+    NotInitialized,
+    /// The host didn't answer in time.
+    Timeout,
+    /// The request queue had no free descriptor.
+    QueueFull,
+}
+
+/// Sets up the first virtio-rng device and seeds the entropy pool from it.
+pub fn init(frame_allocator: &mut BootFrameAllocator) -> Result<(), RngError> {
+    let device = VirtioDevice::find(DeviceType::Entropy).map_err(RngError::Device)?;
+    device.negotiate_features(0);
+    let result = device.setup_queue(REQUEST_QUEUE, frame_allocator).and_then(|requests| {
+        let buffer = frame_allocator.allocate_contiguous(1).ok_or(VirtioError::OutOfMemory)?;
+        Ok(Driver { requests, buffer: buffer.start_address(), pending: false })
+    });
+    let driver = match result {
+        Ok(driver) => driver,
+        Err(err) => {
+            device.fail();
+            return Err(RngError::Device(err));
+        }
+    };
+    device.finish_setup();
+
+    interrupts::without_interrupts(|| *DRIVER.lock() = Some(driver));
+    reseed()
+}
+
+/// Requests random bytes from the host and mixes them into the entropy pool.
+pub fn reseed() -> Result<(), RngError> {
+    interrupts::without_interrupts(|| {
+        let mut guard = DRIVER.lock();
+        let driver = guard.as_mut().ok_or(RngError::NotInitialized)?;
+
+        // after a timeout the device still owns the buffer, so it isn't posted again until
+        // the device returned it, it would write the late answer into the new request.
+        if !driver.pending {
+            let buffer = Buffer { addr: driver.buffer, len: REQUEST_SIZE as u32, device_writes: true };
+            driver.requests.add(&[buffer]).ok_or(RngError::QueueFull)?;
+            driver.requests.notify();
+            driver.pending = true;
+        }
+
+        for _ in 0..MAX_POLLS {
+            if let Some((_, len)) = driver.requests.pop_used() {
+                driver.pending = false;
+                let ptr: *const u8 = memory::phys_to_virt(driver.buffer).as_ptr();
+                let bytes = unsafe { core::slice::from_raw_parts(ptr, (len as usize).min(REQUEST_SIZE)) };
+                random::add_entropy(bytes, 8 * bytes.len() as u32);
+                return Ok(());
+            }
+            core::hint::spin_loop();
+        }
+        // the buffer stays with the device, the next reseed waits for its answer.
+        Err(RngError::Timeout)
+    })
+}
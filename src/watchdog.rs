@@ -0,0 +1,126 @@
+// NMI watchdog.
+//
+// The timer interrupt pets the watchdog on every tick. Independently of that, the first
+// general purpose performance counter counts unhalted CPU cycles and raises an NMI through
+// the local APIC every PERIOD cycles. Since an NMI can't be masked, it also arrives while
+// interrupts are disabled or while the kernel spins on a deadlocked lock. If the timer
+// didn't tick for STALL_LIMIT consecutive watchdog NMIs, we dump the interrupted state and
+// a backtrace and panic instead of hanging forever.
+//
+// Halted cycles are not counted, so an idle kernel sitting in `hlt` doesn't produce NMIs.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::{
+    registers::{
+        control::{Cr0, Cr2, Cr3, Cr4},
+        model_specific::Msr,
+    },
+    structures::idt::InterruptStackFrame,
+};
+use crate::{
+    apic, backtrace::Backtrace, println, serial,
+    perf::{self, PERFEVTSEL_EN, PERFEVTSEL_INT, PERFEVTSEL_OS, PERFEVTSEL_USR},
+};
+
+/// Number of unhalted cycles between two watchdog NMIs. Writing IA32_PMC0 only
+/// sets the low 32 bits (sign extended), so the period has to fit in 31 bits.
+pub const PERIOD: u64 = 0x7fff_ffff;
+/// Number of watchdog NMIs without a timer tick after which the kernel is considered stalled.
+pub const STALL_LIMIT: u64 = 3;
+
+const PMC: u32 = perf::IA32_PMC0 + perf::WATCHDOG_COUNTER;
+const PERFEVTSEL: u32 = perf::IA32_PERFEVTSEL0 + perf::WATCHDOG_COUNTER;
+
+// the architectural "unhalted core cycles" event.
+const EVENT_UNHALTED_CORE_CYCLES: u64 = 0x3c;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchdogError {
+    /// `apic::init` has to be called before the watchdog can deliver NMIs.
+    ApicNotInitialized,
+    /// The CPU (or the emulator) has no architectural performance counters.
+    NoPerfCounters,
+}
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static LAST_SEEN_TICK: AtomicU64 = AtomicU64::new(0);
+static STALLED_NMIS: AtomicU64 = AtomicU64::new(0);
+// width of the performance counters in bits, 0 while the watchdog is not running.
+static COUNTER_WIDTH: AtomicU64 = AtomicU64::new(0);
+
+/// Starts the watchdog.
+pub fn init() -> Result<(), WatchdogError> {
+    if !apic::is_initialized() {
+        return Err(WatchdogError::ApicNotInitialized);
+    }
+
+    let info = perf::info().ok_or(WatchdogError::NoPerfCounters)?;
+    COUNTER_WIDTH.store(u64::from(info.general_width), Ordering::SeqCst);
+
+    unsafe {
+        Msr::new(PERFEVTSEL).write(0);
+        arm();
+        Msr::new(PERFEVTSEL).write(
+            EVENT_UNHALTED_CORE_CYCLES | PERFEVTSEL_USR | PERFEVTSEL_OS | PERFEVTSEL_INT | PERFEVTSEL_EN,
+        );
+        perf::enable_global(1 << perf::WATCHDOG_COUNTER);
+    }
+
+    Ok(())
+}
+
+/// Tells the watchdog that the timer interrupt is still running.
+pub fn pet() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Handles an NMI, returns `false` if it wasn't raised by the watchdog.
+pub fn handle_nmi(stack_frame: &InterruptStackFrame) -> bool {
+    let width = COUNTER_WIDTH.load(Ordering::SeqCst);
+    if width == 0 {
+        return false;
+    }
+
+    // the counter counts up from -PERIOD, its top bit is cleared once it overflowed.
+    let counter = unsafe { Msr::new(PMC).read() };
+    if counter & (1 << (width - 1)) != 0 {
+        return false;
+    }
+    unsafe { arm() };
+
+    let ticks = TICKS.load(Ordering::Relaxed);
+    if LAST_SEEN_TICK.swap(ticks, Ordering::Relaxed) != ticks {
+        STALLED_NMIS.store(0, Ordering::Relaxed);
+        return true;
+    }
+
+    if STALLED_NMIS.fetch_add(1, Ordering::Relaxed) + 1 >= STALL_LIMIT {
+        report_stall(stack_frame);
+    }
+    true
+}
+
+// Reloads the counter and unmasks the APIC entry, which the NMI delivery has masked.
+unsafe fn arm() {
+    Msr::new(PMC).write(PERIOD.wrapping_neg());
+    apic::write(apic::LVT_PERF, apic::DELIVERY_NMI);
+}
+
+fn report_stall(stack_frame: &InterruptStackFrame) -> ! {
+    // the stalled code may well be holding the writer locks. It will never run
+    // again, so we can safely take them over to print the report.
+    unsafe {
+        #[cfg(feature = "vga")]
+        crate::vga_buffer::WRITER.force_unlock();
+        serial::SERIAL1.force_unlock();
+    }
+
+    println!("WATCHDOG: no timer interrupt for {} NMIs", STALL_LIMIT);
+    println!("Interrupted state: {:#?}", stack_frame);
+    println!("CR0: {:?}", Cr0::read());
+    println!("CR2: {:?}", Cr2::read());
+    println!("CR3: {:?}", Cr3::read());
+    println!("CR4: {:?}", Cr4::read());
+    println!("{}", Backtrace::capture());
+    panic!("watchdog: kernel stalled");
+}
@@ -0,0 +1,95 @@
+// Deferred work for interrupt handlers.
+//
+// Interrupt handlers should do the minimum with interrupts disabled: acknowledge the
+// device and grab its data. Everything else can be queued with `schedule` and runs later
+// in task context, from the task returned by `run`, with interrupts enabled.
+//
+// The allocator may be locked by the interrupted code, so queuing must not allocate. A work
+// item therefore is a function pointer plus an argument instead of a boxed closure, closures
+// that don't capture anything coerce to it. The queue has a fixed number of slots.
+
+use core::{
+    future::poll_fn,
+    task::{Poll, Waker},
+};
+use x86_64::instructions::interrupts;
+use crate::sync::Mutex;
+
+/// Number of work items that can be queued at the same time.
+pub const MAX_WORK: usize = 64;
+
+#[derive(Clone, Copy)]
+struct Work {
+    func: fn(usize),
+    arg: usize,
+}
+
+struct Queue {
+    items: [Option<Work>; MAX_WORK],
+    head: usize,
+    len: usize,
+    worker: Option<Waker>,
+}
+
+static QUEUE: Mutex<Queue> = Mutex::new(Queue {
+    items: [None; MAX_WORK],
+    head: 0,
+    len: 0,
+    worker: None,
+});
+
+/// All `MAX_WORK` slots are in use, the worker task doesn't keep up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueFull;
+
+/// Queues `func(arg)` to run in task context, can be called from interrupt handlers.
+pub fn schedule(func: fn(usize), arg: usize) -> Result<(), QueueFull> {
+    interrupts::without_interrupts(|| {
+        let mut queue = QUEUE.lock();
+        if queue.len == MAX_WORK {
+            return Err(QueueFull);
+        }
+        let index = (queue.head + queue.len) % MAX_WORK;
+        queue.items[index] = Some(Work { func, arg });
+        queue.len += 1;
+        if let Some(worker) = queue.worker.take() {
+            worker.wake();
+        }
+        Ok(())
+    })
+}
+
+fn pop() -> Option<Work> {
+    interrupts::without_interrupts(|| {
+        let mut queue = QUEUE.lock();
+        if queue.len == 0 {
+            return None;
+        }
+        let head = queue.head;
+        let work = queue.items[head].take();
+        queue.head = (head + 1) % MAX_WORK;
+        queue.len -= 1;
+        work
+    })
+}
+
+/// The worker task, runs the queued work in the order it was scheduled.
+pub async fn run() {
+    loop {
+        let work = poll_fn(|context| match pop() {
+            Some(work) => Poll::Ready(work),
+            None => {
+                interrupts::without_interrupts(|| {
+                    let mut queue = QUEUE.lock();
+                    queue.worker = Some(context.waker().clone());
+                    // work may have been scheduled before we registered the waker.
+                    if queue.len > 0 {
+                        context.waker().wake_by_ref();
+                    }
+                });
+                Poll::Pending
+            }
+        }).await;
+        (work.func)(work.arg);
+    }
+}
@@ -0,0 +1,169 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::alloc::{alloc, dealloc, Layout};
+use bootloader::{entry_point, BootInfo};
+use core::{panic::PanicInfo, ptr::{self, NonNull}};
+use linked_list_allocator::Heap;
+use rust_os::{allocator, bench, boot::BootData, serial_println, InitStage};
+
+// Allocation throughput and fragmentation of the allocators under a few workload patterns,
+// printed over serial. The kernel heap is compared with a bare linked list heap of the same
+// size, the difference is the cost of the locking and the shrinker checks around it.
+
+const BLOCKS: usize = 64;
+const BARE_HEAP_SIZE: usize = allocator::HEAP_SIZE;
+
+static mut BARE_HEAP: [u8; BARE_HEAP_SIZE] = [0; BARE_HEAP_SIZE];
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    let boot_data = BootData::from(boot_info);
+    rust_os::init(&boot_data);
+    rust_os::init_memory(&boot_data, InitStage::Heap)
+        .expect("heap initialization failed!");
+
+    test_main();
+    loop { }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+trait BenchAllocator {
+    const NAME: &'static str;
+    fn allocate(&mut self, layout: Layout) -> *mut u8;
+    fn deallocate(&mut self, ptr: *mut u8, layout: Layout);
+    fn free_bytes(&self) -> usize;
+}
+
+// The kernel heap, through the global allocator.
+struct Global;
+
+impl BenchAllocator for Global {
+    const NAME: &'static str = "kernel heap";
+
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        unsafe { alloc(layout) }
+    }
+
+    fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        unsafe { dealloc(ptr, layout) }
+    }
+
+    fn free_bytes(&self) -> usize {
+        allocator::stats().unwrap().free
+    }
+}
+
+impl BenchAllocator for Heap {
+    const NAME: &'static str = "bare linked list";
+
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        self.allocate_first_fit(layout).map_or(ptr::null_mut(), NonNull::as_ptr)
+    }
+
+    fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        unsafe { Heap::deallocate(self, NonNull::new(ptr).unwrap(), layout) }
+    }
+
+    fn free_bytes(&self) -> usize {
+        self.free()
+    }
+}
+
+fn bare_heap() -> Heap {
+    let mut heap = Heap::empty();
+    // only this test uses the buffer, and only through one heap at a time.
+    unsafe { heap.init(ptr::addr_of_mut!(BARE_HEAP) as usize, BARE_HEAP_SIZE) };
+    heap
+}
+
+// sizes from 8 to 1024 bytes, the same sequence for every allocator.
+fn size(i: usize) -> usize {
+    8 << (i.wrapping_mul(7) % 8)
+}
+
+fn allocate_all<A: BenchAllocator>(heap: &mut A, blocks: &mut [(*mut u8, Layout)], layout: impl Fn(usize) -> Layout) {
+    for (i, block) in blocks.iter_mut().enumerate() {
+        let layout = layout(i);
+        let ptr = heap.allocate(layout);
+        assert!(!ptr.is_null(), "{}: out of memory", A::NAME);
+        *block = (ptr, layout);
+    }
+}
+
+fn workloads<A: BenchAllocator>(heap: &mut A) {
+    let mut blocks = [(ptr::null_mut(), Layout::new::<u8>()); BLOCKS];
+    let fixed = |_| Layout::from_size_align(64, 8).unwrap();
+    let mixed = |i| Layout::from_size_align(size(i), 8).unwrap();
+    let aligned = |i| Layout::from_size_align(size(i), 64).unwrap();
+    let operations = 2 * BLOCKS as u64;
+
+    serial_println!("{}:", A::NAME);
+    let result = bench::run("fixed size, LIFO", operations, || {
+        allocate_all(heap, &mut blocks, fixed);
+        blocks.iter().rev().for_each(|&(ptr, layout)| heap.deallocate(ptr, layout));
+    });
+    serial_println!("  {}", result);
+    let result = bench::run("mixed sizes, FIFO", operations, || {
+        allocate_all(heap, &mut blocks, mixed);
+        blocks.iter().for_each(|&(ptr, layout)| heap.deallocate(ptr, layout));
+    });
+    serial_println!("  {}", result);
+    let result = bench::run("mixed sizes, interleaved", operations, || {
+        allocate_all(heap, &mut blocks, mixed);
+        blocks.iter().step_by(2).for_each(|&(ptr, layout)| heap.deallocate(ptr, layout));
+        blocks.iter().skip(1).step_by(2).for_each(|&(ptr, layout)| heap.deallocate(ptr, layout));
+    });
+    serial_println!("  {}", result);
+    let result = bench::run("64 byte aligned, LIFO", operations, || {
+        allocate_all(heap, &mut blocks, aligned);
+        blocks.iter().rev().for_each(|&(ptr, layout)| heap.deallocate(ptr, layout));
+    });
+    serial_println!("  {}", result);
+
+    // free every other block and see how much of the free memory is still usable at once.
+    allocate_all(heap, &mut blocks, mixed);
+    blocks.iter().step_by(2).for_each(|&(ptr, layout)| heap.deallocate(ptr, layout));
+    let free = heap.free_bytes();
+    let largest = largest_block(heap);
+    serial_println!("  fragmentation: largest block {} of {} free bytes ({}%)", largest, free, largest * 100 / free.max(1));
+    blocks.iter().skip(1).step_by(2).for_each(|&(ptr, layout)| heap.deallocate(ptr, layout));
+}
+
+// the largest block that can be allocated, found by bisection.
+fn largest_block<A: BenchAllocator>(heap: &mut A) -> usize {
+    let (mut low, mut high) = (0, heap.free_bytes() + 1);
+    while high - low > 1 {
+        let middle = (low + high) / 2;
+        let layout = Layout::from_size_align(middle, 8).unwrap();
+        let ptr = heap.allocate(layout);
+        if ptr.is_null() {
+            high = middle;
+        } else {
+            heap.deallocate(ptr, layout);
+            low = middle;
+        }
+    }
+    low
+}
+
+#[test_case]
+fn allocator_workloads() {
+    let used_before = allocator::stats().unwrap().used;
+    workloads(&mut Global);
+    assert_eq!(allocator::stats().unwrap().used, used_before, "kernel heap leaked");
+
+    let mut heap = bare_heap();
+    workloads(&mut heap);
+    assert_eq!(heap.used(), 0, "bare heap leaked");
+}
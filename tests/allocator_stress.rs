@@ -0,0 +1,149 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::alloc::{alloc, dealloc, realloc, Layout};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use rust_os::{allocator, boot::BootData, InitStage};
+
+// Randomized allocate/free/realloc patterns with different sizes and alignments. Every
+// block is filled with bytes derived from its own seed, which are checked before the block
+// is freed or reallocated, so an allocator that hands out overlapping blocks, or loses the
+// contents on realloc, is caught. The run is reproducible from SEED.
+//
+// The default run is short enough for `cargo test`, build with e.g.
+// `ALLOC_STRESS_ITERATIONS=1000000` for a long run.
+
+const SEED: u64 = 0x2545_f491_4f6c_dd1d;
+const DEFAULT_ITERATIONS: u64 = 20_000;
+const MAX_LIVE: usize = 64;
+const MAX_SIZE: usize = 2048;
+// alignments up to 2^MAX_ALIGN_SHIFT.
+const MAX_ALIGN_SHIFT: u32 = 8;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    let boot_data = BootData::from(boot_info);
+    rust_os::init(&boot_data);
+    rust_os::init_memory(&boot_data, InitStage::Heap)
+        .expect("heap initialization failed!");
+
+    test_main();
+    loop { }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+struct Block {
+    ptr: *mut u8,
+    layout: Layout,
+    seed: u64,
+}
+
+// xorshift64*, a fixed sequence independent of the kernel's entropy pool.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+}
+
+fn pattern(seed: u64, index: usize) -> u8 {
+    (seed.wrapping_add(index as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15) >> 56) as u8
+}
+
+unsafe fn fill(block: &Block) {
+    for i in 0..block.layout.size() {
+        block.ptr.add(i).write(pattern(block.seed, i));
+    }
+}
+
+// checks the first `len` bytes, a shrinking realloc only keeps that many.
+unsafe fn check(block: &Block, len: usize) {
+    for i in 0..len {
+        assert_eq!(block.ptr.add(i).read(), pattern(block.seed, i),
+            "block {:p} ({:?}) corrupted at offset {}", block.ptr, block.layout, i);
+    }
+}
+
+fn random_layout(rng: &mut Rng) -> Layout {
+    let size = 1 + rng.below(MAX_SIZE as u64) as usize;
+    let align = 1 << rng.below(u64::from(MAX_ALIGN_SHIFT) + 1);
+    Layout::from_size_align(size, align).unwrap()
+}
+
+#[test_case]
+fn random_alloc_free_realloc() {
+    let iterations = option_env!("ALLOC_STRESS_ITERATIONS")
+        .and_then(|n| n.parse().ok())
+        .unwrap_or(DEFAULT_ITERATIONS);
+    let used_before = allocator::stats().unwrap().used;
+    let mut rng = Rng(SEED);
+    let mut blocks: [Option<Block>; MAX_LIVE] = [const { None }; MAX_LIVE];
+    let mut failed = 0;
+
+    for _ in 0..iterations {
+        let slot = &mut blocks[rng.below(MAX_LIVE as u64) as usize];
+        match slot.take() {
+            None => {
+                let layout = random_layout(&mut rng);
+                let ptr = unsafe { alloc(layout) };
+                if ptr.is_null() {
+                    // the heap is full or too fragmented, that's fine.
+                    failed += 1;
+                    continue;
+                }
+                assert_eq!(ptr as usize % layout.align(), 0, "{:?} misaligned at {:p}", layout, ptr);
+                let block = Block { ptr, layout, seed: rng.next() };
+                unsafe { fill(&block) };
+                *slot = Some(block);
+            }
+            Some(block) if rng.below(2) == 0 => unsafe {
+                check(&block, block.layout.size());
+                dealloc(block.ptr, block.layout);
+            },
+            Some(block) => unsafe {
+                let new_size = 1 + rng.below(MAX_SIZE as u64) as usize;
+                check(&block, block.layout.size());
+                let ptr = realloc(block.ptr, block.layout, new_size);
+                if ptr.is_null() {
+                    // the old block is still valid.
+                    failed += 1;
+                    *slot = Some(block);
+                    continue;
+                }
+                let moved = Block { ptr, layout: Layout::from_size_align(new_size, block.layout.align()).unwrap(), seed: block.seed };
+                assert_eq!(ptr as usize % moved.layout.align(), 0, "realloc lost the alignment");
+                check(&moved, block.layout.size().min(new_size));
+                fill(&moved);
+                *slot = Some(moved);
+            },
+        }
+    }
+
+    for block in blocks.iter_mut().filter_map(Option::take) {
+        unsafe {
+            check(&block, block.layout.size());
+            dealloc(block.ptr, block.layout);
+        }
+    }
+    assert!(failed < iterations / 2, "{} of {} allocations failed", failed, iterations);
+    assert_eq!(allocator::stats().unwrap().used, used_before, "memory leaked");
+}
@@ -0,0 +1,43 @@
+#![no_std]
+#![no_main]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use x86_64::structures::idt::InterruptStackFrame;
+use rust_os::{exit_qemu, QemuExitCode, test_print, test_println};
+
+// Unlike the stack_overflow test, which installs its own IDT, this test runs with the
+// kernel's IDT and GDT, so it checks that the kernel's double fault handler really gets
+// its own stack from the TSS.
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    test_print!("double_fault::kernel_double_fault_handler...\t");
+
+    rust_os::init(&rust_os::boot::BootData::from(boot_info));
+    rust_os::interrupts::set_double_fault_hook(double_fault_hook);
+
+    // the page fault on the guard page can't push its frame on the exhausted
+    // stack, which turns it into a double fault.
+    stack_overflow();
+
+    panic!("Execution continued after stack overflow");
+}
+
+#[allow(unconditional_recursion)]
+fn stack_overflow() {
+    stack_overflow();
+    // prevent tail recursion optimizations, see the stack_overflow test.
+    volatile::Volatile::new(0).read();
+}
+
+fn double_fault_hook(_stack_frame: &InterruptStackFrame, _error_code: u64) -> ! {
+    test_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
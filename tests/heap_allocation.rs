@@ -9,21 +9,15 @@ extern crate alloc;
 use alloc::{ boxed::Box, vec::Vec };
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
-use rust_os::allocator;
-use rust_os::memory::{self, BootFrameAllocator};
-use x86_64::VirtAddr;
+use rust_os::{boot::BootData, InitStage};
 use rust_os::allocator::HEAP_SIZE;
 
 entry_point!(main);
 
 fn main(boot_info: &'static BootInfo) -> ! {
-    rust_os::init();
-    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe {
-        BootFrameAllocator::init(&boot_info.memory_map)
-    };
-    allocator::init_heap(&mut mapper, &mut frame_allocator)
+    let boot_data = BootData::from(boot_info);
+    rust_os::init(&boot_data);
+    rust_os::init_memory(&boot_data, InitStage::Heap)
         .expect("heap initialization failed!");
 
     test_main();
@@ -62,3 +56,27 @@ fn many_boxes() {
         assert_eq!(*x, i);
     }
 }
+
+#[test_case]
+fn rcu_cell_update_frees_old_value() {
+    use core::sync::atomic::{AtomicUsize, Ordering};
+    use rust_os::rcu::RcuCell;
+
+    static DROPPED: AtomicUsize = AtomicUsize::new(0);
+    struct Counted(u32);
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            DROPPED.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    let cell = RcuCell::new();
+    assert!(cell.read(|value| value.is_none()));
+    cell.update(|_| Counted(1));
+    cell.update(|old| Counted(old.unwrap().0 + 1));
+    assert_eq!(cell.read(|value| value.map(|v| v.0)), Some(2));
+    assert_eq!(DROPPED.load(Ordering::Relaxed), 1);
+    assert_eq!(cell.try_update(|_| Err("no")), Err("no"));
+    drop(cell);
+    assert_eq!(DROPPED.load(Ordering::Relaxed), 2);
+}
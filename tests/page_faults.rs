@@ -0,0 +1,170 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(rust_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::format;
+use bootloader::{entry_point, BootInfo};
+use core::{panic::PanicInfo, ptr, sync::atomic::{AtomicUsize, Ordering}};
+use rust_os::{
+    boot::BootData,
+    exceptions::{PageFaultCause, PageFaultKind},
+    interrupts, memory,
+    sync::Mutex,
+    InitStage, KernelMemory,
+};
+use x86_64::{
+    structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags as Flags, Size4KiB},
+    VirtAddr,
+};
+
+// Faults on purpose, with a page fault hook that records how the kernel classified each
+// fault and resolves it the way the case asks for, so the access is retried and the test
+// goes on. Only faults in the test's own pages are resolved, any other fault goes to the
+// kernel's handler, which reports it and halts, and the test runner times out.
+//
+// Copy on write and demand paging will install the same kind of hook, their cases go here.
+
+// unmapped lower half addresses, away from the heap and the bootloader's mappings.
+const TEST_PAGES_START: u64 = 0x5555_0000_0000;
+const TEST_PAGES: u64 = 16;
+
+static MEMORY: Mutex<Option<KernelMemory>> = Mutex::new(None);
+static LAST_FAULT: Mutex<Option<(PageFaultKind, VirtAddr)>> = Mutex::new(None);
+static FAULTS: AtomicUsize = AtomicUsize::new(0);
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    let boot_data = BootData::from(boot_info);
+    rust_os::init(&boot_data);
+    let kernel_memory = rust_os::init_memory(&boot_data, InitStage::Heap)
+        .expect("heap initialization failed!");
+    *MEMORY.lock() = Some(kernel_memory);
+    interrupts::set_page_fault_hook(resolve);
+
+    test_main();
+    loop { }
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    rust_os::test_panic_handler(info)
+}
+
+fn test_page(index: u64) -> Page {
+    Page::containing_address(VirtAddr::new(TEST_PAGES_START + index * 4096))
+}
+
+fn is_test_page(address: VirtAddr) -> bool {
+    (TEST_PAGES_START..TEST_PAGES_START + TEST_PAGES * 4096).contains(&address.as_u64())
+}
+
+// maps `page` to a fresh zeroed frame. The page tables it creates are writable whatever
+// `flags` are, or making the page writable later wouldn't be enough to write to it.
+fn map_zeroed(kernel_memory: &mut KernelMemory, page: Page, flags: Flags) {
+    let frame = kernel_memory.frame_allocator.allocate_frame().expect("out of frames");
+    let table_flags = Flags::PRESENT | Flags::WRITABLE;
+    unsafe {
+        ptr::write_bytes(memory::phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(), 0, 4096);
+        kernel_memory.mapper
+            .map_to_with_table_flags(page, frame, flags, table_flags, &mut kernel_memory.frame_allocator)
+            .expect("map_to failed")
+            .flush();
+    }
+}
+
+fn resolve(cause: &PageFaultCause) -> bool {
+    if !is_test_page(cause.address) {
+        return false;
+    }
+    FAULTS.fetch_add(1, Ordering::SeqCst);
+    *LAST_FAULT.lock() = Some((cause.kind(), cause.address));
+
+    let page: Page<Size4KiB> = Page::containing_address(cause.address);
+    let mut kernel_memory = MEMORY.lock();
+    let kernel_memory = kernel_memory.as_mut().unwrap();
+    match cause.kind() {
+        PageFaultKind::NotPresent => map_zeroed(kernel_memory, page, Flags::PRESENT | Flags::WRITABLE),
+        PageFaultKind::WriteToReadOnly => unsafe {
+            kernel_memory.mapper.update_flags(page, Flags::PRESENT | Flags::WRITABLE)
+                .expect("update_flags failed")
+                .flush();
+        },
+        PageFaultKind::ExecuteDisabled => unsafe {
+            kernel_memory.mapper.update_flags(page, Flags::PRESENT)
+                .expect("update_flags failed")
+                .flush();
+        },
+        PageFaultKind::ProtectionViolation => return false,
+    }
+    true
+}
+
+// runs `f` and returns the faults it caused.
+fn faults_during(f: impl FnOnce()) -> usize {
+    *LAST_FAULT.lock() = None;
+    let before = FAULTS.load(Ordering::SeqCst);
+    f();
+    FAULTS.load(Ordering::SeqCst) - before
+}
+
+#[test_case]
+fn non_present_read() {
+    let address = test_page(0).start_address() + 8u64;
+    let mut value = 1;
+    let faults = faults_during(|| value = unsafe { ptr::read_volatile(address.as_ptr::<u64>()) });
+    assert_eq!(faults, 1);
+    assert_eq!(*LAST_FAULT.lock(), Some((PageFaultKind::NotPresent, address)));
+    // the page was mapped to a zeroed frame and the read retried.
+    assert_eq!(value, 0);
+    assert!(memory::translate(address).is_some());
+
+    // the page stays mapped.
+    let faults = faults_during(|| unsafe { ptr::write_volatile(address.as_mut_ptr::<u64>(), 42) });
+    assert_eq!(faults, 0);
+    assert_eq!(unsafe { ptr::read_volatile(address.as_ptr::<u64>()) }, 42);
+}
+
+#[test_case]
+fn write_to_read_only() {
+    let page = test_page(1);
+    map_zeroed(MEMORY.lock().as_mut().unwrap(), page, Flags::PRESENT);
+    let address = page.start_address();
+
+    let faults = faults_during(|| unsafe { ptr::read_volatile(address.as_ptr::<u64>()); });
+    assert_eq!(faults, 0, "reading a read-only page faulted");
+
+    let faults = faults_during(|| unsafe { ptr::write_volatile(address.as_mut_ptr::<u64>(), 7) });
+    assert_eq!(faults, 1);
+    assert_eq!(*LAST_FAULT.lock(), Some((PageFaultKind::WriteToReadOnly, address)));
+    assert_eq!(unsafe { ptr::read_volatile(address.as_ptr::<u64>()) }, 7);
+}
+
+#[test_case]
+fn execute_disabled() {
+    let page = test_page(2);
+    map_zeroed(MEMORY.lock().as_mut().unwrap(), page, Flags::PRESENT | Flags::WRITABLE | Flags::NO_EXECUTE);
+    let address = page.start_address();
+    // a single `ret`.
+    unsafe { ptr::write_volatile(address.as_mut_ptr::<u8>(), 0xc3) };
+
+    let function: extern "C" fn() = unsafe { core::mem::transmute(address.as_ptr::<u8>()) };
+    let faults = faults_during(|| function());
+    assert_eq!(faults, 1);
+    assert_eq!(*LAST_FAULT.lock(), Some((PageFaultKind::ExecuteDisabled, address)));
+}
+
+#[test_case]
+fn fault_report() {
+    use x86_64::structures::idt::PageFaultErrorCode;
+
+    let cause = PageFaultCause {
+        error_code: PageFaultErrorCode::PROTECTION_VIOLATION | PageFaultErrorCode::CAUSED_BY_WRITE,
+        address: VirtAddr::new(0x1000),
+    };
+    assert_eq!(format!("{}", cause), "caused by a protection violation write from kernel mode to 0x1000");
+}
@@ -2,11 +2,11 @@
 #![no_main]
 
 use core::panic::PanicInfo;
-use rust_os::{QemuExitCode, serial_println, exit_qemu, serial_print};
+use rust_os::{QemuExitCode, test_println, exit_qemu, test_print};
 
 #[panic_handler]
 fn panic(_info: &PanicInfo) -> ! {
-    serial_println!("[ok]");
+    test_println!("[ok]");
     exit_qemu(QemuExitCode::Success);
 
     loop {  }
@@ -15,13 +15,13 @@ fn panic(_info: &PanicInfo) -> ! {
 #[no_mangle]
 pub extern "C" fn _start() -> ! {
     should_fail();
-    serial_println!("[test did not panic]");
+    test_println!("[test did not panic]");
     exit_qemu(QemuExitCode::Failed);
 
     loop { }
 }
 
 fn should_fail() {
-    serial_print!("should_panic::should_fail...\t");
+    test_print!("should_panic::should_fail...\t");
     assert_eq!(3, 4);
 }
\ No newline at end of file
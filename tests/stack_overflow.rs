@@ -5,11 +5,11 @@
 use core::panic::PanicInfo;
 use lazy_static::lazy_static;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
-use rust_os::{exit_qemu, QemuExitCode, serial_print, serial_println};
+use rust_os::{exit_qemu, QemuExitCode, test_print, test_println};
 
 #[no_mangle]
 pub extern "C" fn _start() -> ! {
-    serial_print!("stack_overflow::stack_overflow...\t");
+    test_print!("stack_overflow::stack_overflow...\t");
 
     rust_os::gdt::init();
     init_test_idt();
@@ -54,7 +54,7 @@ extern "x86-interrupt" fn test_double_fault_handler(
     _stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
-    serial_println!("[ok]");
+    test_println!("[ok]");
     exit_qemu(QemuExitCode::Success);
 
     loop { }
@@ -0,0 +1,336 @@
+// The text mode screen of the kernel's VGA console, 25 rows of 80 characters with a color
+// each, and the Writer that prints to it.
+//
+// The Writer only sees the screen through the TextBuffer trait: the kernel implements it
+// for the VGA text buffer, MemoryBuffer keeps the characters in plain memory. Nothing in
+// here touches hardware, so the crate also builds for the host, where `make test-host`
+// runs the tests with the standard test harness.
+
+#![no_std]
+
+use core::fmt;
+
+#[allow(dead_code)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Colors {
+    // Because of the repr(u8) attribute each enum variant is stored as an u8
+    Black = 0,
+    Blue = 1,
+    Green = 2,
+    Cyan = 3,
+    Red = 4,
+    Magenta = 5,
+    Brown = 6,
+    LightGray = 7,
+    DarkGray = 8,
+    LightBlue = 9,
+    LightGreen = 10,
+    LightCyan = 11,
+    LightRed = 12,
+    Pink = 13,
+    Yellow = 14,
+    White = 15,
+}
+
+// To ensure that the ColorCode has the exact same data layout as
+// an u8, we use the repr(transparent) attribute.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(transparent)]
+pub struct ColorCode(u8);
+
+impl ColorCode {
+    pub fn new(foreground: Colors, background: Colors) -> ColorCode {
+        ColorCode(( background as u8 ) << 4 | (foreground as u8))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(C)]
+pub struct ScreenChar {
+    pub ascii_character: u8,
+    pub color_code: ColorCode
+}
+
+pub const BUFFER_HEIGHT: usize = 25;
+pub const BUFFER_WIDTH: usize = 80;
+
+/// Where the Writer puts the characters, the VGA text buffer or, for tests, plain memory.
+pub trait TextBuffer {
+    fn read(&self, row: usize, col: usize) -> ScreenChar;
+    fn write(&mut self, row: usize, col: usize, character: ScreenChar);
+    /// Moves the blinking cursor, buffers without one ignore it.
+    fn set_cursor(&mut self, _row: usize, _col: usize) {}
+}
+
+/// A text buffer in plain memory, to test the Writer without VGA hardware.
+pub struct MemoryBuffer {
+    chars: [[ScreenChar; BUFFER_WIDTH]; BUFFER_HEIGHT],
+    /// The row and column of the cursor.
+    pub cursor: (usize, usize),
+}
+
+impl MemoryBuffer {
+    pub fn new() -> Self {
+        let blank = ScreenChar { ascii_character: b' ', color_code: ColorCode(0) };
+        MemoryBuffer { chars: [[blank; BUFFER_WIDTH]; BUFFER_HEIGHT], cursor: (0, 0) }
+    }
+}
+
+impl Default for MemoryBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TextBuffer for MemoryBuffer {
+    fn read(&self, row: usize, col: usize) -> ScreenChar {
+        self.chars[row][col]
+    }
+
+    fn write(&mut self, row: usize, col: usize, character: ScreenChar) {
+        self.chars[row][col] = character;
+    }
+
+    fn set_cursor(&mut self, row: usize, col: usize) {
+        self.cursor = (row, col);
+    }
+}
+
+// To actually write to screen, we now create a writer type:
+// The writer will always write to the last line and shift lines up when
+// a line is full (or on \n). The column_position field keeps track of the
+// current position in the last row. The current foreground and background
+// colors are specified by color_code and the VGA buffer, or a MemoryBuffer in
+// tests, is stored in buffer.
+pub struct Writer<B: TextBuffer> {
+    column_position: usize,
+    color_code: ColorCode,
+    buffer: B,
+}
+
+impl<B: TextBuffer> Writer<B> {
+    /// Creates a writer that starts at the beginning of the last row, white on blue.
+    pub fn new(buffer: B) -> Self {
+        Writer {
+            column_position: 0,
+            color_code: ColorCode::new(Colors::White, Colors::LightBlue),
+            buffer,
+        }
+    }
+
+    pub fn buffer(&self) -> &B {
+        &self.buffer
+    }
+
+    pub fn write_byte(&mut self, byte: u8) {
+        match byte {
+            b'\n' => self.new_line(),
+            byte => {
+                if self.column_position >= BUFFER_WIDTH { // we reached end of the screen
+                    self.new_line();
+                }
+
+                let row = BUFFER_HEIGHT - 1;
+                let col = self.column_position;
+
+                let color_code = self.color_code;
+                self.buffer.write(row, col, ScreenChar {
+                    ascii_character: byte,
+                    color_code,
+                });
+                self.column_position += 1; // move the column position a step to the right
+            }
+        }
+    }
+
+    fn new_line(&mut self) {
+        // We iterate over all screen characters and move each character one row up.
+       for row in 1..BUFFER_HEIGHT {
+           for col in 0..BUFFER_WIDTH {
+               let character = self.buffer.read(row, col);
+               self.buffer.write(row - 1, col, character);
+           }
+       }
+       self.clear_row(BUFFER_HEIGHT - 1);
+        self.column_position = 0;
+    }
+    /// Removes the last character of the current line.
+    pub fn backspace(&mut self) {
+        if self.column_position > 0 {
+            self.column_position -= 1;
+            let blank = ScreenChar {
+                ascii_character: b' ',
+                color_code: self.color_code,
+            };
+            self.buffer.write(BUFFER_HEIGHT - 1, self.column_position, blank);
+        }
+        self.update_cursor();
+    }
+
+    /// Returns the column of the current line the next character is written to.
+    pub fn column(&self) -> usize {
+        self.column_position
+    }
+
+    /// Moves the position the next character is written to within the current line,
+    /// for redrawing an edited line in place.
+    pub fn set_column(&mut self, column: usize) {
+        self.column_position = column.min(BUFFER_WIDTH);
+        self.update_cursor();
+    }
+
+    // Moves the blinking cursor to where the next character goes.
+    fn update_cursor(&mut self) {
+        self.buffer.set_cursor(BUFFER_HEIGHT - 1, self.column_position.min(BUFFER_WIDTH - 1));
+    }
+
+    /// Changes the colors used for the characters written from now on.
+    pub fn set_color(&mut self, foreground: Colors, background: Colors) {
+        self.color_code = ColorCode::new(foreground, background);
+    }
+
+    /// Clears the whole screen with the current background color.
+    pub fn clear_screen(&mut self) {
+        for row in 0..BUFFER_HEIGHT {
+            self.clear_row(row);
+        }
+        self.column_position = 0;
+        self.update_cursor();
+    }
+
+    /// Returns the characters currently shown in the given row.
+    pub fn row_text(&self, row: usize) -> [u8; BUFFER_WIDTH] {
+        let mut text = [b' '; BUFFER_WIDTH];
+        for (col, character) in text.iter_mut().enumerate() {
+            *character = self.buffer.read(row, col).ascii_character;
+        }
+        text
+    }
+
+    /// Writes the screen contents in the format of `dump`.
+    pub fn dump_to(&self, out: &mut impl fmt::Write) -> fmt::Result {
+        writeln!(out, "vga-dump {}x{} cursor={},{}", BUFFER_WIDTH, BUFFER_HEIGHT, BUFFER_HEIGHT - 1, self.column_position)?;
+        for row in 0..BUFFER_HEIGHT {
+            self.dump_row(row, out)?;
+        }
+        writeln!(out, "vga-dump end")
+    }
+
+    // a row is two lines, the characters between bars and the color codes in hex.
+    fn dump_row(&self, row: usize, out: &mut impl fmt::Write) -> fmt::Result {
+        write!(out, "{:02} |", row)?;
+        for character in self.row_text(row) {
+            let printable = matches!(character, 0x20..=0x7e);
+            write!(out, "{}", if printable { char::from(character) } else { '.' })?;
+        }
+        write!(out, "|\n{:02} #", row)?;
+        for col in 0..BUFFER_WIDTH {
+            write!(out, "{:02x}", self.buffer.read(row, col).color_code.0)?;
+        }
+        writeln!(out)
+    }
+
+    // clear_row clears a row by overwriting all of its characters with a space character.
+    fn clear_row(&mut self, row: usize) {
+        let blank = ScreenChar {
+            ascii_character: b' ', // space
+            color_code: self.color_code,
+        };
+
+        for col in 0..BUFFER_WIDTH {
+            self.buffer.write(row, col, blank);
+        }
+    }
+
+    // To print whole strings, we can convert them to bytes and print them one-by-one:
+    // The VGA text buffer only supports ASCII and the additional bytes of code page 437.
+    // Rust strings are UTF-8 by default, so they might contain bytes that are not supported
+    // by the VGA text buffer. We use a match to differentiate printable ASCII bytes
+    // For unprintable bytes, we print a ■ character, which has the hex code 0xfe on the VGA hardware.
+    fn write_string(&mut self, s: &str) {
+        for byte in s.bytes() {
+            match byte {
+                // printable ASCII byte or newline
+                0x20..=0x7e | b'\n' => self.write_byte(byte),
+                // backspace, like on a terminal
+                0x08 => self.backspace(),
+                // not part of printable ASCII range
+                _ => self.write_byte(0xfe),
+            }
+        }
+    }
+}
+
+impl<B: TextBuffer> fmt::Write for Writer<B> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_string(s);
+        self.update_cursor();
+        Ok(())
+    }
+}
+
+#[test]
+fn test_long_lines_wrap() {
+    use core::fmt::Write;
+    let mut writer = Writer::new(MemoryBuffer::new());
+    for _ in 0..BUFFER_WIDTH + 5 {
+        write!(writer, "x").unwrap();
+    }
+    assert_eq!(writer.row_text(BUFFER_HEIGHT - 2), [b'x'; BUFFER_WIDTH]);
+    assert_eq!(&writer.row_text(BUFFER_HEIGHT - 1)[..6], b"xxxxx ");
+    assert_eq!(writer.column(), 5);
+    assert_eq!(writer.buffer().cursor, (BUFFER_HEIGHT - 1, 5));
+}
+
+#[test]
+fn test_special_characters() {
+    use core::fmt::Write;
+    let mut writer = Writer::new(MemoryBuffer::new());
+    // newlines scroll, backspace removes, everything outside printable ASCII is a ■.
+    write!(writer, "a\nbc\x08\x07é").unwrap();
+    assert_eq!(writer.row_text(BUFFER_HEIGHT - 2)[0], b'a');
+    assert_eq!(&writer.row_text(BUFFER_HEIGHT - 1)[..5], b"b\xfe\xfe\xfe ");
+}
+
+#[test]
+fn test_colors() {
+    use core::fmt::Write;
+    let mut writer = Writer::new(MemoryBuffer::new());
+    writer.set_color(Colors::Yellow, Colors::Black);
+    write!(writer, "y").unwrap();
+    let character = writer.buffer().read(BUFFER_HEIGHT - 1, 0);
+    assert_eq!(character.color_code, ColorCode::new(Colors::Yellow, Colors::Black));
+    assert_eq!(character.color_code.0, 0x0e);
+}
+
+#[test]
+fn test_dump_row() {
+    use core::fmt::Write;
+
+    // collects the output in a fixed buffer, the crate has no heap.
+    struct Output([u8; 256], usize);
+    impl Write for Output {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            let end = self.1 + s.len();
+            self.0.get_mut(self.1..end).ok_or(fmt::Error)?.copy_from_slice(s.as_bytes());
+            self.1 = end;
+            Ok(())
+        }
+    }
+
+    let mut writer = Writer::new(MemoryBuffer::new());
+    writer.set_color(Colors::Yellow, Colors::Black);
+    write!(writer, "ok\x07").unwrap();
+    let mut output = Output([0; 256], 0);
+    writer.dump_row(BUFFER_HEIGHT - 1, &mut output).unwrap();
+    let mut lines = core::str::from_utf8(&output.0[..output.1]).unwrap().lines();
+
+    let text = lines.next().unwrap();
+    assert_eq!(&text[..8], "24 |ok. ");
+    assert_eq!(text.len(), 4 + BUFFER_WIDTH + 1);
+    let colors = lines.next().unwrap();
+    assert_eq!(&colors[..10], "24 #0e0e0e");
+    assert_eq!(colors.len(), 4 + 2 * BUFFER_WIDTH);
+    assert_eq!(lines.next(), None);
+}